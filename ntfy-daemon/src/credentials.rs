@@ -3,8 +3,11 @@ use std::collections::HashMap;
 use std::rc::Rc;
 use std::sync::{Arc, RwLock};
 
+use anyhow::Context;
 use async_trait::async_trait;
 
+use crate::models;
+
 #[derive(Clone)]
 pub struct KeyringItem {
     attributes: HashMap<String, String>,
@@ -78,6 +81,51 @@ impl LightKeyring for RealKeyring {
     }
 }
 
+// Encrypted keyring stored in a local file, for systems without a Secret Service
+struct FileKeyring {
+    keyring: oo7::portal::Keyring,
+}
+
+#[async_trait]
+impl LightKeyring for FileKeyring {
+    async fn search_items(
+        &self,
+        attributes: HashMap<&str, &str>,
+    ) -> anyhow::Result<Vec<KeyringItem>> {
+        let items = self.keyring.search_items(attributes).await?;
+
+        Ok(items
+            .into_iter()
+            .map(|item| KeyringItem {
+                attributes: item
+                    .attributes()
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                secret: item.secret().to_vec(),
+            })
+            .collect())
+    }
+
+    async fn create_item(
+        &self,
+        label: &str,
+        attributes: HashMap<&str, &str>,
+        secret: &str,
+        replace: bool,
+    ) -> anyhow::Result<()> {
+        self.keyring
+            .create_item(label, attributes, secret, replace)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, attributes: HashMap<&str, &str>) -> anyhow::Result<()> {
+        self.keyring.delete(attributes).await?;
+        Ok(())
+    }
+}
+
 struct NullableKeyring {
     search_response: Vec<KeyringItem>,
 }
@@ -131,16 +179,64 @@ impl NullableKeyring {
     }
 }
 
+pub const UNAVAILABLE_MESSAGE: &str = "Password storage unavailable; accounts disabled.";
+
+// Used when the Secret Service can't be reached. Nothing can be stored, so accounts are disabled.
+struct UnavailableKeyring {}
+
+#[async_trait]
+impl LightKeyring for UnavailableKeyring {
+    async fn search_items(
+        &self,
+        _attributes: HashMap<&str, &str>,
+    ) -> anyhow::Result<Vec<KeyringItem>> {
+        Ok(vec![])
+    }
+
+    async fn create_item(
+        &self,
+        _label: &str,
+        _attributes: HashMap<&str, &str>,
+        _secret: &str,
+        _replace: bool,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!(UNAVAILABLE_MESSAGE)
+    }
+
+    async fn delete(&self, _attributes: HashMap<&str, &str>) -> anyhow::Result<()> {
+        anyhow::bail!(UNAVAILABLE_MESSAGE)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Credential {
     pub username: String,
     pub password: String,
 }
 
+#[derive(Clone)]
+struct StoredCredential {
+    credential: Credential,
+    // The server as saved in the keyring, needed to delete the item
+    keyring_server: String,
+}
+
+// Where passwords are stored
+#[derive(Clone)]
+pub enum Backend {
+    SecretService,
+    File {
+        path: std::path::PathBuf,
+        passphrase: String,
+    },
+}
+
 #[derive(Clone)]
 pub struct Credentials {
     keyring: Arc<dyn LightKeyring + Send + Sync>,
-    creds: Arc<RwLock<HashMap<String, Credential>>>,
+    // Keyed by `models::canonical_server`
+    creds: Arc<RwLock<HashMap<String, StoredCredential>>>,
+    available: bool,
 }
 
 impl Credentials {
@@ -149,21 +245,56 @@ impl Credentials {
             keyring: Arc::new(RealKeyring {
                 keyring: oo7::Keyring::new()
                     .await
-                    .expect("Failed to start Secret Service"),
+                    .context("Failed to start Secret Service")?,
             }),
             creds: Default::default(),
+            available: true,
         };
         this.load().await?;
         Ok(this)
     }
+    pub async fn new_file(path: &std::path::Path, passphrase: &str) -> anyhow::Result<Self> {
+        use sha2::Digest;
+        // The file keyring wants a 64 bytes secret. It's then stretched with PBKDF2 by oo7.
+        let secret = oo7::portal::Secret::from(sha2::Sha512::digest(passphrase).to_vec());
+        let mut this = Self {
+            keyring: Arc::new(FileKeyring {
+                keyring: oo7::portal::Keyring::load(path, secret)
+                    .await
+                    .context("Failed to open the keyring file")?,
+            }),
+            creds: Default::default(),
+            available: true,
+        };
+        this.load().await?;
+        Ok(this)
+    }
+    pub async fn with_backend(backend: &Backend) -> anyhow::Result<Self> {
+        match backend {
+            Backend::SecretService => Self::new().await,
+            Backend::File { path, passphrase } => Self::new_file(path, passphrase).await,
+        }
+    }
     pub async fn new_nullable(credentials: Vec<Credential>) -> anyhow::Result<Self> {
         let mut this = Self {
             keyring: Arc::new(NullableKeyring::with_credentials(credentials)),
             creds: Default::default(),
+            available: true,
         };
         this.load().await?;
         Ok(this)
     }
+    // Credentials without any storage. Anonymous subscriptions keep working.
+    pub fn new_unavailable() -> Self {
+        Self {
+            keyring: Arc::new(UnavailableKeyring {}),
+            creds: Default::default(),
+            available: false,
+        }
+    }
+    pub fn is_available(&self) -> bool {
+        self.available
+    }
     pub async fn load(&mut self) -> anyhow::Result<()> {
         let attrs = HashMap::from([("type", "password")]);
         let values = self.keyring.search_items(attrs).await?;
@@ -173,25 +304,42 @@ impl Credentials {
         for item in values {
             let attrs = item.attributes().await;
             lock.insert(
-                attrs["server"].to_string(),
-                Credential {
-                    username: attrs["username"].to_string(),
-                    password: std::str::from_utf8(&item.secret().await)?.to_string(),
+                models::canonical_server(&attrs["server"]),
+                StoredCredential {
+                    credential: Credential {
+                        username: attrs["username"].to_string(),
+                        password: std::str::from_utf8(&item.secret().await)?.to_string(),
+                    },
+                    keyring_server: attrs["server"].to_string(),
                 },
             );
         }
         Ok(())
     }
+    // Checks whether the Secret Service can be reached, without loading any item
+    pub async fn is_secret_service_available() -> bool {
+        oo7::Keyring::new().await.is_ok()
+    }
     pub fn get(&self, server: &str) -> Option<Credential> {
-        self.creds.read().unwrap().get(server).cloned()
+        self.creds
+            .read()
+            .unwrap()
+            .get(&models::canonical_server(server))
+            .map(|stored| stored.credential.clone())
     }
     pub fn list_all(&self) -> HashMap<String, Credential> {
-        self.creds.read().unwrap().clone()
+        self.creds
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(server, stored)| (server.clone(), stored.credential.clone()))
+            .collect()
     }
     pub async fn insert(&self, server: &str, username: &str, password: &str) -> anyhow::Result<()> {
+        let server = &models::canonical_server(server);
         {
-            if let Some(cred) = self.creds.read().unwrap().get(server) {
-                if cred.username != username {
+            if let Some(stored) = self.creds.read().unwrap().get(server) {
+                if stored.credential.username != username {
                     anyhow::bail!("You can add only one account per server");
                 }
             }
@@ -207,15 +355,19 @@ impl Credentials {
 
         self.creds.write().unwrap().insert(
             server.to_string(),
-            Credential {
-                username: username.to_string(),
-                password: password.to_string(),
+            StoredCredential {
+                credential: Credential {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                },
+                keyring_server: server.to_string(),
             },
         );
         Ok(())
     }
     pub async fn delete(&self, server: &str) -> anyhow::Result<()> {
-        let creds = {
+        let server = &models::canonical_server(server);
+        let stored = {
             self.creds
                 .read()
                 .unwrap()
@@ -225,8 +377,8 @@ impl Credentials {
         };
         let attrs = HashMap::from([
             ("type", "password"),
-            ("username", &creds.username),
-            ("server", server),
+            ("username", stored.credential.username.as_str()),
+            ("server", stored.keyring_server.as_str()),
         ]);
         self.keyring.delete(attrs).await?;
         self.creds
@@ -237,3 +389,68 @@ impl Credentials {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_credentials_roundtrip() {
+        let path =
+            std::env::temp_dir().join(format!("notify-test-{}.keyring", rand::random::<u64>()));
+
+        let creds = Credentials::new_file(&path, "passphrase").await.unwrap();
+        creds
+            .insert("https://ntfy.sh", "user", "secret")
+            .await
+            .unwrap();
+
+        // Reopen the file to check the password was persisted
+        let creds = Credentials::new_file(&path, "passphrase").await.unwrap();
+        let cred = creds.get("https://ntfy.sh").unwrap();
+        assert_eq!(cred.username, "user");
+        assert_eq!(cred.password, "secret");
+
+        creds.delete("https://ntfy.sh").await.unwrap();
+        let creds = Credentials::new_file(&path, "passphrase").await.unwrap();
+        assert!(creds.get("https://ntfy.sh").is_none());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_international_server_credentials() {
+        let path =
+            std::env::temp_dir().join(format!("notify-test-{}.keyring", rand::random::<u64>()));
+        let unicode = "https://notify.café.example";
+        let punycode = "https://notify.xn--caf-dma.example";
+
+        // Saved as typed, before servers were canonicalized
+        let creds = Credentials::new_file(&path, "passphrase").await.unwrap();
+        creds
+            .keyring
+            .create_item(
+                "Password",
+                HashMap::from([
+                    ("type", "password"),
+                    ("username", "user"),
+                    ("server", unicode),
+                ]),
+                "secret",
+                true,
+            )
+            .await
+            .unwrap();
+
+        let creds = Credentials::new_file(&path, "passphrase").await.unwrap();
+        assert_eq!(creds.get(punycode).unwrap().password, "secret");
+        assert_eq!(creds.get(unicode).unwrap().password, "secret");
+        assert!(creds.insert(unicode, "other", "pass").await.is_err());
+
+        creds.delete(punycode).await.unwrap();
+        let creds = Credentials::new_file(&path, "passphrase").await.unwrap();
+        assert!(creds.get(unicode).is_none());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::http_client::HttpClient;
+use crate::models;
+
+// Sender icons are small avatars, anything bigger is probably not an icon
+pub const MAX_ICON_SIZE: usize = 1_000_000; // 1 MB
+
+// Keeps downloaded files on disk, like the sender icons, so they are downloaded only once.
+// Files are keyed by the hash of their url.
+#[derive(Clone, Debug)]
+pub struct DownloadCache {
+    dir: PathBuf,
+    // Bigger files are not downloaded
+    max_size: usize,
+}
+
+impl DownloadCache {
+    pub fn new(dir: impl Into<PathBuf>, max_size: usize) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size,
+        }
+    }
+    // The caches inside `cache_dir`, the app uses them to read what the daemon downloaded
+    pub fn icons(cache_dir: &Path) -> Self {
+        Self::new(cache_dir.join("icons"), MAX_ICON_SIZE)
+    }
+    pub fn attachments(cache_dir: &Path) -> Self {
+        Self::new(cache_dir.join("attachments"), models::MAX_DISPLAYABLE_SIZE)
+    }
+    pub fn path_for(&self, url: &str) -> PathBuf {
+        let hash = Sha256::digest(url.as_bytes());
+        let name: String = hash.iter().map(|b| format!("{b:02x}")).collect();
+        self.dir.join(name)
+    }
+    pub async fn fetch(&self, http_client: &HttpClient, url: &str) -> anyhow::Result<PathBuf> {
+        let path = self.path_for(url);
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Ok(path);
+        }
+
+        debug!(url, "downloading file");
+        let mut res = http_client
+            .execute(http_client.get(url).build()?)
+            .await?
+            .error_for_status()?;
+        if res.content_length().unwrap_or(0) > self.max_size as u64 {
+            bail!("file at {url} is too big");
+        }
+        // The declared length can be missing or wrong, so the body is checked as it arrives
+        let mut bytes = Vec::new();
+        while let Some(chunk) = res.chunk().await? {
+            if bytes.len() + chunk.len() > self.max_size {
+                bail!("file at {url} is too big");
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("creating {}", self.dir.display()))?;
+        // Written under a temporary name, so a partial file is never used
+        let partial = path.with_extension("part");
+        tokio::fs::write(&partial, &bytes).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::NullableClient;
+
+    #[tokio::test]
+    async fn test_icon_is_downloaded_once() {
+        let dir = std::env::temp_dir().join(format!("notify-icon-cache-{}", std::process::id()));
+        let cache = DownloadCache::new(&dir, MAX_ICON_SIZE);
+        let url = "https://example.com/icon.png";
+        let http_client = HttpClient::new_nullable(
+            NullableClient::builder()
+                .text_response(url, 200, "png bytes")
+                .build(),
+        );
+        let tracker = http_client.request_tracker().await;
+
+        let path = cache.fetch(&http_client, url).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "png bytes");
+        let again = cache.fetch(&http_client, url).await.unwrap();
+        assert_eq!(path, again);
+        assert_eq!(tracker.items().await.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_too_big_file_is_not_stored() {
+        let dir = std::env::temp_dir().join(format!("notify-big-cache-{}", std::process::id()));
+        let cache = DownloadCache::new(&dir, 4);
+        let url = "https://example.com/big.png";
+        let http_client = HttpClient::new_nullable(
+            NullableClient::builder()
+                .text_response(url, 200, "png bytes")
+                .build(),
+        );
+
+        assert!(cache.fetch(&http_client, url).await.is_err());
+        assert!(!cache.path_for(url).exists());
+    }
+}
@@ -0,0 +1,121 @@
+use std::io::{self, Write};
+
+use crate::models::ReceivedMessage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+const CSV_HEADER: &str = "time,title,message,priority,tags";
+
+// Quotes the field only when needed, as described by RFC 4180
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn iso_time(time: u64) -> String {
+    chrono::DateTime::from_timestamp(time as i64, 0)
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default()
+}
+
+// Writes the messages one at a time, so the whole export never needs to be in memory
+pub fn write_messages<W: Write>(
+    messages: impl IntoIterator<Item = ReceivedMessage>,
+    format: ExportFormat,
+    mut w: W,
+) -> io::Result<()> {
+    match format {
+        ExportFormat::Json => {
+            w.write_all(b"[")?;
+            for (i, msg) in messages.into_iter().enumerate() {
+                if i > 0 {
+                    w.write_all(b",")?;
+                }
+                w.write_all(b"\n")?;
+                serde_json::to_writer(&mut w, &msg)?;
+            }
+            w.write_all(b"\n]\n")?;
+        }
+        ExportFormat::Csv => {
+            writeln!(w, "{CSV_HEADER}")?;
+            for msg in messages {
+                writeln!(
+                    w,
+                    "{},{},{},{},{}",
+                    iso_time(msg.time),
+                    csv_field(msg.title.as_deref().unwrap_or_default()),
+                    csv_field(msg.message.as_deref().unwrap_or_default()),
+                    msg.priority.map(|p| p.to_string()).unwrap_or_default(),
+                    csv_field(&msg.tags.join(",")),
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(time: u64, title: Option<&str>, message: &str) -> ReceivedMessage {
+        ReceivedMessage {
+            id: time.to_string(),
+            topic: "test".to_string(),
+            time,
+            title: title.map(|t| t.to_string()),
+            message: Some(message.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let mut msg = message(0, Some("Build"), "failed, \"main\"\nsee logs");
+        msg.priority = Some(5);
+        msg.tags = vec!["warning".to_string(), "ci".to_string()];
+        let mut out = vec![];
+        write_messages([msg, message(60, None, "ok")], ExportFormat::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "time,title,message,priority,tags\n\
+             1970-01-01T00:00:00+00:00,Build,\"failed, \"\"main\"\"\nsee logs\",5,\"warning,ci\"\n\
+             1970-01-01T00:01:00+00:00,,ok,,\n"
+        );
+    }
+
+    #[test]
+    fn test_export_json() {
+        let mut out = vec![];
+        write_messages(
+            [message(1, None, "a"), message(2, None, "b")],
+            ExportFormat::Json,
+            &mut out,
+        )
+        .unwrap();
+        let parsed: Vec<ReceivedMessage> = serde_json::from_slice(&out).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].message.as_deref(), Some("b"));
+
+        let mut out = vec![];
+        write_messages([], ExportFormat::Json, &mut out).unwrap();
+        let parsed: Vec<ReceivedMessage> = serde_json::from_slice(&out).unwrap();
+        assert!(parsed.is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixListener;
+use tokio::task::{spawn_local, JoinHandle};
+use tracing::{info, warn};
+
+use crate::NtfyHandle;
+
+// Overall state of the daemon, for external monitors
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    pub uptime_secs: u64,
+    pub subscriptions: usize,
+    pub connected: usize,
+    // Subscriptions retrying after a connection error
+    pub failing: usize,
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        self.failing == 0
+    }
+}
+
+// Removes the socket file when the server stops, so it isn't left behind
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.0) {
+            Ok(()) => info!(path = %self.0.display(), "removed health check socket"),
+            Err(e) => {
+                warn!(error = %e, path = %self.0.display(), "couldn't remove health check socket")
+            }
+        }
+    }
+}
+
+// Every client connecting to the socket receives the current health as a JSON line.
+// Aborting the returned task stops the server and removes the socket.
+pub(crate) fn serve(ntfy: NtfyHandle, path: PathBuf) -> anyhow::Result<JoinHandle<()>> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // A socket nobody answers on was left over by a run that didn't stop cleanly.
+    // One that answers belongs to another running daemon and is kept.
+    match std::os::unix::net::UnixStream::connect(&path) {
+        Ok(_) => anyhow::bail!(
+            "another instance already serves health checks at {}",
+            path.display()
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(&path)?;
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(&path)?;
+    let socket_file = SocketFile(path);
+    info!(path = %socket_file.0.display(), "serving health checks");
+
+    Ok(spawn_local(async move {
+        let _socket_file = socket_file;
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!(error = %e, "health check socket closed");
+                    break;
+                }
+            };
+            let res = async {
+                let mut json = serde_json::to_string(&ntfy.health().await?)?;
+                json.push('\n');
+                stream.write_all(json.as_bytes()).await?;
+                anyhow::Ok(())
+            };
+            if let Err(e) = res.await {
+                warn!(error = %e, "couldn't answer the health check");
+            }
+        }
+    }))
+}
+
+// Asks the health to the daemon serving on `path`
+pub fn query(path: &Path) -> anyhow::Result<Health> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+    let mut json = String::new();
+    stream.read_to_string(&mut json)?;
+    Ok(serde_json::from_str(&json)?)
+}
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use futures::StreamExt;
 use reqwest::{header::HeaderMap, Client, Request, RequestBuilder, Response, ResponseBuilderExt};
 use serde_json::{json, Value};
 use std::collections::{HashMap, VecDeque};
@@ -35,6 +36,7 @@ impl RequestInfo {
 trait LightHttpClient: Send + Sync {
     fn get(&self, url: &str) -> RequestBuilder;
     fn post(&self, url: &str) -> RequestBuilder;
+    fn delete(&self, url: &str) -> RequestBuilder;
     async fn execute(&self, request: Request) -> Result<Response>;
 }
 
@@ -48,6 +50,10 @@ impl LightHttpClient for Client {
         self.post(url)
     }
 
+    fn delete(&self, url: &str) -> RequestBuilder {
+        self.delete(url)
+    }
+
     async fn execute(&self, request: Request) -> Result<Response> {
         Ok(self.execute(request).await?)
     }
@@ -56,34 +62,93 @@ impl LightHttpClient for Client {
 #[derive(Clone)]
 pub struct HttpClient {
     client: Arc<dyn LightHttpClient>,
+    // Used for the servers in `http1_servers`
+    http1_client: Arc<dyn LightHttpClient>,
+    // Origins of the servers forced to HTTP/1.1
+    http1_servers: Arc<std::sync::RwLock<Vec<String>>>,
     request_tracker: OutputTrackerAsync<RequestInfo>,
 }
 
 impl HttpClient {
     pub fn new(client: reqwest::Client) -> Self {
+        let client = Arc::new(client);
         Self {
-            client: Arc::new(client),
+            http1_client: client.clone(),
+            client,
+            http1_servers: Default::default(),
             request_tracker: Default::default(),
         }
     }
     pub fn new_nullable(client: NullableClient) -> Self {
+        let client = Arc::new(client);
         Self {
-            client: Arc::new(client),
+            http1_client: client.clone(),
+            client,
+            http1_servers: Default::default(),
             request_tracker: Default::default(),
         }
     }
 
+    // The client used for the servers set with `set_http1_servers`
+    pub fn with_http1_client(mut self, client: reqwest::Client) -> Self {
+        self.http1_client = Arc::new(client);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_http1_nullable(mut self, client: NullableClient) -> Self {
+        self.http1_client = Arc::new(client);
+        self
+    }
+
+    // Some reverse proxies break long lived HTTP/2 streams,
+    // the requests to these servers use HTTP/1.1 instead.
+    // Returns whether the servers changed.
+    pub fn set_http1_servers(&self, servers: &[String]) -> bool {
+        let origins: Vec<String> = servers
+            .iter()
+            .filter_map(|s| url::Url::parse(s).ok())
+            .map(|url| url.origin().ascii_serialization())
+            .collect();
+        let mut http1_servers = self.http1_servers.write().unwrap();
+        if *http1_servers == origins {
+            return false;
+        }
+        *http1_servers = origins;
+        true
+    }
+
+    pub fn uses_http1(&self, url: &str) -> bool {
+        let Ok(url) = url::Url::parse(url) else {
+            return false;
+        };
+        let origin = url.origin().ascii_serialization();
+        self.http1_servers.read().unwrap().contains(&origin)
+    }
+
+    fn client_for(&self, url: &str) -> &Arc<dyn LightHttpClient> {
+        if self.uses_http1(url) {
+            &self.http1_client
+        } else {
+            &self.client
+        }
+    }
+
     pub async fn request_tracker(&self) -> OutputTrackerAsync<RequestInfo> {
         self.request_tracker.enable().await;
         self.request_tracker.clone()
     }
 
     pub fn get(&self, url: &str) -> RequestBuilder {
-        self.client.get(url)
+        self.client_for(url).get(url)
     }
 
     pub fn post(&self, url: &str) -> RequestBuilder {
-        self.client.post(url)
+        self.client_for(url).post(url)
+    }
+
+    pub fn delete(&self, url: &str) -> RequestBuilder {
+        self.client_for(url).delete(url)
     }
 
     pub async fn execute(&self, request: Request) -> Result<Response> {
@@ -91,7 +156,8 @@ impl HttpClient {
             .push(RequestInfo::from_request(&request))
             .await;
 
-        Ok(self.client.execute(request).await?)
+        let client = self.client_for(request.url().as_str()).clone();
+        Ok(client.execute(request).await?)
     }
 }
 
@@ -99,6 +165,7 @@ impl HttpClient {
 pub struct NullableClient {
     responses: Arc<RwLock<HashMap<String, VecDeque<Response>>>>,
     default_response: Arc<RwLock<Option<Box<dyn Fn() -> Response + Send + Sync + 'static>>>>,
+    echo: Option<EchoServer>,
 }
 
 /// Builder for configuring NullableClient
@@ -106,6 +173,84 @@ pub struct NullableClient {
 pub struct NullableClientBuilder {
     responses: HashMap<String, VecDeque<Response>>,
     default_response: Option<Box<dyn Fn() -> Response + Send + Sync + 'static>>,
+    echo: bool,
+}
+
+/// Acts like a ntfy server for the requests without a configured response:
+/// published messages are stored and sent to the `/<topics>/json` streams
+#[derive(Clone)]
+struct EchoServer {
+    // Published messages, as sent by a server, replayed to every new stream
+    published: Arc<std::sync::Mutex<Vec<String>>>,
+    tx: tokio::sync::broadcast::Sender<String>,
+}
+
+impl EchoServer {
+    fn new() -> Self {
+        Self {
+            published: Default::default(),
+            tx: tokio::sync::broadcast::channel(64).0,
+        }
+    }
+
+    fn respond(&self, request: &Request) -> Option<Response> {
+        if request.method() == reqwest::Method::POST {
+            let body = request.body()?.as_bytes()?;
+            let mut msg: Value = serde_json::from_slice(body).ok()?;
+            msg.get("topic")?;
+            let mut published = self.published.lock().unwrap();
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            msg["id"] = json!((published.len() + 1).to_string());
+            msg["time"] = json!(now);
+            msg["event"] = json!("message");
+            let msg = msg.to_string();
+            published.push(msg.clone());
+            let _ = self.tx.send(msg.clone());
+            return Some(
+                http::response::Builder::new()
+                    .status(200)
+                    .body(msg)
+                    .unwrap()
+                    .into(),
+            );
+        }
+
+        let mut segments = request.url().path_segments()?.rev();
+        if segments.next()? != "json" {
+            return None;
+        }
+        let topic_list = segments.next()?.to_string();
+        let topics: Vec<String> = topic_list.split(',').map(String::from).collect();
+        let (history, rx) = {
+            let published = self.published.lock().unwrap();
+            (published.clone(), self.tx.subscribe())
+        };
+        let published = futures::stream::iter(history).chain(
+            tokio_stream::wrappers::BroadcastStream::new(rx).filter_map(|msg| async { msg.ok() }),
+        );
+        let messages = published.filter(move |msg| {
+            let topic = serde_json::from_str::<Value>(msg)
+                .ok()
+                .and_then(|v| v["topic"].as_str().map(String::from));
+            let matches = topic.is_some_and(|topic| topics.contains(&topic));
+            async move { matches }
+        });
+        let open =
+            json!({"id": "open", "time": 0, "event": "open", "topic": topic_list}).to_string();
+        let body = futures::stream::once(async { open })
+            .chain(messages)
+            .map(|line| Ok::<_, std::io::Error>(line + "\n"));
+        Some(
+            http::response::Builder::new()
+                .status(200)
+                .body(reqwest::Body::wrap_stream(body))
+                .unwrap()
+                .into(),
+        )
+    }
 }
 
 impl NullableClientBuilder {
@@ -167,8 +312,37 @@ impl NullableClientBuilder {
         self.response(url, response)
     }
 
+    /// Helper method to add a text response whose connection drops after the body
+    pub fn interrupted_response(
+        self,
+        url: impl Into<String>,
+        status: u16,
+        body: impl Into<String>,
+    ) -> Self {
+        let chunks: Vec<Result<String, std::io::Error>> = vec![
+            Ok(body.into()),
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "connection dropped",
+            )),
+        ];
+        let response = http::response::Builder::new()
+            .status(status)
+            .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap()
+            .into();
+        self.response(url, response)
+    }
+
+    /// Answer like a ntfy server, echoing the published messages on the topic streams
+    pub fn echo_published(mut self) -> Self {
+        self.echo = true;
+        self
+    }
+
     pub fn build(self) -> NullableClient {
         NullableClient {
+            echo: self.echo.then(EchoServer::new),
             responses: Arc::new(RwLock::new(
                 self.responses
                     .into_iter()
@@ -196,6 +370,10 @@ impl LightHttpClient for NullableClient {
         Client::new().post(url)
     }
 
+    fn delete(&self, url: &str) -> RequestBuilder {
+        Client::new().delete(url)
+    }
+
     async fn execute(&self, request: Request) -> Result<Response> {
         time::sleep(Duration::from_millis(1)).await;
         let url = request.url().to_string();
@@ -215,6 +393,8 @@ impl LightHttpClient for NullableClient {
                     Err(anyhow::anyhow!("no response configured for URL: {}", url))
                 }
             }
+        } else if let Some(response) = self.echo.as_ref().and_then(|e| e.respond(&request)) {
+            Ok(response)
         } else if let Some(default_fn) = &*self.default_response.read().await {
             Ok(default_fn())
         } else {
@@ -321,4 +501,29 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_http1_servers_use_the_http1_client() -> Result<()> {
+        let url = "https://proxied.example.com/topic/json";
+        let http_client = HttpClient::new_nullable(
+            NullableClient::builder()
+                .text_response(url, 200, "default")
+                .build(),
+        )
+        .with_http1_nullable(
+            NullableClient::builder()
+                .text_response(url, 200, "http1")
+                .build(),
+        );
+
+        let request = http_client.get(url).build()?;
+        assert_eq!(http_client.execute(request).await?.text().await?, "default");
+
+        assert!(http_client.set_http1_servers(&["https://proxied.example.com".to_string()]));
+        assert!(http_client.uses_http1(url));
+        assert!(!http_client.uses_http1("https://ntfy.sh/topic/json"));
+        let request = http_client.get(url).build()?;
+        assert_eq!(http_client.execute(request).await?.text().await?, "http1");
+        Ok(())
+    }
 }
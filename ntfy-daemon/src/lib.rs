@@ -1,21 +1,29 @@
 mod actor_utils;
 pub mod credentials;
+mod download_cache;
+pub mod export;
+pub mod health;
 mod http_client;
 mod listener;
 pub mod message_repo;
 pub mod models;
+mod multiplexer;
 mod ntfy;
 mod output_tracker;
 pub mod retry;
 mod subscription;
 
+pub use download_cache::DownloadCache;
 pub use listener::*;
 pub use ntfy::start;
+pub use ntfy::start_oneshot;
+pub use ntfy::start_with_env;
 pub use ntfy::NtfyHandle;
-use std::sync::Arc;
+pub use ntfy::SubscribeOutcome;
+use std::sync::{Arc, RwLock};
 pub use subscription::SubscriptionHandle;
 
-use http_client::HttpClient;
+pub use http_client::{HttpClient, NullableClient, NullableClientBuilder};
 
 #[derive(Clone)]
 pub struct SharedEnv {
@@ -24,12 +32,110 @@ pub struct SharedEnv {
     http_client: HttpClient,
     network_monitor: Arc<dyn models::NetworkMonitorProxy>,
     credentials: credentials::Credentials,
+    notification_settings: Arc<RwLock<models::NotificationSettings>>,
+    icon_cache: DownloadCache,
+    // Images of the messages of the subscriptions downloading them on receipt
+    attachment_cache: DownloadCache,
+    // Shared by all the listeners
+    connection_limiter: ConnectionLimiter,
+    message_transforms: Arc<RwLock<Vec<Arc<dyn models::MessageTransform>>>>,
+}
+
+impl SharedEnv {
+    pub fn builder() -> SharedEnvBuilder {
+        SharedEnvBuilder::default()
+    }
+}
+
+// The parts left unset are nullable: an in-memory database, no notifications, no network
+// access (every request fails) and no stored accounts.
+#[derive(Default)]
+pub struct SharedEnvBuilder {
+    db: Option<message_repo::Db>,
+    notifier: Option<Arc<dyn models::NotificationProxy>>,
+    http_client: Option<HttpClient>,
+    network_monitor: Option<Arc<dyn models::NetworkMonitorProxy>>,
+    credentials: Option<credentials::Credentials>,
+    cache_dir: Option<std::path::PathBuf>,
+}
+
+impl SharedEnvBuilder {
+    pub fn db(mut self, db: message_repo::Db) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    pub fn notifier(mut self, notifier: Arc<dyn models::NotificationProxy>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    pub fn http_client(mut self, http_client: HttpClient) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    pub fn network_monitor(
+        mut self,
+        network_monitor: Arc<dyn models::NetworkMonitorProxy>,
+    ) -> Self {
+        self.network_monitor = Some(network_monitor);
+        self
+    }
+
+    pub fn credentials(mut self, credentials: credentials::Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    // Sender icons and attachments are downloaded in its subdirectories
+    pub fn cache_dir(mut self, dir: impl Into<std::path::PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<SharedEnv> {
+        let db = match self.db {
+            Some(db) => db,
+            None => message_repo::Db::connect(":memory:")?,
+        };
+        let credentials = match self.credentials {
+            Some(credentials) => credentials,
+            None => credentials::Credentials::new_nullable(vec![]).await?,
+        };
+        // Each env gets its own directory, so that envs never share cached files
+        let cache_dir = self.cache_dir.unwrap_or_else(|| {
+            std::env::temp_dir().join(format!("ntfy-daemon-{:016x}", rand::random::<u64>()))
+        });
+        Ok(SharedEnv {
+            db,
+            notifier: self
+                .notifier
+                .unwrap_or_else(|| Arc::new(models::NullNotifier::new())),
+            http_client: self
+                .http_client
+                .unwrap_or_else(|| HttpClient::new_nullable(NullableClient::builder().build())),
+            network_monitor: self
+                .network_monitor
+                .unwrap_or_else(|| Arc::new(models::NullNetworkMonitor::new())),
+            credentials,
+            notification_settings: Default::default(),
+            icon_cache: DownloadCache::icons(&cache_dir),
+            attachment_cache: DownloadCache::attachments(&cache_dir),
+            connection_limiter: Default::default(),
+            message_transforms: Default::default(),
+        })
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("topic {0} must not be empty and must contain only alphanumeric characters and _ (underscore)")]
     InvalidTopic(String),
+    #[error("topic {0} is reserved by the ntfy server and can't be subscribed to")]
+    ReservedTopic(String),
+    #[error("invalid notification filter {0:?}")]
+    InvalidNotifyRegex(String, #[source] regex::Error),
     #[error("invalid server base url {0:?}")]
     InvalidServer(#[from] url::ParseError),
     #[error("multiple errors in subscription model: {0:?}")]
@@ -42,6 +148,27 @@ pub enum Error {
     InvalidMessage(String, #[source] serde_json::Error),
     #[error("database error")]
     Db(#[from] rusqlite::Error),
+    #[error("message {0} not found")]
+    MessageNotFound(String),
     #[error("subscription not found while {0}")]
     SubscriptionNotFound(String),
+    #[error("{0} isn't a link to a topic")]
+    InvalidDeepLink(String),
+    #[error("can't reach the server {0}: {1}")]
+    ServerUnreachable(String, String),
+    #[error("wrong username or password for {0}")]
+    InvalidCredentials(String),
+    #[error("{0} doesn't have accounts, the server has no authentication configured")]
+    AccountsUnsupported(String),
+    #[error("unexpected response from {0}: HTTP {1}")]
+    UnexpectedResponse(String, u16),
+    #[error("server rejected the message (HTTP {status}): {reason}")]
+    PublishRejected {
+        status: u16,
+        reason: String,
+        // The whole response body, the server's JSON error
+        body: String,
+    },
+    #[error("only a single read-only SELECT is allowed: {0}")]
+    WriteQuery(String),
 }
@@ -1,15 +1,14 @@
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncBufReadExt;
 use tokio::task::{self, spawn_local, LocalSet};
 use tokio::{
     select,
-    sync::{mpsc, oneshot},
+    sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore},
 };
-use tokio_stream::wrappers::LinesStream;
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
 use tracing::{debug, error, info, warn, Instrument, Span};
 
 use crate::credentials::Credentials;
@@ -41,6 +40,11 @@ pub enum ServerEvent {
 pub enum ListenerEvent {
     Message(models::ReceivedMessage),
     ConnectionStateChanged(ConnectionState),
+    // Everything up to `time` was received, even if no message arrived
+    KeepAlive { time: u64 },
+    // The server accepted the connection. `expires` is when the server will stop caching
+    // the topic, if it has a limit.
+    Opened { expires: Option<u64> },
 }
 
 #[derive(Clone)]
@@ -50,11 +54,94 @@ pub struct ListenerConfig {
     pub(crate) endpoint: String,
     pub(crate) topic: String,
     pub(crate) since: u64,
+    pub(crate) limiter: ConnectionLimiter,
+}
+
+pub const DEFAULT_MAX_CONCURRENT_CONNECTIONS: usize = 4;
+// Time between two connection starts
+const CONNECTION_STAGGER: Duration = Duration::from_millis(250);
+
+// Many listeners connecting at once, e.g. at startup or when the network comes back,
+// would trip the rate limits of the server. At most `limit` connections are being
+// established at the same time, and their starts are spaced by `CONNECTION_STAGGER`.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimiter {
+    semaphore: Arc<std::sync::Mutex<(usize, Arc<Semaphore>)>>,
+    next_start: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            semaphore: Arc::new(std::sync::Mutex::new((
+                limit,
+                Arc::new(Semaphore::new(limit)),
+            ))),
+            next_start: Arc::new(std::sync::Mutex::new(Instant::now())),
+        }
+    }
+    // The connections already waiting keep the previous limit
+    pub fn set_limit(&self, limit: usize) {
+        let limit = limit.max(1);
+        let mut semaphore = self.semaphore.lock().unwrap();
+        if semaphore.0 != limit {
+            *semaphore = (limit, Arc::new(Semaphore::new(limit)));
+        }
+    }
+    // Held while the connection is being established
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        let semaphore = self.semaphore.lock().unwrap().1.clone();
+        let permit = semaphore
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let start = {
+            let mut next_start = self.next_start.lock().unwrap();
+            let start = (*next_start).max(Instant::now());
+            *next_start = start + CONNECTION_STAGGER;
+            start
+        };
+        tokio::time::sleep_until(start.into()).await;
+        permit
+    }
+}
+
+impl Default for ConnectionLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CONCURRENT_CONNECTIONS)
+    }
+}
+
+// Counters of a listener since it was created, to diagnose unstable connections
+#[derive(Clone, Debug, Default)]
+pub struct ListenerStats {
+    pub reconnects: u64,
+    // Connections that were established and then lost
+    pub closed_connections: u64,
+    // Total time spent by the closed connections
+    pub closed_connections_uptime: Duration,
+    pub last_disconnect_reason: Option<String>,
+    pub bytes_received: u64,
+    pub messages_received: u64,
+    // Lines that couldn't be parsed and were skipped
+    pub invalid_lines: u64,
+}
+
+impl ListenerStats {
+    pub fn average_connection_uptime(&self) -> Option<Duration> {
+        u32::try_from(self.closed_connections)
+            .ok()
+            .filter(|n| *n > 0)
+            .map(|n| self.closed_connections_uptime / n)
+    }
 }
 
 #[derive(Debug)]
 pub enum ListenerCommand {
     Restart,
+    // Restart, fetching again all the messages after the timestamp
+    RestartSince(u64),
     Shutdown,
     GetState(oneshot::Sender<ConnectionState>),
 }
@@ -63,7 +150,7 @@ fn topic_request(
     client: &HttpClient,
     endpoint: &str,
     topic: &str,
-    since: u64,
+    since: &models::Since,
     username: Option<&str>,
     password: Option<&str>,
 ) -> anyhow::Result<reqwest::Request> {
@@ -79,11 +166,15 @@ fn topic_request(
     Ok(req.build()?)
 }
 
-async fn response_lines(
-    res: impl tokio::io::AsyncBufRead,
-) -> Result<impl futures::Stream<Item = Result<String, std::io::Error>>, reqwest::Error> {
-    let lines = LinesStream::new(res.lines());
-    Ok(lines)
+// Well above the message size limit of the servers, longer lines are skipped
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+// Skipped lines are logged at most once in this interval
+const INVALID_LINE_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+fn parse_event(line: &str) -> Result<ServerEvent, Error> {
+    serde_json::from_str::<models::MinMessage>(line)
+        .map_err(|e| Error::InvalidMinMessage(line.to_string(), e))?;
+    serde_json::from_str(line).map_err(|e| Error::InvalidMessage(line.to_string(), e))
 }
 
 #[derive(Clone, Debug)]
@@ -97,11 +188,45 @@ pub enum ConnectionState {
     },
 }
 
+impl ConnectionState {
+    // Reconnections, including the first connection, are a degraded status
+    pub fn status(&self) -> models::Status {
+        match self {
+            ConnectionState::Unitialized => models::Status::Degraded,
+            ConnectionState::Connected => models::Status::Up,
+            ConnectionState::Reconnecting { .. } => models::Status::Degraded,
+        }
+    }
+    // The server refused the connection because the topic requires credentials,
+    // or the stored ones are wrong
+    pub fn needs_login(&self) -> bool {
+        let ConnectionState::Reconnecting {
+            error: Some(error), ..
+        } = self
+        else {
+            return false;
+        };
+        error
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status())
+            .is_some_and(|status| {
+                status == reqwest::StatusCode::UNAUTHORIZED
+                    || status == reqwest::StatusCode::FORBIDDEN
+            })
+    }
+}
+
 pub struct ListenerActor {
     pub event_tx: async_channel::Sender<ListenerEvent>,
     pub commands_rx: Option<mpsc::Receiver<ListenerCommand>>,
     pub config: ListenerConfig,
     pub state: ConnectionState,
+    pub stats: Arc<std::sync::Mutex<ListenerStats>>,
+    // The last message received, to resume exactly after it when reconnecting
+    pub last_message_id: Option<String>,
+    // Lines skipped since the last warning, and when it was logged
+    pub invalid_lines_unreported: u64,
+    pub invalid_lines_warned_at: Option<Instant>,
 }
 
 impl ListenerActor {
@@ -121,6 +246,12 @@ impl ListenerActor {
                                 info!("restarting listener");
                                 continue;
                             }
+                            Some(ListenerCommand::RestartSince(since)) => {
+                                info!(since, "restarting listener");
+                                self.config.since = since;
+                                self.last_message_id = None;
+                                continue;
+                            }
                             Some(ListenerCommand::Shutdown) => {
                                 info!("shutting down listener");
                                 break;
@@ -152,6 +283,24 @@ impl ListenerActor {
             .await
             .unwrap();
     }
+    // A bad line doesn't break the stream, so the connection is kept.
+    // A broken server could send many of them, the warnings are rate limited.
+    fn skip_invalid_line(&mut self, error: &dyn std::error::Error) {
+        debug!(error = %error, "skipping invalid line");
+        self.stats.lock().unwrap().invalid_lines += 1;
+        self.invalid_lines_unreported += 1;
+        let now = Instant::now();
+        if self
+            .invalid_lines_warned_at
+            .is_some_and(|at| now.duration_since(at) < INVALID_LINE_WARNING_INTERVAL)
+        {
+            return;
+        }
+        warn!(error = %error, skipped = self.invalid_lines_unreported, "skipped invalid lines");
+        self.invalid_lines_unreported = 0;
+        self.invalid_lines_warned_at = Some(now);
+    }
+
     async fn run_supervised_loop(&mut self) {
         let span = tracing::info_span!("supervised_loop");
         async {
@@ -167,6 +316,15 @@ impl ListenerActor {
 
                 if let Err(e) = self.recv_and_forward_loop().await {
                     let uptime = std::time::Instant::now().duration_since(start_time);
+                    {
+                        let mut stats = self.stats.lock().unwrap();
+                        stats.reconnects += 1;
+                        if matches!(self.state, ConnectionState::Connected) {
+                            stats.closed_connections += 1;
+                            stats.closed_connections_uptime += uptime;
+                        }
+                        stats.last_disconnect_reason = Some(e.to_string());
+                    }
                     // Reset retry delay to minimum if uptime was decent enough
                     if uptime > Duration::from_secs(60 * 4) {
                         debug!("resetting retry delay due to sufficient uptime");
@@ -190,11 +348,19 @@ impl ListenerActor {
         .await;
     }
 
+    // The first connection starts from the configured time
+    fn since(&self) -> models::Since {
+        match &self.last_message_id {
+            Some(id) => models::Since::Message(id.clone()),
+            None => models::Since::Time(self.config.since),
+        }
+    }
+
     async fn recv_and_forward_loop(&mut self) -> anyhow::Result<()> {
         let span = tracing::info_span!("receive_loop",
             endpoint = %self.config.endpoint,
             topic = %self.config.topic,
-            since = %self.config.since
+            since = %self.since()
         );
         async {
             let creds = self.config.credentials.get(&self.config.endpoint);
@@ -203,11 +369,13 @@ impl ListenerActor {
                 &self.config.http_client,
                 &self.config.endpoint,
                 &self.config.topic,
-                self.config.since,
+                &self.since(),
                 creds.as_ref().map(|x| x.username.as_str()),
                 creds.as_ref().map(|x| x.password.as_str()),
             );
 
+            // Released once connected or failed
+            let permit = self.config.limiter.acquire().await;
             debug!("executing request");
             let res = self.config.http_client.execute(req?).await?;
             let res = res.error_for_status()?;
@@ -215,36 +383,64 @@ impl ListenerActor {
                 res.bytes_stream()
                     .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
             );
-            let stream = response_lines(reader).await?;
-            tokio::pin!(stream);
+            let mut stream =
+                FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
 
             self.set_state(ConnectionState::Connected).await;
+            drop(permit);
             info!("connection established");
 
             info!(topic = %&self.config.topic, "listening");
-            while let Some(msg) = stream.next().await {
-                let msg = msg?;
-
-                let min_msg = serde_json::from_str::<models::MinMessage>(&msg)
-                    .map_err(|e| Error::InvalidMinMessage(msg.to_string(), e))?;
-                self.config.since = min_msg.time.max(self.config.since);
-
-                let event = serde_json::from_str(&msg)
-                    .map_err(|e| Error::InvalidMessage(msg.to_string(), e))?;
+            while let Some(line) = stream.next().await {
+                let msg = match line {
+                    Ok(msg) => msg,
+                    // The codec discards the rest of the line
+                    Err(e @ LinesCodecError::MaxLineLengthExceeded) => {
+                        self.skip_invalid_line(&e);
+                        continue;
+                    }
+                    Err(LinesCodecError::Io(e)) => return Err(e.into()),
+                };
+                // Without the newline and before decompression, close enough to compare connections
+                self.stats.lock().unwrap().bytes_received += msg.len() as u64;
+
+                let event = match parse_event(&msg) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        self.skip_invalid_line(&e);
+                        continue;
+                    }
+                };
 
+                // The open event is sent before the older messages, so its time can't advance `since`:
+                // a reconnection during the replay would skip the rest of it
                 match event {
                     ServerEvent::Message(msg) => {
+                        self.stats.lock().unwrap().messages_received += 1;
+                        self.config.since = msg.time.max(self.config.since);
+                        self.last_message_id = Some(msg.id.clone());
                         debug!(id = %msg.id, "forwarding message");
                         self.event_tx
                             .send(ListenerEvent::Message(msg))
                             .await
                             .unwrap();
                     }
-                    ServerEvent::KeepAlive { id, .. } => {
+                    ServerEvent::KeepAlive { id, time, .. } => {
                         debug!(id = %id, "received keepalive");
+                        self.config.since = (time as u64).max(self.config.since);
+                        self.event_tx
+                            .send(ListenerEvent::KeepAlive { time: time as u64 })
+                            .await
+                            .unwrap();
                     }
-                    ServerEvent::Open { id, .. } => {
-                        debug!(id = %id, "received open event");
+                    ServerEvent::Open { id, expires, .. } => {
+                        debug!(id = %id, ?expires, "received open event");
+                        self.event_tx
+                            .send(ListenerEvent::Opened {
+                                expires: expires.map(|e| e as u64),
+                            })
+                            .await
+                            .unwrap();
                     }
                 }
             }
@@ -262,14 +458,24 @@ pub struct ListenerHandle {
     pub events: async_channel::Receiver<ListenerEvent>,
     pub config: ListenerConfig,
     pub commands: mpsc::Sender<ListenerCommand>,
+    pub stats: Arc<std::sync::Mutex<ListenerStats>>,
 }
 
 impl ListenerHandle {
     pub fn new(config: ListenerConfig) -> ListenerHandle {
+        Self::with_stats(config, Default::default())
+    }
+
+    // The stats are accumulated in `stats`, e.g. to keep counting across listeners
+    pub fn with_stats(
+        config: ListenerConfig,
+        stats: Arc<std::sync::Mutex<ListenerStats>>,
+    ) -> ListenerHandle {
         let (event_tx, event_rx) = async_channel::bounded(64);
         let (commands_tx, commands_rx) = mpsc::channel(1);
 
         let config_clone = config.clone();
+        let stats_clone = stats.clone();
 
         // use a new local set to isolate panics
         let local_set = LocalSet::new();
@@ -279,6 +485,10 @@ impl ListenerHandle {
                 commands_rx: Some(commands_rx),
                 config: config_clone,
                 state: ConnectionState::Unitialized,
+                stats: stats_clone,
+                last_message_id: None,
+                invalid_lines_unreported: 0,
+                invalid_lines_warned_at: None,
             };
 
             this.run_loop().await;
@@ -289,9 +499,40 @@ impl ListenerHandle {
             events: event_rx,
             config,
             commands: commands_tx,
+            stats,
         }
     }
 
+    // A listener without a connection. Events sent to the returned sender are received
+    // as if they came from the server.
+    #[cfg(test)]
+    pub(crate) fn new_nullable(
+        config: ListenerConfig,
+    ) -> (ListenerHandle, async_channel::Sender<ListenerEvent>) {
+        let (event_tx, event_rx) = async_channel::bounded(64);
+        let (commands_tx, mut commands_rx) = mpsc::channel(1);
+
+        spawn_local(async move {
+            while let Some(cmd) = commands_rx.recv().await {
+                if let ListenerCommand::GetState(tx) = cmd {
+                    let _ = tx.send(ConnectionState::Connected);
+                }
+            }
+        });
+
+        let this = Self {
+            events: event_rx,
+            config,
+            commands: commands_tx,
+            stats: Default::default(),
+        };
+        (this, event_tx)
+    }
+
+    pub fn stats(&self) -> ListenerStats {
+        self.stats.lock().unwrap().clone()
+    }
+
     // the response will be sent as an event in self.events
     pub async fn state(&self) -> ConnectionState {
         let (tx, rx) = oneshot::channel();
@@ -305,7 +546,7 @@ impl ListenerHandle {
 
 #[cfg(test)]
 mod tests {
-    use models::Subscription;
+    use models::{Since, Subscription};
     use serde_json::json;
     use task::LocalSet;
 
@@ -313,13 +554,28 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_connection_state_status() {
+        assert_eq!(
+            ConnectionState::Unitialized.status(),
+            models::Status::Degraded
+        );
+        assert_eq!(ConnectionState::Connected.status(), models::Status::Up);
+        let reconnecting = ConnectionState::Reconnecting {
+            retry_count: 1,
+            delay: Duration::from_secs(1),
+            error: None,
+        };
+        assert_eq!(reconnecting.status(), models::Status::Degraded);
+    }
+
     #[tokio::test]
     async fn test_listener_reconnects_on_http_status_500() {
         let local_set = LocalSet::new();
         local_set
             .spawn_local(async {
                 let http_client = HttpClient::new_nullable({
-                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
+                    let url = Subscription::build_url("http://localhost", "test", &Since::Time(0)).unwrap();
                     let nullable = NullableClient::builder()
                         .text_response(url.clone(), 500, "failed")
                         .json_response(url, 200, json!({"id":"SLiKI64DOt","time":1635528757,"event":"open","topic":"mytopic"})).unwrap()
@@ -334,6 +590,7 @@ mod tests {
                     endpoint: "http://localhost".to_string(),
                     topic: "test".to_string(),
                     since: 0,
+                    limiter: Default::default(),
                 };
 
                 let listener = ListenerHandle::new(config.clone());
@@ -353,17 +610,24 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_listener_reconnects_on_invalid_message() {
+    async fn test_listener_skips_invalid_lines() {
         let local_set = LocalSet::new();
         local_set
             .spawn_local(async {
                 let http_client = HttpClient::new_nullable({
-                    let url = Subscription::build_url("http://localhost", "test", 0).unwrap();
-                    let nullable = NullableClient::builder()
-                        .text_response(url.clone(), 200, "invalid message")
-                        .json_response(url, 200, json!({"id":"SLiKI64DOt","time":1635528757,"event":"open","topic":"mytopic"})).unwrap()
-                        .build();
-                    nullable
+                    let url = Subscription::build_url("http://localhost", "test", &Since::Time(0)).unwrap();
+                    NullableClient::builder()
+                        .text_response(
+                            url,
+                            200,
+                            [
+                                "invalid message".to_string(),
+                                "x".repeat(MAX_LINE_LENGTH + 1),
+                                json!({"id":"1","time":100,"event":"message","topic":"test","message":"hi"}).to_string(),
+                            ]
+                            .join("\n"),
+                        )
+                        .build()
                 });
                 let credentials = Credentials::new_nullable(vec![]).await.unwrap();
 
@@ -373,21 +637,210 @@ mod tests {
                     endpoint: "http://localhost".to_string(),
                     topic: "test".to_string(),
                     since: 0,
+                    limiter: Default::default(),
                 };
 
                 let listener = ListenerHandle::new(config.clone());
-                let items: Vec<_> = listener.events.take(3).collect().await;
+                let items: Vec<_> = listener.events.clone().take(3).collect().await;
 
                 dbg!(&items);
                 assert!(matches!(
                     &items[..],
-                    &[
+                    [
                         ListenerEvent::ConnectionStateChanged(ConnectionState::Unitialized),
-                        ListenerEvent::ConnectionStateChanged(ConnectionState::Reconnecting { .. }),
-                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected { .. }),
-                    ]
+                        ListenerEvent::ConnectionStateChanged(ConnectionState::Connected),
+                        ListenerEvent::Message(msg),
+                    ] if msg.id == "1"
                 ));
+                let stats = listener.stats();
+                assert_eq!(stats.invalid_lines, 2);
+                assert_eq!(stats.reconnects, 0);
             });
         local_set.await;
     }
+
+    #[tokio::test]
+    async fn test_reconnect_resumes_from_last_event() {
+        let local_set = LocalSet::new();
+        local_set
+            .spawn_local(async {
+                let first_url = Subscription::build_url("http://localhost", "test", &Since::Time(0)).unwrap();
+                // Exactly after the last message, the keepalive doesn't matter
+                let resumed_url = Subscription::build_url(
+                    "http://localhost",
+                    "test",
+                    &Since::Message("1".to_string()),
+                )
+                .unwrap();
+                let http_client = HttpClient::new_nullable(
+                    NullableClient::builder()
+                        .interrupted_response(
+                            first_url,
+                            200,
+                            [
+                                json!({"id":"1","time":100,"event":"message","topic":"test","message":"hi"}).to_string(),
+                                json!({"id":"2","time":150,"event":"keepalive","topic":"test"}).to_string(),
+                            ]
+                            .join("\n") + "\n",
+                        )
+                        .json_response(resumed_url.clone(), 200, json!({"id":"3","time":200,"event":"open","topic":"test"}))
+                        .unwrap()
+                        .build(),
+                );
+                let request_tracker = http_client.request_tracker().await;
+                let credentials = Credentials::new_nullable(vec![]).await.unwrap();
+
+                let config = ListenerConfig {
+                    http_client,
+                    credentials,
+                    endpoint: "http://localhost".to_string(),
+                    topic: "test".to_string(),
+                    since: 0,
+                    limiter: Default::default(),
+                };
+
+                let listener = ListenerHandle::new(config.clone());
+                let mut connections = 0;
+                let mut items = vec![];
+                while connections < 2 {
+                    let item = listener.events.recv().await.unwrap();
+                    if let ListenerEvent::ConnectionStateChanged(ConnectionState::Connected) = item {
+                        connections += 1;
+                    }
+                    items.push(item);
+                }
+
+                dbg!(&items);
+                assert!(items
+                    .iter()
+                    .any(|item| matches!(item, ListenerEvent::KeepAlive { time: 150 })));
+                let urls: Vec<_> = request_tracker
+                    .items()
+                    .await
+                    .into_iter()
+                    .map(|r| r.url)
+                    .collect();
+                assert_eq!(urls.last(), Some(&resumed_url.to_string()));
+            });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_needs_login_on_http_status_401() {
+        let local_set = LocalSet::new();
+        local_set.spawn_local(async {
+            let http_client = HttpClient::new_nullable({
+                let url =
+                    Subscription::build_url("http://localhost", "test", &Since::Time(0)).unwrap();
+                NullableClient::builder()
+                    .text_response(
+                        url,
+                        401,
+                        r#"{"code":40101,"http":401,"error":"unauthorized"}"#,
+                    )
+                    .build()
+            });
+            let credentials = Credentials::new_nullable(vec![]).await.unwrap();
+
+            let config = ListenerConfig {
+                http_client,
+                credentials,
+                endpoint: "http://localhost".to_string(),
+                topic: "test".to_string(),
+                since: 0,
+                limiter: Default::default(),
+            };
+
+            let listener = ListenerHandle::new(config.clone());
+            let items: Vec<_> = listener.events.take(2).collect().await;
+
+            assert!(matches!(
+                &items[..],
+                [
+                    ListenerEvent::ConnectionStateChanged(ConnectionState::Unitialized),
+                    ListenerEvent::ConnectionStateChanged(state),
+                ] if state.needs_login()
+            ));
+        });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_stats_count_dropped_connections() {
+        let local_set = LocalSet::new();
+        local_set.spawn_local(async {
+            let first_url =
+                Subscription::build_url("http://localhost", "test", &Since::Time(0)).unwrap();
+            let resumed_url = Subscription::build_url(
+                "http://localhost",
+                "test",
+                &Since::Message("1".to_string()),
+            )
+            .unwrap();
+            let http_client = HttpClient::new_nullable(
+                NullableClient::builder()
+                    .interrupted_response(
+                        first_url,
+                        200,
+                        json!({"id":"1","time":100,"event":"message","topic":"test","message":"hi"})
+                            .to_string() + "\n",
+                    )
+                    .json_response(
+                        resumed_url,
+                        200,
+                        json!({"id":"2","time":200,"event":"open","topic":"test"}),
+                    )
+                    .unwrap()
+                    .build(),
+            );
+            let credentials = Credentials::new_nullable(vec![]).await.unwrap();
+
+            let listener = ListenerHandle::new(ListenerConfig {
+                http_client,
+                credentials,
+                endpoint: "http://localhost".to_string(),
+                topic: "test".to_string(),
+                since: 0,
+                limiter: Default::default(),
+            });
+            let mut connections = 0;
+            while connections < 2 {
+                let item = listener.events.recv().await.unwrap();
+                if let ListenerEvent::ConnectionStateChanged(ConnectionState::Connected) = item {
+                    connections += 1;
+                }
+            }
+
+            let stats = listener.stats();
+            assert_eq!(stats.reconnects, 1);
+            assert_eq!(stats.closed_connections, 1);
+            assert!(stats.average_connection_uptime().is_some());
+            assert_eq!(stats.messages_received, 1);
+            assert!(stats.bytes_received > 0);
+            assert!(stats
+                .last_disconnect_reason
+                .is_some_and(|r| r.contains("connection dropped")));
+        });
+        local_set.await;
+    }
+
+    #[tokio::test]
+    async fn test_connection_limiter_limits_and_staggers() {
+        let limiter = ConnectionLimiter::new(1);
+        let started = Instant::now();
+        let first = limiter.acquire().await;
+
+        let waiting = limiter.acquire();
+        tokio::pin!(waiting);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut waiting)
+                .await
+                .is_err(),
+            "a second connection started while the first was being established"
+        );
+
+        drop(first);
+        let _second = waiting.await;
+        assert!(started.elapsed() >= CONNECTION_STAGGER);
+    }
 }
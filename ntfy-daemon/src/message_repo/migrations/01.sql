@@ -0,0 +1,11 @@
+-- Time of the last message received, used as the `since` parameter when reconnecting.
+-- `read_until` is only the read marker, so it can't be used for that.
+ALTER TABLE subscription ADD COLUMN last_received_time INTEGER NOT NULL DEFAULT 0;
+
+UPDATE subscription
+SET last_received_time = COALESCE(
+  (SELECT MAX(m.data ->> '$.time')
+    FROM message m
+    WHERE m.server = subscription.server AND m.topic = subscription.topic),
+  read_until
+);
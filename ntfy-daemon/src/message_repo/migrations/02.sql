@@ -0,0 +1,2 @@
+-- Optional pattern a message must match to show a notification. NULL notifies for every message.
+ALTER TABLE subscription ADD COLUMN notify_regex TEXT;
@@ -0,0 +1,2 @@
+-- How long notifications stay visible: 'default', 'transient' or 'resident'
+ALTER TABLE subscription ADD COLUMN notification_persistence TEXT NOT NULL DEFAULT 'default';
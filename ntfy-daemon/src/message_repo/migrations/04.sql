@@ -0,0 +1,11 @@
+-- Per message read state. Every message up to the subscription `read_until` is read,
+-- so the time index can skip most of the messages when counting the unread ones.
+ALTER TABLE message ADD COLUMN read INTEGER NOT NULL DEFAULT 0;
+
+UPDATE message
+SET read = 1
+WHERE data ->> '$.time' <= (
+  SELECT s.read_until
+  FROM subscription s
+  WHERE s.server = message.server AND s.topic = message.topic
+);
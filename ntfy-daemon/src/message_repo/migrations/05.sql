@@ -0,0 +1,2 @@
+-- Path of the sound played with the notifications of the subscription. NULL plays the default one.
+ALTER TABLE subscription ADD COLUMN sound TEXT;
@@ -0,0 +1,11 @@
+-- Position of the subscription in the sidebar, chosen by the user.
+-- The existing subscriptions keep the previous alphabetical order.
+ALTER TABLE subscription ADD COLUMN order_index INTEGER NOT NULL DEFAULT 0;
+UPDATE subscription SET order_index = (
+  SELECT ordered.position FROM (
+    SELECT sub.rowid AS id, ROW_NUMBER() OVER (ORDER BY server.endpoint, sub.display_name, sub.topic) AS position
+    FROM subscription sub
+    JOIN server ON server.id = sub.server
+  ) ordered
+  WHERE ordered.id = subscription.rowid
+);
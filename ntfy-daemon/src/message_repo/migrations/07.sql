@@ -0,0 +1,2 @@
+-- Unix time until which the notifications of the subscription are snoozed, 0 if not snoozed.
+ALTER TABLE subscription ADD COLUMN snoozed_until INTEGER NOT NULL DEFAULT 0;
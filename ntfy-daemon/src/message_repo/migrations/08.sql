@@ -0,0 +1,2 @@
+-- Download the images attached to new messages on receipt, to view them offline.
+ALTER TABLE subscription ADD COLUMN auto_download_attachments INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Show the messages of the subscription in a monospace font, for logs and scripts.
+ALTER TABLE subscription ADD COLUMN code_messages INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Priority last chosen in the composer, used for the next messages published to the topic.
+ALTER TABLE subscription ADD COLUMN publish_priority INTEGER NOT NULL DEFAULT 3;
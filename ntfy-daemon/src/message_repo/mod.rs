@@ -1,12 +1,95 @@
 use std::sync::{Arc, RwLock};
 use std::{cell::RefCell, rc::Rc};
 
-use rusqlite::{params, Connection, Result};
-use tracing::info;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Result};
+use tracing::{info, warn};
 
 use crate::models;
 use crate::Error;
 
+// Applied in order. The number of applied migrations is the highest version in the `schema_version` table.
+const MIGRATIONS: &[&str] = &[
+    include_str!("./migrations/00.sql"),
+    include_str!("./migrations/01.sql"),
+    include_str!("./migrations/02.sql"),
+    include_str!("./migrations/03.sql"),
+    include_str!("./migrations/04.sql"),
+    include_str!("./migrations/05.sql"),
+    include_str!("./migrations/06.sql"),
+    include_str!("./migrations/07.sql"),
+    include_str!("./migrations/08.sql"),
+    include_str!("./migrations/09.sql"),
+    include_str!("./migrations/10.sql"),
+];
+
+// Applies, in order and only once, the migrations newer than the recorded schema version.
+// Every migration runs in its own transaction, together with the update of the version.
+fn migrate_up_to(conn: &mut Connection, target: usize) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+          version INTEGER PRIMARY KEY,
+          applied_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );",
+    )?;
+    let mut version: usize = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )?;
+    if version == 0 {
+        // Databases created before the schema_version table kept the version in user_version
+        version = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > 0 {
+            conn.execute(
+                "INSERT INTO schema_version (version) VALUES (?1)",
+                params![version],
+            )?;
+        }
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().take(target).skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            params![i + 1],
+        )?;
+        tx.commit()?;
+        info!(version = i + 1, "applied database migration");
+    }
+    Ok(())
+}
+
+/// Stored messages of a topic, parsed. Rows that no longer deserialize are skipped.
+#[derive(Clone, Debug, Default)]
+pub struct ParsedMessages {
+    pub messages: Vec<models::ReceivedMessage>,
+    pub dropped: usize,
+}
+
+// Read by `subscription_from_row`, in this order
+const SUBSCRIPTION_COLUMNS: &str = "server.endpoint, sub.topic, sub.display_name, sub.reserved, sub.muted, sub.archived, sub.symbolic_icon, sub.read_until, sub.last_received_time, sub.notify_regex, sub.notification_persistence, sub.sound, sub.snoozed_until, sub.auto_download_attachments, sub.code_messages, sub.publish_priority";
+
+fn subscription_from_row(row: &rusqlite::Row) -> Result<models::Subscription> {
+    Ok(models::Subscription {
+        server: row.get(0)?,
+        topic: row.get(1)?,
+        display_name: row.get(2)?,
+        reserved: row.get(3)?,
+        muted: row.get(4)?,
+        archived: row.get(5)?,
+        symbolic_icon: row.get(6)?,
+        read_until: row.get(7)?,
+        last_received_time: row.get(8)?,
+        notify_regex: row.get(9)?,
+        notification_persistence: row.get::<_, String>(10)?.parse().unwrap_or_default(),
+        sound: row.get(11)?,
+        snoozed_until: row.get(12)?,
+        auto_download_attachments: row.get(13)?,
+        code_messages: row.get(14)?,
+        publish_priority: row.get(15)?,
+    })
+}
+
 #[derive(Clone, Debug)]
 pub struct Db {
     conn: Arc<RwLock<Connection>>,
@@ -14,9 +97,7 @@ pub struct Db {
 
 impl Db {
     pub fn connect(path: &str) -> Result<Self> {
-        let mut this = Self {
-            conn: Arc::new(RwLock::new(Connection::open(path)?)),
-        };
+        let mut this = Self::from_connection(Connection::open(path)?);
         {
             this.conn.read().unwrap().execute_batch(
                 "PRAGMA foreign_keys = ON;
@@ -24,16 +105,68 @@ impl Db {
             )?;
         }
         this.migrate()?;
+        this.canonicalize_servers()?;
         Ok(this)
     }
+    // Reads the database of a running instance without changing it: the file isn't
+    // created, migrated or switched to WAL. A missing file is an error.
+    pub fn open_readonly(path: &std::path::Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(Self::from_connection(conn))
+    }
+    fn from_connection(conn: Connection) -> Self {
+        Self {
+            conn: Arc::new(RwLock::new(conn)),
+        }
+    }
     fn migrate(&mut self) -> Result<()> {
-        self.conn
-            .read()
-            .unwrap()
-            .execute_batch(include_str!("./migrations/00.sql"))?;
+        let mut conn = self.conn.write().unwrap();
+        migrate_up_to(&mut conn, MIGRATIONS.len())
+    }
+    // Servers stored as typed, before `models::canonical_server`, e.g. with an international
+    // host name, are rewritten so they are found again
+    fn canonicalize_servers(&mut self) -> Result<()> {
+        let mut conn = self.conn.write().unwrap();
+        let tx = conn.transaction()?;
+        let servers: Vec<(i64, String)> = tx
+            .prepare("SELECT id, endpoint FROM server")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_>>()?;
+        for (id, endpoint) in servers {
+            let canonical = models::canonical_server(&endpoint);
+            if canonical == endpoint {
+                continue;
+            }
+            let res = tx.execute(
+                "UPDATE server SET endpoint = ?2 WHERE id = ?1",
+                params![id, canonical],
+            );
+            match res {
+                Ok(_) => info!(endpoint, canonical, "canonicalized stored server"),
+                // Both forms were stored, the subscriptions of each stay separate
+                Err(e) => warn!(error=?e, endpoint, canonical, "can't canonicalize stored server"),
+            }
+        }
+        tx.commit()
+    }
+    // Size of the database, excluding the WAL file
+    pub fn size(&self) -> Result<u64> {
+        let conn = self.conn.read().unwrap();
+        let page_count: u64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let page_size: u64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        Ok(page_count * page_size)
+    }
+    // Rebuilds the database file to give the free pages back to the filesystem and truncates the WAL.
+    // VACUUM can't run inside a transaction and needs exclusive access,
+    // so every other query is blocked until it's done.
+    pub fn vacuum(&self) -> Result<()> {
+        let conn = self.conn.write().unwrap();
+        conn.execute_batch("VACUUM;")?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
         Ok(())
     }
     fn get_or_insert_server(&mut self, server: &str) -> Result<i64> {
+        let server = &models::canonical_server(server);
         let mut conn = self.conn.write().unwrap();
         let tx = conn.transaction()?;
         let mut res = tx.query_row(
@@ -57,6 +190,7 @@ impl Db {
         res
     }
     pub fn insert_message(&mut self, server: &str, json_data: &str) -> Result<(), Error> {
+        let server = &models::canonical_server(server);
         let server_id = self.get_or_insert_server(server)?;
         let res = self.conn.read().unwrap().execute(
             "INSERT INTO message (server, data) VALUES (?1, ?2)",
@@ -78,6 +212,7 @@ impl Db {
         topic: &str,
         since: u64,
     ) -> Result<Vec<String>, rusqlite::Error> {
+        let server = &models::canonical_server(server);
         let conn = self.conn.read().unwrap();
         let mut stmt = conn.prepare(
             "
@@ -94,22 +229,85 @@ impl Db {
             .collect();
         msgs
     }
-    pub fn insert_subscription(&mut self, sub: models::Subscription) -> Result<(), Error> {
+    // None if the subscription has no message `id`, e.g. because it was cleared
+    pub fn get_message(
+        &self,
+        server: &str,
+        topic: &str,
+        id: &str,
+    ) -> Result<Option<models::ReceivedMessage>, Error> {
+        let server = &models::canonical_server(server);
+        let conn = self.conn.read().unwrap();
+        let data: Option<String> = conn
+            .query_row(
+                "SELECT m.data
+                FROM message m
+                JOIN server s ON m.server = s.id
+                WHERE s.endpoint = ?1 AND m.topic = ?2 AND m.data ->> '$.id' = ?3",
+                params![server, topic, id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match data {
+            Some(data) => match serde_json::from_str(&data) {
+                Ok(msg) => Ok(Some(msg)),
+                Err(e) => Err(Error::InvalidMessage(data, e)),
+            },
+            None => Ok(None),
+        }
+    }
+    pub fn list_messages_parsed(
+        &self,
+        server: &str,
+        topic: &str,
+        since: u64,
+    ) -> Result<ParsedMessages, Error> {
+        let server = &models::canonical_server(server);
+        let mut parsed = ParsedMessages::default();
+        for data in self.list_messages(server, topic, since)? {
+            match serde_json::from_str(&data) {
+                Ok(msg) => parsed.messages.push(msg),
+                Err(e) => {
+                    warn!(error = ?e, server, topic, "skipping malformed stored message");
+                    parsed.dropped += 1;
+                }
+            }
+        }
+        Ok(parsed)
+    }
+    // Subscribing again to a stored topic (like an archived one) keeps its messages,
+    // read state and settings, only unarchiving it. Returns the stored subscription.
+    pub fn insert_subscription(
+        &mut self,
+        sub: models::Subscription,
+    ) -> Result<models::Subscription, Error> {
         let server_id = self.get_or_insert_server(&sub.server)?;
         self.conn.read().unwrap().execute(
-            "INSERT INTO subscription (server, topic, display_name, reserved, muted, archived) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            "INSERT INTO subscription (server, topic, display_name, reserved, muted, archived, last_received_time, notify_regex, notification_persistence, sound, auto_download_attachments, code_messages, publish_priority, order_index)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, (SELECT COALESCE(MAX(order_index), 0) + 1 FROM subscription))
+            ON CONFLICT (server, topic) DO UPDATE
+            SET archived = excluded.archived,
+              last_received_time = MAX(subscription.last_received_time, excluded.last_received_time)",
             params![
                 server_id,
                 sub.topic,
                 sub.display_name,
                 sub.reserved,
                 sub.muted,
-                sub.archived
+                sub.archived,
+                sub.last_received_time,
+                sub.notify_regex,
+                sub.notification_persistence.as_str(),
+                sub.sound,
+                sub.auto_download_attachments,
+                sub.code_messages,
+                sub.publish_priority,
             ],
         )?;
-        Ok(())
+        self.get_subscription(&sub.server, &sub.topic)
     }
     pub fn remove_subscription(&mut self, server: &str, topic: &str) -> Result<(), Error> {
+        let server = &models::canonical_server(server);
         let server_id = self.get_or_insert_server(server)?;
         let res = self.conn.read().unwrap().execute(
             "DELETE FROM subscription
@@ -121,36 +319,64 @@ impl Db {
         }
         Ok(())
     }
+    // Archived subscriptions are left out
     pub fn list_subscriptions(&mut self) -> Result<Vec<models::Subscription>, Error> {
         let conn = self.conn.read().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT server.endpoint, sub.topic, sub.display_name, sub.reserved, sub.muted, sub.archived, sub.symbolic_icon, sub.read_until
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {SUBSCRIPTION_COLUMNS}
             FROM subscription sub
             JOIN server ON server.id = sub.server
-            ORDER BY server.endpoint, sub.display_name, sub.topic
-            ",
-        )?;
-        let rows = stmt.query_map(params![], |row| {
-            Ok(models::Subscription {
-                server: row.get(0)?,
-                topic: row.get(1)?,
-                display_name: row.get(2)?,
-                reserved: row.get(3)?,
-                muted: row.get(4)?,
-                archived: row.get(5)?,
-                symbolic_icon: row.get(6)?,
-                read_until: row.get(7)?,
-            })
-        })?;
+            WHERE NOT sub.archived
+            ORDER BY sub.order_index, server.endpoint, sub.display_name, sub.topic
+            "
+        ))?;
+        let rows = stmt.query_map(params![], subscription_from_row)?;
         let subs: Result<Vec<_>, rusqlite::Error> = rows.collect();
         Ok(subs?)
     }
+    pub fn get_subscription(
+        &self,
+        server: &str,
+        topic: &str,
+    ) -> Result<models::Subscription, Error> {
+        let server = &models::canonical_server(server);
+        let conn = self.conn.read().unwrap();
+        conn.query_row(
+            &format!(
+                "SELECT {SUBSCRIPTION_COLUMNS}
+                FROM subscription sub
+                JOIN server ON server.id = sub.server
+                WHERE server.endpoint = ?1 AND sub.topic = ?2"
+            ),
+            params![server, topic],
+            subscription_from_row,
+        )
+        .optional()?
+        .ok_or_else(|| Error::SubscriptionNotFound("getting subscription".into()))
+    }
+
+    // Saves the order of the subscriptions, given as (server, topic) pairs
+    pub fn reorder_subscriptions(&mut self, order: &[(String, String)]) -> Result<(), Error> {
+        let mut conn = self.conn.write().unwrap();
+        let tx = conn.transaction()?;
+        for (i, (server, topic)) in order.iter().enumerate() {
+            let server = &models::canonical_server(server);
+            tx.execute(
+                "UPDATE subscription
+                SET order_index = ?1
+                WHERE server = (SELECT id FROM server WHERE endpoint = ?2) AND topic = ?3",
+                params![i + 1, server, topic],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
 
     pub fn update_subscription(&mut self, sub: models::Subscription) -> Result<(), Error> {
         let server_id = self.get_or_insert_server(&sub.server)?;
         let res = self.conn.read().unwrap().execute(
             "UPDATE subscription
-            SET display_name = ?1, reserved = ?2, muted = ?3, archived = ?4, read_until = ?5
+            SET display_name = ?1, reserved = ?2, muted = ?3, archived = ?4, read_until = ?5, notify_regex = ?8, notification_persistence = ?9, sound = ?10, auto_download_attachments = ?11, code_messages = ?12, publish_priority = ?13
             WHERE server = ?6 AND topic = ?7",
             params![
                 sub.display_name,
@@ -160,6 +386,12 @@ impl Db {
                 sub.read_until,
                 server_id,
                 sub.topic,
+                sub.notify_regex,
+                sub.notification_persistence.as_str(),
+                sub.sound,
+                sub.auto_download_attachments,
+                sub.code_messages,
+                sub.publish_priority,
             ],
         )?;
         if res == 0 {
@@ -169,15 +401,39 @@ impl Db {
         Ok(())
     }
 
+    // `value` is a unix time, 0 ends the snooze
+    pub fn update_snoozed_until(
+        &mut self,
+        server: &str,
+        topic: &str,
+        value: u64,
+    ) -> Result<(), Error> {
+        let server = &models::canonical_server(server);
+        let server_id = self.get_or_insert_server(server)?;
+        let res = self.conn.read().unwrap().execute(
+            "UPDATE subscription
+            SET snoozed_until = ?3
+            WHERE server = ?1 AND topic = ?2",
+            params![server_id, topic, value],
+        )?;
+        if res == 0 {
+            return Err(Error::SubscriptionNotFound("updating snoozed_until".into()));
+        }
+        Ok(())
+    }
+
+    // Marks as read every message up to `value`
     pub fn update_read_until(
         &mut self,
         server: &str,
         topic: &str,
         value: u64,
     ) -> Result<(), Error> {
+        let server = &models::canonical_server(server);
         let server_id = self.get_or_insert_server(server).unwrap();
-        let conn = self.conn.read().unwrap();
-        let res = conn.execute(
+        let mut conn = self.conn.write().unwrap();
+        let tx = conn.transaction()?;
+        let res = tx.execute(
             "UPDATE subscription
             SET read_until = ?3
             WHERE topic = ?2 AND server = ?1
@@ -187,9 +443,147 @@ impl Db {
         if res == 0 {
             return Err(Error::SubscriptionNotFound("updating read_until".into()));
         }
+        tx.execute(
+            "UPDATE message
+            SET read = 1
+            WHERE server = ?1 AND topic = ?2 AND data ->> '$.time' <= ?3 AND NOT read",
+            params![server_id, topic, value],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+    pub fn mark_read(&mut self, server: &str, topic: &str, id: &str) -> Result<u64, Error> {
+        self.set_message_read(server, topic, id, true)
+    }
+    // Returns the new `read_until` of the subscription, which is moved back
+    // before the message so that it doesn't count it as read anymore
+    pub fn mark_unread(&mut self, server: &str, topic: &str, id: &str) -> Result<u64, Error> {
+        self.set_message_read(server, topic, id, false)
+    }
+    fn set_message_read(
+        &mut self,
+        server: &str,
+        topic: &str,
+        id: &str,
+        read: bool,
+    ) -> Result<u64, Error> {
+        let server = &models::canonical_server(server);
+        let server_id = self.get_or_insert_server(server)?;
+        let mut conn = self.conn.write().unwrap();
+        let tx = conn.transaction()?;
+        let time: u64 = tx
+            .query_row(
+                "SELECT data ->> '$.time' FROM message
+                WHERE server = ?1 AND topic = ?2 AND data ->> '$.id' = ?3",
+                params![server_id, topic, id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| Error::MessageNotFound(id.to_string()))?;
+        tx.execute(
+            "UPDATE message SET read = ?4
+            WHERE server = ?1 AND topic = ?2 AND data ->> '$.id' = ?3",
+            params![server_id, topic, id, read],
+        )?;
+        if !read {
+            tx.execute(
+                "UPDATE subscription
+                SET read_until = MIN(read_until, ?3)
+                WHERE server = ?1 AND topic = ?2",
+                params![server_id, topic, time.saturating_sub(1)],
+            )?;
+        }
+        let read_until = tx.query_row(
+            "SELECT read_until FROM subscription WHERE server = ?1 AND topic = ?2",
+            params![server_id, topic],
+            |row| row.get(0),
+        )?;
+        tx.commit()?;
+        Ok(read_until)
+    }
+    pub fn unread_count(&self, server: &str, topic: &str) -> Result<u64, Error> {
+        let server = &models::canonical_server(server);
+        let conn = self.conn.read().unwrap();
+        let count = conn.query_row(
+            "SELECT COUNT(*)
+            FROM subscription sub
+            JOIN server s ON sub.server = s.id
+            JOIN message m ON m.server = sub.server AND m.topic = sub.topic
+            WHERE s.endpoint = ?1 AND sub.topic = ?2
+              AND m.data ->> '$.time' > sub.read_until AND NOT m.read",
+            params![server, topic],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+    // Unread messages of a subscription, oldest first
+    pub fn list_unread_messages(&self, server: &str, topic: &str) -> Result<Vec<String>, Error> {
+        let server = &models::canonical_server(server);
+        let conn = self.conn.read().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT m.data
+            FROM subscription sub
+            JOIN server s ON sub.server = s.id
+            JOIN message m ON m.server = sub.server AND m.topic = sub.topic
+            WHERE s.endpoint = ?1 AND sub.topic = ?2
+              AND m.data ->> '$.time' > sub.read_until AND NOT m.read
+            ORDER BY m.data ->> '$.time'",
+        )?;
+        let msgs: Result<Vec<String>, _> = stmt
+            .query_map(params![server, topic], |row| row.get(0))?
+            .collect();
+        Ok(msgs?)
+    }
+    pub fn update_last_received_time(
+        &mut self,
+        server: &str,
+        topic: &str,
+        value: u64,
+    ) -> Result<(), Error> {
+        let server = &models::canonical_server(server);
+        let server_id = self.get_or_insert_server(server)?;
+        let conn = self.conn.read().unwrap();
+        let res = conn.execute(
+            "UPDATE subscription
+            SET last_received_time = MAX(last_received_time, ?3)
+            WHERE topic = ?2 AND server = ?1
+            ",
+            params![server_id, topic, value],
+        )?;
+        if res == 0 {
+            return Err(Error::SubscriptionNotFound(
+                "updating last_received_time".into(),
+            ));
+        }
+        Ok(())
+    }
+    // Forgets everything received for the subscription, so it can be fetched again from scratch
+    pub fn reset_subscription(&mut self, server: &str, topic: &str) -> Result<(), Error> {
+        let server = &models::canonical_server(server);
+        let server_id = self.get_or_insert_server(server)?;
+        let mut conn = self.conn.write().unwrap();
+        let tx = conn.transaction()?;
+        let res = tx.execute(
+            "UPDATE subscription
+            SET read_until = 0, last_received_time = 0
+            WHERE topic = ?2 AND server = ?1
+            ",
+            params![server_id, topic],
+        )?;
+        if res == 0 {
+            return Err(Error::SubscriptionNotFound("resetting subscription".into()));
+        }
+        tx.execute(
+            "DELETE FROM message
+            WHERE topic = ?2 AND server = ?1
+            ",
+            params![server_id, topic],
+        )?;
+        tx.commit()?;
         Ok(())
     }
     pub fn delete_messages(&mut self, server: &str, topic: &str) -> Result<(), Error> {
+        let server = &models::canonical_server(server);
         let server_id = self.get_or_insert_server(server).unwrap();
         let conn = self.conn.read().unwrap();
         let res = conn.execute(
@@ -203,4 +597,653 @@ impl Db {
         }
         Ok(())
     }
+    // Deletes, at once, the messages of every subscription. The history kept by archived
+    // subscriptions stays. Returns how many were deleted.
+    pub fn delete_all_messages(&mut self) -> Result<u64, Error> {
+        let conn = self.conn.read().unwrap();
+        let deleted = conn.execute(
+            "DELETE FROM message
+            WHERE (server, topic) IN (
+              SELECT server, topic FROM subscription WHERE NOT archived
+            )",
+            params![],
+        )?;
+        Ok(deleted as u64)
+    }
+    // Messages received from `since` by each subscription having any, as (server, topic, count)
+    pub fn message_counts_since(&self, since: u64) -> Result<Vec<(String, String, u64)>, Error> {
+        let conn = self.conn.read().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT s.endpoint, m.topic, COUNT(*)
+            FROM message m
+            JOIN server s ON m.server = s.id
+            WHERE m.data ->> '$.time' >= ?1
+            GROUP BY m.server, m.topic
+            ORDER BY s.endpoint, m.topic",
+        )?;
+        let counts = stmt
+            .query_map(params![since], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<_>>()?;
+        Ok(counts)
+    }
+    // Runs a single SELECT for introspection, returning every row as an object keyed by column.
+    // Anything that could change the database is rejected before running.
+    pub fn query_readonly(&self, sql: &str) -> Result<Vec<serde_json::Value>, Error> {
+        let first_word = sql
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        if !matches!(first_word.as_str(), "SELECT" | "WITH") {
+            return Err(Error::WriteQuery(sql.to_string()));
+        }
+        let conn = self.conn.read().unwrap();
+        let mut batch = rusqlite::Batch::new(&conn, sql);
+        let Some(mut stmt) = batch.next()? else {
+            return Err(Error::WriteQuery(sql.to_string()));
+        };
+        // Trailing statements, like a DROP after the SELECT, are never run
+        if batch.next()?.is_some() {
+            return Err(Error::WriteQuery(sql.to_string()));
+        }
+        // SQLite itself knows whether the statement writes, e.g. a CTE feeding an INSERT
+        if !stmt.readonly() {
+            return Err(Error::WriteQuery(sql.to_string()));
+        }
+        let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+        let mut rows = stmt.query([])?;
+        let mut out = vec![];
+        while let Some(row) = rows.next()? {
+            let mut obj = serde_json::Map::new();
+            for (i, name) in columns.iter().enumerate() {
+                obj.insert(name.clone(), json_value(row.get_ref(i)?));
+            }
+            out.push(serde_json::Value::Object(obj));
+        }
+        Ok(out)
+    }
+}
+
+fn json_value(value: rusqlite::types::ValueRef) -> serde_json::Value {
+    use rusqlite::types::ValueRef;
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => f.into(),
+        ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+        // Hex encoded, JSON has no bytes type
+        ValueRef::Blob(b) => b
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+            .into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrating_old_database_keeps_rows() {
+        let path =
+            std::env::temp_dir().join(format!("notify-migration-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut conn = Connection::open(&path).unwrap();
+            migrate_up_to(&mut conn, 1).unwrap();
+            conn.execute_batch(
+                r#"INSERT INTO server (id, endpoint) VALUES (1, 'https://ntfy.sh');
+                INSERT INTO subscription (server, topic, display_name, read_until)
+                VALUES (1, 'test', 'Test', 150);
+                INSERT INTO message (server, data) VALUES
+                  (1, '{"id":"a","topic":"test","time":100}'),
+                  (1, '{"id":"b","topic":"test","time":200}');"#,
+            )
+            .unwrap();
+        }
+
+        let mut db = Db::connect(path.to_str().unwrap()).unwrap();
+        let version: usize = db
+            .conn
+            .read()
+            .unwrap()
+            .query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        let subs = db.list_subscriptions().unwrap();
+        assert_eq!(subs.len(), 1);
+        assert_eq!(subs[0].display_name, "Test");
+        assert_eq!(subs[0].read_until, 150);
+        assert_eq!(subs[0].last_received_time, 200);
+        let messages = db
+            .list_messages_parsed("https://ntfy.sh", "test", 0)
+            .unwrap();
+        assert_eq!(messages.messages.len(), 2);
+        assert_eq!(db.unread_count("https://ntfy.sh", "test").unwrap(), 1);
+
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_last_received_time_is_separate_from_read_until() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .build()
+            .unwrap();
+        db.insert_subscription(sub.clone()).unwrap();
+
+        db.update_last_received_time(&sub.server, &sub.topic, 100)
+            .unwrap();
+        // An older message must not move the marker backwards
+        db.update_last_received_time(&sub.server, &sub.topic, 50)
+            .unwrap();
+        db.update_read_until(&sub.server, &sub.topic, 10).unwrap();
+
+        let stored = &db.list_subscriptions().unwrap()[0];
+        assert_eq!(stored.last_received_time, 100);
+        assert_eq!(stored.read_until, 10);
+    }
+
+    #[test]
+    fn test_list_messages_parsed_skips_malformed() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .build()
+            .unwrap();
+        db.insert_subscription(sub.clone()).unwrap();
+        db.insert_message(
+            &sub.server,
+            r#"{"id":"1","topic":"test","time":100,"message":"hi"}"#,
+        )
+        .unwrap();
+        // Rows aren't validated on insert, so a stored row may not deserialize
+        db.insert_message(
+            &sub.server,
+            r#"{"id":"2","topic":"test","time":101,"priority":"high"}"#,
+        )
+        .unwrap();
+
+        let parsed = db.list_messages_parsed(&sub.server, &sub.topic, 0).unwrap();
+        assert_eq!(parsed.messages.len(), 1);
+        assert_eq!(parsed.messages[0].message.as_deref(), Some("hi"));
+        assert_eq!(parsed.dropped, 1);
+    }
+
+    #[test]
+    fn test_subscription_snooze_is_stored() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .build()
+            .unwrap();
+        db.insert_subscription(sub.clone()).unwrap();
+        db.update_snoozed_until(&sub.server, &sub.topic, 1000)
+            .unwrap();
+        assert_eq!(db.list_subscriptions().unwrap()[0].snoozed_until, 1000);
+
+        // Editing the other settings keeps the snooze
+        db.update_subscription(sub.clone()).unwrap();
+        assert_eq!(db.list_subscriptions().unwrap()[0].snoozed_until, 1000);
+
+        db.update_snoozed_until(&sub.server, &sub.topic, 0).unwrap();
+        assert_eq!(db.list_subscriptions().unwrap()[0].snoozed_until, 0);
+    }
+
+    #[test]
+    fn test_subscription_sound_is_stored() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .sound(Some("/tmp/bell.ogg".to_string()))
+            .build()
+            .unwrap();
+        db.insert_subscription(sub.clone()).unwrap();
+        assert_eq!(
+            db.list_subscriptions().unwrap()[0].sound.as_deref(),
+            Some("/tmp/bell.ogg")
+        );
+
+        let mut sub = sub;
+        sub.sound = None;
+        db.update_subscription(sub).unwrap();
+        assert_eq!(db.list_subscriptions().unwrap()[0].sound, None);
+    }
+
+    #[test]
+    fn test_subscription_publish_priority_is_stored() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .build()
+            .unwrap();
+        assert_eq!(sub.publish_priority, models::DEFAULT_PRIORITY);
+        db.insert_subscription(sub.clone()).unwrap();
+
+        db.update_subscription(models::Subscription {
+            publish_priority: 5,
+            ..sub
+        })
+        .unwrap();
+        assert_eq!(db.list_subscriptions().unwrap()[0].publish_priority, 5);
+
+        let clamped = models::Subscription::builder("test".to_string())
+            .publish_priority(9)
+            .build()
+            .unwrap();
+        assert_eq!(clamped.publish_priority, 5);
+    }
+
+    #[test]
+    fn test_reorder_subscriptions() {
+        let mut db = Db::connect(":memory:").unwrap();
+        for topic in ["a", "b", "c"] {
+            let sub = models::Subscription::builder(topic.to_string())
+                .build()
+                .unwrap();
+            db.insert_subscription(sub).unwrap();
+        }
+        let topics = |db: &mut Db| -> Vec<String> {
+            db.list_subscriptions()
+                .unwrap()
+                .into_iter()
+                .map(|s| s.topic)
+                .collect()
+        };
+        assert_eq!(topics(&mut db), ["a", "b", "c"]);
+
+        let server = models::DEFAULT_SERVER.to_string();
+        db.reorder_subscriptions(&[
+            (server.clone(), "c".to_string()),
+            (server.clone(), "a".to_string()),
+            (server, "b".to_string()),
+        ])
+        .unwrap();
+        assert_eq!(topics(&mut db), ["c", "a", "b"]);
+
+        // New subscriptions go to the end
+        let sub = models::Subscription::builder("0".to_string())
+            .build()
+            .unwrap();
+        db.insert_subscription(sub).unwrap();
+        assert_eq!(topics(&mut db), ["c", "a", "b", "0"]);
+    }
+
+    #[test]
+    fn test_reorder_subscriptions_of_international_server() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let server = "https://notify.café.example".to_string();
+        for topic in ["a", "b"] {
+            let sub = models::Subscription::builder(topic.to_string())
+                .server(server.clone())
+                .build()
+                .unwrap();
+            db.insert_subscription(sub).unwrap();
+        }
+
+        db.reorder_subscriptions(&[(server.clone(), "b".to_string()), (server, "a".to_string())])
+            .unwrap();
+        let topics: Vec<String> = db
+            .list_subscriptions()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.topic)
+            .collect();
+        assert_eq!(topics, ["b", "a"]);
+    }
+
+    #[test]
+    fn test_resubscribe_keeps_history_and_read_state() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .display_name("Test".to_string())
+            .last_received_time(100)
+            .build()
+            .unwrap();
+        db.insert_subscription(sub.clone()).unwrap();
+        db.insert_message(
+            &sub.server,
+            r#"{"id":"1","topic":"test","time":150,"message":"hi"}"#,
+        )
+        .unwrap();
+        db.update_last_received_time(&sub.server, &sub.topic, 150)
+            .unwrap();
+        db.update_read_until(&sub.server, &sub.topic, 150).unwrap();
+
+        // Unsubscribing softly only archives the subscription
+        db.update_subscription(models::Subscription {
+            archived: true,
+            ..db.get_subscription(&sub.server, &sub.topic).unwrap()
+        })
+        .unwrap();
+
+        let again = models::Subscription::builder("test".to_string())
+            .last_received_time(120)
+            .build()
+            .unwrap();
+        let stored = db.insert_subscription(again).unwrap();
+        assert!(!stored.archived);
+        assert_eq!(stored.display_name, "Test");
+        assert_eq!(stored.read_until, 150);
+        assert_eq!(stored.last_received_time, 150);
+        assert_eq!(
+            db.list_messages(&sub.server, &sub.topic, 0).unwrap().len(),
+            1
+        );
+        assert_eq!(db.list_subscriptions().unwrap().len(), 1);
+
+        // Messages missed while archived are fetched from the newer time
+        let later = models::Subscription::builder("test".to_string())
+            .last_received_time(200)
+            .build()
+            .unwrap();
+        let stored = db.insert_subscription(later).unwrap();
+        assert_eq!(stored.last_received_time, 200);
+        assert_eq!(stored.read_until, 150);
+    }
+
+    #[test]
+    fn test_per_message_read_state() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .build()
+            .unwrap();
+        db.insert_subscription(sub.clone()).unwrap();
+        for i in 1..=3 {
+            db.insert_message(
+                &sub.server,
+                &format!(r#"{{"id":"{i}","topic":"test","time":{}}}"#, i * 100),
+            )
+            .unwrap();
+        }
+        assert_eq!(db.unread_count(&sub.server, &sub.topic).unwrap(), 3);
+
+        // A message in the middle can be read alone
+        db.mark_read(&sub.server, &sub.topic, "2").unwrap();
+        assert_eq!(db.unread_count(&sub.server, &sub.topic).unwrap(), 2);
+        let unread = db.list_unread_messages(&sub.server, &sub.topic).unwrap();
+        assert_eq!(unread.len(), 2);
+        assert!(unread[0].contains(r#""id":"1""#));
+        assert!(unread[1].contains(r#""id":"3""#));
+
+        db.update_read_until(&sub.server, &sub.topic, 300).unwrap();
+        assert_eq!(db.unread_count(&sub.server, &sub.topic).unwrap(), 0);
+
+        // Marking an old message unread moves read_until back before it
+        let read_until = db.mark_unread(&sub.server, &sub.topic, "1").unwrap();
+        assert_eq!(read_until, 99);
+        assert_eq!(db.unread_count(&sub.server, &sub.topic).unwrap(), 1);
+
+        assert!(matches!(
+            db.mark_read(&sub.server, &sub.topic, "missing"),
+            Err(Error::MessageNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_reset_subscription() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .build()
+            .unwrap();
+        db.insert_subscription(sub.clone()).unwrap();
+        db.insert_message(
+            &sub.server,
+            r#"{"id":"1","topic":"test","time":100,"message":"hi"}"#,
+        )
+        .unwrap();
+        db.update_last_received_time(&sub.server, &sub.topic, 100)
+            .unwrap();
+        db.update_read_until(&sub.server, &sub.topic, 100).unwrap();
+
+        db.reset_subscription(&sub.server, &sub.topic).unwrap();
+
+        assert!(db
+            .list_messages(&sub.server, &sub.topic, 0)
+            .unwrap()
+            .is_empty());
+        let stored = &db.list_subscriptions().unwrap()[0];
+        assert_eq!(stored.last_received_time, 0);
+        assert_eq!(stored.read_until, 0);
+    }
+
+    #[test]
+    fn test_vacuum_reclaims_deleted_messages() {
+        let path =
+            std::env::temp_dir().join(format!("notify-test-{}.sqlite", rand::random::<u64>()));
+        let mut db = Db::connect(path.to_str().unwrap()).unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .build()
+            .unwrap();
+        db.insert_subscription(sub.clone()).unwrap();
+        let body = "x".repeat(1000);
+        for i in 0..200 {
+            db.insert_message(
+                &sub.server,
+                &format!(r#"{{"id":"{i}","topic":"test","time":{i},"message":"{body}"}}"#),
+            )
+            .unwrap();
+        }
+        db.delete_messages(&sub.server, &sub.topic).unwrap();
+
+        let before = db.size().unwrap();
+        db.vacuum().unwrap();
+        assert!(db.size().unwrap() < before);
+
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[test]
+    fn test_international_server_is_stored_once() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let unicode = "https://notify.café.example";
+        let punycode = "https://notify.xn--caf-dma.example";
+        let sub = models::Subscription::builder("test".to_string())
+            .server(unicode.to_string())
+            .build()
+            .unwrap();
+        assert_eq!(sub.server, punycode);
+        db.insert_subscription(sub).unwrap();
+        db.insert_message(unicode, r#"{"id":"1","topic":"test","time":100}"#)
+            .unwrap();
+        db.insert_message(punycode, r#"{"id":"2","topic":"test","time":200}"#)
+            .unwrap();
+
+        let servers = db.query_readonly("SELECT endpoint FROM server").unwrap();
+        assert_eq!(servers, vec![serde_json::json!({"endpoint": punycode})]);
+        assert_eq!(db.list_messages(unicode, "test", 0).unwrap().len(), 2);
+        assert!(db.get_subscription(unicode, "test").is_ok());
+    }
+
+    #[test]
+    fn test_stored_international_server_is_canonicalized() {
+        let path =
+            std::env::temp_dir().join(format!("notify-test-{}.sqlite", rand::random::<u64>()));
+        {
+            let mut conn = Connection::open(&path).unwrap();
+            migrate_up_to(&mut conn, MIGRATIONS.len()).unwrap();
+            conn.execute_batch(
+                "INSERT INTO server (id, endpoint) VALUES (1, 'https://notify.café.example');
+                INSERT INTO subscription (server, topic, display_name) VALUES (1, 'test', 'Test');",
+            )
+            .unwrap();
+        }
+
+        let mut db = Db::connect(path.to_str().unwrap()).unwrap();
+        let subs = db.list_subscriptions().unwrap();
+        assert_eq!(subs[0].server, "https://notify.xn--caf-dma.example");
+
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_message_counts_since() {
+        let mut db = Db::connect(":memory:").unwrap();
+        for topic in ["alerts", "backups"] {
+            let sub = models::Subscription::builder(topic.to_string())
+                .build()
+                .unwrap();
+            db.insert_subscription(sub).unwrap();
+        }
+        let server = models::DEFAULT_SERVER;
+        for (id, topic, time) in [
+            ("1", "backups", 100),
+            ("2", "backups", 200),
+            ("3", "backups", 300),
+            ("4", "alerts", 50),
+        ] {
+            db.insert_message(
+                server,
+                &format!(r#"{{"id":"{id}","topic":"{topic}","time":{time}}}"#),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            db.message_counts_since(200).unwrap(),
+            vec![(server.to_string(), "backups".to_string(), 2)]
+        );
+        assert_eq!(db.message_counts_since(0).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_get_message() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .build()
+            .unwrap();
+        db.insert_subscription(sub.clone()).unwrap();
+        db.insert_message(
+            &sub.server,
+            r#"{"id":"abc","topic":"test","time":100,"message":"hi"}"#,
+        )
+        .unwrap();
+
+        let msg = db.get_message(&sub.server, "test", "abc").unwrap().unwrap();
+        assert_eq!(msg.message.as_deref(), Some("hi"));
+        assert!(db
+            .get_message(&sub.server, "test", "missing")
+            .unwrap()
+            .is_none());
+        assert!(db
+            .get_message(&sub.server, "other", "abc")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_delete_all_messages() {
+        let mut db = Db::connect(":memory:").unwrap();
+        for topic in ["alerts", "backups", "archived"] {
+            let sub = models::Subscription::builder(topic.to_string())
+                .archived(topic == "archived")
+                .build()
+                .unwrap();
+            db.insert_subscription(sub).unwrap();
+        }
+        let server = models::DEFAULT_SERVER;
+        for (id, topic) in [
+            ("1", "alerts"),
+            ("2", "backups"),
+            ("3", "backups"),
+            ("4", "archived"),
+        ] {
+            db.insert_message(
+                server,
+                &format!(r#"{{"id":"{id}","topic":"{topic}","time":100}}"#),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(db.delete_all_messages().unwrap(), 3);
+        assert!(db.list_messages(server, "alerts", 0).unwrap().is_empty());
+        assert!(db.list_messages(server, "backups", 0).unwrap().is_empty());
+        assert_eq!(db.list_messages(server, "archived", 0).unwrap().len(), 1);
+        assert_eq!(db.delete_all_messages().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_query_readonly() {
+        let mut db = Db::connect(":memory:").unwrap();
+        let sub = models::Subscription::builder("test".to_string())
+            .build()
+            .unwrap();
+        db.insert_subscription(sub.clone()).unwrap();
+        db.insert_message(
+            &sub.server,
+            r#"{"id":"1","topic":"test","time":100,"message":"hi"}"#,
+        )
+        .unwrap();
+
+        let rows = db
+            .query_readonly("SELECT topic, data ->> '$.time' AS time, NULL AS missing FROM message")
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![serde_json::json!({"topic": "test", "time": 100, "missing": null})]
+        );
+    }
+
+    #[test]
+    fn test_open_readonly() {
+        let path =
+            std::env::temp_dir().join(format!("notify-test-{}.sqlite", rand::random::<u64>()));
+        assert!(Db::open_readonly(&path).is_err());
+        assert!(!path.exists());
+
+        let mut db = Db::connect(path.to_str().unwrap()).unwrap();
+        db.insert_subscription(
+            models::Subscription::builder("test".to_string())
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        drop(db);
+        let db = Db::open_readonly(&path).unwrap();
+        assert_eq!(
+            db.query_readonly("SELECT topic FROM subscription")
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(db
+            .conn
+            .read()
+            .unwrap()
+            .execute("DELETE FROM subscription", [])
+            .is_err());
+
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[test]
+    fn test_query_readonly_rejects_writes() {
+        let db = Db::connect(":memory:").unwrap();
+        for sql in [
+            "DELETE FROM message",
+            "DROP TABLE message",
+            "PRAGMA user_version = 3",
+            "ATTACH DATABASE 'other.sqlite' AS other",
+            "SELECT 1; DROP TABLE message",
+            "WITH t AS (SELECT 1) INSERT INTO server (endpoint) SELECT * FROM t",
+        ] {
+            assert!(db.query_readonly(sql).is_err(), "{sql} was accepted");
+        }
+        assert_eq!(
+            db.query_readonly("SELECT COUNT(*) AS n FROM server")
+                .unwrap()
+                .len(),
+            1
+        );
+    }
 }
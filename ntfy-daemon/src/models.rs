@@ -1,20 +1,173 @@
 use std::collections::HashMap;
+use std::path::Path;
 use std::pin::Pin;
 use std::sync::OnceLock;
 
 use futures::stream::Stream;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 use crate::Error;
 
 pub const DEFAULT_SERVER: &str = "https://ntfy.sh";
+// How much history a new subscription fetches from the server
+pub const DEFAULT_BACKFILL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+// Paths used by the ntfy server for its own routes
+pub const RESERVED_TOPICS: &[&str] = &[
+    "docs", "static", "file", "app", "metrics", "account", "settings", "signup", "login", "stats",
+    "json", "ws", "auth", "v1",
+];
 static EMOJI_MAP: OnceLock<HashMap<String, String>> = OnceLock::new();
 
+fn builtin_emoji_map() -> HashMap<String, String> {
+    serde_json::from_str(include_str!("../data/mailer_emoji_map.json")).unwrap()
+}
+
+// User mappings replace the built-in ones with the same tag
+fn merge_emoji_map(user_json: &str) -> Result<HashMap<String, String>, serde_json::Error> {
+    let user: HashMap<String, String> = serde_json::from_str(user_json)?;
+    let mut map = builtin_emoji_map();
+    map.extend(user);
+    Ok(map)
+}
+
+// Loads the user tag to emoji mappings from a JSON object stored at `path`.
+// Must be called before any message is displayed, otherwise only the built-in map is used.
+// A missing file is not an error; an invalid one is ignored with a warning.
+pub fn load_user_emoji_map(path: &Path) {
+    let map = match std::fs::read_to_string(path) {
+        Ok(json) => merge_emoji_map(&json).unwrap_or_else(|e| {
+            warn!(error = %e, path = %path.display(), "invalid emoji map, using the built-in one");
+            builtin_emoji_map()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => builtin_emoji_map(),
+        Err(e) => {
+            warn!(error = %e, path = %path.display(), "can't read emoji map, using the built-in one");
+            builtin_emoji_map()
+        }
+    };
+    if EMOJI_MAP.set(map).is_err() {
+        warn!("emoji map already in use, user mappings not loaded");
+    }
+}
+
 fn emoji_map() -> &'static HashMap<String, String> {
-    EMOJI_MAP.get_or_init(move || {
-        serde_json::from_str(include_str!("../data/mailer_emoji_map.json")).unwrap()
-    })
+    EMOJI_MAP.get_or_init(builtin_emoji_map)
+}
+
+// The tags listed under a message. Those known as emoji are already shown
+// before the title, `emoji` lists them again.
+pub fn listed_tags(tags: &[String], emoji: bool, other: bool) -> Vec<&str> {
+    tags.iter()
+        .filter(|t| {
+            if emoji_for_tag(t).is_some() {
+                emoji
+            } else {
+                other
+            }
+        })
+        .map(|t| t.as_str())
+        .collect()
+}
+
+// Messages sharing the first tag that starts with `prefix`, like "deploy-1234", are
+// shown as a group. An empty prefix disables grouping.
+pub fn group_key<'a>(tags: &'a [String], prefix: &str) -> Option<&'a str> {
+    if prefix.is_empty() {
+        return None;
+    }
+    tags.iter()
+        .map(|t| t.as_str())
+        .find(|t| t.len() > prefix.len() && t.starts_with(prefix))
+}
+
+pub fn emoji_for_tag(tag: &str) -> Option<&'static str> {
+    emoji_map().get(tag).map(|e| e.as_str())
+}
+
+// Bodies longer than this are shortened in the message list, to keep the UI responsive
+pub const MAX_DISPLAYED_BODY: usize = 4 * 1024;
+// Desktop notifications only show a short summary of the body, in characters
+pub const DEFAULT_PREVIEW_LENGTH: usize = 200;
+
+// Cuts `text` to at most `max` bytes without splitting a character.
+// The second value is true if something was cut.
+pub fn truncate_text(text: &str, max: usize) -> (&str, bool) {
+    if text.len() <= max {
+        return (text, false);
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    (&text[..end], true)
+}
+
+// Where the server starts sending messages from. After a message ID it's exact, while from
+// a timestamp the messages sharing its second are sent again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Since {
+    Time(u64),
+    Message(String),
+}
+
+impl std::fmt::Display for Since {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Since::Time(time) => write!(f, "{time}"),
+            Since::Message(id) => f.write_str(id),
+        }
+    }
+}
+
+// How long ago `time` was, like "just now", "5m ago" or "2h ago".
+// Future times, like the ones of scheduled messages, are "in 5m".
+pub fn relative_time(time: u64, now: u64) -> String {
+    let (delta, future) = match now.checked_sub(time) {
+        Some(delta) => (delta, false),
+        None => (time - now, true),
+    };
+    let amount = match delta {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => format!("{}m", delta / 60),
+        3600..=86_399 => format!("{}h", delta / 3600),
+        86_400..=2_591_999 => format!("{}d", delta / 86_400),
+        2_592_000..=31_535_999 => format!("{}mo", delta / 2_592_000),
+        _ => format!("{}y", delta / 31_536_000),
+    };
+    if future {
+        format!("in {amount}")
+    } else {
+        format!("{amount} ago")
+    }
+}
+
+// Shown instead of times too far in the past or future to be a date
+pub const INVALID_TIME: &str = "Invalid date";
+
+// `time` as a date in the timezone `tz`, like "2024-05-01 18:30:00"
+pub fn absolute_time<Tz: chrono::TimeZone>(time: u64, tz: &Tz) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    i64::try_from(time)
+        .ok()
+        .and_then(|time| chrono::DateTime::from_timestamp(time, 0))
+        .map(|time| {
+            time.with_timezone(tz)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| INVALID_TIME.to_string())
+}
+
+// Like `truncate_text`, but counting characters instead of bytes
+pub fn truncate_chars(text: &str, max: usize) -> (&str, bool) {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => (&text[..end], true),
+        None => (text, false),
+    }
 }
 
 pub fn validate_topic(topic: &str) -> Result<&str, Error> {
@@ -26,6 +179,74 @@ pub fn validate_topic(topic: &str) -> Result<&str, Error> {
     }
 }
 
+// A server base url, like https://ntfy.example.com
+pub fn validate_server(server: &str) -> Result<&str, Error> {
+    let url = url::Url::parse(server)?;
+    if url.cannot_be_a_base() {
+        return Err(url::ParseError::RelativeUrlWithCannotBeABaseBase.into());
+    }
+    if !url.has_host() {
+        return Err(url::ParseError::EmptyHost.into());
+    }
+    Ok(server)
+}
+
+// The form servers are stored and compared in: the host is lowercased and international
+// names become punycode, so https://café.example and https://xn--caf-dma.example are the same
+// server. The rest of the url is kept as typed. Invalid urls are returned unchanged.
+pub fn canonical_server(server: &str) -> String {
+    let Ok(url) = url::Url::parse(server) else {
+        return server.to_string();
+    };
+    let (Some(host), Some((scheme, rest))) = (url.host_str(), server.split_once("://")) else {
+        return server.to_string();
+    };
+    let authority_len = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_len);
+    let (userinfo, host_port) = match authority.rsplit_once('@') {
+        Some((userinfo, host_port)) => (Some(userinfo), host_port),
+        None => (None, authority),
+    };
+    let port = match host_port.rsplit_once(':') {
+        Some((_, port)) if port.chars().all(|c| c.is_ascii_digit()) => Some(port),
+        _ => None,
+    };
+    let mut out = format!("{scheme}://");
+    if let Some(userinfo) = userinfo {
+        out.push_str(userinfo);
+        out.push('@');
+    }
+    out.push_str(host);
+    if let Some(port) = port {
+        out.push(':');
+        out.push_str(port);
+    }
+    out.push_str(path);
+    out
+}
+
+// Like `validate_topic`, but also rejects topics colliding with the server routes.
+// The collision is reported as `Error::ReservedTopic`, so callers can treat it as a warning.
+pub fn validate_topic_safe(topic: &str) -> Result<&str, Error> {
+    let topic = validate_topic(topic)?;
+    if RESERVED_TOPICS.contains(&topic) {
+        Err(Error::ReservedTopic(topic.to_string()))
+    } else {
+        Ok(topic)
+    }
+}
+
+const CODE_TAGS: &[&str] = &["code", "log", "logs"];
+
+// How the body of a message is shown
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BodyStyle {
+    // Wrapped text in the normal font
+    Text,
+    // Monospace, keeping the whitespace. `language` is the syntax to highlight, if known.
+    Code { language: Option<String> },
+}
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct ReceivedMessage {
     pub id: String,
@@ -63,7 +284,7 @@ impl ReceivedMessage {
     fn extend_with_emojis(&self, text: &mut String) {
         // Add emojis
         for t in &self.tags {
-            if let Some(emoji) = emoji_map().get(t) {
+            if let Some(emoji) = emoji_for_tag(t) {
                 text.push_str(emoji);
             }
         }
@@ -81,8 +302,11 @@ impl ReceivedMessage {
             title_text
         })
     }
+    // A message with only a title shows the title as the body, under the subscription name
     pub fn notification_title(&self, subscription: &Subscription) -> String {
-        self.display_title()
+        self.message
+            .as_ref()
+            .and_then(|_| self.display_title())
             .or(if subscription.display_name.is_empty() {
                 None
             } else {
@@ -91,6 +315,63 @@ impl ReceivedMessage {
             .unwrap_or(self.topic.to_string())
     }
 
+    // Messages published with a delay have the time they will be delivered at
+    pub fn is_scheduled(&self) -> bool {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.time > now
+    }
+
+    // Max priority escalations with a phone call must stay visible until the user dismisses them
+    pub fn requires_ack(&self) -> bool {
+        self.priority == Some(5) && self.call.is_some()
+    }
+
+    // At most `max_chars` characters of the message, followed by an ellipsis if cut
+    pub fn notification_body(&self, max_chars: usize) -> String {
+        let message = self
+            .display_message()
+            .or_else(|| self.display_title())
+            .unwrap_or_default();
+        match truncate_chars(&message, max_chars) {
+            (summary, true) => format!("{summary}…"),
+            (message, false) => message.to_string(),
+        }
+    }
+
+    // Logs and scripts read badly in a proportional font. Messages tagged `code` or `log`,
+    // or `lang-<language>` to highlight them, are shown as code, like JSON bodies.
+    // `code_messages` shows every message with a body as code.
+    pub fn body_style(&self, code_messages: bool) -> BodyStyle {
+        let Some(message) = self.message.as_deref() else {
+            return BodyStyle::Text;
+        };
+        let language = self
+            .tags
+            .iter()
+            .find_map(|t| t.strip_prefix("lang-"))
+            .filter(|l| !l.is_empty());
+        if let Some(language) = language {
+            return BodyStyle::Code {
+                language: Some(language.to_lowercase()),
+            };
+        }
+        let trimmed = message.trim_start();
+        if (trimmed.starts_with('{') || trimmed.starts_with('['))
+            && serde_json::from_str::<serde_json::Value>(message).is_ok()
+        {
+            return BodyStyle::Code {
+                language: Some("json".to_string()),
+            };
+        }
+        if code_messages || self.tags.iter().any(|t| CODE_TAGS.contains(&t.as_str())) {
+            return BodyStyle::Code { language: None };
+        }
+        BodyStyle::Text
+    }
+
     pub fn display_message(&self) -> Option<String> {
         self.message.as_ref().map(|message| {
             let mut out = String::new();
@@ -105,13 +386,37 @@ impl ReceivedMessage {
             out
         })
     }
+    // The actions of the message, with the placeholders of the HTTP bodies filled
+    pub fn templated_actions(&self) -> Vec<Action> {
+        self.actions
+            .iter()
+            .map(|a| a.clone().with_message_fields(self))
+            .collect()
+    }
+    // The content to publish again somewhere else. The caller sets the topic
+    pub fn forward(&self) -> OutgoingMessage {
+        OutgoingMessage {
+            message: self.message.clone(),
+            title: self.title.clone(),
+            tags: self.tags.clone(),
+            ..Default::default()
+        }
+    }
 }
 
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+// A message to publish as JSON. The server rejects `null` for some fields,
+// so every unset field is left out of the serialized message.
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct OutgoingMessage {
     pub topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<String>,
     #[serde(default = "Default::default")]
+    #[serde(skip_serializing_if = "is_zero")]
     pub time: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub title: Option<String>,
@@ -128,7 +433,9 @@ pub struct OutgoingMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub filename: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub delay: Option<usize>,
+    // Accepts what the server accepts: a unix timestamp, a duration like "30m"
+    // or a natural language time like "tomorrow, 10am"
+    pub delay: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub email: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -136,6 +443,57 @@ pub struct OutgoingMessage {
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub actions: Vec<Action>,
+    // Sent as the `Cache` and `Firebase` request headers, see `split_publish_headers`.
+    // None keeps the server default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firebase: Option<bool>,
+    // Lets the clients render the message as Markdown
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub markdown: bool,
+}
+
+// Credentials used for a single publish instead of the ones stored for the server.
+// Never stored, and left out of the logs.
+#[derive(Clone)]
+pub enum PublishAuth {
+    Basic { username: String, password: String },
+    Token(String),
+}
+
+impl std::fmt::Debug for PublishAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PublishAuth::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            PublishAuth::Token(_) => f.write_str("Token(..)"),
+        }
+    }
+}
+
+// The server only reads these options from the request headers, not from the JSON body
+const HEADER_FIELDS: &[(&str, &str)] = &[("cache", "Cache"), ("firebase", "Firebase")];
+
+// Header names and values, from `HEADER_FIELDS`
+pub type PublishHeaders = Vec<(&'static str, &'static str)>;
+
+// Removes the options that must be sent as headers from the JSON message,
+// returning the remaining body and the headers to send with it.
+pub fn split_publish_headers(msg: &str) -> Result<(String, PublishHeaders), serde_json::Error> {
+    let mut value: serde_json::Value = serde_json::from_str(msg)?;
+    let mut headers = vec![];
+    if let Some(obj) = value.as_object_mut() {
+        for (field, header) in HEADER_FIELDS {
+            if let Some(enabled) = obj.remove(*field).and_then(|v| v.as_bool()) {
+                headers.push((*header, if enabled { "yes" } else { "no" }));
+            }
+        }
+    }
+    Ok((value.to_string(), headers))
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -158,12 +516,63 @@ pub struct Attachment {
     pub expires: Option<usize>,
 }
 
+// Attachments bigger than this are never downloaded to be displayed inline
+pub const MAX_DISPLAYABLE_SIZE: usize = 5 * 1_000_000; // 5 MB
+
+const DISPLAYABLE_IMAGES: &[&str] = &["image/jpeg", "image/png", "image/webp", "image/gif"];
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum AttachmentKind {
+    Image,
+    Audio,
+    Video,
+    Other,
+}
+
 impl Attachment {
-    pub fn is_image(&self) -> bool {
-        let Some(ext) = self.name.split('.').last() else {
-            return false;
+    // The MIME type sent by the server wins over the file extension
+    fn mime_type(&self) -> Option<String> {
+        if let Some(atype) = &self.atype {
+            return Some(atype.split(';').next().unwrap_or("").trim().to_lowercase());
+        }
+        let (_, ext) = self.name.rsplit_once('.')?;
+        let mime = match ext.to_lowercase().as_str() {
+            "jpeg" | "jpg" => "image/jpeg",
+            "png" => "image/png",
+            "webp" => "image/webp",
+            "gif" => "image/gif",
+            "mp3" => "audio/mpeg",
+            "ogg" | "oga" => "audio/ogg",
+            "wav" => "audio/wav",
+            "mp4" => "video/mp4",
+            "webm" => "video/webm",
+            "mkv" => "video/x-matroska",
+            _ => return None,
         };
-        ["jpeg", "jpg", "png", "webp", "gif"].contains(&ext)
+        Some(mime.to_string())
+    }
+    pub fn kind(&self) -> AttachmentKind {
+        match self.mime_type() {
+            Some(m) if m.starts_with("image/") => AttachmentKind::Image,
+            Some(m) if m.starts_with("audio/") => AttachmentKind::Audio,
+            Some(m) if m.starts_with("video/") => AttachmentKind::Video,
+            _ => AttachmentKind::Other,
+        }
+    }
+    pub fn is_image(&self) -> bool {
+        self.kind() == AttachmentKind::Image
+    }
+    // True if the attachment is an image we can decode and it's small enough to be shown inline
+    pub fn is_displayable(&self) -> bool {
+        let supported = self
+            .mime_type()
+            .is_some_and(|m| DISPLAYABLE_IMAGES.contains(&m.as_str()));
+        let small_enough = self.size.is_none_or(|s| s <= MAX_DISPLAYABLE_SIZE);
+        supported && small_enough
+    }
+    // The server deletes attachments after `expires`, they can't be downloaded anymore
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|e| e as u64 <= now)
     }
 }
 
@@ -177,10 +586,91 @@ pub struct Subscription {
     pub reserved: bool,
     pub symbolic_icon: Option<String>,
     pub read_until: u64,
+    pub last_received_time: u64,
+    // Notifications are only shown for messages whose title or body match this
+    pub notify_regex: Option<String>,
+    pub notification_persistence: NotificationPersistence,
+    // Sound file played with the notifications, instead of the default one
+    pub sound: Option<String>,
+    // Unix time until which notifications aren't shown, 0 if not snoozed
+    pub snoozed_until: u64,
+    // Images attached to new messages are downloaded on receipt
+    pub auto_download_attachments: bool,
+    // Every message is shown as code, see `ReceivedMessage::body_style`
+    pub code_messages: bool,
+    // Last priority chosen to publish to the topic, between 1 and 5
+    pub publish_priority: i8,
+}
+
+// How the subscriptions connect to their servers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConnectionMode {
+    // A request for each subscribed topic
+    #[default]
+    PerTopic,
+    // The topics of the same server are joined by commas in a single request,
+    // the messages are dispatched to the subscriptions by their topic
+    SharedPerServer,
+}
+
+// How long the desktop notifications of a subscription stay visible
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NotificationPersistence {
+    // Decided by the desktop, except for max priority messages which are resident
+    #[default]
+    Default,
+    // Dismissed automatically after a short time
+    Transient,
+    // Visible until the user dismisses them
+    Resident,
+}
+
+impl NotificationPersistence {
+    pub const ALL: [Self; 3] = [Self::Default, Self::Transient, Self::Resident];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Transient => "transient",
+            Self::Resident => "resident",
+        }
+    }
+
+    // The persistence of the notification for `msg`, in a subscription with this setting
+    pub fn for_message(self, msg: &ReceivedMessage) -> Self {
+        if msg.requires_ack() {
+            return Self::Resident;
+        }
+        match self {
+            Self::Default if msg.priority == Some(5) => Self::Resident,
+            other => other,
+        }
+    }
+}
+
+impl std::str::FromStr for NotificationPersistence {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|p| p.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("invalid notification persistence {s:?}"))
+    }
 }
 
 impl Subscription {
-    pub fn build_url(server: &str, topic: &str, since: u64) -> Result<url::Url, crate::Error> {
+    pub fn display_name_or_topic(&self) -> &str {
+        if self.display_name.is_empty() {
+            &self.topic
+        } else {
+            &self.display_name
+        }
+    }
+    pub fn is_snoozed(&self, now: u64) -> bool {
+        now < self.snoozed_until
+    }
+    pub fn build_url(server: &str, topic: &str, since: &Since) -> Result<url::Url, crate::Error> {
         let mut url = url::Url::parse(server)?;
         url.path_segments_mut()
             .map_err(|_| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
@@ -190,6 +680,18 @@ impl Subscription {
             .append_pair("since", &since.to_string());
         Ok(url)
     }
+    // Messages waiting for their delayed delivery, only returned by the servers supporting it
+    pub fn build_scheduled_url(server: &str, topic: &str) -> Result<url::Url, crate::Error> {
+        let mut url = url::Url::parse(server)?;
+        url.path_segments_mut()
+            .map_err(|_| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
+            .push(topic)
+            .push("json");
+        url.query_pairs_mut()
+            .append_pair("poll", "1")
+            .append_pair("sched", "1");
+        Ok(url)
+    }
     pub fn build_auth_url(server: &str, topic: &str) -> Result<url::Url, crate::Error> {
         let mut url = url::Url::parse(server)?;
         url.path_segments_mut()
@@ -198,12 +700,78 @@ impl Subscription {
             .push("auth");
         Ok(url)
     }
+    // Page of the topic in the server web app
+    pub fn build_web_url(server: &str, topic: &str) -> Result<url::Url, crate::Error> {
+        let mut url = url::Url::parse(server)?;
+        url.path_segments_mut()
+            .map_err(|_| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
+            .pop_if_empty()
+            .push(topic);
+        Ok(url)
+    }
+    // Link subscribing to the topic in the ntfy apps, like ntfy://ntfy.sh/mytopic.
+    // Plain http servers are marked with secure=false.
+    pub fn build_deep_link(server: &str, topic: &str) -> Result<url::Url, crate::Error> {
+        let web_url = Self::build_web_url(server, topic)?;
+        let mut url = url::Url::parse(&format!(
+            "ntfy://{}",
+            &web_url[url::Position::BeforeHost..url::Position::AfterPath]
+        ))?;
+        if web_url.scheme() == "http" {
+            url.query_pairs_mut().append_pair("secure", "false");
+        }
+        Ok(url)
+    }
+    // Server and topic of a link made by `build_deep_link`, or of the web page of a topic
+    pub fn parse_deep_link(link: &str) -> Result<(String, String), crate::Error> {
+        let invalid = || Error::InvalidDeepLink(link.to_string());
+        let url = url::Url::parse(link).map_err(|_| invalid())?;
+        let scheme = match url.scheme() {
+            "ntfy"
+                if url
+                    .query_pairs()
+                    .any(|(k, v)| k == "secure" && v == "false") =>
+            {
+                "http"
+            }
+            "ntfy" => "https",
+            "http" | "https" => url.scheme(),
+            _ => return Err(invalid()),
+        };
+        let host = url.host_str().ok_or_else(invalid)?;
+        let mut segments: Vec<&str> = url
+            .path_segments()
+            .ok_or_else(invalid)?
+            .filter(|s| !s.is_empty())
+            .collect();
+        let topic = segments.pop().ok_or_else(invalid)?;
+        validate_topic(topic)?;
+
+        let mut server = format!("{scheme}://{host}");
+        if let Some(port) = url.port() {
+            server.push_str(&format!(":{port}"));
+        }
+        for segment in segments {
+            server.push('/');
+            server.push_str(segment);
+        }
+        Ok((server, topic.to_string()))
+    }
+    pub fn compile_notify_regex(&self) -> Result<Option<Regex>, crate::Error> {
+        self.notify_regex
+            .as_deref()
+            .map(|re| Regex::new(re).map_err(|e| Error::InvalidNotifyRegex(re.to_string(), e)))
+            .transpose()
+    }
     pub fn validate(self) -> Result<Self, crate::Error> {
         let mut errs = vec![];
+        if let Err(e) = self.compile_notify_regex() {
+            errs.push(e);
+        };
         if let Err(e) = validate_topic(&self.topic) {
             errs.push(e);
         };
-        if let Err(e) = Self::build_url(&self.server, &self.topic, 0) {
+        if let Err(e) = Self::build_url(&self.server, &self.topic, &Since::Time(0)) {
             errs.push(e);
         };
         if !errs.is_empty() {
@@ -225,6 +793,13 @@ pub struct SubscriptionBuilder {
     reserved: bool,
     symbolic_icon: Option<String>,
     display_name: String,
+    last_received_time: u64,
+    notify_regex: Option<String>,
+    notification_persistence: NotificationPersistence,
+    sound: Option<String>,
+    auto_download_attachments: bool,
+    code_messages: bool,
+    publish_priority: i8,
 }
 
 impl SubscriptionBuilder {
@@ -237,6 +812,13 @@ impl SubscriptionBuilder {
             reserved: false,
             symbolic_icon: None,
             display_name: String::new(),
+            last_received_time: 0,
+            notify_regex: None,
+            notification_persistence: NotificationPersistence::Default,
+            sound: None,
+            auto_download_attachments: false,
+            code_messages: false,
+            publish_priority: DEFAULT_PRIORITY,
         }
     }
 
@@ -270,9 +852,53 @@ impl SubscriptionBuilder {
         self
     }
 
+    // Messages older than this are not fetched when the listener connects
+    pub fn last_received_time(mut self, last_received_time: u64) -> Self {
+        self.last_received_time = last_received_time;
+        self
+    }
+
+    pub fn notify_regex(mut self, notify_regex: Option<String>) -> Self {
+        self.notify_regex = notify_regex.filter(|re| !re.is_empty());
+        self
+    }
+
+    pub fn notification_persistence(mut self, persistence: NotificationPersistence) -> Self {
+        self.notification_persistence = persistence;
+        self
+    }
+
+    pub fn sound(mut self, sound: Option<String>) -> Self {
+        self.sound = sound.filter(|path| !path.is_empty());
+        self
+    }
+
+    pub fn auto_download_attachments(mut self, auto_download_attachments: bool) -> Self {
+        self.auto_download_attachments = auto_download_attachments;
+        self
+    }
+
+    pub fn code_messages(mut self, code_messages: bool) -> Self {
+        self.code_messages = code_messages;
+        self
+    }
+
+    // Out of range priorities are clamped
+    pub fn publish_priority(mut self, publish_priority: i8) -> Self {
+        self.publish_priority = publish_priority.clamp(1, 5);
+        self
+    }
+
+    // Server, display name and notification filter of the preset
+    pub fn preset(self, preset: &SubscriptionPreset) -> Self {
+        self.server(preset.server.clone())
+            .display_name(preset.display_name.clone())
+            .notify_regex(preset.notify_regex.clone())
+    }
+
     pub fn build(self) -> Result<Subscription, Error> {
         let res = Subscription {
-            server: self.server,
+            server: canonical_server(&self.server),
             topic: self.topic,
             muted: self.muted,
             archived: self.archived,
@@ -280,11 +906,50 @@ impl SubscriptionBuilder {
             symbolic_icon: self.symbolic_icon,
             display_name: self.display_name,
             read_until: 0,
+            last_received_time: self.last_received_time,
+            notify_regex: self.notify_regex,
+            notification_persistence: self.notification_persistence,
+            sound: self.sound,
+            snoozed_until: 0,
+            auto_download_attachments: self.auto_download_attachments,
+            code_messages: self.code_messages,
+            publish_priority: self.publish_priority,
         };
         res.validate()
     }
 }
 
+fn default_server() -> String {
+    DEFAULT_SERVER.to_string()
+}
+
+// Defaults for subscribing quickly to many similar topics, stored in the settings as a JSON list.
+// `{random}` in the topic template is replaced with a random name.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriptionPreset {
+    pub name: String,
+    #[serde(default = "default_server")]
+    pub server: String,
+    #[serde(default)]
+    pub topic_template: String,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub notify_regex: Option<String>,
+}
+
+impl SubscriptionPreset {
+    pub const RANDOM_PLACEHOLDER: &'static str = "{random}";
+
+    pub fn parse_list(json: &str) -> Result<Vec<Self>, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+    pub fn topic(&self, random_name: &str) -> String {
+        self.topic_template
+            .replace(Self::RANDOM_PLACEHOLDER, random_name)
+    }
+}
+
 fn default_method() -> String {
     "POST".to_string()
 }
@@ -310,10 +975,15 @@ pub enum Action {
         body: String,
         #[serde(default)]
         clear: bool,
+        // Not part of the ntfy protocol: shown by Notify when the request succeeds
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        success_label: Option<String>,
     },
     #[serde(rename = "broadcast")]
     Broadcast {
         label: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
         intent: Option<String>,
         #[serde(default)]
         extras: HashMap<String, String>,
@@ -322,7 +992,70 @@ pub enum Action {
     },
 }
 
-#[derive(Debug, PartialEq, Copy, Clone, Default)]
+impl Action {
+    // Fills `{title}`, `{message}`, `{id}` and `{tag}` (the tags, comma separated) in the body
+    // of an HTTP action, escaped for JSON and form bodies. Unknown placeholders are left as they are.
+    pub fn with_message_fields(mut self, msg: &ReceivedMessage) -> Self {
+        let Action::Http { headers, body, .. } = &mut self else {
+            return self;
+        };
+        let content_type = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+            .map(|(_, v)| v.to_ascii_lowercase())
+            .unwrap_or_default();
+        let escape = |value: &str| -> String {
+            if content_type.contains("json") {
+                let quoted = serde_json::Value::from(value).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            } else if content_type.contains("x-www-form-urlencoded") {
+                url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+            } else {
+                value.to_string()
+            }
+        };
+        *body = fill_placeholders(body, |name| {
+            let value = match name {
+                "title" => msg.title.clone().unwrap_or_default(),
+                "message" => msg.message.clone().unwrap_or_default(),
+                "id" => msg.id.clone(),
+                "tag" => msg.tags.join(","),
+                _ => return None,
+            };
+            Some(escape(&value))
+        });
+        self
+    }
+}
+
+// Replaces every `{name}` for which `value` returns something
+fn fill_placeholders(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after
+            .find('}')
+            .and_then(|end| Some((end, value(&after[..end])?)))
+        {
+            Some((end, filled)) => {
+                out.push_str(&filled);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// Connection status of a subscription, see `ConnectionState::status`.
+// Stored as u8 where a plain number is needed, like in GObject properties.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
 pub enum Status {
     #[default]
     Down,
@@ -330,13 +1063,15 @@ pub enum Status {
     Up,
 }
 
-impl From<u8> for Status {
-    fn from(item: u8) -> Self {
+// Gives back the invalid value
+impl TryFrom<u8> for Status {
+    type Error = u8;
+    fn try_from(item: u8) -> Result<Self, Self::Error> {
         match item {
-            0 => Status::Down,
-            1 => Status::Degraded,
-            2 => Status::Up,
-            _ => Status::Down,
+            0 => Ok(Status::Down),
+            1 => Ok(Status::Degraded),
+            2 => Ok(Status::Up),
+            _ => Err(item),
         }
     }
 }
@@ -357,29 +1092,234 @@ pub struct Account {
     pub username: String,
 }
 
+// A topic reserved by an account. `everyone` is the access granted to the other users,
+// e.g. "deny-all" or "read-only".
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reservation {
+    pub topic: String,
+    pub everyone: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessToken {
+    pub token: String,
+    pub label: Option<String>,
+    // Unix times
+    pub last_access: Option<u64>,
+    pub expires: Option<u64>,
+}
+
+// Priority ntfy assigns to messages without an explicit one
+pub const DEFAULT_PRIORITY: i8 = 3;
+
+// Settings shared by every subscription, changeable at runtime
+#[derive(Clone, Debug)]
+pub struct NotificationSettings {
+    // Messages below this priority are stored but don't show a desktop notification
+    pub min_priority: i8,
+    // Show a notification when a subscription can't reach its server for a while
+    pub notify_connection_lost: bool,
+    // Every notification is held back, the messages are still stored
+    pub paused: bool,
+    // Unix time ending the pause, None pauses until resumed
+    pub resume_at: Option<u64>,
+    // Maximum characters of the message shown in the notification body
+    pub preview_length: usize,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            min_priority: 1,
+            notify_connection_lost: false,
+            paused: false,
+            resume_at: None,
+            preview_length: DEFAULT_PREVIEW_LENGTH,
+        }
+    }
+}
+
+impl NotificationSettings {
+    pub fn is_paused(&self, now: u64) -> bool {
+        self.paused && self.resume_at.is_none_or(|t| now < t)
+    }
+}
+
+// Time from `now` to the next `at`, later today or else tomorrow, in the time zone of `now`
+pub fn until_next<Tz: chrono::TimeZone>(
+    at: chrono::NaiveTime,
+    now: &chrono::DateTime<Tz>,
+) -> std::time::Duration {
+    let now = now.naive_local();
+    let mut next = now.date().and_time(at);
+    if next <= now {
+        next += chrono::Duration::days(1);
+    }
+    (next - now).to_std().unwrap_or_default()
+}
+
+// How many messages each subscription received, named by `display_name_or_topic`.
+// None if there were none.
+pub fn digest_notification(counts: &[(String, u64)]) -> Option<Notification> {
+    let total: u64 = counts.iter().map(|(_, count)| count).sum();
+    if total == 0 {
+        return None;
+    }
+    let body = counts
+        .iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(name, count)| format!("{name}: {count}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(Notification {
+        title: format!(
+            "{total} {} in the last 24 hours",
+            if total == 1 { "message" } else { "messages" }
+        ),
+        body,
+        actions: vec![],
+        persistence: NotificationPersistence::Default,
+        priority: DEFAULT_PRIORITY,
+        sound: None,
+        source: None,
+    })
+}
+
+#[derive(Clone, Debug)]
 pub struct Notification {
     pub title: String,
     pub body: String,
     pub actions: Vec<Action>,
+    pub persistence: NotificationPersistence,
+    pub priority: i8,
+    // Played instead of the default sound, if the file can be played
+    pub sound: Option<String>,
+    // The message notified, None for the notifications about the app itself
+    pub source: Option<NotificationSource>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationSource {
+    pub server: String,
+    pub topic: String,
+    // Missing in the notifications sent by older versions
+    #[serde(default)]
+    pub id: String,
+    pub time: u64,
 }
 
 pub trait NotificationProxy: Sync + Send {
     fn send(&self, n: Notification) -> anyhow::Result<()>;
 }
 
+// Rewrites the received messages before they are stored and notified,
+// e.g. to redact secrets. Applied in order, the default is none.
+pub trait MessageTransform: Sync + Send {
+    fn apply(&self, msg: ReceivedMessage) -> ReceivedMessage;
+}
+
+// Scripts often send their colored terminal output, which is unreadable outside a terminal
+pub struct StripAnsiEscapes;
+
+impl StripAnsiEscapes {
+    pub fn strip(text: &str) -> String {
+        static ANSI_ESCAPE: OnceLock<Regex> = OnceLock::new();
+        let re = ANSI_ESCAPE.get_or_init(|| {
+            // CSI sequences (colors, cursor movement), OSC sequences (titles, links)
+            // and the other two characters escapes
+            Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)|\x1b[@-Z\\-_]")
+                .unwrap()
+        });
+        re.replace_all(text, "").into_owned()
+    }
+}
+
+impl MessageTransform for StripAnsiEscapes {
+    fn apply(&self, mut msg: ReceivedMessage) -> ReceivedMessage {
+        msg.title = msg.title.map(|t| Self::strip(&t));
+        msg.message = msg.message.map(|m| Self::strip(&m));
+        msg
+    }
+}
+
 pub trait NetworkMonitorProxy: Sync + Send {
     fn listen(&self) -> Pin<Box<dyn Stream<Item = ()>>>;
 }
 
-pub struct NullNotifier {}
+#[derive(Default)]
+pub struct NullNotifier {
+    sent: std::sync::Mutex<Vec<Notification>>,
+}
 
 impl NullNotifier {
     pub fn new() -> Self {
-        Self {}
+        Self::default()
+    }
+    // Notifications received so far, in order
+    pub fn sent(&self) -> Vec<Notification> {
+        self.sent.lock().unwrap().clone()
     }
 }
 impl NotificationProxy for NullNotifier {
     fn send(&self, n: Notification) -> anyhow::Result<()> {
+        self.sent.lock().unwrap().push(n);
+        Ok(())
+    }
+}
+
+// Writes each notification as a line of JSON, for running without a desktop.
+// Other tools can follow the output to forward the notifications.
+pub struct LogNotifier {
+    output: std::sync::Mutex<Box<dyn std::io::Write + Send>>,
+}
+
+#[derive(Serialize)]
+struct LoggedNotification<'a> {
+    title: &'a str,
+    body: &'a str,
+    priority: i8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<u64>,
+}
+
+impl LogNotifier {
+    pub fn new(output: impl std::io::Write + Send + 'static) -> Self {
+        Self {
+            output: std::sync::Mutex::new(Box::new(output)),
+        }
+    }
+    // Appends to the file at `path`, or writes to the standard output if it's "-"
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        if path == Path::new("-") {
+            return Ok(Self::new(std::io::stdout()));
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self::new(file))
+    }
+}
+
+impl NotificationProxy for LogNotifier {
+    fn send(&self, n: Notification) -> anyhow::Result<()> {
+        let source = n.source.as_ref();
+        let mut line = serde_json::to_string(&LoggedNotification {
+            title: &n.title,
+            body: &n.body,
+            priority: n.priority,
+            server: source.map(|s| s.server.as_str()),
+            topic: source.map(|s| s.topic.as_str()),
+            time: source.map(|s| s.time),
+        })?;
+        line.push('\n');
+        let mut output = self.output.lock().unwrap();
+        output.write_all(line.as_bytes())?;
+        output.flush()?;
         Ok(())
     }
 }
@@ -397,3 +1337,627 @@ impl NetworkMonitorProxy for NullNetworkMonitor {
         Box::pin(futures::stream::empty())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_topic_safe() {
+        assert!(validate_topic_safe("my_topic").is_ok());
+        assert!(matches!(
+            validate_topic_safe("docs"),
+            Err(Error::ReservedTopic(_))
+        ));
+        assert!(matches!(
+            validate_topic_safe("not valid"),
+            Err(Error::InvalidTopic(_))
+        ));
+        // Reserved names are still accepted by the plain validation
+        assert!(validate_topic("v1").is_ok());
+    }
+
+    #[test]
+    fn test_until_next() {
+        use chrono::TimeZone;
+        let at = chrono::NaiveTime::from_hms_opt(20, 0, 0).unwrap();
+        let tz = chrono::FixedOffset::east_opt(2 * 3600).unwrap();
+        let morning = tz.with_ymd_and_hms(2024, 3, 1, 8, 0, 0).unwrap();
+        assert_eq!(until_next(at, &morning).as_secs(), 12 * 3600);
+        let evening = tz.with_ymd_and_hms(2024, 3, 1, 20, 0, 0).unwrap();
+        assert_eq!(until_next(at, &evening).as_secs(), 24 * 3600);
+    }
+
+    #[test]
+    fn test_digest_notification() {
+        assert!(digest_notification(&[]).is_none());
+        assert!(digest_notification(&[("Backups".to_string(), 0)]).is_none());
+        let n = digest_notification(&[
+            ("Backups".to_string(), 2),
+            ("alerts".to_string(), 1),
+            ("quiet".to_string(), 0),
+        ])
+        .unwrap();
+        assert_eq!(n.title, "3 messages in the last 24 hours");
+        assert_eq!(n.body, "Backups: 2\nalerts: 1");
+    }
+
+    #[test]
+    fn test_body_style() {
+        let msg = |message: &str, tags: &[&str]| ReceivedMessage {
+            message: Some(message.to_string()),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        };
+        assert_eq!(msg("hello", &[]).body_style(false), BodyStyle::Text);
+        assert_eq!(
+            msg("hello", &[]).body_style(true),
+            BodyStyle::Code { language: None }
+        );
+        assert_eq!(
+            msg("ERROR disk full", &["warning", "log"]).body_style(false),
+            BodyStyle::Code { language: None }
+        );
+        assert_eq!(
+            msg("echo hi", &["lang-SH"]).body_style(false),
+            BodyStyle::Code {
+                language: Some("sh".to_string())
+            }
+        );
+        assert_eq!(
+            msg(r#" {"status": "ok"}"#, &[]).body_style(false),
+            BodyStyle::Code {
+                language: Some("json".to_string())
+            }
+        );
+        // Brackets alone don't make a message JSON
+        assert_eq!(msg("[backup] done", &[]).body_style(false), BodyStyle::Text);
+        assert_eq!(ReceivedMessage::default().body_style(true), BodyStyle::Text);
+    }
+
+    #[test]
+    fn test_canonical_server() {
+        assert_eq!(
+            canonical_server("https://notify.café.example"),
+            "https://notify.xn--caf-dma.example"
+        );
+        assert_eq!(
+            canonical_server("https://notify.xn--caf-dma.example"),
+            "https://notify.xn--caf-dma.example"
+        );
+        assert_eq!(
+            canonical_server("https://User@Café.example:8080/ntfy/"),
+            "https://User@xn--caf-dma.example:8080/ntfy/"
+        );
+        assert_eq!(canonical_server("https://ntfy.sh"), "https://ntfy.sh");
+        assert_eq!(canonical_server("http://[::1]:80"), "http://[::1]:80");
+        assert_eq!(canonical_server("not a url"), "not a url");
+    }
+
+    #[test]
+    fn test_validate_server() {
+        assert!(validate_server("https://ntfy.example.com").is_ok());
+        assert!(validate_server("http://192.168.1.2:8080").is_ok());
+        for server in [
+            "ntfy.example.com",
+            "mailto:me@example.com",
+            "file:///tmp",
+            "",
+        ] {
+            assert!(
+                matches!(validate_server(server), Err(Error::InvalidServer(_))),
+                "{server}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_truncate_text_at_boundary() {
+        let text = "a".repeat(MAX_DISPLAYED_BODY);
+        assert_eq!(
+            truncate_text(&text, MAX_DISPLAYED_BODY),
+            (text.as_str(), false)
+        );
+
+        let text = "a".repeat(MAX_DISPLAYED_BODY + 1);
+        let (cut, truncated) = truncate_text(&text, MAX_DISPLAYED_BODY);
+        assert!(truncated);
+        assert_eq!(cut.len(), MAX_DISPLAYED_BODY);
+
+        // Multi-byte characters are never split
+        let text = "é".repeat(MAX_DISPLAYED_BODY);
+        let (cut, truncated) = truncate_text(&text, MAX_DISPLAYED_BODY + 1);
+        assert!(truncated);
+        assert_eq!(cut.len(), MAX_DISPLAYED_BODY);
+    }
+
+    #[test]
+    fn test_notification_body_is_summarized() {
+        let msg = ReceivedMessage {
+            message: Some("x".repeat(5 * 1_000_000)),
+            ..Default::default()
+        };
+        let body = msg.notification_body(DEFAULT_PREVIEW_LENGTH);
+        assert_eq!(body.chars().count(), DEFAULT_PREVIEW_LENGTH + 1);
+        assert!(body.ends_with('…'));
+
+        // Multi-byte characters count as one and are never split
+        let msg = ReceivedMessage {
+            message: Some("é".repeat(10)),
+            ..Default::default()
+        };
+        assert_eq!(msg.notification_body(4), "éééé…");
+        assert_eq!(msg.notification_body(10), "é".repeat(10));
+    }
+
+    #[test]
+    fn test_title_only_message_notification() {
+        let sub = Subscription::builder("alerts".to_string())
+            .display_name("Alerts".to_string())
+            .build()
+            .unwrap();
+        let msg = ReceivedMessage {
+            topic: "alerts".to_string(),
+            title: Some("Backup done".to_string()),
+            tags: vec!["tada".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(msg.notification_title(&sub), "Alerts");
+        assert_eq!(
+            msg.notification_body(DEFAULT_PREVIEW_LENGTH),
+            "🎉 Backup done"
+        );
+
+        let msg = ReceivedMessage {
+            message: Some("42 files".to_string()),
+            ..msg
+        };
+        assert_eq!(msg.notification_title(&sub), "🎉 Backup done");
+        assert_eq!(msg.notification_body(DEFAULT_PREVIEW_LENGTH), "42 files");
+    }
+
+    #[test]
+    fn test_listed_tags() {
+        let msg = ReceivedMessage {
+            title: Some("Deploy".to_string()),
+            tags: vec!["tada".to_string(), "prod".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(listed_tags(&msg.tags, true, true), ["tada", "prod"]);
+        assert_eq!(listed_tags(&msg.tags, false, true), ["prod"]);
+        assert!(listed_tags(&msg.tags, false, false).is_empty());
+        // The emoji stays in the title
+        assert_eq!(msg.display_title().as_deref(), Some("🎉 Deploy"));
+    }
+
+    #[test]
+    fn test_group_key() {
+        let tags = ["prod".to_string(), "deploy-1234".to_string()];
+        assert_eq!(group_key(&tags, "deploy-"), Some("deploy-1234"));
+        assert_eq!(group_key(&tags, "build-"), None);
+        assert_eq!(group_key(&tags, ""), None);
+        // The bare prefix doesn't identify a group
+        assert_eq!(group_key(&["deploy-".to_string()], "deploy-"), None);
+    }
+
+    #[test]
+    fn test_subscription_preset() {
+        let presets = SubscriptionPreset::parse_list(
+            r#"[{"name":"Backups","server":"https://ntfy.example.com","topic_template":"backup-{random}","display_name":"Backup","notify_regex":"FAILED"},{"name":"Plain"}]"#,
+        )
+        .unwrap();
+        assert_eq!(presets[0].topic("a1b2"), "backup-a1b2");
+        assert_eq!(presets[1].server, DEFAULT_SERVER);
+
+        let sub = Subscription::builder(presets[0].topic("a1b2"))
+            .preset(&presets[0])
+            .build()
+            .unwrap();
+        assert_eq!(sub.server, "https://ntfy.example.com");
+        assert_eq!(sub.display_name, "Backup");
+        assert_eq!(sub.notify_regex.as_deref(), Some("FAILED"));
+
+        assert!(SubscriptionPreset::parse_list(r#"{"name":"x"}"#).is_err());
+    }
+
+    #[test]
+    fn test_http_action_body_placeholders() {
+        let action = |content_type: Option<&str>, body: &str| Action::Http {
+            label: "send".to_string(),
+            url: "https://example.com".to_string(),
+            method: "POST".to_string(),
+            headers: content_type
+                .map(|t| HashMap::from([("Content-Type".to_string(), t.to_string())]))
+                .unwrap_or_default(),
+            body: body.to_string(),
+            clear: false,
+            success_label: None,
+        };
+        let body = |action: Action| match action {
+            Action::Http { body, .. } => body,
+            _ => unreachable!(),
+        };
+        let msg = ReceivedMessage {
+            id: "abc".to_string(),
+            title: Some("Say \"hi\"".to_string()),
+            message: Some("a & b {id}".to_string()),
+            tags: vec!["one".to_string(), "two".to_string()],
+            ..Default::default()
+        };
+
+        let filled = action(
+            Some("application/json"),
+            r#"{"text":"{title}: {message}","id":"{id}","keep":"{unknown}"}"#,
+        )
+        .with_message_fields(&msg);
+        assert_eq!(
+            body(filled),
+            r#"{"text":"Say \"hi\": a & b {id}","id":"abc","keep":"{unknown}"}"#
+        );
+
+        let filled = action(Some("application/x-www-form-urlencoded"), "text={message}")
+            .with_message_fields(&msg);
+        assert_eq!(body(filled), "text=a+%26+b+%7Bid%7D");
+
+        let filled = action(None, "{tag} {title} {").with_message_fields(&msg);
+        assert_eq!(body(filled), "one,two Say \"hi\" {");
+    }
+
+    #[test]
+    fn test_log_notifier_writes_json_lines() {
+        #[derive(Clone, Default)]
+        struct Buffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl std::io::Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let notifier = LogNotifier::new(buffer.clone());
+        let notification = |body: &str, source| Notification {
+            title: "Backup".to_string(),
+            body: body.to_string(),
+            actions: vec![],
+            persistence: NotificationPersistence::Default,
+            priority: 3,
+            sound: None,
+            source,
+        };
+        notifier
+            .send(notification(
+                "done",
+                Some(NotificationSource {
+                    server: DEFAULT_SERVER.to_string(),
+                    topic: "backups".to_string(),
+                    id: "1".to_string(),
+                    time: 100,
+                }),
+            ))
+            .unwrap();
+        notifier.send(notification("line\nbreak", None)).unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"title":"Backup","body":"done","priority":3,"server":"https://ntfy.sh","topic":"backups","time":100}"#,
+                r#"{"title":"Backup","body":"line\nbreak","priority":3}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_status_conversions() {
+        for status in [Status::Down, Status::Degraded, Status::Up] {
+            assert_eq!(Status::try_from(u8::from(status)), Ok(status));
+        }
+        assert_eq!(Status::try_from(3), Err(3));
+        assert_eq!(Status::try_from(u8::MAX), Err(u8::MAX));
+    }
+
+    #[test]
+    fn test_relative_time() {
+        let now = 1_700_000_000;
+        assert_eq!(relative_time(now, now), "just now");
+        assert_eq!(relative_time(now - 59, now), "just now");
+        assert_eq!(relative_time(now - 5 * 60, now), "5m ago");
+        assert_eq!(relative_time(now - 2 * 3600 - 1, now), "2h ago");
+        assert_eq!(relative_time(now - 3 * 86_400, now), "3d ago");
+        assert_eq!(relative_time(now - 65 * 86_400, now), "2mo ago");
+        assert_eq!(relative_time(0, now), "53y ago");
+        assert_eq!(relative_time(now + 10 * 60, now), "in 10m");
+    }
+
+    #[test]
+    fn test_absolute_time() {
+        let tz = chrono::FixedOffset::east_opt(2 * 3600).unwrap();
+        assert_eq!(
+            absolute_time(1_700_000_000, &chrono::Utc),
+            "2023-11-14 22:13:20"
+        );
+        assert_eq!(absolute_time(1_700_000_000, &tz), "2023-11-15 00:13:20");
+        assert_eq!(absolute_time(0, &chrono::Utc), "1970-01-01 00:00:00");
+        assert_eq!(absolute_time(u64::MAX, &tz), INVALID_TIME);
+        assert_eq!(absolute_time(i64::MAX as u64, &tz), INVALID_TIME);
+    }
+
+    #[test]
+    fn test_invalid_notify_regex_is_rejected() {
+        let res = Subscription::builder("test".to_string())
+            .notify_regex(Some("FAILED(".to_string()))
+            .build();
+        assert!(matches!(
+            res,
+            Err(Error::InvalidSubscription(errs)) if matches!(errs[..], [Error::InvalidNotifyRegex(..)])
+        ));
+
+        let sub = Subscription::builder("test".to_string())
+            .notify_regex(Some(String::new()))
+            .build()
+            .unwrap();
+        assert!(sub.notify_regex.is_none());
+    }
+
+    #[test]
+    fn test_user_emoji_map_overrides_builtin() {
+        let builtin = builtin_emoji_map();
+        let map = merge_emoji_map(r#"{"warning": "🟡", "deploy": "🚀"}"#).unwrap();
+        assert_eq!(map["warning"], "🟡");
+        assert_eq!(map["deploy"], "🚀");
+        assert_eq!(map["tada"], builtin["tada"]);
+        assert!(merge_emoji_map(r#"["not", "a", "map"]"#).is_err());
+    }
+
+    #[test]
+    fn test_forward_keeps_title_message_and_tags() {
+        let msg = ReceivedMessage {
+            topic: "alerts".to_string(),
+            message: Some("disk full".to_string()),
+            time: 1000,
+            title: Some("server1".to_string()),
+            tags: vec!["warning".to_string()],
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&OutgoingMessage {
+            topic: "ops".to_string(),
+            ..msg.forward()
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            r#"{"topic":"ops","message":"disk full","title":"server1","tags":["warning"]}"#
+        );
+    }
+
+    #[test]
+    fn test_outgoing_message_leaves_out_unset_fields() {
+        let msg = OutgoingMessage {
+            topic: "test".to_string(),
+            message: Some("hi".to_string()),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, r#"{"topic":"test","message":"hi"}"#);
+
+        let json = serde_json::to_string(&OutgoingMessage {
+            topic: "test".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"topic":"test"}"#);
+
+        let json = serde_json::to_string(&OutgoingMessage {
+            markdown: true,
+            ..msg.clone()
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"topic":"test","message":"hi","markdown":true}"#);
+
+        let msg = OutgoingMessage {
+            title: Some("title".to_string()),
+            tags: vec!["tag".to_string()],
+            priority: Some(5),
+            delay: Some("30m".to_string()),
+            actions: vec![Action::Broadcast {
+                label: "label".to_string(),
+                intent: None,
+                extras: Default::default(),
+                clear: false,
+            }],
+            ..msg
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+        assert!(!value.to_string().contains("null"));
+        let parsed: OutgoingMessage = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.title, msg.title);
+        assert_eq!(parsed.tags, msg.tags);
+        assert_eq!(parsed.priority, msg.priority);
+        assert_eq!(parsed.delay, msg.delay);
+        assert_eq!(parsed.time, 0);
+    }
+
+    #[test]
+    fn test_split_publish_headers() {
+        let msg = serde_json::to_string(&OutgoingMessage {
+            topic: "test".to_string(),
+            cache: Some(false),
+            firebase: Some(true),
+            ..Default::default()
+        })
+        .unwrap();
+        let (body, headers) = split_publish_headers(&msg).unwrap();
+        assert_eq!(headers, vec![("Cache", "no"), ("Firebase", "yes")]);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body.get("cache").is_none());
+        assert!(body.get("firebase").is_none());
+        assert_eq!(body["topic"], "test");
+
+        let (_, headers) = split_publish_headers(r#"{"topic":"test"}"#).unwrap();
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_notification_persistence_for_message() {
+        let mut msg = ReceivedMessage::default();
+        assert_eq!(
+            NotificationPersistence::Default.for_message(&msg),
+            NotificationPersistence::Default
+        );
+        msg.priority = Some(5);
+        assert_eq!(
+            NotificationPersistence::Default.for_message(&msg),
+            NotificationPersistence::Resident
+        );
+        assert_eq!(
+            NotificationPersistence::Transient.for_message(&msg),
+            NotificationPersistence::Transient
+        );
+        msg.call = Some("+12223334444".to_string());
+        assert_eq!(
+            NotificationPersistence::Transient.for_message(&msg),
+            NotificationPersistence::Resident
+        );
+        for p in NotificationPersistence::ALL {
+            assert_eq!(p.as_str().parse::<NotificationPersistence>().unwrap(), p);
+        }
+    }
+
+    #[test]
+    fn test_requires_ack() {
+        let mut msg = ReceivedMessage {
+            priority: Some(5),
+            ..Default::default()
+        };
+        assert!(!msg.requires_ack());
+        msg.call = Some("+12223334444".to_string());
+        assert!(msg.requires_ack());
+        msg.priority = Some(4);
+        assert!(!msg.requires_ack());
+    }
+
+    #[test]
+    fn test_build_web_url() {
+        let url = Subscription::build_web_url("https://ntfy.sh", "mytopic").unwrap();
+        assert_eq!(url.as_str(), "https://ntfy.sh/mytopic");
+        let url = Subscription::build_web_url("https://example.com/ntfy/", "mytopic").unwrap();
+        assert_eq!(url.as_str(), "https://example.com/ntfy/mytopic");
+    }
+
+    #[test]
+    fn test_build_deep_link() {
+        let url = Subscription::build_deep_link("https://ntfy.sh", "mytopic").unwrap();
+        assert_eq!(url.as_str(), "ntfy://ntfy.sh/mytopic");
+        let url = Subscription::build_deep_link("http://localhost:8080/ntfy/", "mytopic").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "ntfy://localhost:8080/ntfy/mytopic?secure=false"
+        );
+    }
+
+    #[test]
+    fn test_parse_deep_link() {
+        for (server, topic) in [
+            ("https://ntfy.sh", "mytopic"),
+            ("http://localhost:8080/ntfy", "mytopic"),
+        ] {
+            let link = Subscription::build_deep_link(server, topic).unwrap();
+            assert_eq!(
+                Subscription::parse_deep_link(link.as_str()).unwrap(),
+                (server.to_string(), topic.to_string())
+            );
+        }
+        assert_eq!(
+            Subscription::parse_deep_link("https://ntfy.sh/mytopic").unwrap(),
+            ("https://ntfy.sh".to_string(), "mytopic".to_string())
+        );
+        assert!(Subscription::parse_deep_link("ntfy://ntfy.sh").is_err());
+        assert!(Subscription::parse_deep_link("ntfy://ntfy.sh/my topic").is_err());
+        assert!(Subscription::parse_deep_link("mailto:me@example.com").is_err());
+    }
+
+    #[test]
+    fn test_strip_ansi_escapes() {
+        assert_eq!(
+            StripAnsiEscapes::strip("\x1b[1;31mFAILED\x1b[0m: 2 tests"),
+            "FAILED: 2 tests"
+        );
+        assert_eq!(
+            StripAnsiEscapes::strip("\x1b]8;;https://ntfy.sh\x1b\\link\x1b]8;;\x1b\\"),
+            "link"
+        );
+        assert_eq!(StripAnsiEscapes::strip("plain [text]"), "plain [text]");
+    }
+
+    #[test]
+    fn test_build_scheduled_url() {
+        let url = Subscription::build_scheduled_url("https://ntfy.sh", "mytopic").unwrap();
+        assert_eq!(url.as_str(), "https://ntfy.sh/mytopic/json?poll=1&sched=1");
+    }
+
+    fn attachment(name: &str, atype: Option<&str>, size: Option<usize>) -> Attachment {
+        Attachment {
+            name: name.to_string(),
+            url: url::Url::parse("https://ntfy.sh/file/abc").unwrap(),
+            atype: atype.map(|s| s.to_string()),
+            size,
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn test_attachment_kind_from_extension() {
+        assert_eq!(
+            attachment("a.PNG", None, None).kind(),
+            AttachmentKind::Image
+        );
+        assert_eq!(
+            attachment("a.mp3", None, None).kind(),
+            AttachmentKind::Audio
+        );
+        assert_eq!(
+            attachment("a.webm", None, None).kind(),
+            AttachmentKind::Video
+        );
+        assert_eq!(
+            attachment("a.txt", None, None).kind(),
+            AttachmentKind::Other
+        );
+        assert_eq!(
+            attachment("photo", None, None).kind(),
+            AttachmentKind::Other
+        );
+    }
+
+    #[test]
+    fn test_attachment_mime_wins_over_extension() {
+        // Image without extension
+        let a = attachment("photo", Some("image/png"), None);
+        assert!(a.is_image());
+        assert!(a.is_displayable());
+
+        // HTML disguised as an image
+        let a = attachment("photo.png", Some("text/html; charset=utf-8"), None);
+        assert!(!a.is_image());
+        assert!(!a.is_displayable());
+
+        let a = attachment("song.png", Some("audio/ogg"), None);
+        assert_eq!(a.kind(), AttachmentKind::Audio);
+    }
+
+    #[test]
+    fn test_attachment_displayable() {
+        // Images we can't decode are still images, but not displayable
+        let a = attachment("a.svg", Some("image/svg+xml"), None);
+        assert!(a.is_image());
+        assert!(!a.is_displayable());
+
+        let a = attachment("a.png", None, Some(MAX_DISPLAYABLE_SIZE + 1));
+        assert!(a.is_image());
+        assert!(!a.is_displayable());
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::spawn_local;
+use tracing::{debug, info};
+
+use crate::listener::{
+    ConnectionState, ListenerCommand, ListenerConfig, ListenerEvent, ListenerStats,
+};
+use crate::ListenerHandle;
+
+// Subscriptions to the same server sharing a single connection, with the topics joined by commas.
+// Every subscription still gets its own `ListenerHandle`, the events are dispatched by topic.
+// Changing the set of topics reconnects, starting from the oldest `since` of the members.
+
+// Changes arriving closer than this, like the subscriptions restored at startup,
+// reconnect only once
+const RECONNECT_DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(100);
+
+enum MultiplexerCommand {
+    Join {
+        topic: String,
+        since: u64,
+        resp_tx: oneshot::Sender<ListenerHandle>,
+    },
+    // A command sent to the `ListenerHandle` of a member
+    Listener {
+        topic: String,
+        cmd: ListenerCommand,
+    },
+}
+
+struct Member {
+    event_tx: async_channel::Sender<ListenerEvent>,
+    // Time of the last event dispatched to the member
+    since: u64,
+}
+
+struct MultiplexerActor {
+    config: ListenerConfig,
+    members: BTreeMap<String, Member>,
+    listener: Option<ListenerHandle>,
+    state: ConnectionState,
+    // Of the shared connection, kept across reconnections
+    stats: Arc<Mutex<ListenerStats>>,
+    command_tx: mpsc::Sender<MultiplexerCommand>,
+    command_rx: mpsc::Receiver<MultiplexerCommand>,
+}
+
+#[derive(Clone)]
+pub struct MultiplexerHandle {
+    command_tx: mpsc::Sender<MultiplexerCommand>,
+}
+
+impl MultiplexerHandle {
+    // `config.topic` and `config.since` are ignored, they come from the members
+    pub fn new(config: ListenerConfig) -> Self {
+        let (command_tx, command_rx) = mpsc::channel(8);
+        let actor = MultiplexerActor {
+            config,
+            members: Default::default(),
+            listener: None,
+            state: ConnectionState::Unitialized,
+            stats: Default::default(),
+            command_tx: command_tx.clone(),
+            command_rx,
+        };
+        spawn_local(actor.run());
+        Self { command_tx }
+    }
+
+    pub async fn join(&self, topic: &str, since: u64) -> ListenerHandle {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(MultiplexerCommand::Join {
+                topic: topic.to_string(),
+                since,
+                resp_tx,
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+}
+
+impl MultiplexerActor {
+    async fn run(mut self) {
+        loop {
+            let events = self.listener.as_ref().map(|l| l.events.clone());
+            let next_event = async {
+                match events {
+                    Some(events) => events.recv().await.ok(),
+                    None => std::future::pending().await,
+                }
+            };
+            select! {
+                Some(cmd) = self.command_rx.recv() => {
+                    if self.handle_command(cmd) {
+                        self.reconnect_when_quiet().await;
+                    }
+                }
+                Some(ev) = next_event => self.dispatch(ev).await,
+            }
+        }
+    }
+
+    // Collects the commands following a change, then reconnects once
+    async fn reconnect_when_quiet(&mut self) {
+        while let Ok(Some(cmd)) =
+            tokio::time::timeout(RECONNECT_DEBOUNCE, self.command_rx.recv()).await
+        {
+            self.handle_command(cmd);
+        }
+        self.reconnect().await;
+    }
+
+    // Returns whether the connection must be restarted
+    fn handle_command(&mut self, cmd: MultiplexerCommand) -> bool {
+        match cmd {
+            MultiplexerCommand::Join {
+                topic,
+                since,
+                resp_tx,
+            } => {
+                let _ = resp_tx.send(self.join(topic, since));
+                true
+            }
+            MultiplexerCommand::Listener { topic, cmd } => match cmd {
+                ListenerCommand::Restart => true,
+                ListenerCommand::RestartSince(since) => {
+                    if let Some(member) = self.members.get_mut(&topic) {
+                        member.since = since;
+                    }
+                    true
+                }
+                ListenerCommand::Shutdown => {
+                    info!(topic, "leaving shared connection");
+                    self.members.remove(&topic).is_some()
+                }
+                ListenerCommand::GetState(tx) => {
+                    let _ = tx.send(self.state.clone());
+                    false
+                }
+            },
+        }
+    }
+
+    fn join(&mut self, topic: String, since: u64) -> ListenerHandle {
+        info!(endpoint = %self.config.endpoint, topic, "joining shared connection");
+        let (event_tx, event_rx) = async_channel::bounded(64);
+        let (commands_tx, mut commands_rx) = mpsc::channel(1);
+
+        let command_tx = self.command_tx.clone();
+        let member_topic = topic.clone();
+        spawn_local(async move {
+            while let Some(cmd) = commands_rx.recv().await {
+                let cmd = MultiplexerCommand::Listener {
+                    topic: member_topic.clone(),
+                    cmd,
+                };
+                if command_tx.send(cmd).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Like a new listener, the member starts knowing the connection state
+        let _ = event_tx.try_send(ListenerEvent::ConnectionStateChanged(self.state.clone()));
+        self.members
+            .insert(topic.clone(), Member { event_tx, since });
+
+        ListenerHandle {
+            events: event_rx,
+            config: ListenerConfig {
+                topic,
+                since,
+                ..self.config.clone()
+            },
+            commands: commands_tx,
+            stats: self.stats.clone(),
+        }
+    }
+
+    async fn reconnect(&mut self) {
+        if let Some(old) = self.listener.take() {
+            let _ = old.commands.send(ListenerCommand::Shutdown).await;
+            // Drained until the old listener stops, so it never sends to a closed channel
+            spawn_local(async move { while old.events.recv().await.is_ok() {} });
+        }
+        if self.members.is_empty() {
+            return;
+        }
+        let topics: Vec<&str> = self.members.keys().map(|t| t.as_str()).collect();
+        let since = self.members.values().map(|m| m.since).min().unwrap_or(0);
+        debug!(endpoint = %self.config.endpoint, ?topics, since, "connecting shared listener");
+        self.listener = Some(ListenerHandle::with_stats(
+            ListenerConfig {
+                topic: topics.join(","),
+                since,
+                ..self.config.clone()
+            },
+            self.stats.clone(),
+        ));
+    }
+
+    async fn dispatch(&mut self, ev: ListenerEvent) {
+        match ev {
+            ListenerEvent::Message(msg) => {
+                let Some(member) = self.members.get_mut(&msg.topic) else {
+                    debug!(topic = %msg.topic, "message for a topic without members");
+                    return;
+                };
+                // The connection restarts from the oldest `since` of the members,
+                // the others already had these messages
+                if msg.time < member.since {
+                    return;
+                }
+                member.since = msg.time;
+                let _ = member.event_tx.send(ListenerEvent::Message(msg)).await;
+            }
+            ev => {
+                match &ev {
+                    ListenerEvent::ConnectionStateChanged(state) => self.state = state.clone(),
+                    ListenerEvent::KeepAlive { time } => {
+                        for member in self.members.values_mut() {
+                            member.since = member.since.max(*time);
+                        }
+                    }
+                    _ => {}
+                }
+                for member in self.members.values() {
+                    let _ = member.event_tx.send(ev.clone()).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::LocalSet;
+
+    use super::*;
+    use crate::credentials::Credentials;
+    use crate::http_client::{HttpClient, NullableClient};
+    use crate::models::{Since, Subscription};
+
+    async fn next_message(handle: &ListenerHandle) -> crate::models::ReceivedMessage {
+        loop {
+            if let ListenerEvent::Message(msg) = handle.events.recv().await.unwrap() {
+                return msg;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_dispatched_by_topic() {
+        LocalSet::new()
+            .run_until(async {
+                let url =
+                    Subscription::build_url("http://localhost", "a,b", &Since::Time(0)).unwrap();
+                let body = [
+                    r#"{"id":"o","time":1,"event":"open","topic":"a,b"}"#,
+                    r#"{"id":"1","time":10,"event":"message","topic":"b","message":"to b"}"#,
+                    r#"{"id":"2","time":20,"event":"message","topic":"a","message":"to a"}"#,
+                ]
+                .join("\n");
+                let http_client = HttpClient::new_nullable(
+                    NullableClient::builder()
+                        .text_response(url.as_str(), 200, body)
+                        .build(),
+                );
+                let tracker = http_client.request_tracker().await;
+                let multiplexer = MultiplexerHandle::new(ListenerConfig {
+                    http_client,
+                    credentials: Credentials::new_nullable(vec![]).await.unwrap(),
+                    endpoint: "http://localhost".to_string(),
+                    topic: String::new(),
+                    since: 0,
+                    limiter: Default::default(),
+                });
+
+                let a = multiplexer.join("a", 0).await;
+                let b = multiplexer.join("b", 0).await;
+
+                assert_eq!(next_message(&a).await.id, "2");
+                assert_eq!(next_message(&b).await.id, "1");
+                // Joined together, a single connection is opened for both
+                let urls: Vec<_> = tracker.items().await.into_iter().map(|r| r.url).collect();
+                assert_eq!(urls, [url.to_string()]);
+            })
+            .await;
+    }
+}
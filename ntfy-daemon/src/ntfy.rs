@@ -4,37 +4,57 @@ use crate::models::NullNotifier;
 use anyhow::{anyhow, Context};
 use futures::future::join_all;
 use futures::StreamExt;
-use std::{collections::HashMap, future::Future, sync::Arc};
+use std::{cell::RefCell, collections::HashMap, future::Future, sync::Arc, time::Instant};
 use tokio::select;
 use tokio::{
-    sync::{broadcast, mpsc, oneshot, RwLock},
+    sync::{broadcast, mpsc, oneshot, watch, RwLock},
     task::{spawn_local, LocalSet},
 };
-use tracing::{error, info};
+use tracing::{debug, debug_span, error, info, instrument, warn, Instrument};
 
 use crate::{
+    health::Health,
     http_client::HttpClient,
     message_repo::Db,
     models::{self, Account},
-    ListenerActor, ListenerCommand, ListenerConfig, ListenerHandle, SharedEnv, SubscriptionHandle,
+    multiplexer::MultiplexerHandle,
+    ConnectionState, ListenerActor, ListenerCommand, ListenerConfig, ListenerEvent, ListenerHandle,
+    SharedEnv, SubscriptionHandle,
 };
 
 const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+// Time for `roundtrip_test` to connect, publish and receive the message back
+const ROUNDTRIP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+// Connecting to the keyring at startup, before falling back to no accounts
+const KEYRING_ATTEMPTS: u64 = 4;
+// How late a wall clock deadline can be after the machine resumes from suspend
+const WALL_CLOCK_CHECK: std::time::Duration = std::time::Duration::from_secs(60);
 const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(240); // 4 minutes
 
-pub fn build_client() -> anyhow::Result<reqwest::Client> {
-    Ok(reqwest::Client::builder()
+pub fn build_client(http1_only: bool) -> anyhow::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder()
         .connect_timeout(CONNECT_TIMEOUT)
         .pool_idle_timeout(TIMEOUT)
         // rustls is used because HTTP 2 isn't discovered with native-tls.
-        // HTTP 2 is required to multiplex multiple requests over a single connection.
+        // HTTP 2 is preferred to multiplex multiple requests over a single connection.
         // You can check that the app is using a single connection to a server by doing
         // ```
         // ping ntfy.sh # to get the ip address
         // netstat | grep $ip
         // ```
-        .use_rustls_tls()
-        .build()?)
+        .use_rustls_tls();
+    // For the servers behind proxies breaking HTTP 2 streams, see `HttpClient::set_http1_servers`.
+    // Each request needs its own connection.
+    let builder = if http1_only {
+        builder.http1_only()
+    } else {
+        builder
+    };
+    Ok(builder.build()?)
+}
+
+fn build_http_client() -> anyhow::Result<HttpClient> {
+    Ok(HttpClient::new(build_client(false)?).with_http1_client(build_client(true)?))
 }
 
 // Message types for the actor
@@ -43,11 +63,12 @@ pub enum NtfyCommand {
     Subscribe {
         server: String,
         topic: String,
-        resp_tx: oneshot::Sender<Result<SubscriptionHandle, anyhow::Error>>,
+        resp_tx: oneshot::Sender<Result<SubscribeOutcome, anyhow::Error>>,
     },
     Unsubscribe {
         server: String,
         topic: String,
+        keep_history: bool,
         resp_tx: oneshot::Sender<anyhow::Result<()>>,
     },
     RefreshAll {
@@ -56,12 +77,19 @@ pub enum NtfyCommand {
     ListSubscriptions {
         resp_tx: oneshot::Sender<anyhow::Result<Vec<SubscriptionHandle>>>,
     },
+    ReorderSubscriptions {
+        order: Vec<(String, String)>,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
     ListAccounts {
         resp_tx: oneshot::Sender<anyhow::Result<Vec<Account>>>,
     },
     WatchSubscribed {
         resp_tx: oneshot::Sender<anyhow::Result<()>>,
     },
+    CredentialsAvailable {
+        resp_tx: oneshot::Sender<anyhow::Result<bool>>,
+    },
     AddAccount {
         server: String,
         username: String,
@@ -72,6 +100,147 @@ pub enum NtfyCommand {
         server: String,
         resp_tx: oneshot::Sender<anyhow::Result<()>>,
     },
+    ListReservations {
+        server: String,
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<models::Reservation>>>,
+    },
+    ListTokens {
+        server: String,
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<models::AccessToken>>>,
+    },
+    DeleteReservation {
+        server: String,
+        topic: String,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    DeleteToken {
+        server: String,
+        token: String,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetMinPriority {
+        priority: i8,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    PauseNotifications {
+        resume_at: Option<u64>,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    ResumeNotifications {
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetHttp1Servers {
+        servers: Vec<String>,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetMessageTransforms {
+        transforms: Vec<Arc<dyn models::MessageTransform>>,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetNotifyConnectionLost {
+        enabled: bool,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetPreviewLength {
+        length: usize,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetMaxConcurrentConnections {
+        limit: usize,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetDailyDigest {
+        at: Option<chrono::NaiveTime>,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    CompactDatabase {
+        resp_tx: oneshot::Sender<anyhow::Result<u64>>,
+    },
+    ClearAllNotifications {
+        resp_tx: oneshot::Sender<anyhow::Result<u64>>,
+    },
+    RoundtripTest {
+        server: String,
+        resp_tx: oneshot::Sender<anyhow::Result<std::time::Duration>>,
+    },
+    Publish {
+        server: String,
+        msg: String,
+        auth: Option<models::PublishAuth>,
+        resp_tx: oneshot::Sender<anyhow::Result<String>>,
+    },
+    ReplayUnreadNotifications {
+        resp_tx: oneshot::Sender<anyhow::Result<usize>>,
+    },
+    Health {
+        resp_tx: oneshot::Sender<anyhow::Result<Health>>,
+    },
+    ServeHealth {
+        ntfy: NtfyHandle,
+        path: std::path::PathBuf,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    StopServingHealth {
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+}
+
+impl NtfyCommand {
+    // Names the command in the tracing spans
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Subscribe { .. } => "Subscribe",
+            Self::Unsubscribe { .. } => "Unsubscribe",
+            Self::RefreshAll { .. } => "RefreshAll",
+            Self::ListSubscriptions { .. } => "ListSubscriptions",
+            Self::ReorderSubscriptions { .. } => "ReorderSubscriptions",
+            Self::ListAccounts { .. } => "ListAccounts",
+            Self::WatchSubscribed { .. } => "WatchSubscribed",
+            Self::CredentialsAvailable { .. } => "CredentialsAvailable",
+            Self::AddAccount { .. } => "AddAccount",
+            Self::RemoveAccount { .. } => "RemoveAccount",
+            Self::ListReservations { .. } => "ListReservations",
+            Self::ListTokens { .. } => "ListTokens",
+            Self::DeleteReservation { .. } => "DeleteReservation",
+            Self::DeleteToken { .. } => "DeleteToken",
+            Self::SetMinPriority { .. } => "SetMinPriority",
+            Self::PauseNotifications { .. } => "PauseNotifications",
+            Self::ResumeNotifications { .. } => "ResumeNotifications",
+            Self::SetHttp1Servers { .. } => "SetHttp1Servers",
+            Self::SetMessageTransforms { .. } => "SetMessageTransforms",
+            Self::SetNotifyConnectionLost { .. } => "SetNotifyConnectionLost",
+            Self::SetPreviewLength { .. } => "SetPreviewLength",
+            Self::SetMaxConcurrentConnections { .. } => "SetMaxConcurrentConnections",
+            Self::SetDailyDigest { .. } => "SetDailyDigest",
+            Self::CompactDatabase { .. } => "CompactDatabase",
+            Self::ClearAllNotifications { .. } => "ClearAllNotifications",
+            Self::RoundtripTest { .. } => "RoundtripTest",
+            Self::Publish { .. } => "Publish",
+            Self::ReplayUnreadNotifications { .. } => "ReplayUnreadNotifications",
+            Self::Health { .. } => "Health",
+            Self::ServeHealth { .. } => "ServeHealth",
+            Self::StopServingHealth { .. } => "StopServingHealth",
+        }
+    }
+}
+
+pub enum SubscribeOutcome {
+    Subscribed(SubscriptionHandle),
+    // The topic was already subscribed, the handle is the existing one
+    AlreadySubscribed(SubscriptionHandle),
+}
+
+impl SubscribeOutcome {
+    pub fn handle(&self) -> &SubscriptionHandle {
+        match self {
+            Self::Subscribed(handle) | Self::AlreadySubscribed(handle) => handle,
+        }
+    }
+    pub fn into_handle(self) -> SubscriptionHandle {
+        match self {
+            Self::Subscribed(handle) | Self::AlreadySubscribed(handle) => handle,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -80,59 +249,122 @@ pub struct WatchKey {
     topic: String,
 }
 
+impl WatchKey {
+    fn new(server: &str, topic: &str) -> Self {
+        Self {
+            server: models::canonical_server(server),
+            topic: topic.to_string(),
+        }
+    }
+}
+
 pub struct NtfyActor {
     listener_handles: Arc<RwLock<HashMap<WatchKey, SubscriptionHandle>>>,
     env: SharedEnv,
+    connection_mode: models::ConnectionMode,
+    multiplexers: RefCell<HashMap<String, MultiplexerHandle>>,
+    started_at: Instant,
     command_rx: mpsc::Receiver<NtfyCommand>,
+    // True once the stored subscriptions are watched
+    ready_tx: watch::Sender<bool>,
+    health_server: Option<tokio::task::JoinHandle<()>>,
+    // Local time of the daily digest, None if disabled
+    digest_at: Option<chrono::NaiveTime>,
+    // On the wall clock, which keeps going while the machine is suspended
+    next_digest: Option<chrono::DateTime<chrono::Local>>,
 }
 
 #[derive(Clone)]
 pub struct NtfyHandle {
     command_tx: mpsc::Sender<NtfyCommand>,
+    ready_rx: watch::Receiver<bool>,
 }
 
 impl NtfyActor {
     pub fn new(env: SharedEnv) -> (Self, NtfyHandle) {
         let (command_tx, command_rx) = mpsc::channel(32);
+        let (ready_tx, ready_rx) = watch::channel(false);
 
         let actor = Self {
             listener_handles: Default::default(),
             env,
+            connection_mode: Default::default(),
+            multiplexers: Default::default(),
+            started_at: Instant::now(),
             command_rx,
+            ready_tx,
+            health_server: None,
+            digest_at: None,
+            next_digest: None,
         };
 
-        let handle = NtfyHandle { command_tx };
+        let handle = NtfyHandle {
+            command_tx,
+            ready_rx,
+        };
 
         (actor, handle)
     }
 
+    #[instrument(skip(self))]
     async fn handle_subscribe(
         &self,
         server: String,
         topic: String,
-    ) -> Result<SubscriptionHandle, anyhow::Error> {
+    ) -> Result<SubscribeOutcome, anyhow::Error> {
+        let key = WatchKey::new(&server, &topic);
+        if let Some(handle) = self.listener_handles.read().await.get(&key) {
+            info!(server, topic, "already subscribed");
+            return Ok(SubscribeOutcome::AlreadySubscribed(handle.clone()));
+        }
+        // Don't download the whole retained history of busy topics
+        let since = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .saturating_sub(models::DEFAULT_BACKFILL)
+            .as_secs();
         let subscription = models::Subscription::builder(topic.clone())
             .server(server.clone())
+            .last_received_time(since)
             .build()?;
 
         let mut db = self.env.db.clone();
-        db.insert_subscription(subscription.clone())?;
+        // An archived subscription keeps its history and settings
+        let subscription = db.insert_subscription(subscription)?;
 
-        self.listen(subscription).await
+        Ok(SubscribeOutcome::Subscribed(
+            self.listen(subscription).await?,
+        ))
     }
 
-    async fn handle_unsubscribe(&mut self, server: String, topic: String) -> anyhow::Result<()> {
-        let subscription = self.listener_handles.write().await.remove(&WatchKey {
-            server: server.clone(),
-            topic: topic.clone(),
-        });
+    // Keeping the history archives the subscription: its messages and settings come back
+    // when subscribing again. Otherwise its messages are deleted with it.
+    async fn handle_unsubscribe(
+        &mut self,
+        server: String,
+        topic: String,
+        keep_history: bool,
+    ) -> anyhow::Result<()> {
+        let subscription = self
+            .listener_handles
+            .write()
+            .await
+            .remove(&WatchKey::new(&server, &topic));
 
         if let Some(sub) = subscription {
             sub.shutdown().await?;
         }
 
-        self.env.db.remove_subscription(&server, &topic)?;
-        info!(server, topic, "Unsubscribed");
+        if keep_history {
+            let sub = self.env.db.get_subscription(&server, &topic)?;
+            self.env.db.update_subscription(models::Subscription {
+                archived: true,
+                ..sub
+            })?;
+        } else {
+            // The messages are deleted by the foreign key cascade
+            self.env.db.remove_subscription(&server, &topic)?;
+        }
+        info!(server, topic, keep_history, "Unsubscribed");
         Ok(())
     }
 
@@ -143,7 +375,21 @@ impl NtfyActor {
                 Some(_) = network_change_stream.next() => {
                     let _ = self.refresh_all().await;
                 },
-                Some(command) = self.command_rx.recv() => self.handle_command(command).await,
+                _ = wait_until(self.next_digest) => {
+                    if self.next_digest.is_some_and(|at| chrono::Local::now() >= at) {
+                        if let Err(e) = self.send_digest() {
+                            error!(error = ?e, "can't send the daily digest");
+                        }
+                        self.schedule_digest();
+                    }
+                },
+                Some(command) = self.command_rx.recv() => {
+                    // Commands answered by a spawned task only count the time to start it
+                    let span = debug_span!("ntfy_command", command = command.name());
+                    let started = Instant::now();
+                    self.handle_command(command).instrument(span.clone()).await;
+                    debug!(parent: &span, elapsed_ms = started.elapsed().as_millis() as u64, "handled command");
+                }
             };
         }
     }
@@ -162,9 +408,10 @@ impl NtfyActor {
             NtfyCommand::Unsubscribe {
                 server,
                 topic,
+                keep_history,
                 resp_tx,
             } => {
-                let result = self.handle_unsubscribe(server, topic).await;
+                let result = self.handle_unsubscribe(server, topic, keep_history).await;
                 let _ = resp_tx.send(result);
             }
 
@@ -174,14 +421,12 @@ impl NtfyActor {
             }
 
             NtfyCommand::ListSubscriptions { resp_tx } => {
-                let subs = self
-                    .listener_handles
-                    .read()
-                    .await
-                    .values()
-                    .cloned()
-                    .collect();
-                let _ = resp_tx.send(Ok(subs));
+                let _ = resp_tx.send(self.handle_list_subscriptions().await);
+            }
+
+            NtfyCommand::ReorderSubscriptions { order, resp_tx } => {
+                let res = self.env.db.reorder_subscriptions(&order);
+                let _ = resp_tx.send(res.map_err(|e| e.into()));
             }
 
             NtfyCommand::ListAccounts { resp_tx } => {
@@ -200,30 +445,324 @@ impl NtfyActor {
 
             NtfyCommand::WatchSubscribed { resp_tx } => {
                 let result = self.handle_watch_subscribed().await;
+                // Even on failure, so the callers waiting don't hang
+                self.ready_tx.send_replace(true);
                 let _ = resp_tx.send(result);
             }
 
+            NtfyCommand::CredentialsAvailable { resp_tx } => {
+                let _ = resp_tx.send(Ok(self.env.credentials.is_available()));
+            }
+
             NtfyCommand::AddAccount {
                 server,
                 username,
                 password,
                 resp_tx,
             } => {
-                let result = self
-                    .env
-                    .credentials
-                    .insert(&server, &username, &password)
-                    .await;
-                let _ = resp_tx.send(result);
+                let env = self.env.clone();
+                spawn_local(async move {
+                    let res = async {
+                        validate_account(&env.http_client, &server, &username, &password).await?;
+                        env.credentials.insert(&server, &username, &password).await
+                    };
+                    let _ = resp_tx.send(res.await);
+                });
             }
 
             NtfyCommand::RemoveAccount { server, resp_tx } => {
                 let result = self.env.credentials.delete(&server).await;
                 let _ = resp_tx.send(result);
             }
+
+            NtfyCommand::ListReservations { server, resp_tx } => {
+                let env = self.env.clone();
+                spawn_local(async move {
+                    let res = fetch_account_info(&env, &server).await;
+                    let _ = resp_tx.send(res.map(|info| info.reservations));
+                });
+            }
+
+            NtfyCommand::ListTokens { server, resp_tx } => {
+                let env = self.env.clone();
+                spawn_local(async move {
+                    let res = fetch_account_info(&env, &server).await;
+                    let _ = resp_tx.send(res.map(|info| info.tokens));
+                });
+            }
+
+            NtfyCommand::DeleteReservation {
+                server,
+                topic,
+                resp_tx,
+            } => {
+                info!(server, topic, "deleting reservation");
+                let env = self.env.clone();
+                spawn_local(async move {
+                    let res =
+                        delete_account_item(&env, &server, &["reservation", &topic], None).await;
+                    let _ = resp_tx.send(res);
+                });
+            }
+
+            NtfyCommand::DeleteToken {
+                server,
+                token,
+                resp_tx,
+            } => {
+                info!(server, "revoking access token");
+                let env = self.env.clone();
+                spawn_local(async move {
+                    let res = delete_account_item(&env, &server, &["token"], Some(&token)).await;
+                    let _ = resp_tx.send(res);
+                });
+            }
+
+            NtfyCommand::SetMinPriority { priority, resp_tx } => {
+                info!(priority, "setting global minimum notification priority");
+                self.env.notification_settings.write().unwrap().min_priority = priority;
+                let _ = resp_tx.send(Ok(()));
+            }
+
+            NtfyCommand::PauseNotifications { resume_at, resp_tx } => {
+                info!(?resume_at, "pausing notifications");
+                let mut settings = self.env.notification_settings.write().unwrap();
+                settings.paused = true;
+                settings.resume_at = resume_at;
+                let _ = resp_tx.send(Ok(()));
+            }
+
+            NtfyCommand::ResumeNotifications { resp_tx } => {
+                info!("resuming notifications");
+                let mut settings = self.env.notification_settings.write().unwrap();
+                settings.paused = false;
+                settings.resume_at = None;
+                let _ = resp_tx.send(Ok(()));
+            }
+
+            NtfyCommand::SetHttp1Servers { servers, resp_tx } => {
+                let res = if self.env.http_client.set_http1_servers(&servers) {
+                    info!(?servers, "forcing HTTP/1.1");
+                    // Reconnected with the new protocol
+                    self.refresh_all().await
+                } else {
+                    Ok(())
+                };
+                let _ = resp_tx.send(res);
+            }
+
+            NtfyCommand::SetMessageTransforms {
+                transforms,
+                resp_tx,
+            } => {
+                *self.env.message_transforms.write().unwrap() = transforms;
+                let _ = resp_tx.send(Ok(()));
+            }
+
+            NtfyCommand::SetNotifyConnectionLost { enabled, resp_tx } => {
+                self.env
+                    .notification_settings
+                    .write()
+                    .unwrap()
+                    .notify_connection_lost = enabled;
+                let _ = resp_tx.send(Ok(()));
+            }
+
+            NtfyCommand::SetPreviewLength { length, resp_tx } => {
+                self.env
+                    .notification_settings
+                    .write()
+                    .unwrap()
+                    .preview_length = length;
+                let _ = resp_tx.send(Ok(()));
+            }
+
+            NtfyCommand::SetMaxConcurrentConnections { limit, resp_tx } => {
+                self.env.connection_limiter.set_limit(limit);
+                let _ = resp_tx.send(Ok(()));
+            }
+
+            NtfyCommand::SetDailyDigest { at, resp_tx } => {
+                self.digest_at = at;
+                self.schedule_digest();
+                let _ = resp_tx.send(Ok(()));
+            }
+
+            NtfyCommand::CompactDatabase { resp_tx } => {
+                let _ = resp_tx.send(self.handle_compact_database());
+            }
+
+            NtfyCommand::ClearAllNotifications { resp_tx } => {
+                let res = self.env.db.delete_all_messages();
+                if let Ok(cleared) = res {
+                    info!(cleared, "cleared all the notifications");
+                }
+                let _ = resp_tx.send(res.map_err(Into::into));
+            }
+
+            NtfyCommand::ReplayUnreadNotifications { resp_tx } => {
+                let subs: Vec<_> = self
+                    .listener_handles
+                    .read()
+                    .await
+                    .values()
+                    .cloned()
+                    .collect();
+                let notifier = self.env.notifier.clone();
+                // Sent in the background, a few at a time
+                spawn_local(async move {
+                    let _ = resp_tx.send(replay_unread_notifications(subs, notifier).await);
+                });
+            }
+
+            NtfyCommand::Publish {
+                server,
+                msg,
+                auth,
+                resp_tx,
+            } => {
+                let env = self.env.clone();
+                spawn_local(async move {
+                    let res = crate::subscription::publish(&env, &server, &msg, auth).await;
+                    let _ = resp_tx.send(res);
+                });
+            }
+
+            NtfyCommand::RoundtripTest { server, resp_tx } => {
+                let env = self.env.clone();
+                spawn_local(async move {
+                    let _ = resp_tx.send(roundtrip_test(&env, &server).await);
+                });
+            }
+
+            NtfyCommand::Health { resp_tx } => {
+                let _ = resp_tx.send(Ok(self.handle_health().await));
+            }
+
+            NtfyCommand::ServeHealth {
+                ntfy,
+                path,
+                resp_tx,
+            } => {
+                self.stop_serving_health().await;
+                let res = crate::health::serve(ntfy, path).map(|server| {
+                    self.health_server = Some(server);
+                });
+                let _ = resp_tx.send(res);
+            }
+
+            NtfyCommand::StopServingHealth { resp_tx } => {
+                self.stop_serving_health().await;
+                let _ = resp_tx.send(Ok(()));
+            }
+        }
+    }
+
+    fn schedule_digest(&mut self) {
+        self.next_digest = self.digest_at.map(|at| {
+            let now = chrono::Local::now();
+            let delay = models::until_next(at, &now);
+            debug!(?delay, "scheduled the daily digest");
+            now + delay
+        });
+    }
+
+    // Counts the messages of the last day. Held back, like the other notifications,
+    // while notifications are paused.
+    fn send_digest(&self) -> anyhow::Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        if self
+            .env
+            .notification_settings
+            .read()
+            .unwrap()
+            .is_paused(now)
+        {
+            info!("notifications paused, skipping the daily digest");
+            return Ok(());
+        }
+        let since = now.saturating_sub(24 * 60 * 60);
+        let subs = self.env.db.clone().list_subscriptions()?;
+        let counts: Vec<(String, u64)> = self
+            .env
+            .db
+            .message_counts_since(since)?
+            .into_iter()
+            .map(|(server, topic, count)| {
+                let name = subs
+                    .iter()
+                    .find(|s| s.server == server && s.topic == topic)
+                    .map_or(topic, |s| s.display_name_or_topic().to_string());
+                (name, count)
+            })
+            .collect();
+        match models::digest_notification(&counts) {
+            Some(n) => {
+                info!("sending the daily digest");
+                self.env.notifier.send(n)?;
+            }
+            None => debug!("no messages in the last 24 hours, skipping the daily digest"),
+        }
+        Ok(())
+    }
+
+    // Resolves once the socket is removed
+    async fn stop_serving_health(&mut self) {
+        if let Some(server) = self.health_server.take() {
+            server.abort();
+            let _ = server.await;
+        }
+    }
+
+    async fn handle_health(&self) -> Health {
+        let subs: Vec<_> = self
+            .listener_handles
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect();
+        let mut health = Health {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            subscriptions: subs.len(),
+            ..Default::default()
+        };
+        for sub in subs {
+            match sub.connection_state().await {
+                ConnectionState::Connected => health.connected += 1,
+                ConnectionState::Reconnecting { error: Some(_), .. } => health.failing += 1,
+                _ => {}
+            }
         }
+        health
+    }
+
+    // In the order chosen by the user
+    async fn handle_list_subscriptions(&self) -> anyhow::Result<Vec<SubscriptionHandle>> {
+        let mut handles = self.listener_handles.read().await.clone();
+        let mut subs: Vec<_> = self
+            .env
+            .db
+            .clone()
+            .list_subscriptions()?
+            .into_iter()
+            .filter_map(|m| handles.remove(&WatchKey::new(&m.server, &m.topic)))
+            .collect();
+        subs.extend(handles.into_values());
+        Ok(subs)
+    }
+
+    fn handle_compact_database(&self) -> anyhow::Result<u64> {
+        let before = self.env.db.size()?;
+        self.env.db.vacuum()?;
+        let reclaimed = before.saturating_sub(self.env.db.size()?);
+        info!(reclaimed, "compacted database");
+        Ok(reclaimed)
     }
 
+    #[instrument(skip_all)]
     async fn handle_watch_subscribed(&mut self) -> anyhow::Result<()> {
         let f: Vec<_> = self
             .env
@@ -232,6 +771,7 @@ impl NtfyActor {
             .into_iter()
             .map(|m| self.listen(m))
             .collect();
+        debug!(count = f.len(), "restoring subscriptions");
 
         join_all(f.into_iter().map(|x| async move {
             if let Err(e) = x.await {
@@ -249,21 +789,37 @@ impl NtfyActor {
     ) -> impl Future<Output = anyhow::Result<SubscriptionHandle>> {
         let server = sub.server.clone();
         let topic = sub.topic.clone();
-        let listener = ListenerHandle::new(ListenerConfig {
+        let config = ListenerConfig {
             http_client: self.env.http_client.clone(),
             credentials: self.env.credentials.clone(),
             endpoint: server.clone(),
             topic: topic.clone(),
-            since: sub.read_until,
-        });
+            since: sub.last_received_time,
+            limiter: self.env.connection_limiter.clone(),
+        };
+        let multiplexer = match self.connection_mode {
+            models::ConnectionMode::PerTopic => None,
+            models::ConnectionMode::SharedPerServer => Some(
+                self.multiplexers
+                    .borrow_mut()
+                    .entry(server.clone())
+                    .or_insert_with(|| MultiplexerHandle::new(config.clone()))
+                    .clone(),
+            ),
+        };
         let listener_handles = self.listener_handles.clone();
-        let sub = SubscriptionHandle::new(listener.clone(), sub, &self.env);
+        let env = self.env.clone();
 
         async move {
+            let listener = match multiplexer {
+                Some(multiplexer) => multiplexer.join(&topic, sub.last_received_time).await,
+                None => ListenerHandle::new(config),
+            };
+            let sub = SubscriptionHandle::new(listener, sub, &env);
             listener_handles
                 .write()
                 .await
-                .insert(WatchKey { server, topic }, sub.clone());
+                .insert(WatchKey::new(&server, &topic), sub.clone());
             Ok(sub)
         }
     }
@@ -285,7 +841,7 @@ impl NtfyHandle {
         &self,
         server: &str,
         topic: &str,
-    ) -> Result<SubscriptionHandle, anyhow::Error> {
+    ) -> Result<SubscribeOutcome, anyhow::Error> {
         send_command!(self, |resp_tx| NtfyCommand::Subscribe {
             server: server.to_string(),
             topic: topic.to_string(),
@@ -293,10 +849,17 @@ impl NtfyHandle {
         })
     }
 
-    pub async fn unsubscribe(&self, server: &str, topic: &str) -> anyhow::Result<()> {
+    // `keep_history` keeps the stored messages, shown again when subscribing to the topic
+    pub async fn unsubscribe(
+        &self,
+        server: &str,
+        topic: &str,
+        keep_history: bool,
+    ) -> anyhow::Result<()> {
         send_command!(self, |resp_tx| NtfyCommand::Unsubscribe {
             server: server.to_string(),
             topic: topic.to_string(),
+            keep_history,
             resp_tx,
         })
     }
@@ -309,6 +872,14 @@ impl NtfyHandle {
         send_command!(self, |resp_tx| NtfyCommand::ListSubscriptions { resp_tx })
     }
 
+    // Saves the order of the subscriptions, given as (server, topic) pairs
+    pub async fn reorder_subscriptions(&self, order: Vec<(String, String)>) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::ReorderSubscriptions {
+            order,
+            resp_tx
+        })
+    }
+
     pub async fn list_accounts(&self) -> anyhow::Result<Vec<Account>> {
         send_command!(self, |resp_tx| NtfyCommand::ListAccounts { resp_tx })
     }
@@ -317,6 +888,23 @@ impl NtfyHandle {
         send_command!(self, |resp_tx| NtfyCommand::WatchSubscribed { resp_tx })
     }
 
+    // Resolves once the stored subscriptions are watched, so listing them returns all of them
+    pub async fn wait_ready(&self) -> anyhow::Result<()> {
+        self.ready_rx
+            .clone()
+            .wait_for(|ready| *ready)
+            .await
+            .map_err(|_| anyhow!("the daemon stopped before being ready"))?;
+        Ok(())
+    }
+
+    // False if the password storage couldn't be reached and accounts are disabled
+    pub async fn credentials_available(&self) -> anyhow::Result<bool> {
+        send_command!(self, |resp_tx| NtfyCommand::CredentialsAvailable {
+            resp_tx
+        })
+    }
+
     pub async fn add_account(
         &self,
         server: &str,
@@ -337,15 +925,484 @@ impl NtfyHandle {
             resp_tx,
         })
     }
+
+    // Topics reserved by the stored account of the server.
+    // Empty if there's no account or the server doesn't support reservations.
+    pub async fn list_reservations(
+        &self,
+        server: &str,
+    ) -> anyhow::Result<Vec<models::Reservation>> {
+        send_command!(self, |resp_tx| NtfyCommand::ListReservations {
+            server: server.to_string(),
+            resp_tx,
+        })
+    }
+
+    // Access tokens of the stored account of the server, empty like `list_reservations`
+    pub async fn list_tokens(&self, server: &str) -> anyhow::Result<Vec<models::AccessToken>> {
+        send_command!(self, |resp_tx| NtfyCommand::ListTokens {
+            server: server.to_string(),
+            resp_tx,
+        })
+    }
+
+    pub async fn delete_reservation(&self, server: &str, topic: &str) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::DeleteReservation {
+            server: server.to_string(),
+            topic: topic.to_string(),
+            resp_tx,
+        })
+    }
+
+    // Clients using the token lose access to the account
+    pub async fn delete_token(&self, server: &str, token: &str) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::DeleteToken {
+            server: server.to_string(),
+            token: token.to_string(),
+            resp_tx,
+        })
+    }
+
+    // Applied to the messages received from now on
+    pub async fn set_message_transforms(
+        &self,
+        transforms: Vec<Arc<dyn models::MessageTransform>>,
+    ) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::SetMessageTransforms {
+            transforms,
+            resp_tx
+        })
+    }
+
+    pub async fn set_notify_connection_lost(&self, enabled: bool) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::SetNotifyConnectionLost {
+            enabled,
+            resp_tx
+        })
+    }
+
+    // Maximum characters of the message shown in the desktop notifications
+    pub async fn set_preview_length(&self, length: usize) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::SetPreviewLength {
+            length,
+            resp_tx
+        })
+    }
+
+    // How many connections can be established at the same time
+    pub async fn set_max_concurrent_connections(&self, limit: usize) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::SetMaxConcurrentConnections {
+            limit,
+            resp_tx
+        })
+    }
+
+    // Returns the number of bytes reclaimed. The database is blocked while this runs.
+    pub async fn compact_database(&self) -> anyhow::Result<u64> {
+        send_command!(self, |resp_tx| NtfyCommand::CompactDatabase { resp_tx })
+    }
+
+    // Deletes the messages of all the subscriptions. Returns how many were deleted.
+    pub async fn clear_all_notifications(&self) -> anyhow::Result<u64> {
+        send_command!(self, |resp_tx| NtfyCommand::ClearAllNotifications {
+            resp_tx
+        })
+    }
+
+    // Shows again the notifications of the unread messages, e.g. the ones lost with a restart.
+    // Returns how many were sent.
+    pub async fn replay_unread_notifications(&self) -> anyhow::Result<usize> {
+        send_command!(self, |resp_tx| NtfyCommand::ReplayUnreadNotifications {
+            resp_tx
+        })
+    }
+
+    // Publishes to any server, subscribed or not, with the stored credentials of the server.
+    // Returns the server response.
+    // `auth` is used instead of the account stored for the server, only for this message
+    pub async fn publish(
+        &self,
+        server: &str,
+        msg: &str,
+        auth: Option<models::PublishAuth>,
+    ) -> anyhow::Result<String> {
+        send_command!(self, |resp_tx| NtfyCommand::Publish {
+            server: server.to_string(),
+            msg: msg.to_string(),
+            auth,
+            resp_tx,
+        })
+    }
+
+    // Subscribes to a random topic of `server`, publishes a message to it and waits for it
+    // to come back. Returns the time between publishing and receiving.
+    pub async fn roundtrip_test(&self, server: &str) -> anyhow::Result<std::time::Duration> {
+        send_command!(self, |resp_tx| NtfyCommand::RoundtripTest {
+            server: server.to_string(),
+            resp_tx,
+        })
+    }
+
+    pub async fn health(&self) -> anyhow::Result<Health> {
+        send_command!(self, |resp_tx| NtfyCommand::Health { resp_tx })
+    }
+
+    // Answers health checks on the unix socket at `path`, see `health::query`
+    pub async fn serve_health(&self, path: std::path::PathBuf) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::ServeHealth {
+            ntfy: self.clone(),
+            path,
+            resp_tx,
+        })
+    }
+
+    // Sends every day, at the local time `at`, how many messages each topic received.
+    // None disables it.
+    pub async fn set_daily_digest(&self, at: Option<chrono::NaiveTime>) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::SetDailyDigest { at, resp_tx })
+    }
+
+    // Removes the health check socket. Must be called before exiting, the daemon thread
+    // is stopped without running any cleanup.
+    pub async fn stop_serving_health(&self) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::StopServingHealth { resp_tx })
+    }
+
+    // Holds back every notification, for `duration` or until resumed.
+    // Unlike muting a subscription, this is temporary and applies to every topic.
+    pub async fn pause_notifications(
+        &self,
+        duration: Option<std::time::Duration>,
+    ) -> anyhow::Result<()> {
+        let resume_at = match duration {
+            Some(duration) => Some(
+                (std::time::SystemTime::now() + duration)
+                    .duration_since(std::time::UNIX_EPOCH)?
+                    .as_secs(),
+            ),
+            None => None,
+        };
+        send_command!(self, |resp_tx| NtfyCommand::PauseNotifications {
+            resume_at,
+            resp_tx
+        })
+    }
+
+    pub async fn resume_notifications(&self) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::ResumeNotifications { resp_tx })
+    }
+
+    // Servers (like https://ntfy.example.com) always contacted with HTTP/1.1
+    pub async fn set_http1_servers(&self, servers: Vec<String>) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::SetHttp1Servers {
+            servers,
+            resp_tx
+        })
+    }
+
+    // Messages with a lower priority won't show desktop notifications, on every topic.
+    // They are still stored and shown in the app.
+    pub async fn set_min_priority(&self, priority: i8) -> anyhow::Result<()> {
+        send_command!(self, |resp_tx| NtfyCommand::SetMinPriority {
+            priority,
+            resp_tx
+        })
+    }
+}
+
+// `<server>/v1/account/<segments>`
+fn account_url(server: &str, segments: &[&str]) -> anyhow::Result<url::Url> {
+    let mut url = url::Url::parse(server).map_err(crate::Error::from)?;
+    url.path_segments_mut()
+        .map_err(|_| {
+            crate::Error::InvalidServer(url::ParseError::RelativeUrlWithCannotBeABaseBase)
+        })?
+        .pop_if_empty()
+        .extend(["v1", "account"])
+        .extend(segments);
+    Ok(url)
+}
+
+fn account_error(server: &str, status: reqwest::StatusCode) -> crate::Error {
+    match status {
+        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+            crate::Error::InvalidCredentials(server.to_string())
+        }
+        reqwest::StatusCode::NOT_FOUND => crate::Error::AccountsUnsupported(server.to_string()),
+        _ => crate::Error::UnexpectedResponse(server.to_string(), status.as_u16()),
+    }
+}
+
+async fn execute_account_request(
+    http_client: &HttpClient,
+    server: &str,
+    req: reqwest::Request,
+) -> anyhow::Result<reqwest::Response> {
+    let res = http_client
+        .execute(req)
+        .await
+        .map_err(|e| crate::Error::ServerUnreachable(server.to_string(), e.to_string()))?;
+    if !res.status().is_success() {
+        return Err(account_error(server, res.status()).into());
+    }
+    Ok(res)
+}
+
+// Logs in to the account endpoint of the server, so a wrong password or server
+// is reported before storing the credentials
+async fn validate_account(
+    http_client: &HttpClient,
+    server: &str,
+    username: &str,
+    password: &str,
+) -> anyhow::Result<()> {
+    let req = http_client
+        .get(account_url(server, &[])?.as_str())
+        .basic_auth(username, Some(password))
+        .build()?;
+    execute_account_request(http_client, server, req).await?;
+    Ok(())
+}
+
+// The parts of the account endpoint response managed by Notify.
+// Servers without reservations or tokens leave them out.
+#[derive(Debug, Default, serde::Deserialize)]
+struct AccountInfo {
+    #[serde(default)]
+    reservations: Vec<models::Reservation>,
+    #[serde(default)]
+    tokens: Vec<models::AccessToken>,
+}
+
+// Empty if there's no stored account for the server, or the server has no accounts
+async fn fetch_account_info(env: &SharedEnv, server: &str) -> anyhow::Result<AccountInfo> {
+    let Some(creds) = env.credentials.get(server) else {
+        return Ok(AccountInfo::default());
+    };
+    let req = env
+        .http_client
+        .get(account_url(server, &[])?.as_str())
+        .basic_auth(&creds.username, Some(&creds.password))
+        .build()?;
+    match execute_account_request(&env.http_client, server, req).await {
+        Ok(res) => Ok(serde_json::from_slice(&res.bytes().await?)?),
+        Err(e) if matches!(e.downcast_ref(), Some(crate::Error::AccountsUnsupported(_))) => {
+            Ok(AccountInfo::default())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Removes a reservation or a token of the stored account of the server
+async fn delete_account_item(
+    env: &SharedEnv,
+    server: &str,
+    segments: &[&str],
+    token: Option<&str>,
+) -> anyhow::Result<()> {
+    let creds = env
+        .credentials
+        .get(server)
+        .ok_or_else(|| anyhow!("no account for {server}"))?;
+    let mut req = env
+        .http_client
+        .delete(account_url(server, segments)?.as_str())
+        .basic_auth(&creds.username, Some(&creds.password));
+    if let Some(token) = token {
+        req = req.header("X-Token", token);
+    }
+    execute_account_request(&env.http_client, server, req.build()?).await?;
+    Ok(())
+}
+
+// The topic isn't stored as a subscription, its listener stops when this returns
+async fn roundtrip_test(env: &SharedEnv, server: &str) -> anyhow::Result<std::time::Duration> {
+    use rand::distributions::{Alphanumeric, DistString};
+
+    let random = || Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+    let topic = format!("notify-selftest-{}", random());
+    let token = random();
+    let since = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let listener = ListenerHandle::new(ListenerConfig {
+        http_client: env.http_client.clone(),
+        credentials: env.credentials.clone(),
+        endpoint: server.to_string(),
+        topic: topic.clone(),
+        since,
+        limiter: env.connection_limiter.clone(),
+    });
+
+    let test = async {
+        // Published before the subscription is open, the message wouldn't be sent to it
+        loop {
+            match listener.events.recv().await? {
+                ListenerEvent::ConnectionStateChanged(ConnectionState::Connected) => break,
+                ListenerEvent::ConnectionStateChanged(ConnectionState::Reconnecting {
+                    error: Some(e),
+                    ..
+                }) => return Err(anyhow!("can't subscribe to {server}: {e}")),
+                _ => {}
+            }
+        }
+        let msg = serde_json::to_string(&models::OutgoingMessage {
+            topic: topic.clone(),
+            message: Some(token.clone()),
+            ..Default::default()
+        })?;
+        let started = Instant::now();
+        crate::subscription::publish(env, server, &msg, None).await?;
+        loop {
+            if let ListenerEvent::Message(msg) = listener.events.recv().await? {
+                if msg.message.as_deref() == Some(token.as_str()) {
+                    return anyhow::Ok(started.elapsed());
+                }
+            }
+        }
+    };
+    let res = tokio::time::timeout(ROUNDTRIP_TIMEOUT, test)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "the message didn't come back from {server} within {}s",
+                ROUNDTRIP_TIMEOUT.as_secs()
+            )
+        })
+        .and_then(|res| res);
+    let _ = listener.commands.send(ListenerCommand::Shutdown).await;
+    match &res {
+        Ok(latency) => info!(server, ?latency, "round trip test succeeded"),
+        Err(e) => warn!(server, error = %e, "round trip test failed"),
+    }
+    res
+}
+
+// Never resolves without a deadline
+// The monotonic clock of `sleep` stops while the machine is suspended, so the wall clock
+// is checked again at least every `WALL_CLOCK_CHECK`. The caller must check it was reached.
+async fn wait_until(deadline: Option<chrono::DateTime<chrono::Local>>) {
+    match deadline {
+        Some(deadline) => {
+            let remaining = (deadline - chrono::Local::now())
+                .to_std()
+                .unwrap_or_default();
+            tokio::time::sleep(remaining.min(WALL_CLOCK_CHECK)).await
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Pause between the notifications shown by a replay, to not flood the desktop
+const REPLAY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+async fn replay_unread_notifications(
+    subs: Vec<SubscriptionHandle>,
+    notifier: Arc<dyn models::NotificationProxy>,
+) -> anyhow::Result<usize> {
+    let mut sent = 0;
+    for sub in subs {
+        for n in sub.unread_notifications().await? {
+            if sent > 0 {
+                tokio::time::sleep(REPLAY_INTERVAL).await;
+            }
+            notifier.send(n)?;
+            sent += 1;
+        }
+    }
+    info!(sent, "replayed unread notifications");
+    Ok(sent)
 }
 
 pub fn start(
     dbpath: &str,
+    cache_dir: &str,
+    credentials_backend: crate::credentials::Backend,
     notification_proxy: Arc<dyn models::NotificationProxy>,
     network_proxy: Arc<dyn models::NetworkMonitorProxy>,
+    connection_mode: models::ConnectionMode,
 ) -> anyhow::Result<NtfyHandle> {
     let dbpath = dbpath.to_owned();
+    let cache_dir = cache_dir.to_owned();
+
+    start_with_env(
+        move || async move {
+            SharedEnv::builder()
+                .db(Db::connect(&dbpath).unwrap())
+                .notifier(notification_proxy)
+                .http_client(build_http_client().unwrap())
+                .network_monitor(network_proxy)
+                .credentials(load_credentials(&credentials_backend).await)
+                .cache_dir(cache_dir)
+                .build()
+                .await
+                .unwrap()
+        },
+        connection_mode,
+    )
+}
+
+// Like `start`, with the environment built by `build_env`, e.g. with nullable infrastructure.
+// It's built in the thread of the daemon, because `SharedEnv` can't be sent between threads.
+pub fn start_with_env<F: Future<Output = SharedEnv> + 'static>(
+    build_env: impl FnOnce() -> F + Send + 'static,
+    connection_mode: models::ConnectionMode,
+) -> anyhow::Result<NtfyHandle> {
+    spawn_actor(true, connection_mode, build_env)
+}
+
+// For one-off commands, like publishing from the command line.
+// Nothing is stored and the subscribed topics are not watched.
+pub fn start_oneshot(
+    credentials_backend: crate::credentials::Backend,
+) -> anyhow::Result<NtfyHandle> {
+    spawn_actor(
+        false,
+        models::ConnectionMode::PerTopic,
+        move || async move {
+            SharedEnv::builder()
+                .http_client(build_http_client().unwrap())
+                .credentials(load_credentials(&credentials_backend).await)
+                .build()
+                .await
+                .unwrap()
+        },
+    )
+}
+
+async fn load_credentials(
+    backend: &crate::credentials::Backend,
+) -> crate::credentials::Credentials {
+    // The Secret Service may be started after us, e.g. inside the flatpak sandbox.
+    // A wrong passphrase of the file keyring doesn't get better by retrying.
+    let attempts = match backend {
+        crate::credentials::Backend::SecretService => KEYRING_ATTEMPTS,
+        crate::credentials::Backend::File { .. } => 1,
+    };
+    let mut retry = crate::retry::WaitExponentialRandom::builder()
+        .min(std::time::Duration::from_secs(1))
+        .max(std::time::Duration::from_secs(4))
+        .build();
+    loop {
+        match crate::credentials::Credentials::with_backend(backend).await {
+            Ok(credentials) => return credentials,
+            Err(e) if retry.count() + 1 < attempts => {
+                warn!(error = ?e, attempt = retry.count() + 1, "keyring not available yet, retrying");
+                retry.wait().await;
+            }
+            Err(e) => {
+                warn!(error = ?e, "{}", crate::credentials::UNAVAILABLE_MESSAGE);
+                return crate::credentials::Credentials::new_unavailable();
+            }
+        }
+    }
+}
 
+fn spawn_actor<F: Future<Output = SharedEnv> + 'static>(
+    watch_subscribed: bool,
+    connection_mode: models::ConnectionMode,
+    build_env: impl FnOnce() -> F + Send + 'static,
+) -> anyhow::Result<NtfyHandle> {
     // Create a channel to receive the handle from the spawned thread
     let (handle_tx, handle_rx) = oneshot::channel();
 
@@ -356,18 +1413,13 @@ pub fn start(
             .unwrap();
 
         // Create everything inside the new thread's runtime
-        let credentials =
-            rt.block_on(async move { crate::credentials::Credentials::new().await.unwrap() });
-
-        let env = SharedEnv {
-            db: Db::connect(&dbpath).unwrap(),
-            notifier: notification_proxy,
-            http_client: HttpClient::new(build_client().unwrap()),
-            network_monitor: network_proxy,
-            credentials,
-        };
+        let env = rt.block_on(build_env());
 
         let (mut actor, handle) = NtfyActor::new(env);
+        actor.connection_mode = connection_mode;
+        if !watch_subscribed {
+            actor.ready_tx.send_replace(true);
+        }
         let handle_clone = handle.clone();
 
         // Send the handle back to the calling thread
@@ -376,11 +1428,13 @@ pub fn start(
         rt.block_on({
             let local_set = LocalSet::new();
             // Spawn the watch_subscribed task
-            local_set.spawn_local(async move {
-                if let Err(e) = handle_clone.watch_subscribed().await {
-                    error!(error = ?e, "Failed to watch subscribed topics");
-                }
-            });
+            if watch_subscribed {
+                local_set.spawn_local(async move {
+                    if let Err(e) = handle_clone.watch_subscribed().await {
+                        error!(error = ?e, "Failed to watch subscribed topics");
+                    }
+                });
+            }
 
             // Run the actor
             local_set.spawn_local(async move {
@@ -401,19 +1455,32 @@ mod tests {
     use std::time::Duration;
 
     use models::{OutgoingMessage, ReceivedMessage};
-    use tokio::time::sleep;
 
     use crate::ListenerEvent;
 
     use super::*;
 
+    // Publishes a message and receives it back from the subscription, without network
     #[test]
     fn test_subscribe_and_publish() {
-        let notification_proxy = Arc::new(NullNotifier::new());
-        let network_proxy = Arc::new(NullNetworkMonitor::new());
-        let dbpath = ":memory:";
-
-        let handle = start(dbpath, notification_proxy, network_proxy).unwrap();
+        let notifier = Arc::new(NullNotifier::new());
+        let notifierc = notifier.clone();
+        let handle = start_with_env(
+            move || async move {
+                SharedEnv::builder()
+                    .notifier(notifierc)
+                    .http_client(HttpClient::new_nullable(
+                        crate::http_client::NullableClient::builder()
+                            .echo_published()
+                            .build(),
+                    ))
+                    .build()
+                    .await
+                    .unwrap()
+            },
+            models::ConnectionMode::PerTopic,
+        )
+        .unwrap();
 
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
@@ -424,27 +1491,451 @@ mod tests {
             let server = "http://localhost:8000";
             let topic = "test_topic";
 
-            // Subscribe to the topic
-            let subscription_handle = handle.subscribe(server, topic).await.unwrap();
+            // Subscribing while the stored subscriptions are restored would race with them
+            handle.wait_ready().await.unwrap();
+            let subscription_handle = handle.subscribe(server, topic).await.unwrap().into_handle();
 
-            // Publish a message
             let message = serde_json::to_string(&OutgoingMessage {
                 topic: topic.to_string(),
+                title: Some("Backup".to_string()),
+                message: Some("Backup completed".to_string()),
                 ..Default::default()
             })
             .unwrap();
-            let result = subscription_handle.publish(message).await;
-            assert!(result.is_ok());
+            subscription_handle.publish(message).await.unwrap();
 
-            sleep(Duration::from_millis(250)).await;
-
-            // Attach to the subscription and check if the message is received and stored
-            let (events, receiver) = subscription_handle.attach().await;
-            dbg!(&events);
-            assert!(events.iter().any(|event| match event {
+            // The message comes back from the subscription stream
+            let (events, mut receiver) = subscription_handle.attach().await;
+            let received = |event: &ListenerEvent| match event {
                 ListenerEvent::Message(msg) => msg.topic == topic,
                 _ => false,
-            }));
+            };
+            if !events.iter().any(received) {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while !received(&receiver.recv().await.unwrap()) {}
+                })
+                .await
+                .unwrap();
+            }
+
+            let sent = notifier.sent();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].title, "Backup");
+            assert_eq!(sent[0].body, "Backup completed");
         });
     }
+
+    #[tokio::test]
+    async fn test_subscribe_twice_returns_existing() {
+        LocalSet::new()
+            .run_until(async {
+                let env = SharedEnv::builder().build().await.unwrap();
+                let (actor, _handle) = NtfyActor::new(env.clone());
+                let server = models::DEFAULT_SERVER.to_string();
+
+                let first = actor
+                    .handle_subscribe(server.clone(), "test".to_string())
+                    .await
+                    .unwrap();
+                assert!(matches!(first, SubscribeOutcome::Subscribed(_)));
+                let second = actor
+                    .handle_subscribe(server, "test".to_string())
+                    .await
+                    .unwrap();
+                assert!(matches!(second, SubscribeOutcome::AlreadySubscribed(_)));
+
+                assert_eq!(env.db.clone().list_subscriptions().unwrap().len(), 1);
+                assert_eq!(actor.listener_handles.read().await.len(), 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_test() {
+        LocalSet::new()
+            .run_until(async {
+                let env = SharedEnv::builder()
+                    .http_client(HttpClient::new_nullable(
+                        crate::http_client::NullableClient::builder()
+                            .echo_published()
+                            .build(),
+                    ))
+                    .build()
+                    .await
+                    .unwrap();
+                roundtrip_test(&env, "http://localhost:8000").await.unwrap();
+                // Nothing is kept of the test topic
+                assert!(env.db.clone().list_subscriptions().unwrap().is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_test_reports_subscribe_errors() {
+        LocalSet::new()
+            .run_until(async {
+                let env = SharedEnv::builder()
+                    .http_client(HttpClient::new_nullable(
+                        crate::http_client::NullableClient::builder()
+                            .default_response(|| {
+                                http::response::Builder::new()
+                                    .status(500)
+                                    .body("")
+                                    .unwrap()
+                                    .into()
+                            })
+                            .build(),
+                    ))
+                    .build()
+                    .await
+                    .unwrap();
+                let err = roundtrip_test(&env, "http://localhost:8000")
+                    .await
+                    .unwrap_err();
+                assert!(err.to_string().contains("can't subscribe"));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_keeping_history() {
+        LocalSet::new()
+            .run_until(async {
+                let env = SharedEnv::builder().build().await.unwrap();
+                let (mut actor, _handle) = NtfyActor::new(env.clone());
+                let server = models::DEFAULT_SERVER.to_string();
+                let mut db = env.db.clone();
+                for topic in ["kept", "deleted"] {
+                    actor
+                        .handle_subscribe(server.clone(), topic.to_string())
+                        .await
+                        .unwrap();
+                    db.insert_message(
+                        &server,
+                        &format!(r#"{{"id":"{topic}","topic":"{topic}","time":1,"message":"hi"}}"#),
+                    )
+                    .unwrap();
+                }
+
+                actor
+                    .handle_unsubscribe(server.clone(), "kept".to_string(), true)
+                    .await
+                    .unwrap();
+                actor
+                    .handle_unsubscribe(server.clone(), "deleted".to_string(), false)
+                    .await
+                    .unwrap();
+                assert!(db.list_subscriptions().unwrap().is_empty());
+                assert!(actor.listener_handles.read().await.is_empty());
+                assert_eq!(db.list_messages(&server, "kept", 0).unwrap().len(), 1);
+                assert!(db.list_messages(&server, "deleted", 0).unwrap().is_empty());
+
+                actor
+                    .handle_subscribe(server.clone(), "kept".to_string())
+                    .await
+                    .unwrap();
+                assert_eq!(db.list_subscriptions().unwrap().len(), 1);
+                assert_eq!(db.list_messages(&server, "kept", 0).unwrap().len(), 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_ready_after_restoring_subscriptions() {
+        LocalSet::new()
+            .run_until(async {
+                let env = SharedEnv::builder().build().await.unwrap();
+                let mut db = env.db.clone();
+                for topic in ["a", "b"] {
+                    db.insert_subscription(
+                        models::Subscription::builder(topic.to_string())
+                            .build()
+                            .unwrap(),
+                    )
+                    .unwrap();
+                }
+                let (mut actor, handle) = NtfyActor::new(env);
+                spawn_local(async move { actor.run().await });
+                assert!(!*handle.ready_rx.borrow());
+
+                let h = handle.clone();
+                spawn_local(async move { h.watch_subscribed().await.unwrap() });
+                handle.wait_ready().await.unwrap();
+                assert_eq!(handle.list_subscriptions().await.unwrap().len(), 2);
+            })
+            .await;
+    }
+
+    struct ReplayFixture {
+        env: SharedEnv,
+        notifier: Arc<NullNotifier>,
+        // Keeps the subscription running
+        _actor: NtfyActor,
+        sub: SubscriptionHandle,
+    }
+
+    // A subscription to "test" on the default server with the unread message "unread".
+    // Must be called inside a LocalSet
+    async fn replay_fixture() -> ReplayFixture {
+        let notifier = Arc::new(NullNotifier::new());
+        let env = SharedEnv::builder()
+            .notifier(notifier.clone())
+            .build()
+            .await
+            .unwrap();
+        let (actor, _handle) = NtfyActor::new(env.clone());
+        let sub = actor
+            .handle_subscribe(models::DEFAULT_SERVER.to_string(), "test".to_string())
+            .await
+            .unwrap()
+            .into_handle();
+        env.db
+            .clone()
+            .insert_message(
+                models::DEFAULT_SERVER,
+                r#"{"id":"unread","topic":"test","time":200,"message":"unread"}"#,
+            )
+            .unwrap();
+        ReplayFixture {
+            env,
+            notifier,
+            _actor: actor,
+            sub,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_unread_notifications() {
+        LocalSet::new()
+            .run_until(async {
+                let f = replay_fixture().await;
+                f.env
+                    .db
+                    .clone()
+                    .insert_message(
+                        models::DEFAULT_SERVER,
+                        r#"{"id":"read","topic":"test","time":100,"message":"read"}"#,
+                    )
+                    .unwrap();
+                f.sub.update_read_until(100).await.unwrap();
+
+                let sent = replay_unread_notifications(vec![f.sub], f.notifier.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(sent, 1);
+                assert_eq!(f.notifier.sent()[0].body, "unread");
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_replay_skips_paused_notifications() {
+        LocalSet::new()
+            .run_until(async {
+                let f = replay_fixture().await;
+                f.env.notification_settings.write().unwrap().paused = true;
+
+                let sent = replay_unread_notifications(vec![f.sub], f.notifier.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(sent, 0);
+                assert!(f.notifier.sent().is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_replay_skips_snoozed_subscriptions() {
+        LocalSet::new()
+            .run_until(async {
+                let f = replay_fixture().await;
+                f.sub
+                    .snooze(std::time::Duration::from_secs(3600))
+                    .await
+                    .unwrap();
+
+                // Like after a restart, the snooze is read back from the database
+                let (mut actor, handle) = NtfyActor::new(f.env.clone());
+                spawn_local(async move { actor.run().await });
+                handle.watch_subscribed().await.unwrap();
+                let subs = handle.list_subscriptions().await.unwrap();
+                let sent = replay_unread_notifications(subs, f.notifier.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(sent, 0);
+                assert!(f.notifier.sent().is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_health_counts_failing_subscriptions() {
+        LocalSet::new()
+            .run_until(async {
+                let env = SharedEnv::builder().build().await.unwrap();
+                // No response configured, every connection fails
+                let (actor, _handle) = NtfyActor::new(env);
+                actor
+                    .handle_subscribe(models::DEFAULT_SERVER.to_string(), "test".to_string())
+                    .await
+                    .unwrap();
+
+                let health = loop {
+                    let health = actor.handle_health().await;
+                    if health.failing > 0 {
+                        break health;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                };
+                assert_eq!(health.subscriptions, 1);
+                assert_eq!(health.connected, 0);
+                assert!(!health.is_healthy());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_health_socket_is_removed_when_stopped() {
+        LocalSet::new()
+            .run_until(async {
+                let dir =
+                    std::env::temp_dir().join(format!("notify-test-{}", rand::random::<u64>()));
+                // The directory doesn't exist yet
+                let path = dir.join("runtime").join("health");
+                let env = SharedEnv::builder().build().await.unwrap();
+                let (mut actor, handle) = NtfyActor::new(env);
+                spawn_local(async move { actor.run().await });
+
+                handle.serve_health(path.clone()).await.unwrap();
+                assert!(path.exists());
+                handle.stop_serving_health().await.unwrap();
+                assert!(!path.exists());
+
+                std::fs::remove_dir_all(&dir).unwrap();
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_health_socket_of_running_instance_is_kept() {
+        LocalSet::new()
+            .run_until(async {
+                let dir =
+                    std::env::temp_dir().join(format!("notify-test-{}", rand::random::<u64>()));
+                std::fs::create_dir_all(&dir).unwrap();
+                let path = dir.join("health");
+                // Left over by a crash, nobody listens on it
+                drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+                let spawn = || async {
+                    let env = SharedEnv::builder().build().await.unwrap();
+                    let (mut actor, handle) = NtfyActor::new(env);
+                    spawn_local(async move { actor.run().await });
+                    handle
+                };
+
+                let first = spawn().await;
+                first.serve_health(path.clone()).await.unwrap();
+                let second = spawn().await;
+                assert!(second.serve_health(path.clone()).await.is_err());
+                assert!(path.exists());
+
+                first.stop_serving_health().await.unwrap();
+                std::fs::remove_dir_all(&dir).unwrap();
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_validate_account_errors() {
+        let server = "https://ntfy.example.com";
+        let url = "https://ntfy.example.com/v1/account";
+        let http_client = HttpClient::new_nullable(
+            crate::http_client::NullableClient::builder()
+                .text_response(url, 200, "{}")
+                .text_response(url, 401, "")
+                .text_response(url, 404, "")
+                .text_response(url, 500, "")
+                .build(),
+        );
+        let validate = || validate_account(&http_client, server, "user", "pass");
+
+        assert!(validate().await.is_ok());
+        let errors = [
+            validate().await.unwrap_err(),
+            validate().await.unwrap_err(),
+            validate().await.unwrap_err(),
+            // No more responses, like an unreachable server
+            validate().await.unwrap_err(),
+        ];
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|e| e.downcast_ref::<crate::Error>().unwrap())
+            .collect();
+        assert!(matches!(errors[0], crate::Error::InvalidCredentials(_)));
+        assert!(matches!(errors[1], crate::Error::AccountsUnsupported(_)));
+        assert!(matches!(
+            errors[2],
+            crate::Error::UnexpectedResponse(_, 500)
+        ));
+        assert!(matches!(errors[3], crate::Error::ServerUnreachable(..)));
+    }
+
+    #[tokio::test]
+    async fn test_account_reservations_and_tokens() {
+        let server = "https://ntfy.example.com";
+        let url = "https://ntfy.example.com/v1/account";
+        let http_client = HttpClient::new_nullable(
+            crate::http_client::NullableClient::builder()
+                .json_response(
+                    url,
+                    200,
+                    serde_json::json!({
+                        "username": "user",
+                        "reservations": [{"topic": "alerts", "everyone": "read-only"}],
+                        "tokens": [{"token": "tk_123", "label": "phone", "last_access": 1}],
+                    }),
+                )
+                .unwrap()
+                .text_response(url, 404, "")
+                .text_response(format!("{url}/token"), 200, "{}")
+                .build(),
+        );
+        let tracker = http_client.request_tracker().await;
+        let env = SharedEnv::builder()
+            .http_client(http_client)
+            .build()
+            .await
+            .unwrap();
+
+        // Without an account nothing is requested
+        let info = fetch_account_info(&env, server).await.unwrap();
+        assert!(info.reservations.is_empty() && info.tokens.is_empty());
+        assert!(tracker.items().await.is_empty());
+
+        env.credentials
+            .insert(server, "user", "pass")
+            .await
+            .unwrap();
+        let info = fetch_account_info(&env, server).await.unwrap();
+        assert_eq!(
+            info.reservations,
+            vec![models::Reservation {
+                topic: "alerts".to_string(),
+                everyone: "read-only".to_string(),
+            }]
+        );
+        assert_eq!(info.tokens[0].label.as_deref(), Some("phone"));
+        assert_eq!(info.tokens[0].expires, None);
+
+        // A server without accounts has nothing to list
+        let info = fetch_account_info(&env, server).await.unwrap();
+        assert!(info.reservations.is_empty() && info.tokens.is_empty());
+
+        delete_account_item(&env, server, &["token"], Some("tk_123"))
+            .await
+            .unwrap();
+        let req = tracker.items().await.pop().unwrap();
+        assert_eq!(req.method, "DELETE");
+        assert_eq!(req.headers["X-Token"], "tk_123");
+    }
 }
@@ -1,10 +1,19 @@
-use crate::listener::{ListenerEvent, ListenerHandle};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::export::{self, ExportFormat};
+use crate::listener::{ConnectionState, ListenerEvent, ListenerHandle};
 use crate::models::{self, ReceivedMessage};
 use crate::{Error, SharedEnv};
+use regex::Regex;
 use tokio::select;
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::task::spawn_local;
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, debug_span, error, info, trace, warn, Instrument};
+
+// A subscription must be unreachable for this long before notifying the user
+const CONNECTION_LOST_GRACE: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Debug)]
 enum SubscriptionCommand {
@@ -29,6 +38,71 @@ enum SubscriptionCommand {
         timestamp: u64,
         resp_tx: oneshot::Sender<anyhow::Result<()>>,
     },
+    Snooze {
+        until: u64,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Resync {
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetMessageRead {
+        id: String,
+        read: bool,
+        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+    },
+    UnreadCount {
+        resp_tx: oneshot::Sender<anyhow::Result<u64>>,
+    },
+    UnreadNotifications {
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<models::Notification>>>,
+    },
+    ListScheduled {
+        resp_tx: oneshot::Sender<anyhow::Result<Vec<ReceivedMessage>>>,
+    },
+    FetchIcon {
+        url: String,
+        resp_tx: oneshot::Sender<anyhow::Result<std::path::PathBuf>>,
+    },
+    GetConnectionState {
+        resp_tx: oneshot::Sender<ConnectionState>,
+    },
+    ExportMessages {
+        format: ExportFormat,
+        resp_tx: oneshot::Sender<anyhow::Result<String>>,
+    },
+    GetMessage {
+        id: String,
+        resp_tx: oneshot::Sender<anyhow::Result<Option<ReceivedMessage>>>,
+    },
+    // Simulates a bug in the actor
+    #[cfg(test)]
+    Panic,
+}
+
+impl SubscriptionCommand {
+    // Names the command in the tracing spans, without the arguments
+    fn name(&self) -> &'static str {
+        match self {
+            Self::GetModel { .. } => "GetModel",
+            Self::UpdateInfo { .. } => "UpdateInfo",
+            Self::Attach { .. } => "Attach",
+            Self::Publish { .. } => "Publish",
+            Self::ClearNotifications { .. } => "ClearNotifications",
+            Self::UpdateReadUntil { .. } => "UpdateReadUntil",
+            Self::Snooze { .. } => "Snooze",
+            Self::Resync { .. } => "Resync",
+            Self::SetMessageRead { .. } => "SetMessageRead",
+            Self::UnreadCount { .. } => "UnreadCount",
+            Self::UnreadNotifications { .. } => "UnreadNotifications",
+            Self::ListScheduled { .. } => "ListScheduled",
+            Self::FetchIcon { .. } => "FetchIcon",
+            Self::GetConnectionState { .. } => "GetConnectionState",
+            Self::ExportMessages { .. } => "ExportMessages",
+            Self::GetMessage { .. } => "GetMessage",
+            #[cfg(test)]
+            Self::Panic => "Panic",
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -41,14 +115,14 @@ impl SubscriptionHandle {
     pub fn new(listener: ListenerHandle, model: models::Subscription, env: &SharedEnv) -> Self {
         let (command_tx, command_rx) = mpsc::channel(32);
         let broadcast_tx = broadcast::channel(8).0;
-        let actor = SubscriptionActor {
-            listener: listener.clone(),
+        let actor = SubscriptionActor::new(
+            listener.clone(),
             model,
-            command_rx,
-            env: env.clone(),
-            broadcast_tx: broadcast_tx.clone(),
-        };
-        spawn_local(actor.run());
+            env.clone(),
+            broadcast_tx,
+            Rc::new(tokio::sync::Mutex::new(command_rx)),
+        );
+        spawn_local(supervise(actor));
         Self {
             command_tx,
             listener,
@@ -126,109 +200,599 @@ impl SubscriptionHandle {
             .unwrap();
         resp_rx.await.unwrap()
     }
+
+    // New messages are stored but not notified for `duration`, even after a restart.
+    // A zero duration ends the snooze.
+    pub async fn snooze(&self, duration: std::time::Duration) -> anyhow::Result<()> {
+        let until = if duration.is_zero() {
+            0
+        } else {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .saturating_add(duration)
+                .as_secs()
+        };
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::Snooze { until, resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    // Deletes the stored messages and the read marker, then downloads the whole history again.
+    // Messages received again this way don't show notifications.
+    pub async fn resync(&self) -> anyhow::Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::Resync { resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    pub async fn set_message_read(&self, id: &str, read: bool) -> anyhow::Result<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::SetMessageRead {
+                id: id.to_string(),
+                read,
+                resp_tx,
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    pub async fn unread_count(&self) -> anyhow::Result<u64> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::UnreadCount { resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    pub async fn unread_notifications(&self) -> anyhow::Result<Vec<models::Notification>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::UnreadNotifications { resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    // Messages published with a delay and not delivered yet.
+    // Empty if the server doesn't support listing them.
+    pub async fn list_scheduled(&self) -> anyhow::Result<Vec<ReceivedMessage>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::ListScheduled { resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    // Returns the path of the downloaded icon, cached on disk
+    pub async fn fetch_icon(&self, url: &str) -> anyhow::Result<std::path::PathBuf> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::FetchIcon {
+                url: url.to_string(),
+                resp_tx,
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    pub fn listener_stats(&self) -> crate::ListenerStats {
+        self.listener.stats()
+    }
+
+    // The last state sent by the listener, without asking the listener
+    pub async fn connection_state(&self) -> ConnectionState {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::GetConnectionState { resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    pub async fn export_messages(&self, format: ExportFormat) -> anyhow::Result<String> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::ExportMessages { format, resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    // The stored message `id`, None if it isn't stored anymore
+    pub async fn get_message(&self, id: &str) -> anyhow::Result<Option<ReceivedMessage>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.command_tx
+            .send(SubscriptionCommand::GetMessage {
+                id: id.to_string(),
+                resp_tx,
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap()
+    }
 }
 
 struct SubscriptionActor {
     listener: ListenerHandle,
     model: models::Subscription,
-    command_rx: mpsc::Receiver<SubscriptionCommand>,
+    // Compiled from `model.notify_regex`
+    notify_regex: Option<Regex>,
+    // Shared with the supervisor, so it outlives a panicking actor
+    command_rx: Rc<tokio::sync::Mutex<mpsc::Receiver<SubscriptionCommand>>>,
     env: SharedEnv,
     broadcast_tx: broadcast::Sender<ListenerEvent>,
+    // Messages up to this time don't show notifications
+    silent_until: u64,
+    // Expiration of the topic cache, as sent by the server when the connection opened
+    expires: Option<u64>,
+    connection_state: ConnectionState,
+    down_since: Option<Instant>,
+    // Only one notification is shown each time the connection is lost
+    down_notified: bool,
+}
+
+// Restarts the actor if it panics, so a bug handling a message or a command doesn't
+// stop the subscription for good. The restart is shown to the UI as a connection error.
+async fn supervise(mut actor: SubscriptionActor) {
+    let mut retry = crate::retry::WaitExponentialRandom::builder()
+        .min(Duration::from_secs(1))
+        .max(Duration::from_secs(5 * 60))
+        .build();
+    loop {
+        let listener = actor.listener.clone();
+        let model = actor.model.clone();
+        let env = actor.env.clone();
+        let broadcast_tx = actor.broadcast_tx.clone();
+        let command_rx = actor.command_rx.clone();
+
+        match spawn_local(actor.run()).await {
+            Ok(()) => break,
+            Err(e) if e.is_panic() => {
+                error!(topic = ?model.topic, "subscription actor panicked, restarting it");
+            }
+            Err(e) => {
+                error!(error = ?e, topic = ?model.topic, "subscription actor stopped");
+                break;
+            }
+        }
+        let state = ConnectionState::Reconnecting {
+            retry_count: retry.count(),
+            delay: retry.next_delay(),
+            error: Some(Arc::new(anyhow::anyhow!(
+                "the subscription stopped unexpectedly"
+            ))),
+        };
+        let _ = broadcast_tx.send(ListenerEvent::ConnectionStateChanged(state));
+        retry.wait().await;
+
+        // The panicking actor may have held changes newer than the model it started with
+        let model = env
+            .db
+            .clone()
+            .list_subscriptions()
+            .ok()
+            .and_then(|subs| {
+                subs.into_iter()
+                    .find(|s| s.server == model.server && s.topic == model.topic)
+            })
+            .unwrap_or(model);
+        actor = SubscriptionActor::new(listener, model, env, broadcast_tx, command_rx);
+        // The listener sends its state again, and fetches what the actor may have missed
+        let _ = actor
+            .listener
+            .commands
+            .send(crate::ListenerCommand::Restart)
+            .await;
+    }
 }
 
 impl SubscriptionActor {
+    fn new(
+        listener: ListenerHandle,
+        model: models::Subscription,
+        env: SharedEnv,
+        broadcast_tx: broadcast::Sender<ListenerEvent>,
+        command_rx: Rc<tokio::sync::Mutex<mpsc::Receiver<SubscriptionCommand>>>,
+    ) -> Self {
+        let notify_regex = model.compile_notify_regex().unwrap_or_else(|e| {
+            error!(error = ?e, topic = ?model.topic, "ignoring invalid notification filter");
+            None
+        });
+        Self {
+            listener,
+            model,
+            notify_regex,
+            command_rx,
+            env,
+            broadcast_tx,
+            silent_until: 0,
+            expires: None,
+            connection_state: ConnectionState::Unitialized,
+            down_since: None,
+            down_notified: false,
+        }
+    }
+
+    // Returns when every handle was dropped
     async fn run(mut self) {
+        let command_rx = self.command_rx.clone();
+        let mut command_rx = command_rx
+            .try_lock()
+            .expect("only one actor runs at a time");
         loop {
             select! {
                 Ok(event) = self.listener.events.recv() => {
                     debug!(?event, "received listener event");
                     match event {
                         ListenerEvent::Message(msg) => self.handle_msg_event(msg),
-                        other => {
-                            let _ = self.broadcast_tx.send(other);
+                        ListenerEvent::KeepAlive { time } => self.update_last_received_time(time),
+                        ListenerEvent::Opened { expires } => {
+                            self.expires = expires;
+                            let _ = self.broadcast_tx.send(ListenerEvent::Opened { expires });
+                        }
+                        ListenerEvent::ConnectionStateChanged(state) => {
+                            self.handle_connection_state(&state);
+                            let _ = self.broadcast_tx.send(ListenerEvent::ConnectionStateChanged(state));
                         }
                     }
                 }
-                Some(command) = self.command_rx.recv() => {
+                command = command_rx.recv() => {
+                    let Some(command) = command else {
+                        break;
+                    };
                     trace!(?command, "processing subscription command");
-                    match command {
-                        SubscriptionCommand::GetModel { resp_tx } => {
-                            debug!("getting subscription model");
-                            let _ = resp_tx.send(self.model.clone());
-                        }
-                        SubscriptionCommand::UpdateInfo {
-                            mut new_model,
-                            resp_tx,
-                        } => {
-                            debug!(server=?new_model.server, topic=?new_model.topic, "updating subscription info");
-                            new_model.server = self.model.server.clone();
-                            new_model.topic = self.model.topic.clone();
-                            let res = self.env.db.update_subscription(new_model.clone());
-                            if let Ok(_) = res {
-                                self.model = new_model;
-                            }
-                            let _ = resp_tx.send(res.map_err(|e| e.into()));
-                        }
-                        SubscriptionCommand::Publish {msg, resp_tx} => {
-                            debug!(topic=?self.model.topic, "publishing message");
-                            let _ = resp_tx.send(self.publish(msg).await);
-                        }
-                        SubscriptionCommand::Attach { resp_tx } => {
-                            debug!(topic=?self.model.topic, "attaching new listener");
-                            let messages = self
-                            .env
-                                .db
-                                .list_messages(&self.model.server, &self.model.topic, 0)
-                                .unwrap_or_default();
-                            let mut previous_events: Vec<ListenerEvent> = messages
-                                .into_iter()
-                                .filter_map(|msg| {
-                                    let msg = serde_json::from_str(&msg);
-                                    match msg {
-                                        Err(e) => {
-                                            error!(error = ?e, "error parsing stored message");
-                                            None
-                                        }
-                                        Ok(msg) => Some(msg),
-                                    }
-                                })
-                                .map(ListenerEvent::Message)
-                                .collect();
-                            previous_events.push(ListenerEvent::ConnectionStateChanged(self.listener.state().await));
-                            let _ = resp_tx.send((previous_events, self.broadcast_tx.subscribe()));
-                        }
-                        SubscriptionCommand::ClearNotifications {resp_tx} => {
-                            debug!(topic=?self.model.topic, "clearing notifications");
-                            let _ = resp_tx.send(self.env.db.delete_messages(&self.model.server, &self.model.topic).map_err(|e| anyhow::anyhow!(e)));
-                        }
-                        SubscriptionCommand::UpdateReadUntil { timestamp, resp_tx } => {
-                            debug!(topic=?self.model.topic, timestamp=timestamp, "updating read until timestamp");
-                            let res = self.env.db.update_read_until(&self.model.server, &self.model.topic, timestamp);
-                            let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
-                        }
-                    }
+                    let span = debug_span!("subscription_command", topic = %self.model.topic, command = command.name());
+                    let started = Instant::now();
+                    self.handle_command(command).instrument(span.clone()).await;
+                    debug!(parent: &span, elapsed_ms = started.elapsed().as_millis() as u64, "handled command");
+                }
+            }
+        }
+    }
+
+    async fn handle_command(&mut self, command: SubscriptionCommand) {
+        match command {
+            SubscriptionCommand::GetModel { resp_tx } => {
+                debug!("getting subscription model");
+                let _ = resp_tx.send(self.model.clone());
+            }
+            SubscriptionCommand::UpdateInfo {
+                mut new_model,
+                resp_tx,
+            } => {
+                debug!(server=?new_model.server, topic=?new_model.topic, "updating subscription info");
+                new_model.server = self.model.server.clone();
+                new_model.topic = self.model.topic.clone();
+                new_model.read_until = self.model.read_until;
+                new_model.last_received_time = self.model.last_received_time;
+                new_model.snoozed_until = self.model.snoozed_until;
+                let res = new_model.compile_notify_regex().and_then(|notify_regex| {
+                    self.env.db.update_subscription(new_model.clone())?;
+                    Ok(notify_regex)
+                });
+                let res = res.map(|notify_regex| {
+                    self.notify_regex = notify_regex;
+                    self.model = new_model;
+                });
+                let _ = resp_tx.send(res.map_err(|e| e.into()));
+            }
+            SubscriptionCommand::Publish { msg, resp_tx } => {
+                debug!(topic=?self.model.topic, "publishing message");
+                let _ = resp_tx.send(self.publish(msg).await);
+            }
+            SubscriptionCommand::Attach { resp_tx } => {
+                debug!(topic=?self.model.topic, "attaching new listener");
+                let parsed = self
+                    .env
+                    .db
+                    .list_messages_parsed(&self.model.server, &self.model.topic, 0)
+                    .unwrap_or_else(|e| {
+                        error!(error = ?e, "error listing stored messages");
+                        Default::default()
+                    });
+                let mut previous_events: Vec<ListenerEvent> = parsed
+                    .messages
+                    .into_iter()
+                    .map(ListenerEvent::Message)
+                    .collect();
+                previous_events.push(ListenerEvent::Opened {
+                    expires: self.expires,
+                });
+                previous_events.push(ListenerEvent::ConnectionStateChanged(
+                    self.listener.state().await,
+                ));
+                let _ = resp_tx.send((previous_events, self.broadcast_tx.subscribe()));
+            }
+            SubscriptionCommand::ClearNotifications { resp_tx } => {
+                debug!(topic=?self.model.topic, "clearing notifications");
+                let _ = resp_tx.send(
+                    self.env
+                        .db
+                        .delete_messages(&self.model.server, &self.model.topic)
+                        .map_err(|e| anyhow::anyhow!(e)),
+                );
+            }
+            SubscriptionCommand::UpdateReadUntil { timestamp, resp_tx } => {
+                debug!(topic=?self.model.topic, timestamp=timestamp, "updating read until timestamp");
+                let res =
+                    self.env
+                        .db
+                        .update_read_until(&self.model.server, &self.model.topic, timestamp);
+                if res.is_ok() {
+                    self.model.read_until = timestamp;
                 }
+                let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
+            }
+            SubscriptionCommand::Snooze { until, resp_tx } => {
+                debug!(topic=?self.model.topic, until, "snoozing notifications");
+                let _ = resp_tx.send(self.set_snoozed_until(until).map_err(|e| e.into()));
+            }
+            SubscriptionCommand::SetMessageRead { id, read, resp_tx } => {
+                debug!(topic=?self.model.topic, id, read, "updating message read state");
+                let res = if read {
+                    self.env
+                        .db
+                        .mark_read(&self.model.server, &self.model.topic, &id)
+                } else {
+                    self.env
+                        .db
+                        .mark_unread(&self.model.server, &self.model.topic, &id)
+                };
+                let res = res.map(|read_until| self.model.read_until = read_until);
+                let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
+            }
+            SubscriptionCommand::UnreadCount { resp_tx } => {
+                let res = self
+                    .env
+                    .db
+                    .unread_count(&self.model.server, &self.model.topic);
+                let _ = resp_tx.send(res.map_err(|e| anyhow::anyhow!(e)));
+            }
+            SubscriptionCommand::UnreadNotifications { resp_tx } => {
+                let _ = resp_tx.send(self.unread_notifications());
+            }
+            SubscriptionCommand::ListScheduled { resp_tx } => {
+                let env = self.env.clone();
+                let server = self.model.server.clone();
+                let topic = self.model.topic.clone();
+                spawn_local(async move {
+                    let _ = resp_tx.send(list_scheduled(&env, &server, &topic).await);
+                });
             }
+            SubscriptionCommand::FetchIcon { url, resp_tx } => {
+                let env = self.env.clone();
+                // Downloads don't block the other commands
+                spawn_local(async move {
+                    let res = env.icon_cache.fetch(&env.http_client, &url).await;
+                    let _ = resp_tx.send(res);
+                });
+            }
+            SubscriptionCommand::GetConnectionState { resp_tx } => {
+                let _ = resp_tx.send(self.connection_state.clone());
+            }
+            SubscriptionCommand::ExportMessages { format, resp_tx } => {
+                debug!(topic=?self.model.topic, ?format, "exporting messages");
+                let _ = resp_tx.send(self.export_messages(format));
+            }
+            SubscriptionCommand::GetMessage { id, resp_tx } => {
+                let res = self
+                    .env
+                    .db
+                    .get_message(&self.model.server, &self.model.topic, &id);
+                let _ = resp_tx.send(res.map_err(Into::into));
+            }
+            SubscriptionCommand::Resync { resp_tx } => {
+                info!(topic=?self.model.topic, "resyncing subscription");
+                let _ = resp_tx.send(self.resync().await);
+            }
+            #[cfg(test)]
+            SubscriptionCommand::Panic => panic!("simulated panic"),
         }
     }
 
+    fn handle_connection_state(&mut self, state: &ConnectionState) {
+        self.connection_state = state.clone();
+        let ConnectionState::Reconnecting { error, .. } = state else {
+            if matches!(state, ConnectionState::Connected) {
+                self.down_since = None;
+                self.down_notified = false;
+            }
+            return;
+        };
+        let down_since = *self.down_since.get_or_insert_with(Instant::now);
+        if self.down_notified
+            || down_since.elapsed() < CONNECTION_LOST_GRACE
+            || !self
+                .env
+                .notification_settings
+                .read()
+                .unwrap()
+                .notify_connection_lost
+        {
+            return;
+        }
+
+        self.down_notified = true;
+        info!(topic=?self.model.topic, "notifying lost connection");
+        let n = models::Notification {
+            title: format!("{} is unreachable", self.model.display_name_or_topic()),
+            body: match error {
+                Some(e) => format!("Can't connect to {}: {e}", self.model.server),
+                None => format!("Can't connect to {}", self.model.server),
+            },
+            actions: vec![],
+            persistence: models::NotificationPersistence::Default,
+            priority: models::DEFAULT_PRIORITY,
+            sound: None,
+            source: None,
+        };
+        if let Err(e) = self.env.notifier.send(n) {
+            error!(error=?e, "can't show the lost connection notification");
+        }
+    }
+
+    async fn resync(&mut self) -> anyhow::Result<()> {
+        self.env
+            .db
+            .reset_subscription(&self.model.server, &self.model.topic)?;
+        self.model.read_until = 0;
+        self.model.last_received_time = 0;
+        self.silent_until = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        self.listener
+            .commands
+            .send(crate::ListenerCommand::RestartSince(0))
+            .await?;
+        Ok(())
+    }
+
     async fn publish(&self, msg: String) -> anyhow::Result<()> {
-        let server = &self.model.server;
-        debug!(server=?server, "preparing to publish message");
-        let creds = self.env.credentials.get(server);
-        let mut req = self.env.http_client.post(server);
-        if let Some(creds) = creds {
-            req = req.basic_auth(creds.username, Some(creds.password));
+        publish(&self.env, &self.model.server, &msg, None).await?;
+        Ok(())
+    }
+    fn export_messages(&self, format: ExportFormat) -> anyhow::Result<String> {
+        let parsed = self
+            .env
+            .db
+            .list_messages_parsed(&self.model.server, &self.model.topic, 0)?;
+        if parsed.dropped > 0 {
+            warn!(topic=?self.model.topic, dropped=parsed.dropped, "malformed messages left out of the export");
+        }
+        let mut out = vec![];
+        export::write_messages(parsed.messages, format, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    fn matches_notify_regex(&self, msg: &ReceivedMessage) -> bool {
+        let Some(re) = &self.notify_regex else {
+            return true;
+        };
+        [&msg.title, &msg.message]
+            .into_iter()
+            .flatten()
+            .any(|text| re.is_match(text))
+    }
+
+    // Persisted, so a restart doesn't fetch again what was already received
+    fn update_last_received_time(&mut self, time: u64) {
+        if time <= self.model.last_received_time {
+            return;
         }
+        match self
+            .env
+            .db
+            .update_last_received_time(&self.model.server, &self.model.topic, time)
+        {
+            Ok(_) => self.model.last_received_time = time,
+            Err(e) => {
+                error!(error=?e, topic=?self.model.topic, "can't store the last received time")
+            }
+        }
+    }
 
-        info!(server=?server, "sending message");
-        let res = req.body(msg).send().await?;
-        res.error_for_status()?;
-        debug!(server=?server, "message published successfully");
+    fn set_snoozed_until(&mut self, until: u64) -> Result<(), Error> {
+        self.env
+            .db
+            .update_snoozed_until(&self.model.server, &self.model.topic, until)?;
+        self.model.snoozed_until = until;
         Ok(())
     }
-    fn handle_msg_event(&mut self, msg: ReceivedMessage) {
+
+    // None if the subscription settings say the message must not be notified
+    fn notification_for(&self, msg: &ReceivedMessage) -> Option<models::Notification> {
+        debug!(topic=?self.model.topic, muted=?self.model.muted, "checking if notification should be shown");
+        let (min_priority, preview_length) = {
+            let settings = self.env.notification_settings.read().unwrap();
+            (settings.min_priority, settings.preview_length)
+        };
+        let priority = msg.priority.unwrap_or(models::DEFAULT_PRIORITY);
+        if self.model.muted {
+            debug!(topic=?self.model.topic, "notification muted, skipping");
+        } else if priority < min_priority {
+            debug!(topic=?self.model.topic, priority, min_priority, "priority below the minimum, skipping notification");
+        } else if !self.matches_notify_regex(msg) {
+            debug!(topic=?self.model.topic, "message doesn't match the notification filter, skipping notification");
+        } else {
+            return Some(models::Notification {
+                title: msg.notification_title(&self.model),
+                body: msg.notification_body(preview_length),
+                actions: msg.templated_actions(),
+                persistence: self.model.notification_persistence.for_message(msg),
+                priority,
+                sound: self.model.sound.clone(),
+                source: Some(models::NotificationSource {
+                    server: self.model.server.clone(),
+                    topic: self.model.topic.clone(),
+                    id: msg.id.clone(),
+                    time: msg.time,
+                }),
+            });
+        }
+        None
+    }
+
+    // Notifications of the stored unread messages, for showing them again after a restart
+    fn unread_notifications(&self) -> anyhow::Result<Vec<models::Notification>> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        // Like the new messages, nothing is shown while notifications are paused
+        if self
+            .env
+            .notification_settings
+            .read()
+            .unwrap()
+            .is_paused(now)
+        {
+            debug!(topic=?self.model.topic, "notifications paused, skipping the unread notifications");
+            return Ok(vec![]);
+        }
+        // The snooze is stored, it still holds after a restart
+        if self.model.is_snoozed(now) {
+            debug!(topic=?self.model.topic, snoozed_until=self.model.snoozed_until, "subscription snoozed, skipping the unread notifications");
+            return Ok(vec![]);
+        }
+        let msgs = self
+            .env
+            .db
+            .list_unread_messages(&self.model.server, &self.model.topic)?;
+        Ok(msgs
+            .iter()
+            .filter_map(|json| serde_json::from_str::<ReceivedMessage>(json).ok())
+            .filter_map(|msg| self.notification_for(&msg))
+            .collect())
+    }
+
+    fn handle_msg_event(&mut self, mut msg: ReceivedMessage) {
         debug!(topic=?self.model.topic, "handling new message");
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        // Servers not setting the time get the one of reception
+        if msg.time == 0 {
+            msg.time = now;
+        }
+        let msg = self
+            .env
+            .message_transforms
+            .read()
+            .unwrap()
+            .iter()
+            .fold(msg, |msg, t| t.apply(msg));
         // Store in database
         let already_stored: bool = {
             let json_ev = &serde_json::to_string(&msg).unwrap();
@@ -248,24 +812,35 @@ impl SubscriptionActor {
             }
         };
 
-        if !already_stored {
-            debug!(topic=?self.model.topic, muted=?self.model.muted, "checking if notification should be shown");
-            // Show notification. If this fails, panic
-            if !{ self.model.muted } {
-                let notifier = self.env.notifier.clone();
-
-                let title = { msg.notification_title(&self.model) };
-
-                let n = models::Notification {
-                    title,
-                    body: msg.display_message().as_deref().unwrap_or("").to_string(),
-                    actions: msg.actions.clone(),
-                };
+        self.update_last_received_time(msg.time);
 
+        if !already_stored {
+            if self.model.snoozed_until != 0 && !self.model.is_snoozed(now) {
+                debug!(topic=?self.model.topic, "snooze expired");
+                if let Err(e) = self.set_snoozed_until(0) {
+                    error!(error=?e, topic=?self.model.topic, "can't end the snooze");
+                }
+            }
+            if msg.time <= self.silent_until {
+                debug!(topic=?self.model.topic, "message fetched again by a resync, skipping notification");
+            } else if self
+                .env
+                .notification_settings
+                .read()
+                .unwrap()
+                .is_paused(now)
+            {
+                debug!(topic=?self.model.topic, "notifications paused, skipping notification");
+            } else if self.model.is_snoozed(now) {
+                debug!(topic=?self.model.topic, snoozed_until=self.model.snoozed_until, "subscription snoozed, skipping notification");
+            } else if let Some(n) = self.notification_for(&msg) {
+                // Show notification. If this fails, panic
                 info!(topic=?self.model.topic, "showing notification");
-                notifier.send(n).unwrap();
-            } else {
-                debug!(topic=?self.model.topic, "notification muted, skipping");
+                self.env.notifier.send(n).unwrap();
+            }
+
+            if self.model.auto_download_attachments {
+                self.download_attachment(&msg, now);
             }
 
             // Forward to app
@@ -273,4 +848,614 @@ impl SubscriptionActor {
             let _ = self.broadcast_tx.send(ListenerEvent::Message(msg));
         }
     }
+
+    // Stores the attached image in the cache, so it's shown even when the server
+    // deleted it or the device is offline
+    fn download_attachment(&self, msg: &ReceivedMessage, now: u64) {
+        let Some(attachment) = msg.attachment.as_ref() else {
+            return;
+        };
+        if !attachment.is_displayable() || attachment.is_expired(now) {
+            debug!(topic=?self.model.topic, "attachment not downloadable, skipping");
+            return;
+        }
+        let env = self.env.clone();
+        let url = attachment.url.to_string();
+        let topic = self.model.topic.clone();
+        // Downloads don't block the other messages
+        spawn_local(async move {
+            if let Err(e) = env.attachment_cache.fetch(&env.http_client, &url).await {
+                warn!(error=?e, topic, "can't download the attachment");
+            }
+        });
+    }
+}
+
+// Returns the server response, the published message as JSON.
+// `auth` replaces the credentials stored for the server.
+pub(crate) async fn publish(
+    env: &SharedEnv,
+    server: &str,
+    msg: &str,
+    auth: Option<models::PublishAuth>,
+) -> anyhow::Result<String> {
+    debug!(server=?server, "preparing to publish message");
+    let (msg, headers) = models::split_publish_headers(msg)?;
+    let mut req = env.http_client.post(server);
+    match auth {
+        Some(models::PublishAuth::Basic { username, password }) => {
+            req = req.basic_auth(username, Some(password));
+        }
+        Some(models::PublishAuth::Token(token)) => req = req.bearer_auth(token),
+        None => {
+            if let Some(creds) = env.credentials.get(server) {
+                req = req.basic_auth(creds.username, Some(creds.password));
+            }
+        }
+    }
+    for (name, value) in headers {
+        req = req.header(name, value);
+    }
+
+    info!(server=?server, "sending message");
+    let res = env.http_client.execute(req.body(msg).build()?).await?;
+    let status = res.status();
+    let body = res.text().await.unwrap_or_default();
+    if !status.is_success() {
+        // The server explains why it rejected the message, e.g. an invalid delay
+        // or delayed delivery being disabled
+        let reason = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|v| v["error"].as_str().map(|e| e.to_string()))
+            .unwrap_or_else(|| body.clone());
+        return Err(crate::Error::PublishRejected {
+            status: status.as_u16(),
+            reason,
+            body,
+        }
+        .into());
+    }
+    debug!(server=?server, "message published successfully");
+    Ok(body)
+}
+
+async fn list_scheduled(
+    env: &SharedEnv,
+    server: &str,
+    topic: &str,
+) -> anyhow::Result<Vec<ReceivedMessage>> {
+    let url = models::Subscription::build_scheduled_url(server, topic)?;
+    let mut req = env.http_client.get(url.as_str());
+    if let Some(creds) = env.credentials.get(server) {
+        req = req.basic_auth(creds.username, Some(creds.password));
+    }
+    let res = env.http_client.execute(req.build()?).await?;
+    let status = res.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        anyhow::bail!("the server refused to list the scheduled messages ({status})");
+    }
+    if !status.is_success() {
+        debug!(server, topic, %status, "scheduled messages not available");
+        return Ok(vec![]);
+    }
+    let body = res.text().await?;
+    // Servers ignoring `sched` return the cached messages, those are already delivered
+    let messages = body
+        .lines()
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(crate::ServerEvent::Message(msg)) => Some(msg),
+            _ => None,
+        })
+        .filter(|msg| msg.is_scheduled())
+        .collect();
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::task::LocalSet;
+
+    use super::*;
+    use crate::credentials::Credentials;
+    use crate::http_client::{HttpClient, NullableClient};
+    use crate::listener::ListenerConfig;
+    use crate::models::NullNotifier;
+
+    struct Fixture {
+        env: SharedEnv,
+        notifier: Arc<NullNotifier>,
+        model: models::Subscription,
+        handle: SubscriptionHandle,
+        // Events sent here are received by the subscription as if they came from the server
+        events: async_channel::Sender<ListenerEvent>,
+    }
+
+    // Must be called inside a LocalSet
+    async fn fixture(model: models::Subscription) -> Fixture {
+        fixture_with_client(model, NullableClient::builder().build()).await
+    }
+
+    async fn fixture_with_client(model: models::Subscription, client: NullableClient) -> Fixture {
+        let notifier = Arc::new(NullNotifier::new());
+        let http_client = HttpClient::new_nullable(client);
+        let credentials = Credentials::new_nullable(vec![]).await.unwrap();
+        let env = SharedEnv::builder()
+            .notifier(notifier.clone())
+            .http_client(http_client.clone())
+            .credentials(credentials.clone())
+            .build()
+            .await
+            .unwrap();
+        env.db.clone().insert_subscription(model.clone()).unwrap();
+        let (listener, events) = ListenerHandle::new_nullable(ListenerConfig {
+            http_client,
+            credentials,
+            endpoint: model.server.clone(),
+            topic: model.topic.clone(),
+            since: model.last_received_time,
+            limiter: Default::default(),
+        });
+        let handle = SubscriptionHandle::new(listener, model.clone(), &env);
+        Fixture {
+            env,
+            notifier,
+            model,
+            handle,
+            events,
+        }
+    }
+
+    impl Fixture {
+        fn stored_messages(&self) -> Vec<String> {
+            self.env
+                .db
+                .list_messages(&self.model.server, &self.model.topic, 0)
+                .unwrap()
+        }
+        // Waits until the actor stored `n` messages
+        async fn wait_stored(&self, n: usize) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while self.stored_messages().len() < n {
+                    tokio::task::yield_now().await;
+                }
+            })
+            .await
+            .expect("message not stored in time");
+        }
+    }
+
+    fn message(id: &str, time: u64) -> ReceivedMessage {
+        ReceivedMessage {
+            id: id.to_string(),
+            topic: "test".to_string(),
+            time,
+            message: Some("hello".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_is_stored_and_notified_once() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let f = fixture(model).await;
+
+                f.events
+                    .send(ListenerEvent::Message(message("1", 100)))
+                    .await
+                    .unwrap();
+                f.wait_stored(1).await;
+
+                assert_eq!(f.stored_messages().len(), 1);
+                let sent = f.notifier.sent();
+                assert_eq!(sent.len(), 1);
+                assert_eq!(sent[0].body, "hello");
+                assert_eq!(sent[0].source.as_ref().map(|s| s.time), Some(100));
+                assert_eq!(f.handle.model().await.last_received_time, 100);
+            })
+            .await;
+    }
+
+    // The actor is the only place storing and notifying messages. A message delivered again,
+    // e.g. after a reconnection, must not show a second notification.
+    #[tokio::test]
+    async fn test_redelivered_message_is_notified_once() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let f = fixture(model).await;
+                let (_, mut rx) = f.handle.attach().await;
+
+                for _ in 0..2 {
+                    f.events
+                        .send(ListenerEvent::Message(message("1", 100)))
+                        .await
+                        .unwrap();
+                }
+                f.events
+                    .send(ListenerEvent::Message(message("2", 200)))
+                    .await
+                    .unwrap();
+                f.wait_stored(2).await;
+                // The second message is processed after the duplicate
+                let mut forwarded = vec![];
+                while forwarded.len() < 2 {
+                    if let ListenerEvent::Message(msg) = rx.recv().await.unwrap() {
+                        forwarded.push(msg.id);
+                    }
+                }
+
+                assert_eq!(forwarded, ["1", "2"]);
+                assert_eq!(f.stored_messages().len(), 2);
+                assert_eq!(f.notifier.sent().len(), 2);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_message_transforms_apply_before_storing() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let f = fixture(model).await;
+                f.env
+                    .message_transforms
+                    .write()
+                    .unwrap()
+                    .push(Arc::new(models::StripAnsiEscapes));
+
+                let mut msg = message("1", 100);
+                msg.message = Some("\x1b[32mok\x1b[0m".to_string());
+                f.events.send(ListenerEvent::Message(msg)).await.unwrap();
+                f.wait_stored(1).await;
+
+                assert!(f.stored_messages()[0].contains(r#""message":"ok""#));
+                assert_eq!(f.notifier.sent()[0].body, "ok");
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_message_without_time_gets_reception_time() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let f = fixture(model).await;
+                let before = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+
+                f.events
+                    .send(ListenerEvent::Message(message("1", 0)))
+                    .await
+                    .unwrap();
+                f.wait_stored(1).await;
+
+                let stored: ReceivedMessage =
+                    serde_json::from_str(&f.stored_messages()[0]).unwrap();
+                assert!(stored.time >= before);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_snoozed_subscription_stores_without_notifying() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let f = fixture(model).await;
+                f.handle.snooze(Duration::from_secs(3600)).await.unwrap();
+                let stored = f
+                    .env
+                    .db
+                    .get_subscription(&f.model.server, &f.model.topic)
+                    .unwrap();
+                assert!(stored.snoozed_until > 0);
+
+                f.events
+                    .send(ListenerEvent::Message(message("1", 100)))
+                    .await
+                    .unwrap();
+                f.wait_stored(1).await;
+                assert!(f.notifier.sent().is_empty());
+
+                f.handle.snooze(Duration::ZERO).await.unwrap();
+                f.events
+                    .send(ListenerEvent::Message(message("2", 200)))
+                    .await
+                    .unwrap();
+                f.wait_stored(2).await;
+                assert_eq!(f.notifier.sent().len(), 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_expired_snooze_is_cleared() {
+        LocalSet::new()
+            .run_until(async {
+                let mut model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                // Snoozed until a time in the past, e.g. before a restart
+                model.snoozed_until = 1;
+                let f = fixture(model).await;
+
+                f.events
+                    .send(ListenerEvent::Message(message("1", 100)))
+                    .await
+                    .unwrap();
+                f.wait_stored(1).await;
+                assert_eq!(f.notifier.sent().len(), 1);
+                assert_eq!(f.handle.model().await.snoozed_until, 0);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_paused_notifications_are_stored_only() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let f = fixture(model).await;
+                f.env.notification_settings.write().unwrap().paused = true;
+
+                f.events
+                    .send(ListenerEvent::Message(message("1", 100)))
+                    .await
+                    .unwrap();
+                f.wait_stored(1).await;
+                assert!(f.notifier.sent().is_empty());
+
+                // A pause ending in the past resumes by itself
+                f.env.notification_settings.write().unwrap().resume_at = Some(1);
+                f.events
+                    .send(ListenerEvent::Message(message("2", 200)))
+                    .await
+                    .unwrap();
+                f.wait_stored(2).await;
+                assert_eq!(f.notifier.sent().len(), 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_list_scheduled_keeps_only_future_messages() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let url =
+                    models::Subscription::build_scheduled_url(&model.server, &model.topic).unwrap();
+                let body = [
+                    serde_json::to_string(&message("past", 100)).unwrap(),
+                    serde_json::to_string(&message("future", u64::MAX / 2)).unwrap(),
+                ]
+                .map(|m| m.replacen('{', r#"{"event":"message","#, 1))
+                .join("\n");
+                let client = NullableClient::builder()
+                    .text_response(url.as_str(), 200, body)
+                    .build();
+                let f = fixture_with_client(model, client).await;
+
+                let scheduled = f.handle.list_scheduled().await.unwrap();
+                assert_eq!(scheduled.len(), 1);
+                assert_eq!(scheduled[0].id, "future");
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_list_scheduled_unsupported_is_empty() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let url =
+                    models::Subscription::build_scheduled_url(&model.server, &model.topic).unwrap();
+                let client = NullableClient::builder()
+                    .text_response(url.as_str(), 404, "")
+                    .build();
+                let f = fixture_with_client(model, client).await;
+
+                assert!(f.handle.list_scheduled().await.unwrap().is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_panicking_actor_is_restarted() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let f = fixture(model).await;
+                let (_, mut events) = f.handle.attach().await;
+
+                f.handle
+                    .command_tx
+                    .send(SubscriptionCommand::Panic)
+                    .await
+                    .unwrap();
+                let state = loop {
+                    if let ListenerEvent::ConnectionStateChanged(state) =
+                        events.recv().await.unwrap()
+                    {
+                        break state;
+                    }
+                };
+                assert!(matches!(
+                    state,
+                    ConnectionState::Reconnecting { error: Some(_), .. }
+                ));
+
+                // The restarted actor handles commands and messages
+                assert_eq!(f.handle.model().await.topic, "test");
+                f.events
+                    .send(ListenerEvent::Message(message("1", 100)))
+                    .await
+                    .unwrap();
+                f.wait_stored(1).await;
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_publish_auth_overrides_stored_credentials() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let url = url::Url::parse(&model.server).unwrap();
+                let client = NullableClient::builder()
+                    .text_response(url.as_str(), 200, "{}")
+                    .text_response(url.as_str(), 200, "{}")
+                    .build();
+                let f = fixture_with_client(model, client).await;
+                let tracker = f.env.http_client.request_tracker().await;
+                f.env
+                    .credentials
+                    .insert(&f.model.server, "stored", "pass")
+                    .await
+                    .unwrap();
+                let msg = r#"{"topic":"test","message":"hi"}"#;
+
+                let auth = models::PublishAuth::Token("tk_secret".to_string());
+                assert!(!format!("{auth:?}").contains("tk_secret"));
+                publish(&f.env, &f.model.server, msg, Some(auth))
+                    .await
+                    .unwrap();
+                publish(&f.env, &f.model.server, msg, None).await.unwrap();
+
+                let requests = tracker.items().await;
+                assert_eq!(requests[0].headers["Authorization"], "Bearer tk_secret");
+                assert!(requests[1].headers["Authorization"]
+                    .to_str()
+                    .unwrap()
+                    .starts_with("Basic "));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_publish_rejection_keeps_server_response() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let url = url::Url::parse(&model.server).unwrap();
+                let response = r#"{"code":40024,"http":400,"error":"invalid request: priority invalid","link":"https://ntfy.sh/docs/publish/#message-priority"}"#;
+                let client = NullableClient::builder()
+                    .text_response(url.as_str(), 400, response)
+                    .build();
+                let f = fixture_with_client(model, client).await;
+                let msg = r#"{"topic":"test","priority":9}"#;
+
+                let err = publish(&f.env, &f.model.server, msg, None)
+                    .await
+                    .unwrap_err();
+                assert_eq!(
+                    err.to_string(),
+                    "server rejected the message (HTTP 400): invalid request: priority invalid"
+                );
+                let Some(crate::Error::PublishRejected { status, body, .. }) = err.downcast_ref()
+                else {
+                    panic!("unexpected error {err:?}");
+                };
+                assert_eq!(*status, 400);
+                assert_eq!(body, response);
+            })
+            .await;
+    }
+
+    fn message_with_image(id: &str, url: &str) -> ReceivedMessage {
+        ReceivedMessage {
+            attachment: Some(models::Attachment {
+                name: "photo.png".to_string(),
+                url: url::Url::parse(url).unwrap(),
+                atype: Some("image/png".to_string()),
+                size: Some(9),
+                expires: None,
+            }),
+            ..message(id, 100)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_attachment_is_downloaded_when_enabled() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .auto_download_attachments(true)
+                    .build()
+                    .unwrap();
+                let url = format!("https://ntfy.sh/file/{}.png", std::process::id());
+                let client = NullableClient::builder()
+                    .text_response(&url, 200, "png bytes")
+                    .build();
+                let f = fixture_with_client(model, client).await;
+
+                f.events
+                    .send(ListenerEvent::Message(message_with_image("1", &url)))
+                    .await
+                    .unwrap();
+                let path = f.env.attachment_cache.path_for(&url);
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while !path.exists() {
+                        tokio::task::yield_now().await;
+                    }
+                })
+                .await
+                .expect("attachment not downloaded in time");
+                assert_eq!(std::fs::read_to_string(&path).unwrap(), "png bytes");
+                std::fs::remove_file(&path).unwrap();
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_attachment_is_not_downloaded_by_default() {
+        LocalSet::new()
+            .run_until(async {
+                let model = models::Subscription::builder("test".to_string())
+                    .build()
+                    .unwrap();
+                let f = fixture(model).await;
+                let tracker = f.env.http_client.request_tracker().await;
+
+                f.events
+                    .send(ListenerEvent::Message(message_with_image(
+                        "1",
+                        "https://ntfy.sh/file/default.png",
+                    )))
+                    .await
+                    .unwrap();
+                f.wait_stored(1).await;
+                tokio::task::yield_now().await;
+
+                assert!(tracker.items().await.is_empty());
+            })
+            .await;
+    }
 }
@@ -13,6 +13,9 @@ use tracing::{debug, error, info, warn};
 use crate::config::{APP_ID, PKGDATADIR, PROFILE, VERSION};
 use crate::widgets::*;
 
+// Transient notifications are withdrawn after this time
+const TRANSIENT_NOTIFICATION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
 mod imp {
     use std::cell::RefCell;
 
@@ -26,6 +29,15 @@ mod imp {
         pub window: RefCell<WeakRef<NotifyWindow>>,
         pub hold_guard: OnceCell<gio::ApplicationHoldGuard>,
         pub ntfy: OnceCell<NtfyHandle>,
+        pub settings: OnceCell<gio::Settings>,
+        // None outside GNOME
+        pub desktop_notification_settings: OnceCell<Option<gio::Settings>>,
+        // Kept during Do Not Disturb, to be shown when it ends
+        pub held_notifications: RefCell<Vec<models::Notification>>,
+        // The stream stops playing when dropped, so the last one is kept here
+        pub playing_sound: RefCell<Option<gtk::MediaFile>>,
+        // Ids of the notifications on screen, to withdraw them
+        pub shown_notifications: RefCell<std::collections::HashSet<String>>,
     }
 
     #[glib::object_subclass]
@@ -56,6 +68,16 @@ mod imp {
             app.setup_gactions();
             app.setup_accels();
         }
+        fn shutdown(&self) {
+            debug!("AdwApplication<NotifyApplication>::shutdown");
+            if let Some(ntfy) = self.ntfy.get() {
+                // The daemon thread won't clean up after itself
+                if let Err(e) = futures::executor::block_on(ntfy.stop_serving_health()) {
+                    warn!(error = %e, "couldn't remove the health check socket");
+                }
+            }
+            self.parent_shutdown();
+        }
         fn command_line(&self, command_line: &gio::ApplicationCommandLine) -> glib::ExitCode {
             debug!("AdwApplication<NotifyApplication>::command_line");
             let arguments = command_line.arguments();
@@ -78,6 +100,21 @@ mod imp {
 
             app.ensure_window_present();
 
+            // Subscription links, e.g. ntfy://ntfy.sh/mytopic opened from the browser
+            for arg in arguments.iter().skip(1).filter_map(|a| a.to_str()) {
+                if !arg.contains("://") {
+                    continue;
+                }
+                let window = app.main_window();
+                if let Err(e) = window.open_subscription_link(arg) {
+                    warn!(error = %e, "can't open the link");
+                    window
+                        .imp()
+                        .toast_overlay
+                        .add_toast(adw::Toast::new(&e.to_string()));
+                }
+            }
+
             glib::ExitCode::SUCCESS
         }
     }
@@ -148,11 +185,28 @@ impl NotifyApplication {
                 app.handle_message_action(action);
             })
             .build();
+        // The default action of the message notifications
+        let notification_activated = gio::ActionEntry::builder("notification-activated")
+            .parameter_type(Some(glib::VariantTy::STRING))
+            .activate(|app: &Self, _, params| {
+                let Some(source) = params
+                    .and_then(|p| p.str())
+                    .and_then(|s| serde_json::from_str::<models::NotificationSource>(s).ok())
+                else {
+                    error!("invalid notification source");
+                    return;
+                };
+                app.ensure_window_present();
+                let mark_read = app.setting_enabled("notification-marks-read");
+                app.main_window().show_notified_message(&source, mark_read);
+            })
+            .build();
         self.add_action_entries([
             action_quit,
             action_about,
             action_preferences,
             message_action,
+            notification_activated,
         ]);
     }
 
@@ -165,24 +219,15 @@ impl NotifyApplication {
                     |_| {},
                 );
             }
-            models::Action::Http {
-                method,
-                url,
-                body,
-                headers,
-                ..
-            } => {
-                gio::spawn_blocking(move || {
-                    let mut req = ureq::request(method.as_str(), url.as_str());
-                    for (k, v) in headers.iter() {
-                        req = req.set(&k, &v);
-                    }
-                    let res = req.send(body.as_bytes());
-                    match res {
+            action @ models::Action::Http { .. } => {
+                glib::MainContext::default().spawn_local(async move {
+                    match send_http_action(action).await {
                         Err(e) => {
                             error!(error = ?e, "Error sending request");
                         }
-                        Ok(_) => {}
+                        Ok(feedback) => {
+                            debug!(?feedback, "HTTP action sent");
+                        }
                     }
                 });
             }
@@ -194,6 +239,8 @@ impl NotifyApplication {
     fn setup_accels(&self) {
         self.set_accels_for_action("app.quit", &["<Control>q"]);
         self.set_accels_for_action("window.close", &["<Control>w"]);
+        self.set_accels_for_action("win.quick-switch", &["<Control>k"]);
+        self.set_accels_for_action("win.filter-messages", &["<Control>f"]);
     }
 
     fn setup_css(&self) {
@@ -225,10 +272,10 @@ impl NotifyApplication {
         win.present(Some(&self.main_window()));
     }
 
-    pub fn run(&self) -> glib::ExitCode {
+    pub fn run(&self, args: &[String]) -> glib::ExitCode {
         info!(app_id = %APP_ID, version = %VERSION, profile = %PROFILE, datadir = %PKGDATADIR, "running");
 
-        ApplicationExtManual::run(self)
+        ApplicationExtManual::run_with_args(self, args)
     }
     async fn run_in_background() -> ashpd::Result<()> {
         let response = ashpd::desktop::background::Background::request()
@@ -245,8 +292,64 @@ impl NotifyApplication {
         Ok(())
     }
 
+    pub fn database_path() -> std::path::PathBuf {
+        glib::user_data_dir().join("com.ranfdev.Notify.sqlite")
+    }
+
+    // Downloaded sender icons and attachments
+    pub fn cache_path() -> std::path::PathBuf {
+        glib::user_cache_dir().join("com.ranfdev.Notify")
+    }
+
+    // The cache the daemon downloads attachments into
+    pub fn attachment_cache() -> ntfy_daemon::DownloadCache {
+        ntfy_daemon::DownloadCache::attachments(&Self::cache_path())
+    }
+
+    pub fn emoji_map_path() -> std::path::PathBuf {
+        glib::user_config_dir().join("com.ranfdev.Notify.emoji-map.json")
+    }
+
+    // Written instead of showing notifications when running without a display
+    pub fn notification_log_path() -> std::path::PathBuf {
+        glib::user_data_dir().join("com.ranfdev.Notify.notifications.log")
+    }
+
+    // Unix socket answering `notify health`
+    pub fn health_socket_path() -> std::path::PathBuf {
+        glib::user_runtime_dir().join("com.ranfdev.Notify.health")
+    }
+
+    pub fn credentials_backend() -> ntfy_daemon::credentials::Backend {
+        use ntfy_daemon::credentials::Backend;
+
+        let settings = gio::Settings::new(APP_ID);
+        if settings.string("credentials-storage") != "file" {
+            return Backend::SecretService;
+        }
+        match std::env::var("NOTIFY_KEYRING_PASSPHRASE") {
+            Ok(passphrase) => Backend::File {
+                path: glib::user_data_dir().join("com.ranfdev.Notify.keyring"),
+                passphrase,
+            },
+            Err(_) => {
+                warn!("NOTIFY_KEYRING_PASSPHRASE is not set, using the Secret Service");
+                Backend::SecretService
+            }
+        }
+    }
+
+    // Read once, changing it applies on the next start
+    pub fn connection_mode() -> models::ConnectionMode {
+        if gio::Settings::new(APP_ID).boolean("share-server-connections") {
+            models::ConnectionMode::SharedPerServer
+        } else {
+            models::ConnectionMode::PerTopic
+        }
+    }
+
     fn ensure_rpc_running(&self) {
-        let dbpath = glib::user_data_dir().join("com.ranfdev.Notify.sqlite");
+        let dbpath = Self::database_path();
         info!(database_path = %dbpath.display());
 
         // Here I'm sending notifications to the desktop environment and listening for network changes.
@@ -260,26 +363,14 @@ impl NotifyApplication {
         let app = self.clone();
         glib::MainContext::ref_thread_default().spawn_local(async move {
             while let Ok(n) = r.recv().await {
-                let gio_notif = gio::Notification::new(&n.title);
-                gio_notif.set_body(Some(&n.body));
-
-                let action_name = |a| {
-                    let json = serde_json::to_string(a).unwrap();
-                    gio::Action::print_detailed_name("app.message-action", Some(&json.into()))
-                };
-                for a in n.actions.iter() {
-                    match a {
-                        models::Action::View { label, .. } => {
-                            gio_notif.add_button(&label, &action_name(a))
-                        }
-                        models::Action::Http { label, .. } => {
-                            gio_notif.add_button(&label, &action_name(a))
-                        }
-                        _ => {}
+                if app.do_not_disturb() && !app.allowed_during_dnd(&n) {
+                    debug!(title = %n.title, "Do Not Disturb is active, holding back notification");
+                    if app.setting_enabled("dnd-renotify") {
+                        app.imp().held_notifications.borrow_mut().push(n);
                     }
+                    continue;
                 }
-
-                app.send_notification(None, &gio_notif);
+                app.show_notification(n);
             }
         });
         struct Proxies {
@@ -309,13 +400,224 @@ impl NotifyApplication {
             }
         }
         let proxies = std::sync::Arc::new(Proxies { notification: s });
-        let ntfy = ntfy_daemon::start(dbpath.to_str().unwrap(), proxies.clone(), proxies).unwrap();
+        models::load_user_emoji_map(&Self::emoji_map_path());
+        let ntfy = ntfy_daemon::start(
+            dbpath.to_str().unwrap(),
+            Self::cache_path().to_str().unwrap(),
+            Self::credentials_backend(),
+            proxies.clone(),
+            proxies,
+            Self::connection_mode(),
+        )
+        .unwrap();
         self.imp()
             .ntfy
             .set(ntfy)
             .or(Err(anyhow::anyhow!("failed setting ntfy")))
             .unwrap();
         self.imp().hold_guard.set(self.hold()).unwrap();
+        self.bind_daemon_settings();
+        self.bind_do_not_disturb();
+        self.replay_unread_notifications();
+        self.serve_health();
+    }
+
+    fn serve_health(&self) {
+        let ntfy = self.imp().ntfy.get().unwrap().clone();
+        glib::MainContext::default().spawn_local(async move {
+            if let Err(e) = ntfy.serve_health(Self::health_socket_path()).await {
+                warn!(error = %e, "couldn't serve health checks");
+            }
+        });
+    }
+
+    // Opt-in, a burst of old notifications at login would be surprising
+    fn replay_unread_notifications(&self) {
+        let settings = self.imp().settings.get().unwrap();
+        if !settings.boolean("replay-unread-notifications") {
+            return;
+        }
+        let ntfy = self.imp().ntfy.get().unwrap().clone();
+        glib::MainContext::default().spawn_local(async move {
+            let res = async {
+                ntfy.wait_ready().await?;
+                ntfy.replay_unread_notifications().await
+            };
+            if let Err(e) = res.await {
+                warn!(error = %e, "couldn't show the unread notifications again");
+            }
+        });
+    }
+
+    fn show_notification(&self, n: models::Notification) {
+        let gio_notif = gio::Notification::new(&n.title);
+        if !n.body.is_empty() {
+            gio_notif.set_body(Some(&n.body));
+        }
+        if n.persistence == models::NotificationPersistence::Resident {
+            // Urgent notifications aren't hidden until the user dismisses them
+            gio_notif.set_priority(gio::NotificationPriority::Urgent);
+        }
+
+        let action_name = |a| {
+            let json = serde_json::to_string(a).unwrap();
+            gio::Action::print_detailed_name("app.message-action", Some(&json.into()))
+        };
+        if let Some(source) = &n.source {
+            let json = serde_json::to_string(source).unwrap();
+            gio_notif.set_default_action_and_target_value(
+                "app.notification-activated",
+                Some(&json.to_variant()),
+            );
+        }
+        for a in n.actions.iter() {
+            match a {
+                models::Action::View { label, .. } => gio_notif.add_button(&label, &action_name(a)),
+                models::Action::Http { label, .. } => gio_notif.add_button(&label, &action_name(a)),
+                _ => {}
+            }
+        }
+
+        if let Some(sound) = n.sound.as_deref() {
+            if let Some(media) = Self::play_sound(sound) {
+                self.imp().playing_sound.replace(Some(media));
+            }
+        }
+
+        let id = glib::uuid_string_random().to_string();
+        self.send_notification(Some(&id), &gio_notif);
+        if n.persistence == models::NotificationPersistence::Transient {
+            // gio has no transient hint, so the notification is withdrawn after a while
+            let app = self.clone();
+            let id = id.clone();
+            glib::timeout_add_local_once(TRANSIENT_NOTIFICATION_TIMEOUT, move || {
+                app.withdraw_notification(&id);
+                app.imp().shown_notifications.borrow_mut().remove(&id);
+            });
+        }
+        self.imp().shown_notifications.borrow_mut().insert(id);
+    }
+
+    pub fn withdraw_all_notifications(&self) {
+        for id in self.imp().shown_notifications.take() {
+            self.withdraw_notification(&id);
+        }
+    }
+
+    fn setting_enabled(&self, key: &str) -> bool {
+        self.imp()
+            .settings
+            .get()
+            .is_some_and(|settings| settings.boolean(key))
+    }
+
+    fn desktop_notification_settings(&self) -> Option<&gio::Settings> {
+        self.imp()
+            .desktop_notification_settings
+            .get_or_init(|| {
+                let schema = "org.gnome.desktop.notifications";
+                gio::SettingsSchemaSource::default()?.lookup(schema, true)?;
+                Some(gio::Settings::new(schema))
+            })
+            .as_ref()
+    }
+
+    // GNOME implements Do Not Disturb by hiding the notification banners
+    fn do_not_disturb(&self) -> bool {
+        self.desktop_notification_settings()
+            .is_some_and(|settings| !settings.boolean("show-banners"))
+    }
+
+    fn allowed_during_dnd(&self, n: &models::Notification) -> bool {
+        n.priority >= 5 && self.setting_enabled("dnd-allow-urgent")
+    }
+
+    // Shows what was held back, once Do Not Disturb ends
+    fn bind_do_not_disturb(&self) {
+        let Some(settings) = self.desktop_notification_settings() else {
+            return;
+        };
+        let app = self.clone();
+        settings.connect_changed(Some("show-banners"), move |_, _| {
+            if app.do_not_disturb() {
+                return;
+            }
+            let held = app.imp().held_notifications.take();
+            if !app.setting_enabled("dnd-renotify") {
+                return;
+            }
+            for n in held {
+                app.show_notification(n);
+            }
+        });
+    }
+
+    // Missing or unplayable files are only logged, the desktop still plays its default sound
+    fn play_sound(path: &str) -> Option<gtk::MediaFile> {
+        let path = std::path::Path::new(path);
+        if !path.is_file() {
+            warn!(path = %path.display(), "notification sound not found");
+            return None;
+        }
+        let media = gtk::MediaFile::for_filename(path);
+        media.connect_error_notify(|media| {
+            if let Some(e) = media.error() {
+                warn!(error = %e, "can't play the notification sound");
+            }
+        });
+        media.play();
+        Some(media)
+    }
+
+    // Keeps the daemon in sync with the settings it cares about
+    fn bind_daemon_settings(&self) {
+        let settings = gio::Settings::new(APP_ID);
+        let ntfy = self.imp().ntfy.get().unwrap().clone();
+
+        let apply = move |settings: &gio::Settings| {
+            let ntfy = ntfy.clone();
+            let notify_connection_lost = settings.boolean("notify-connection-lost");
+            let preview_length = settings.uint("notification-preview-length") as usize;
+            let max_connections = settings.uint("max-concurrent-connections") as usize;
+            let digest_at = settings
+                .boolean("daily-digest")
+                .then(|| {
+                    let time = settings.string("daily-digest-time");
+                    chrono::NaiveTime::parse_from_str(&time, "%H:%M")
+                        .inspect_err(|e| warn!(%time, error = %e, "invalid daily digest time"))
+                        .ok()
+                })
+                .flatten();
+            let mut transforms: Vec<std::sync::Arc<dyn models::MessageTransform>> = vec![];
+            if settings.boolean("strip-ansi-escapes") {
+                transforms.push(std::sync::Arc::new(models::StripAnsiEscapes));
+            }
+            let mut http1_servers: Vec<String> = settings
+                .strv("http1-servers")
+                .iter()
+                .map(|s| s.to_string())
+                .collect();
+            if let Ok(servers) = std::env::var("NOTIFY_HTTP1_SERVERS") {
+                http1_servers.extend(servers.split(',').map(|s| s.trim().to_string()));
+            }
+            glib::MainContext::default().spawn_local(async move {
+                let res = async {
+                    ntfy.set_notify_connection_lost(notify_connection_lost)
+                        .await?;
+                    ntfy.set_preview_length(preview_length).await?;
+                    ntfy.set_max_concurrent_connections(max_connections).await?;
+                    ntfy.set_daily_digest(digest_at).await?;
+                    ntfy.set_http1_servers(http1_servers).await?;
+                    ntfy.set_message_transforms(transforms).await
+                };
+                if let Err(e) = res.await {
+                    warn!(error = %e, "couldn't update the daemon settings");
+                }
+            });
+        };
+        apply(&settings);
+        settings.connect_changed(None, move |settings, _| apply(settings));
+        self.imp().settings.set(settings).unwrap();
     }
 
     fn build_window(&self) {
@@ -326,6 +628,33 @@ impl NotifyApplication {
     }
 }
 
+// Sends the request of an HTTP action. Returns the feedback to show on success:
+// the `success_label` of the action, or else the `X-Title` header of the response.
+pub async fn send_http_action(action: models::Action) -> anyhow::Result<Option<String>> {
+    let models::Action::Http {
+        method,
+        url,
+        body,
+        headers,
+        success_label,
+        ..
+    } = action
+    else {
+        return Ok(None);
+    };
+    let title = gio::spawn_blocking(move || {
+        let mut req = ureq::request(method.as_str(), url.as_str());
+        for (k, v) in headers.iter() {
+            req = req.set(k, v);
+        }
+        let res = req.send(body.as_bytes())?;
+        anyhow::Ok(res.header("X-Title").map(|t| t.to_string()))
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("the HTTP request panicked"))??;
+    Ok(success_label.or(title))
+}
+
 impl Default for NotifyApplication {
     fn default() -> Self {
         glib::Object::builder()
@@ -0,0 +1,212 @@
+use std::io::Read;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use gtk::glib;
+use ntfy_daemon::models;
+
+use crate::application::NotifyApplication;
+
+pub const PUBLISH_USAGE: &str = "usage: notify publish [--server URL] [--title TITLE] [--priority 1-5] [--tags a,b] [--token TOKEN | --user USER:PASSWORD] <topic> <message>
+Use - as the message to read it from stdin.
+--token and --user are used instead of the stored account, without saving them.";
+
+pub struct PublishArgs {
+    pub server: String,
+    pub msg: models::OutgoingMessage,
+    pub auth: Option<models::PublishAuth>,
+}
+
+fn value<'a>(args: &mut impl Iterator<Item = &'a String>, name: &str) -> anyhow::Result<&'a str> {
+    args.next()
+        .map(|v| v.as_str())
+        .ok_or_else(|| anyhow!("{name} needs a value"))
+}
+
+// Arguments following `notify publish`
+pub fn parse_publish_args(args: &[String]) -> anyhow::Result<PublishArgs> {
+    let mut server = models::DEFAULT_SERVER.to_string();
+    let mut msg = models::OutgoingMessage::default();
+    let mut auth = None;
+    let mut positional = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = value(&mut args, arg)?.to_string(),
+            "--title" => msg.title = Some(value(&mut args, arg)?.to_string()),
+            "--priority" => {
+                let priority = value(&mut args, arg)?;
+                msg.priority = match priority.parse() {
+                    Ok(p @ 1..=5) => Some(p),
+                    _ => bail!("invalid priority {priority}, it must be between 1 and 5"),
+                };
+            }
+            "--tags" => {
+                msg.tags = value(&mut args, arg)?
+                    .split(',')
+                    .map(|t| t.trim().to_string())
+                    .filter(|t| !t.is_empty())
+                    .collect();
+            }
+            "--token" => {
+                auth = Some(models::PublishAuth::Token(
+                    value(&mut args, arg)?.to_string(),
+                ));
+            }
+            "--user" => {
+                let Some((username, password)) = value(&mut args, arg)?.split_once(':') else {
+                    bail!("--user must be given as USER:PASSWORD");
+                };
+                auth = Some(models::PublishAuth::Basic {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                });
+            }
+            a if a.starts_with("--") => bail!("unknown option {a}"),
+            _ => positional.push(arg.as_str()),
+        }
+    }
+
+    let [topic, message] = positional[..] else {
+        bail!("expected a topic and a message");
+    };
+    // Validates the topic and the server url
+    models::Subscription::builder(topic.to_string())
+        .server(server.clone())
+        .build()
+        .map_err(|e| anyhow!("{e}"))?;
+
+    msg.topic = topic.to_string();
+    msg.message = Some(if message == "-" {
+        let mut body = String::new();
+        std::io::stdin().read_to_string(&mut body)?;
+        body
+    } else {
+        message.to_string()
+    });
+    Ok(PublishArgs { server, msg, auth })
+}
+
+// Publishes with the given or the stored credentials for the server, without starting the UI.
+// Returns the server response.
+pub fn publish(args: PublishArgs) -> anyhow::Result<String> {
+    let ntfy = ntfy_daemon::start_oneshot(NotifyApplication::credentials_backend())?;
+    let json = serde_json::to_string(&args.msg)?;
+    futures::executor::block_on(ntfy.publish(&args.server, &json, args.auth))
+}
+
+// Health of the running instance, over its health check socket.
+// Returns whether every subscription is working.
+pub fn health() -> anyhow::Result<bool> {
+    let path = NotifyApplication::health_socket_path();
+    let health = ntfy_daemon::health::query(&path)
+        .map_err(|e| anyhow!("can't reach Notify at {}: {e}", path.display()))?;
+    println!("uptime: {}s", health.uptime_secs);
+    println!("subscriptions: {}", health.subscriptions);
+    println!("connected: {}", health.connected);
+    println!("failing: {}", health.failing);
+    Ok(health.is_healthy())
+}
+
+// Prints the rows returned by a SELECT on the message database, one JSON object per line
+pub fn query(sql: &str) -> anyhow::Result<()> {
+    let path = NotifyApplication::database_path();
+    if !path.exists() {
+        bail!("no message database at {}", path.display());
+    }
+    let db = ntfy_daemon::message_repo::Db::open_readonly(&path)
+        .with_context(|| format!("can't open {}", path.display()))?;
+    for row in db.query_readonly(sql)? {
+        println!("{row}");
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+// `--log-format text|json` takes precedence over NOTIFY_LOG_FORMAT.
+// The option is removed from `args`, GTK doesn't know it.
+pub fn take_log_format(args: &mut Vec<String>) -> anyhow::Result<LogFormat> {
+    let format = match args.iter().position(|a| a == "--log-format") {
+        Some(i) if i + 1 < args.len() => {
+            let format = args.remove(i + 1);
+            args.remove(i);
+            format
+        }
+        Some(_) => bail!("--log-format needs a value"),
+        None => match std::env::var("NOTIFY_LOG_FORMAT") {
+            Ok(format) => format,
+            Err(_) => return Ok(LogFormat::Text),
+        },
+    };
+    match format.as_str() {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        _ => bail!("unknown log format {format}, expected text or json"),
+    }
+}
+
+// Colors are left out when NO_COLOR is set to anything, see https://no-color.org.
+// RUST_LOG chooses what's logged, INFO and above by default.
+pub fn init_logging(format: LogFormat) {
+    let ansi = std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+    let filter = tracing_subscriber::EnvFilter::builder()
+        .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(ansi)
+        .with_env_filter(filter);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+// GTK can't start without a display
+pub fn is_headless() -> bool {
+    std::env::var_os("DISPLAY").is_none() && std::env::var_os("WAYLAND_DISPLAY").is_none()
+}
+
+// Watches the subscriptions without the UI, appending their notifications as JSON lines to
+// the file in NOTIFY_NOTIFICATION_LOG ("-" is the standard output). Runs until interrupted or terminated.
+pub fn headless_daemon() -> anyhow::Result<()> {
+    let log_path = std::env::var_os("NOTIFY_NOTIFICATION_LOG")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(NotifyApplication::notification_log_path);
+    let notifier = models::LogNotifier::open(&log_path)
+        .map_err(|e| anyhow!("can't open {}: {e}", log_path.display()))?;
+    tracing::info!(path = %log_path.display(), "writing notifications to a file");
+
+    models::load_user_emoji_map(&NotifyApplication::emoji_map_path());
+    let ntfy = ntfy_daemon::start(
+        NotifyApplication::database_path().to_str().unwrap(),
+        NotifyApplication::cache_path().to_str().unwrap(),
+        NotifyApplication::credentials_backend(),
+        Arc::new(notifier),
+        Arc::new(models::NullNetworkMonitor::new()),
+        NotifyApplication::connection_mode(),
+    )?;
+    futures::executor::block_on(ntfy.serve_health(NotifyApplication::health_socket_path()))?;
+
+    // Runs until stopped by a signal, then removes the health check socket
+    let main_loop = glib::MainLoop::new(None, false);
+    for signal in [SIGINT, SIGTERM] {
+        let main_loop = main_loop.clone();
+        glib::unix_signal_add(signal, move || {
+            main_loop.quit();
+            glib::ControlFlow::Break
+        });
+    }
+    main_loop.run();
+    tracing::info!("stopping");
+    futures::executor::block_on(ntfy.stop_serving_health())
+}
+
+// From signal.h, the same on every Linux architecture
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
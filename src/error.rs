@@ -47,4 +47,18 @@ impl ErrorBoundary {
             },
         );
     }
+
+    // Like `spawn`, also showing the message returned on success, if any
+    pub fn spawn_with_near_toast(
+        self,
+        f: impl Future<Output = Result<Option<String>, Error>> + 'static,
+    ) {
+        let boundary = self.boundary.clone();
+        self.spawn(async move {
+            if let (Some(msg), Some(boundary)) = (f.await?, boundary) {
+                boundary.add_toast(adw::Toast::new(&msg));
+            }
+            Ok(())
+        });
+    }
 }
@@ -2,6 +2,7 @@ mod application;
 #[rustfmt::skip]
 mod config;
 mod async_utils;
+mod cli;
 pub mod error;
 mod subscription;
 pub mod widgets;
@@ -10,11 +11,114 @@ use gettextrs::{gettext, LocaleCategory};
 use gtk::{gio, glib};
 
 use self::application::NotifyApplication;
-use self::config::{GETTEXT_PACKAGE, LOCALEDIR, RESOURCES_FILE};
+use self::config::{
+    APP_ID, GETTEXT_PACKAGE, LOCALEDIR, PKGDATADIR, PROFILE, RESOURCES_FILE, VERSION,
+};
+
+fn print_config() {
+    let secret_service = futures::executor::block_on(
+        ntfy_daemon::credentials::Credentials::is_secret_service_available(),
+    );
+    println!("app id: {APP_ID}");
+    println!("version: {VERSION}");
+    println!("profile: {PROFILE}");
+    println!("datadir: {PKGDATADIR}");
+    println!(
+        "database path: {}",
+        NotifyApplication::database_path().display()
+    );
+    println!(
+        "emoji map path: {}",
+        NotifyApplication::emoji_map_path().display()
+    );
+    println!(
+        "notification log path: {}",
+        NotifyApplication::notification_log_path().display()
+    );
+    println!(
+        "secret service: {}",
+        if secret_service {
+            "reachable"
+        } else {
+            "unreachable"
+        }
+    );
+}
 
 fn main() -> glib::ExitCode {
     // Initialize logger
-    tracing_subscriber::fmt::init();
+    let mut args: Vec<String> = std::env::args().collect();
+    match cli::take_log_format(&mut args) {
+        Ok(format) => cli::init_logging(format),
+        Err(e) => {
+            eprintln!("error: {e}");
+            return glib::ExitCode::FAILURE;
+        }
+    }
+
+    // Diagnostic flags, handled before starting GTK
+    if args.iter().any(|a| a == "--version") {
+        println!("Notify {VERSION} {PROFILE}");
+        return glib::ExitCode::SUCCESS;
+    }
+    if args.iter().any(|a| a == "--print-config") {
+        print_config();
+        return glib::ExitCode::SUCCESS;
+    }
+    if args.get(1).map(String::as_str) == Some("publish") {
+        let publish_args = match cli::parse_publish_args(&args[2..]) {
+            Ok(publish_args) => publish_args,
+            Err(e) => {
+                eprintln!("error: {e}\n{}", cli::PUBLISH_USAGE);
+                return glib::ExitCode::FAILURE;
+            }
+        };
+        return match cli::publish(publish_args) {
+            Ok(response) => {
+                println!("{}", response.trim_end());
+                glib::ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                glib::ExitCode::FAILURE
+            }
+        };
+    }
+    if args.get(1).map(String::as_str) == Some("health") {
+        return match cli::health() {
+            Ok(true) => glib::ExitCode::SUCCESS,
+            Ok(false) => glib::ExitCode::FAILURE,
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                glib::ExitCode::FAILURE
+            }
+        };
+    }
+    if args.get(1).map(String::as_str) == Some("query") {
+        let [_, _, sql] = &args[..] else {
+            eprintln!("usage: notify query <SELECT statement>");
+            return glib::ExitCode::FAILURE;
+        };
+        return match cli::query(sql) {
+            Ok(()) => glib::ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                glib::ExitCode::FAILURE
+            }
+        };
+    }
+
+    if args.get(1).map(String::as_str) == Some("--daemon")
+        && (args.iter().any(|a| a == "--headless") || cli::is_headless())
+    {
+        return match cli::headless_daemon() {
+            Ok(()) => glib::ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                glib::ExitCode::FAILURE
+            }
+        };
+    }
 
     // Prepare i18n
     gettextrs::setlocale(LocaleCategory::LcAll, "");
@@ -27,5 +131,5 @@ fn main() -> glib::ExitCode {
     gio::resources_register(&res);
 
     let app = NotifyApplication::default();
-    app.run()
+    app.run(&args)
 }
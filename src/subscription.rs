@@ -1,6 +1,5 @@
 use std::cell::{Cell, OnceCell, RefCell};
 use std::future::Future;
-use std::rc::Rc;
 
 use adw::prelude::*;
 use glib::subclass::prelude::*;
@@ -9,30 +8,8 @@ use gtk::{gio, glib};
 use ntfy_daemon::{models, ConnectionState, ListenerEvent};
 use tracing::{error, instrument};
 
-#[repr(u16)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum Status {
-    Down = 0,
-    Degraded = 1,
-    Up = 2,
-}
-
-impl From<u16> for Status {
-    fn from(value: u16) -> Self {
-        match value {
-            0 => Status::Down,
-            1 => Status::Degraded,
-            2 => Status::Up,
-            _ => panic!("Invalid value for Status"),
-        }
-    }
-}
-
-impl From<Status> for u16 {
-    fn from(status: Status) -> Self {
-        status as u16
-    }
-}
+// Topics expiring within this time show a warning
+const EXPIRY_WARNING: std::time::Duration = std::time::Duration::from_secs(60 * 60);
 
 mod imp {
     use super::*;
@@ -49,11 +26,41 @@ mod imp {
         #[property(get)]
         pub server: RefCell<String>,
         #[property(get = Self::get_status, type = u8)]
-        pub status: Rc<Cell<Status>>,
+        pub status: Cell<models::Status>,
         #[property(get)]
         pub muted: Cell<bool>,
+        // Notifications are held back until this unix time, 0 if not snoozed
+        #[property(get)]
+        pub snoozed_until: Cell<u64>,
+        #[property(get)]
+        pub notify_regex: RefCell<String>,
+        pub notification_persistence: Cell<models::NotificationPersistence>,
+        // Path of the notification sound, empty for the default one
+        #[property(get)]
+        pub sound: RefCell<String>,
+        // Attached images are downloaded on receipt
+        #[property(get)]
+        pub auto_download_attachments: Cell<bool>,
+        // Messages are shown in a monospace font
+        #[property(get)]
+        pub code_messages: Cell<bool>,
+        // Priority of the messages published from the composer
+        #[property(get)]
+        pub publish_priority: Cell<i8>,
         #[property(get)]
         pub unread_count: Cell<u32>,
+        // The server asked for credentials the last time it was contacted
+        #[property(get)]
+        pub needs_login: Cell<bool>,
+        // When the server stops caching the topic, 0 if it doesn't expire
+        #[property(get)]
+        pub expires: Cell<u64>,
+        // Icon name chosen by the user, takes precedence over `sender_icon`
+        #[property(get)]
+        pub symbolic_icon: RefCell<String>,
+        // Url of the icon attached to the most recent message having one
+        #[property(get)]
+        pub sender_icon: RefCell<String>,
         pub read_until: Cell<u64>,
         pub messages: gio::ListStore,
         pub client: OnceCell<ntfy_daemon::SubscriptionHandle>,
@@ -61,8 +68,7 @@ mod imp {
 
     impl Subscription {
         fn get_status(&self) -> u8 {
-            let s: u16 = Cell::get(&self.status).into();
-            s as u8
+            self.status.get().into()
         }
     }
 
@@ -73,11 +79,22 @@ mod imp {
                 topic: Default::default(),
                 url: Default::default(),
                 muted: Default::default(),
+                snoozed_until: Default::default(),
+                notify_regex: Default::default(),
+                notification_persistence: Default::default(),
+                sound: Default::default(),
+                auto_download_attachments: Default::default(),
+                code_messages: Default::default(),
+                publish_priority: Cell::new(models::DEFAULT_PRIORITY),
                 server: Default::default(),
-                status: Rc::new(Cell::new(Status::Down)),
+                status: Default::default(),
                 messages: gio::ListStore::new::<glib::BoxedAnyObject>(),
                 client: Default::default(),
                 unread_count: Default::default(),
+                needs_login: Default::default(),
+                expires: Default::default(),
+                symbolic_icon: Default::default(),
+                sender_icon: Default::default(),
                 read_until: Default::default(),
             }
         }
@@ -117,24 +134,36 @@ impl Subscription {
         this
     }
 
-    fn init_info(
-        &self,
-        topic: &str,
-        server: &str,
-        muted: bool,
-        read_until: u64,
-        display_name: &str,
-    ) {
+    fn init_info(&self, model: &models::Subscription) {
         let imp = self.imp();
-        imp.topic.replace(topic.to_string());
+        imp.topic.replace(model.topic.clone());
         self.notify_topic();
-        imp.server.replace(server.to_string());
+        imp.server.replace(model.server.clone());
         self.notify_server();
-        imp.muted.replace(muted);
+        imp.muted.replace(model.muted);
         self.notify_muted();
-        imp.read_until.replace(read_until);
+        imp.snoozed_until.set(model.snoozed_until);
+        self.notify_snoozed_until();
+        imp.notify_regex
+            .replace(model.notify_regex.clone().unwrap_or_default());
+        self.notify_notify_regex();
+        imp.notification_persistence
+            .set(model.notification_persistence);
+        imp.sound.replace(model.sound.clone().unwrap_or_default());
+        self.notify_sound();
+        imp.auto_download_attachments
+            .set(model.auto_download_attachments);
+        self.notify_auto_download_attachments();
+        imp.code_messages.set(model.code_messages);
+        self.notify_code_messages();
+        imp.publish_priority.set(model.publish_priority);
+        self.notify_publish_priority();
+        imp.symbolic_icon
+            .replace(model.symbolic_icon.clone().unwrap_or_default());
+        self.notify_symbolic_icon();
+        imp.read_until.replace(model.read_until);
         self.notify_unread_count();
-        self._set_display_name(display_name.to_string());
+        self._set_display_name(model.display_name.clone());
     }
 
     fn load(&self) -> impl Future<Output = anyhow::Result<()>> {
@@ -143,19 +172,18 @@ impl Subscription {
             let remote_subscription = this.imp().client.get().unwrap();
             let model = remote_subscription.model().await;
 
-            this.init_info(
-                &model.topic,
-                &model.server,
-                model.muted,
-                model.read_until,
-                &model.display_name,
-            );
+            this.init_info(&model);
 
             let (prev_msgs, mut rx) = remote_subscription.attach().await;
 
             for msg in prev_msgs {
-                this.handle_event(msg);
+                if let ListenerEvent::Message(msg) = msg {
+                    this.append_message(msg);
+                } else {
+                    this.handle_event(msg);
+                }
             }
+            this.update_unread_count();
 
             while let Ok(ev) = rx.recv().await {
                 this.handle_event(ev);
@@ -167,23 +195,34 @@ impl Subscription {
     fn handle_event(&self, ev: ListenerEvent) {
         match ev {
             ListenerEvent::Message(msg) => {
-                self.imp().messages.append(&glib::BoxedAnyObject::new(msg));
+                self.append_message(msg);
                 self.update_unread_count();
             }
             ListenerEvent::ConnectionStateChanged(connection_state) => {
                 self.set_connection_state(connection_state);
             }
+            ListenerEvent::KeepAlive { .. } => {}
+            ListenerEvent::Opened { expires } => {
+                self.imp().expires.set(expires.unwrap_or(0));
+                self.notify_expires();
+            }
         }
     }
 
+    fn append_message(&self, msg: models::ReceivedMessage) {
+        if let Some(icon) = msg.icon.as_ref().filter(|icon| !icon.is_empty()) {
+            if *self.imp().sender_icon.borrow() != *icon {
+                self.imp().sender_icon.replace(icon.clone());
+                self.notify_sender_icon();
+            }
+        }
+        self.imp().messages.append(&glib::BoxedAnyObject::new(msg));
+    }
+
     fn set_connection_state(&self, state: ConnectionState) {
-        let status = match state {
-            ConnectionState::Unitialized => Status::Degraded,
-            ConnectionState::Connected => Status::Up,
-            ConnectionState::Reconnecting { .. } => Status::Degraded,
-        };
-        self.imp().status.set(status);
-        dbg!(status);
+        self.imp().needs_login.set(state.needs_login());
+        self.notify_needs_login();
+        self.imp().status.set(state.status());
         self.notify_status();
     }
 
@@ -209,17 +248,22 @@ impl Subscription {
 
     async fn send_updated_info(&self) -> anyhow::Result<()> {
         let imp = self.imp();
-        imp.client
-            .get()
-            .unwrap()
-            .update_info(
-                models::Subscription::builder(self.topic())
-                    .display_name((imp.display_name.borrow().to_string()))
-                    .muted(imp.muted.get())
-                    .build()
-                    .map_err(|e| anyhow::anyhow!("invalid subscription data {:?}", e))?,
-            )
-            .await?;
+        // The borrows must end before awaiting
+        let display_name = imp.display_name.borrow().to_string();
+        let notify_regex = imp.notify_regex.borrow().to_string();
+        let sound = imp.sound.borrow().to_string();
+        let model = models::Subscription::builder(self.topic())
+            .display_name(display_name)
+            .muted(imp.muted.get())
+            .notify_regex(Some(notify_regex))
+            .notification_persistence(imp.notification_persistence.get())
+            .sound(Some(sound))
+            .auto_download_attachments(imp.auto_download_attachments.get())
+            .code_messages(imp.code_messages.get())
+            .publish_priority(imp.publish_priority.get())
+            .build()
+            .map_err(|e| anyhow::anyhow!("invalid subscription data {:?}", e))?;
+        imp.client.get().unwrap().update_info(model).await?;
         Ok(())
     }
     fn last_message(list: &gio::ListStore) -> Option<models::ReceivedMessage> {
@@ -231,13 +275,16 @@ impl Subscription {
         Some(last.clone())
     }
     fn update_unread_count(&self) {
-        let imp = self.imp();
-        if Self::last_message(&imp.messages).map(|last| last.time) > Some(imp.read_until.get()) {
-            imp.unread_count.set(1);
-        } else {
-            imp.unread_count.set(0);
-        }
-        self.notify_unread_count();
+        let this = self.clone();
+        glib::spawn_future_local(async move {
+            match this.imp().client.get().unwrap().unread_count().await {
+                Ok(count) => {
+                    this.imp().unread_count.set(count as u32);
+                    this.notify_unread_count();
+                }
+                Err(e) => error!(error = %e, "counting unread messages"),
+            }
+        });
     }
 
     pub fn set_muted(&self, value: bool) -> impl Future<Output = anyhow::Result<()>> {
@@ -249,24 +296,118 @@ impl Subscription {
             Ok(())
         }
     }
+    // A zero duration ends the snooze
+    pub fn snooze(
+        &self,
+        duration: std::time::Duration,
+    ) -> impl Future<Output = anyhow::Result<()>> {
+        let this = self.clone();
+        async move {
+            let client = this.imp().client.get().unwrap();
+            client.snooze(duration).await?;
+            this.imp()
+                .snoozed_until
+                .set(client.model().await.snoozed_until);
+            this.notify_snoozed_until();
+            Ok(())
+        }
+    }
+    // An invalid pattern is rejected and the previous one is kept
+    pub fn set_notify_regex(&self, value: String) -> impl Future<Output = anyhow::Result<()>> {
+        let this = self.clone();
+        async move {
+            let old = this.imp().notify_regex.replace(value);
+            if let Err(e) = this.send_updated_info().await {
+                this.imp().notify_regex.replace(old);
+                return Err(e);
+            }
+            this.notify_notify_regex();
+            Ok(())
+        }
+    }
+    // True if the server stops caching the topic within `EXPIRY_WARNING`
+    pub fn expires_soon(&self) -> bool {
+        let expires = self.imp().expires.get();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        expires != 0 && expires.saturating_sub(now) < EXPIRY_WARNING.as_secs()
+    }
+    pub fn notification_persistence(&self) -> models::NotificationPersistence {
+        self.imp().notification_persistence.get()
+    }
+    pub fn set_notification_persistence(
+        &self,
+        value: models::NotificationPersistence,
+    ) -> impl Future<Output = anyhow::Result<()>> {
+        let this = self.clone();
+        async move {
+            this.imp().notification_persistence.set(value);
+            this.send_updated_info().await?;
+            Ok(())
+        }
+    }
+    // An empty path goes back to the default sound
+    pub fn set_sound(&self, value: String) -> impl Future<Output = anyhow::Result<()>> {
+        let this = self.clone();
+        async move {
+            this.imp().sound.replace(value);
+            this.notify_sound();
+            this.send_updated_info().await?;
+            Ok(())
+        }
+    }
+    pub fn set_auto_download_attachments(
+        &self,
+        value: bool,
+    ) -> impl Future<Output = anyhow::Result<()>> {
+        let this = self.clone();
+        async move {
+            this.imp().auto_download_attachments.set(value);
+            this.notify_auto_download_attachments();
+            this.send_updated_info().await?;
+            Ok(())
+        }
+    }
+    pub fn set_publish_priority(&self, value: i8) -> impl Future<Output = anyhow::Result<()>> {
+        let this = self.clone();
+        async move {
+            this.imp().publish_priority.set(value);
+            this.notify_publish_priority();
+            this.send_updated_info().await?;
+            Ok(())
+        }
+    }
+    pub fn set_code_messages(&self, value: bool) -> impl Future<Output = anyhow::Result<()>> {
+        let this = self.clone();
+        async move {
+            this.imp().code_messages.set(value);
+            this.notify_code_messages();
+            this.send_updated_info().await?;
+            Ok(())
+        }
+    }
     pub async fn flag_all_as_read(&self) -> anyhow::Result<()> {
-        let imp = self.imp();
-        let Some(value) = Self::last_message(&imp.messages)
-            .map(|last| last.time)
-            .filter(|time| *time > self.imp().read_until.get())
-        else {
+        let Some(last) = Self::last_message(&self.imp().messages) else {
             return Ok(());
         };
+        self.flag_as_read_until(last.time).await
+    }
+    // Messages up to `time` become read, the ones already read stay read
+    pub async fn flag_as_read_until(&self, time: u64) -> anyhow::Result<()> {
+        if time <= self.imp().read_until.get() {
+            return Ok(());
+        }
 
-        let this = self.clone();
-        this.imp()
+        self.imp()
             .client
             .get()
             .unwrap()
-            .update_read_until(value)
+            .update_read_until(time)
             .await?;
-        this.imp().read_until.set(value);
-        this.update_unread_count();
+        self.imp().read_until.set(time);
+        self.update_unread_count();
 
         Ok(())
     }
@@ -279,16 +420,64 @@ impl Subscription {
         imp.client.get().unwrap().publish(json).await?;
         Ok(())
     }
+    pub async fn restart(&self) -> anyhow::Result<()> {
+        self.imp().client.get().unwrap().restart().await
+    }
+    pub fn listener_stats(&self) -> ntfy_daemon::ListenerStats {
+        self.imp().client.get().unwrap().listener_stats()
+    }
+    // Downloads the icon, or returns it from the disk cache
+    pub async fn fetch_icon(&self, url: &str) -> anyhow::Result<std::path::PathBuf> {
+        self.imp().client.get().unwrap().fetch_icon(url).await
+    }
+    pub async fn get_message(&self, id: &str) -> anyhow::Result<Option<models::ReceivedMessage>> {
+        self.imp().client.get().unwrap().get_message(id).await
+    }
+    // Index of the message in the loaded list
+    pub fn message_position(&self, id: &str) -> Option<u32> {
+        self.imp()
+            .messages
+            .iter::<glib::BoxedAnyObject>()
+            .flatten()
+            .position(|obj| obj.borrow::<models::ReceivedMessage>().id == id)
+            .map(|i| i as u32)
+    }
+    pub async fn export_messages(
+        &self,
+        format: ntfy_daemon::export::ExportFormat,
+    ) -> anyhow::Result<String> {
+        self.imp()
+            .client
+            .get()
+            .unwrap()
+            .export_messages(format)
+            .await
+    }
+    #[instrument(skip_all)]
+    pub async fn resync(&self) -> anyhow::Result<()> {
+        let imp = self.imp();
+        imp.client.get().unwrap().resync().await?;
+        imp.messages.remove_all();
+        imp.read_until.set(0);
+        self.update_unread_count();
+
+        Ok(())
+    }
     #[instrument(skip_all)]
     pub async fn clear_notifications(&self) -> anyhow::Result<()> {
         let imp = self.imp();
         imp.client.get().unwrap().clear_notifications().await?;
-        self.imp().messages.remove_all();
+        self.forget_messages();
 
         Ok(())
     }
+    // Empties the list after the stored messages were deleted
+    pub fn forget_messages(&self) {
+        self.imp().messages.remove_all();
+        self.update_unread_count();
+    }
 
-    pub fn nice_status(&self) -> Status {
-        Status::try_from(self.imp().status.get() as u16).unwrap()
+    pub fn nice_status(&self) -> models::Status {
+        self.imp().status.get()
     }
 }
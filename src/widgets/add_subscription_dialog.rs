@@ -11,9 +11,12 @@ use once_cell::sync::Lazy;
 
 #[derive(Default, Debug, Clone)]
 pub struct Widgets {
+    pub preset_row: adw::ComboRow,
     pub topic_entry: adw::EntryRow,
     pub server_entry: adw::EntryRow,
     pub server_expander: adw::ExpanderRow,
+    pub topic_warning: gtk::Label,
+    pub auth_icon: gtk::Image,
     pub sub_btn: gtk::Button,
 }
 mod imp {
@@ -22,6 +25,8 @@ mod imp {
     pub struct AddSubscriptionDialog {
         pub widgets: RefCell<Widgets>,
         pub init_custom_server: OnceCell<String>,
+        pub accounts: RefCell<Vec<models::Account>>,
+        pub presets: RefCell<Vec<models::SubscriptionPreset>>,
     }
 
     #[glib::object_subclass]
@@ -91,6 +96,10 @@ impl AddSubscriptionDialog {
                     },
                     append = &gtk::ListBox {
                         add_css_class: "boxed-list",
+                        append: preset_row = &adw::ComboRow {
+                            set_title: "Preset",
+                            set_visible: false,
+                        },
                         append: topic_entry = &adw::EntryRow {
                             set_title: "Topic",
                             set_activates_default: true,
@@ -100,11 +109,7 @@ impl AddSubscriptionDialog {
                                 set_valign: gtk::Align::Center,
                                 add_css_class: "flat",
                                 connect_clicked[topic_entry] => move |_| {
-                                    use rand::distributions::Alphanumeric;
-                                    use rand::{thread_rng, Rng};
-                                    let mut rng = thread_rng();
-                                    let chars: String = (0..10).map(|_| rng.sample(Alphanumeric) as char).collect();
-                                    topic_entry.set_text(&chars);
+                                    topic_entry.set_text(&random_topic_name());
                                 }
                             }
                         },
@@ -116,9 +121,20 @@ impl AddSubscriptionDialog {
                             add_row: server_entry = &adw::EntryRow {
                                 set_title: "Server",
                                 set_text: imp.init_custom_server.get().map(|x| x.as_str()).unwrap_or(""),
+                                add_suffix: auth_icon = &gtk::Image {
+                                    set_icon_name: Some("dialog-password-symbolic"),
+                                    set_visible: false,
+                                }
                             }
                         }
                     },
+                    append: topic_warning = &gtk::Label {
+                        add_css_class: "warning",
+                        set_visible: false,
+                        set_wrap: true,
+                        set_xalign: 0.0,
+                        set_wrap_mode: gtk::pango::WrapMode::WordChar
+                    },
                     append: sub_btn = &gtk::Button {
                         set_label: "Subscribe",
                         add_css_class: "suggested-action",
@@ -155,20 +171,92 @@ impl AddSubscriptionDialog {
             .connect_changed(move |_| f.clone()());
         let f = debounced_error_check.clone();
         server_expander.connect_enable_expansion_notify(move |_| f.clone()());
+        let objc = obj.clone();
+        preset_row.connect_selected_notify(move |row| objc.apply_preset(row.selected()));
 
         imp.widgets.replace(Widgets {
+            preset_row,
             topic_entry,
             server_expander,
             server_entry,
+            topic_warning,
+            auth_icon,
             sub_btn,
         });
 
         obj.set_content_width(480);
         obj.set_child(Some(&toolbar_view));
     }
+    // Prefilled from a link, waiting for the user to confirm
+    pub fn set_topic(&self, topic: &str) {
+        let w = { self.imp().widgets.borrow().clone() };
+        w.topic_entry.set_text(topic);
+        self.check_errors();
+    }
+    // Offers the servers of the configured accounts as suggestions
+    pub fn set_accounts(&self, accounts: Vec<models::Account>) {
+        let w = { self.imp().widgets.borrow().clone() };
+        for a in accounts.iter() {
+            let row = adw::ActionRow::builder()
+                .title(&a.server)
+                .subtitle(format!("Account: {}", a.username))
+                .activatable(true)
+                .build();
+            let server_entry = w.server_entry.clone();
+            let server = a.server.clone();
+            row.connect_activated(move |_| server_entry.set_text(&server));
+            w.server_expander.add_row(&row);
+        }
+        self.imp().accounts.replace(accounts);
+        self.check_errors();
+    }
+    // The first choice of the row is "None", the presets follow
+    pub fn set_presets(&self, presets: Vec<models::SubscriptionPreset>) {
+        let w = { self.imp().widgets.borrow().clone() };
+        let names: Vec<&str> = std::iter::once("None")
+            .chain(presets.iter().map(|p| p.name.as_str()))
+            .collect();
+        w.preset_row.set_model(Some(&gtk::StringList::new(&names)));
+        w.preset_row.set_visible(!presets.is_empty());
+        self.imp().presets.replace(presets);
+    }
+    fn selected_preset(&self) -> Option<models::SubscriptionPreset> {
+        let w = { self.imp().widgets.borrow().clone() };
+        let i = w.preset_row.selected().checked_sub(1)?;
+        self.imp().presets.borrow().get(i as usize).cloned()
+    }
+    fn apply_preset(&self, selected: u32) {
+        if selected == gtk::INVALID_LIST_POSITION {
+            return;
+        }
+        let w = { self.imp().widgets.borrow().clone() };
+        let Some(preset) = self.selected_preset() else {
+            return;
+        };
+        w.topic_entry.set_text(&preset.topic(&random_topic_name()));
+        let custom_server = preset.server != models::DEFAULT_SERVER;
+        w.server_expander.set_enable_expansion(custom_server);
+        w.server_entry
+            .set_text(if custom_server { &preset.server } else { "" });
+        self.check_errors();
+    }
+    fn account_for(&self, server: &str) -> Option<models::Account> {
+        let server = server.trim_end_matches('/');
+        self.imp()
+            .accounts
+            .borrow()
+            .iter()
+            .find(|a| a.server.trim_end_matches('/') == server)
+            .cloned()
+    }
     pub fn subscription(&self) -> Result<models::Subscription, ntfy_daemon::Error> {
         let w = { self.imp().widgets.borrow().clone() };
         let mut sub = models::Subscription::builder(w.topic_entry.text().to_string());
+        if let Some(preset) = self.selected_preset() {
+            sub = sub.preset(&preset);
+        }
+        // The form wins over the preset, it may have been edited
+        sub = sub.server(models::DEFAULT_SERVER.to_string());
         if w.server_expander.enables_expansion() {
             sub = sub.server(w.server_entry.text().to_string());
         }
@@ -181,8 +269,32 @@ impl AddSubscriptionDialog {
 
         w.server_entry.remove_css_class("error");
         w.topic_entry.remove_css_class("error");
+        w.topic_entry.remove_css_class("warning");
+        w.topic_warning.set_visible(false);
         w.sub_btn.set_sensitive(true);
 
+        let server = if w.server_expander.enables_expansion() {
+            w.server_entry.text().to_string()
+        } else {
+            models::DEFAULT_SERVER.to_string()
+        };
+        match self.account_for(&server) {
+            Some(account) => {
+                w.auth_icon.set_visible(true);
+                w.auth_icon
+                    .set_tooltip_text(Some(&format!("Authenticated as {}", account.username)));
+            }
+            None => w.auth_icon.set_visible(false),
+        }
+
+        if let Err(e @ ntfy_daemon::Error::ReservedTopic(_)) =
+            models::validate_topic_safe(&w.topic_entry.text())
+        {
+            w.topic_entry.add_css_class("warning");
+            w.topic_warning.set_label(&e.to_string());
+            w.topic_warning.set_visible(true);
+        }
+
         if let Err(ntfy_daemon::Error::InvalidSubscription(errs)) = sub {
             w.sub_btn.set_sensitive(false);
             for e in errs {
@@ -202,3 +314,10 @@ impl AddSubscriptionDialog {
         self.emit_by_name::<()>("subscribe-request", &[]);
     }
 }
+
+fn random_topic_name() -> String {
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+    let mut rng = thread_rng();
+    (0..10).map(|_| rng.sample(Alphanumeric) as char).collect()
+}
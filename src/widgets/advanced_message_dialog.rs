@@ -5,7 +5,7 @@ use adw::subclass::prelude::*;
 use gsv::prelude::*;
 use gtk::{gio, glib};
 
-use crate::error::*;
+use crate::config::APP_ID;
 use crate::subscription::Subscription;
 
 mod imp {
@@ -14,6 +14,8 @@ mod imp {
     #[derive(Debug, Default)]
     pub struct AdvancedMessageDialog {
         pub subscription: OnceCell<Subscription>,
+        // Kept alive to receive change notifications
+        pub settings: OnceCell<gio::Settings>,
     }
 
     #[glib::object_subclass]
@@ -124,6 +126,30 @@ impl AdvancedMessageDialog {
                                         text_view.buffer().insert_at_cursor(r#""priority": 5"#)
                                     }
                                 },
+                                append = &gtk::Button {
+                                    add_css_class: "pill",
+                                    add_css_class: "small",
+                                    set_label: "Delay",
+                                    connect_clicked[text_view] => move |_| {
+                                        text_view.buffer().insert_at_cursor(r#""delay": "30m""#)
+                                    }
+                                },
+                                append = &gtk::Button {
+                                    add_css_class: "pill",
+                                    add_css_class: "small",
+                                    set_label: "No Cache",
+                                    connect_clicked[text_view] => move |_| {
+                                        text_view.buffer().insert_at_cursor(r#""cache": false"#)
+                                    }
+                                },
+                                append = &gtk::Button {
+                                    add_css_class: "pill",
+                                    add_css_class: "small",
+                                    set_label: "No Firebase",
+                                    connect_clicked[text_view] => move |_| {
+                                        text_view.buffer().insert_at_cursor(r#""firebase": false"#)
+                                    }
+                                },
                                 append = &gtk::Button {
                                     add_css_class: "pill",
                                     add_css_class: "small",
@@ -176,6 +202,7 @@ impl AdvancedMessageDialog {
                                 connect_clicked[this, toast_overlay, text_view] => move |_| {
                                     let thisc = this.clone();
                                     let text_view = text_view.clone();
+                                    let toast_overlay = toast_overlay.clone();
                                     let f = async move {
                                         let buffer = text_view.buffer();
                                         let msg = serde_json::from_str(&buffer.text(
@@ -186,7 +213,11 @@ impl AdvancedMessageDialog {
                                         thisc.imp().subscription.get().unwrap()
                                             .publish_msg(msg).await
                                     };
-                                    toast_overlay.error_boundary().spawn(f);
+                                    glib::spawn_future_local(async move {
+                                        if let Err(e) = f.await {
+                                            Self::show_publish_error(&toast_overlay, e);
+                                        }
+                                    });
                                 }
                             }
                         }
@@ -205,14 +236,49 @@ impl AdvancedMessageDialog {
         ));
         text_view.set_buffer(Some(&buffer));
 
+        let settings = gio::Settings::new(APP_ID);
+        Self::update_style_scheme(&buffer, &settings);
         let manager = adw::StyleManager::default();
-        let scheme_name = if manager.is_dark() {
-            "solarized-dark"
-        } else {
-            "solarized-light"
-        };
-        let scheme = gsv::StyleSchemeManager::default().scheme(scheme_name);
-        buffer.set_style_scheme(scheme.as_ref());
+        let (b, s) = (buffer.clone(), settings.clone());
+        let dark_handler = std::cell::Cell::new(Some(
+            manager.connect_dark_notify(move |_| Self::update_style_scheme(&b, &s)),
+        ));
+        let b = buffer.clone();
+        settings.connect_changed(Some("editor-color-scheme"), move |settings, _| {
+            Self::update_style_scheme(&b, settings)
+        });
+        this.connect_closed(move |_| {
+            if let Some(id) = dark_handler.take() {
+                manager.disconnect(id);
+            }
+        });
+        this.imp().settings.set(settings).unwrap();
         this.set_child(Some(&content));
     }
+    // The JSON error of the server explains what's wrong with the message, it can be copied
+    fn show_publish_error(toast_overlay: &adw::ToastOverlay, e: anyhow::Error) {
+        tracing::error!(error = ?e, "publishing the advanced message");
+        let toast = adw::Toast::new(&e.to_string());
+        if let Some(ntfy_daemon::Error::PublishRejected { body, .. }) = e.downcast_ref() {
+            toast.set_button_label(Some("Copy Details"));
+            let (overlay, body) = (toast_overlay.clone(), body.clone());
+            toast.connect_button_clicked(move |_| overlay.clipboard().set_text(&body));
+        }
+        toast_overlay.add_toast(toast);
+    }
+    // The scheme chosen in the preferences, also used for the messages shown as code
+    pub fn update_style_scheme(buffer: &gsv::Buffer, settings: &gio::Settings) {
+        let schemes = gsv::StyleSchemeManager::default();
+        let chosen = settings.string("editor-color-scheme");
+        // Fall back to solarized if the chosen scheme isn't installed anymore
+        let scheme = schemes.scheme(&chosen).or_else(|| {
+            let name = if adw::StyleManager::default().is_dark() {
+                "solarized-dark"
+            } else {
+                "solarized-light"
+            };
+            schemes.scheme(name)
+        });
+        buffer.set_style_scheme(scheme.as_ref());
+    }
 }
@@ -2,18 +2,51 @@ use std::io::Read;
 
 use adw::prelude::*;
 use adw::subclass::prelude::*;
-use chrono::NaiveDateTime;
+use gsv::prelude::*;
 use gtk::{gdk, gio, glib};
 use ntfy_daemon::models;
 use tracing::error;
 
+use crate::application::NotifyApplication;
+use crate::config::APP_ID;
 use crate::error::*;
+use crate::widgets::AdvancedMessageDialog;
+
+// Parts of the row that can be hidden in the preferences
+#[derive(Debug, Clone, Copy)]
+pub struct RowFields {
+    pub time: bool,
+    pub priority: bool,
+    pub tags: bool,
+    pub actions: bool,
+}
+
+impl RowFields {
+    pub fn from_settings(settings: &gio::Settings) -> Self {
+        Self {
+            time: settings.boolean("show-message-time"),
+            priority: settings.boolean("show-message-priority"),
+            tags: settings.boolean("show-message-tags"),
+            actions: settings.boolean("show-message-actions"),
+        }
+    }
+}
 
 mod imp {
     use super::*;
 
     #[derive(Debug, Default)]
-    pub struct MessageRow {}
+    pub struct MessageRow {
+        pub time: std::cell::Cell<u64>,
+        pub time_label: std::cell::OnceCell<gtk::Label>,
+        // Filled by `update_tags` with the tags chosen in the settings
+        pub tags_box: std::cell::OnceCell<gtk::FlowBox>,
+        pub id: std::cell::OnceCell<String>,
+        // For `models::listed_tags` and `models::group_key`
+        pub tags: std::cell::OnceCell<Vec<String>>,
+        // Lowercase title, message and tags, matched by the message filter
+        pub search_text: std::cell::RefCell<String>,
+    }
 
     #[glib::object_subclass]
     impl ObjectSubclass for MessageRow {
@@ -34,12 +67,88 @@ glib::wrapper! {
 }
 
 impl MessageRow {
-    pub fn new(msg: models::ReceivedMessage) -> Self {
+    // `code_messages` shows the body as code, see `ReceivedMessage::body_style`
+    pub fn new(msg: models::ReceivedMessage, code_messages: bool, fields: RowFields) -> Self {
         let this: Self = glib::Object::new();
-        this.build_ui(msg);
+        this.imp().time.set(msg.time);
+        let search_text = [msg.title.as_deref(), msg.message.as_deref()]
+            .into_iter()
+            .flatten()
+            .chain(msg.tags.iter().map(|t| t.as_str()))
+            .collect::<Vec<_>>()
+            .join("\n")
+            .to_lowercase();
+        this.imp().search_text.replace(search_text);
+        this.imp().id.set(msg.id.clone()).unwrap();
+        this.imp().tags.set(msg.tags.clone()).unwrap();
+        this.build_ui(msg, code_messages, fields);
         this
     }
-    fn build_ui(&self, msg: models::ReceivedMessage) {
+    pub fn time(&self) -> u64 {
+        self.imp().time.get()
+    }
+    pub fn id(&self) -> &str {
+        self.imp().id.get().map_or("", |id| id.as_str())
+    }
+    pub fn group_key(&self, prefix: &str) -> Option<&str> {
+        models::group_key(self.imp().tags.get()?, prefix)
+    }
+    // `query` must be lowercase
+    pub fn matches(&self, query: &str) -> bool {
+        self.imp().search_text.borrow().contains(query)
+    }
+    // In the date and time format of the user's locale. Dates glib can't represent
+    // fall back to a fixed format.
+    fn absolute_time(time: u64) -> String {
+        i64::try_from(time)
+            .ok()
+            .and_then(|time| glib::DateTime::from_unix_local(time).ok())
+            .and_then(|time| time.format("%x %X").ok())
+            .map(|time| time.to_string())
+            .unwrap_or_else(|| models::absolute_time(time, &chrono::Local))
+    }
+    // Relative times ("2h ago") keep the absolute one as tooltip. They must be refreshed
+    // periodically to stay correct.
+    pub fn update_time(&self, relative: bool) {
+        let Some(label) = self.imp().time_label.get() else {
+            return;
+        };
+        let absolute = Self::absolute_time(self.time());
+        if relative && absolute != models::INVALID_TIME {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            label.set_label(&models::relative_time(self.time(), now));
+            label.set_tooltip_text(Some(&absolute));
+        } else {
+            label.set_label(&absolute);
+            label.set_tooltip_text(None);
+        }
+    }
+    // Tags known as emoji are shown as their glyph, the others as text.
+    // `emoji` and `other` choose which of them are listed.
+    pub fn update_tags(&self, emoji: bool, other: bool) {
+        let (Some(b), Some(tags)) = (self.imp().tags_box.get(), self.imp().tags.get()) else {
+            return;
+        };
+        b.remove_all();
+        let tags = models::listed_tags(tags, emoji, other);
+        for tag in &tags {
+            let chip = match models::emoji_for_tag(tag) {
+                Some(emoji) => gtk::Label::builder()
+                    .label(emoji)
+                    .tooltip_text(*tag)
+                    .build(),
+                None => gtk::Label::new(Some(tag)),
+            };
+            chip.add_css_class("caption");
+            chip.add_css_class("chip");
+            b.append(&chip);
+        }
+        b.set_visible(!tags.is_empty());
+    }
+    fn build_ui(&self, msg: models::ReceivedMessage, code_messages: bool, fields: RowFields) {
         self.set_margin_top(8);
         self.set_margin_bottom(8);
         self.set_margin_start(8);
@@ -48,18 +157,44 @@ impl MessageRow {
         self.set_row_spacing(8);
         let mut row = 0;
 
-        let time = gtk::Label::builder()
-            .label(
-                &NaiveDateTime::from_timestamp_opt(msg.time as i64, 0)
-                    .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
-                    .unwrap_or_default(),
-            )
-            .xalign(0.0)
-            .build();
-        time.add_css_class("caption");
-        self.attach(&time, 0, row, 1, 1);
+        if msg.requires_ack() {
+            let banner = gtk::Label::builder()
+                .label(format!(
+                    "Urgent: phone call escalation to {}",
+                    msg.call.as_deref().unwrap_or_default()
+                ))
+                .wrap(true)
+                .wrap_mode(gtk::pango::WrapMode::WordChar)
+                .xalign(0.0)
+                .build();
+            banner.add_css_class("urgent-banner");
+            self.attach(&banner, 0, row, 3, 1);
+            row += 1;
+        }
+
+        let time_box = gtk::Box::builder().spacing(8).build();
+        if fields.time {
+            let time = gtk::Label::builder()
+                .label(&Self::absolute_time(msg.time))
+                .xalign(0.0)
+                .build();
+            time.add_css_class("caption");
+            self.imp().time_label.set(time.clone()).unwrap();
+            time_box.append(&time);
+        }
+        if msg.is_scheduled() {
+            let scheduled = gtk::Label::builder()
+                .label("Scheduled")
+                .tooltip_text("This message will be delivered at the shown time")
+                .build();
+            scheduled.add_css_class("caption");
+            scheduled.add_css_class("chip");
+            scheduled.add_css_class("chip--info");
+            time_box.append(&scheduled);
+        }
+        self.attach(&time_box, 0, row, 1, 1);
 
-        if let Some(p) = msg.priority {
+        if let Some(p) = msg.priority.filter(|_| fields.priority) {
             let text = format!(
                 "Priority: {}",
                 match p {
@@ -80,8 +215,9 @@ impl MessageRow {
                 priority.add_css_class("chip--warning")
             }
             priority.set_halign(gtk::Align::End);
-            self.attach(&priority, 1, 0, 2, 1);
+            self.attach(&priority, 1, row, 1, 1);
         }
+        self.attach(&self.build_menu_btn(&msg), 2, row, 1, 1);
         row += 1;
 
         if let Some(title) = msg.display_title() {
@@ -97,9 +233,40 @@ impl MessageRow {
             row += 1;
         }
 
-        if let Some(message) = msg.display_message() {
+        if let models::BodyStyle::Code { language } = msg.body_style(code_messages) {
+            let message = msg.message.clone().unwrap_or_default();
+            let (shown, truncated) = models::truncate_text(&message, models::MAX_DISPLAYED_BODY);
+            let buffer = self.build_code_buffer(language.as_deref());
+            buffer.set_text(shown);
+            let view = gsv::View::builder()
+                .buffer(&buffer)
+                .editable(false)
+                .cursor_visible(false)
+                .monospace(true)
+                .wrap_mode(gtk::WrapMode::WordChar)
+                .hexpand(true)
+                .top_margin(8)
+                .bottom_margin(8)
+                .left_margin(8)
+                .right_margin(8)
+                .build();
+            view.add_css_class("card");
+            self.attach(&view, 0, row, 3, 1);
+            row += 1;
+
+            if truncated {
+                let btns = self.build_truncated_btns(message, move |text| buffer.set_text(text));
+                self.attach(&btns, 0, row, 3, 1);
+                row += 1;
+            }
+        } else if let Some(message) = msg.display_message() {
+            let (shown, truncated) = models::truncate_text(&message, models::MAX_DISPLAYED_BODY);
             let label = gtk::Label::builder()
-                .label(&message)
+                .label(if truncated {
+                    format!("{shown}…")
+                } else {
+                    message.clone()
+                })
                 .wrap_mode(gtk::pango::WrapMode::WordChar)
                 .xalign(0.0)
                 .wrap(true)
@@ -108,16 +275,26 @@ impl MessageRow {
                 .build();
             self.attach(&label, 0, row, 3, 1);
             row += 1;
+
+            if truncated {
+                let labelc = label.clone();
+                let btns = self.build_truncated_btns(message, move |text| labelc.set_label(text));
+                self.attach(&btns, 0, row, 3, 1);
+                row += 1;
+            }
         }
 
+        let actions = msg.templated_actions();
         if let Some(attachment) = msg.attachment {
-            if attachment.is_image() {
+            if attachment.is_displayable() {
                 self.attach(&self.build_image(attachment.url.to_string()), 0, row, 3, 1);
-                row += 1;
+            } else {
+                self.attach(&self.build_attachment_link(&attachment), 0, row, 3, 1);
             }
+            row += 1;
         }
 
-        if msg.actions.len() > 0 {
+        if actions.len() > 0 && fields.actions {
             let action_btns = gtk::FlowBox::builder()
                 .row_spacing(8)
                 .column_spacing(8)
@@ -125,7 +302,7 @@ impl MessageRow {
                 .selection_mode(gtk::SelectionMode::None)
                 .build();
 
-            for a in msg.actions {
+            for a in actions {
                 let btn = self.build_action_btn(a);
                 action_btns.append(&btn);
             }
@@ -133,20 +310,82 @@ impl MessageRow {
             self.attach(&action_btns, 0, row, 3, 1);
             row += 1;
         }
-        if msg.tags.len() > 0 {
-            let mut tags_text = String::from("tags: ");
-            tags_text.push_str(&msg.tags.join(", "));
-            let tags = gtk::Label::builder()
-                .label(&tags_text)
-                .xalign(0.0)
-                .wrap(true)
-                .wrap_mode(gtk::pango::WrapMode::WordChar)
-                .build();
-            self.attach(&tags, 0, row, 3, 1);
+        if msg.tags.len() > 0 && fields.tags {
+            let tags_box = self.build_tags();
+            self.attach(&tags_box, 0, row, 3, 1);
+            self.imp().tags_box.set(tags_box).unwrap();
+            self.update_tags(true, true);
         }
     }
+    fn build_menu_btn(&self, msg: &models::ReceivedMessage) -> gtk::MenuButton {
+        let menu = gio::Menu::new();
+        let item = gio::MenuItem::new(Some("Forward to…"), None);
+        item.set_action_and_target_value(
+            Some("win.forward-message"),
+            Some(&serde_json::to_string(msg).unwrap().to_variant()),
+        );
+        menu.append_item(&item);
+
+        let btn = gtk::MenuButton::builder()
+            .icon_name("view-more-symbolic")
+            .tooltip_text("Message Actions")
+            .menu_model(&menu)
+            .halign(gtk::Align::End)
+            .valign(gtk::Align::Center)
+            .build();
+        btn.add_css_class("flat");
+        btn
+    }
+    fn build_tags(&self) -> gtk::FlowBox {
+        gtk::FlowBox::builder()
+            .row_spacing(4)
+            .column_spacing(4)
+            .max_children_per_line(20)
+            .halign(gtk::Align::Start)
+            .selection_mode(gtk::SelectionMode::None)
+            .build()
+    }
+    // Highlighted with the scheme of the message editor, if the language is known
+    fn build_code_buffer(&self, language: Option<&str>) -> gsv::Buffer {
+        let buffer = gsv::Buffer::new(None);
+        let language = language.and_then(|l| gsv::LanguageManager::default().language(l));
+        buffer.set_language(language.as_ref());
+        buffer.set_highlight_syntax(language.is_some());
+        AdvancedMessageDialog::update_style_scheme(&buffer, &gio::Settings::new(APP_ID));
+        buffer
+    }
+    // Huge bodies (like log dumps) would freeze the window if shown all at once.
+    // `show_full` replaces the shown part with the whole message.
+    fn build_truncated_btns(
+        &self,
+        message: String,
+        show_full: impl Fn(&str) + 'static,
+    ) -> gtk::Box {
+        let b = gtk::Box::builder().spacing(8).build();
+
+        let show_more = gtk::Button::with_label("Show More");
+        show_more.add_css_class("pill");
+        show_more.add_css_class("small");
+        let copy = gtk::Button::with_label("Copy Full Message");
+        copy.add_css_class("pill");
+        copy.add_css_class("small");
+
+        let messagec = message.clone();
+        show_more.connect_clicked(move |btn| {
+            show_full(&messagec);
+            btn.set_visible(false);
+        });
+        copy.connect_clicked(move |btn| {
+            btn.clipboard().set_text(&message);
+        });
+
+        b.append(&show_more);
+        b.append(&copy);
+        b
+    }
     fn fetch_image_bytes(url: &str) -> anyhow::Result<Vec<u8>> {
-        let path = glib::user_cache_dir().join("com.ranfdev.Notify").join(&url);
+        // Subscriptions downloading attachments on receipt already stored it
+        let path = NotifyApplication::attachment_cache().path_for(url);
         let bytes = if path.exists() {
             std::fs::read(&path)?
         } else {
@@ -154,7 +393,7 @@ impl MessageRow {
             ureq::get(&url)
                 .call()?
                 .into_reader()
-                .take(5 * 1_000_000) // 5 MB
+                .take(models::MAX_DISPLAYABLE_SIZE as u64)
                 .read_to_end(&mut bytes)?;
             bytes
         };
@@ -185,6 +424,20 @@ impl MessageRow {
 
         picture
     }
+    fn build_attachment_link(&self, attachment: &models::Attachment) -> gtk::Box {
+        let icon_name = match attachment.kind() {
+            models::AttachmentKind::Image => "image-x-generic-symbolic",
+            models::AttachmentKind::Audio => "audio-x-generic-symbolic",
+            models::AttachmentKind::Video => "video-x-generic-symbolic",
+            models::AttachmentKind::Other => "text-x-generic-symbolic",
+        };
+        let b = gtk::Box::builder().spacing(4).build();
+        b.append(&gtk::Image::from_icon_name(icon_name));
+        let link = gtk::LinkButton::with_label(attachment.url.as_str(), &attachment.name);
+        link.set_halign(gtk::Align::Start);
+        b.append(&link);
+        b
+    }
     fn build_action_btn(&self, action: models::Action) -> gtk::Button {
         let btn = gtk::Button::new();
         match &action {
@@ -199,8 +452,12 @@ impl MessageRow {
             } => {
                 btn.set_label(&label);
                 btn.set_tooltip_text(Some(&format!("Send HTTP {method} to {url}")));
-                btn.set_action_name(Some("app.message-action"));
-                btn.set_action_target_value(Some(&serde_json::to_string(&action).unwrap().into()));
+                let action = action.clone();
+                btn.connect_clicked(move |btn| {
+                    btn.error_boundary().spawn_with_near_toast(
+                        crate::application::send_http_action(action.clone()),
+                    );
+                });
             }
             models::Action::Broadcast { label, .. } => {
                 btn.set_label(&label);
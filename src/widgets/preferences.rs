@@ -3,7 +3,11 @@ use std::cell::OnceCell;
 use adw::prelude::*;
 use adw::subclass::prelude::*;
 use gtk::{gio, glib};
+use tracing::warn;
 
+use ntfy_daemon::models;
+
+use crate::config::APP_ID;
 use crate::error::*;
 
 mod imp {
@@ -25,7 +29,60 @@ mod imp {
         #[template_child]
         pub added_accounts: TemplateChild<gtk::ListBox>,
         #[template_child]
+        pub new_account_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
         pub added_accounts_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
+        pub server_access_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
+        pub server_access: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub notify_connection_lost_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub marks_read_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub replay_unread_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub preview_length_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub digest_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub digest_time_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub dnd_allow_urgent_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub dnd_renotify_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub strip_ansi_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub relative_times_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub emoji_tags_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub other_tags_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub show_time_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub show_priority_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub show_tags_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub show_actions_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub group_prefix_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub default_server_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub share_connections_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub max_connections_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub roundtrip_btn: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub compact_btn: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub editor_scheme_row: TemplateChild<adw::ComboRow>,
+        pub settings: gio::Settings,
         pub notifier: OnceCell<NtfyHandle>,
     }
 
@@ -37,7 +94,34 @@ mod imp {
                 password_entry: Default::default(),
                 add_btn: Default::default(),
                 added_accounts: Default::default(),
+                new_account_group: Default::default(),
                 added_accounts_group: Default::default(),
+                server_access_group: Default::default(),
+                server_access: Default::default(),
+                editor_scheme_row: Default::default(),
+                roundtrip_btn: Default::default(),
+                compact_btn: Default::default(),
+                notify_connection_lost_row: Default::default(),
+                marks_read_row: Default::default(),
+                replay_unread_row: Default::default(),
+                preview_length_row: Default::default(),
+                digest_row: Default::default(),
+                digest_time_row: Default::default(),
+                dnd_allow_urgent_row: Default::default(),
+                dnd_renotify_row: Default::default(),
+                strip_ansi_row: Default::default(),
+                relative_times_row: Default::default(),
+                emoji_tags_row: Default::default(),
+                other_tags_row: Default::default(),
+                show_time_row: Default::default(),
+                show_priority_row: Default::default(),
+                show_tags_row: Default::default(),
+                show_actions_row: Default::default(),
+                group_prefix_row: Default::default(),
+                default_server_row: Default::default(),
+                share_connections_row: Default::default(),
+                max_connections_row: Default::default(),
+                settings: gio::Settings::new(APP_ID),
                 notifier: Default::default(),
             };
 
@@ -96,17 +180,273 @@ impl NotifyPreferences {
             .added_accounts
             .error_boundary()
             .spawn(async move { this.show_accounts().await });
+        obj.bind_editor_scheme();
+        obj.bind_default_server();
+        obj.bind_digest_time();
+        obj.bind_group_prefix();
+        obj.imp()
+            .settings
+            .bind(
+                "notify-connection-lost",
+                &*obj.imp().notify_connection_lost_row,
+                "active",
+            )
+            .build();
+        obj.imp()
+            .settings
+            .bind(
+                "notification-marks-read",
+                &*obj.imp().marks_read_row,
+                "active",
+            )
+            .build();
+        obj.imp()
+            .settings
+            .bind(
+                "replay-unread-notifications",
+                &*obj.imp().replay_unread_row,
+                "active",
+            )
+            .build();
+        obj.imp()
+            .settings
+            .bind(
+                "notification-preview-length",
+                &*obj.imp().preview_length_row,
+                "value",
+            )
+            .build();
+        obj.imp()
+            .settings
+            .bind(
+                "dnd-allow-urgent",
+                &*obj.imp().dnd_allow_urgent_row,
+                "active",
+            )
+            .build();
+        obj.imp()
+            .settings
+            .bind("daily-digest", &*obj.imp().digest_row, "active")
+            .build();
+        obj.imp()
+            .settings
+            .bind("dnd-renotify", &*obj.imp().dnd_renotify_row, "active")
+            .build();
+        obj.imp()
+            .settings
+            .bind("strip-ansi-escapes", &*obj.imp().strip_ansi_row, "active")
+            .build();
+        obj.imp()
+            .settings
+            .bind(
+                "relative-timestamps",
+                &*obj.imp().relative_times_row,
+                "active",
+            )
+            .build();
+        obj.imp()
+            .settings
+            .bind("list-emoji-tags", &*obj.imp().emoji_tags_row, "active")
+            .build();
+        obj.imp()
+            .settings
+            .bind("list-other-tags", &*obj.imp().other_tags_row, "active")
+            .build();
+        obj.imp()
+            .settings
+            .bind("show-message-time", &*obj.imp().show_time_row, "active")
+            .build();
+        obj.imp()
+            .settings
+            .bind(
+                "show-message-priority",
+                &*obj.imp().show_priority_row,
+                "active",
+            )
+            .build();
+        obj.imp()
+            .settings
+            .bind("show-message-tags", &*obj.imp().show_tags_row, "active")
+            .build();
+        obj.imp()
+            .settings
+            .bind(
+                "show-message-actions",
+                &*obj.imp().show_actions_row,
+                "active",
+            )
+            .build();
+        obj.imp()
+            .settings
+            .bind(
+                "share-server-connections",
+                &*obj.imp().share_connections_row,
+                "active",
+            )
+            .build();
+        obj.imp()
+            .settings
+            .bind(
+                "max-concurrent-connections",
+                &*obj.imp().max_connections_row,
+                "value",
+            )
+            .build();
+        let this = obj.clone();
+        obj.imp().roundtrip_btn.connect_clicked(move |btn| {
+            let this = this.clone();
+            btn.error_boundary()
+                .spawn(async move { this.roundtrip_test().await });
+        });
+        let this = obj.clone();
+        obj.imp().compact_btn.connect_clicked(move |btn| {
+            let this = this.clone();
+            btn.error_boundary()
+                .spawn(async move { this.compact_database().await });
+        });
         obj
     }
 
+    pub async fn compact_database(&self) -> anyhow::Result<()> {
+        let imp = self.imp();
+        imp.compact_btn.set_sensitive(false);
+        let res = imp.notifier.get().unwrap().compact_database().await;
+        imp.compact_btn.set_sensitive(true);
+        let reclaimed = res?;
+        self.add_toast(adw::Toast::new(&format!(
+            "Reclaimed {}",
+            glib::format_size(reclaimed)
+        )));
+        Ok(())
+    }
+
+    pub async fn roundtrip_test(&self) -> anyhow::Result<()> {
+        let imp = self.imp();
+        let server = std::env::var("NOTIFY_DEFAULT_SERVER")
+            .unwrap_or_else(|_| imp.settings.string("default-server").to_string());
+        imp.roundtrip_btn.set_sensitive(false);
+        let res = imp.notifier.get().unwrap().roundtrip_test(&server).await;
+        imp.roundtrip_btn.set_sensitive(true);
+        let latency = res?;
+        self.add_toast(adw::Toast::new(&format!(
+            "Delivered by {server} in {} ms",
+            latency.as_millis()
+        )));
+        Ok(())
+    }
+
+    // Only valid urls are saved
+    fn bind_default_server(&self) {
+        let imp = self.imp();
+        imp.default_server_row
+            .set_text(&imp.settings.string("default-server"));
+        let this = self.clone();
+        imp.default_server_row.connect_apply(move |row| {
+            let server = row.text().trim().trim_end_matches('/').to_string();
+            row.remove_css_class("error");
+            if let Err(e) = models::validate_server(&server) {
+                row.add_css_class("error");
+                this.add_toast(adw::Toast::new(&e.to_string()));
+                return;
+            }
+            if let Err(e) = this.imp().settings.set_string("default-server", &server) {
+                warn!(error = %e, "Failed to save the default server");
+                return;
+            }
+            row.set_text(&server);
+        });
+    }
+
+    // Only valid times are saved
+    fn bind_digest_time(&self) {
+        let imp = self.imp();
+        imp.digest_time_row
+            .set_text(&imp.settings.string("daily-digest-time"));
+        let this = self.clone();
+        imp.digest_time_row.connect_apply(move |row| {
+            let text = row.text();
+            row.remove_css_class("error");
+            let time = match chrono::NaiveTime::parse_from_str(text.trim(), "%H:%M") {
+                Ok(time) => time.format("%H:%M").to_string(),
+                Err(_) => {
+                    row.add_css_class("error");
+                    this.add_toast(adw::Toast::new("The time must be like 20:00"));
+                    return;
+                }
+            };
+            if let Err(e) = this.imp().settings.set_string("daily-digest-time", &time) {
+                warn!(error = %e, "Failed to save the daily digest time");
+                return;
+            }
+            row.set_text(&time);
+        });
+    }
+
+    fn bind_group_prefix(&self) {
+        let imp = self.imp();
+        imp.group_prefix_row
+            .set_text(&imp.settings.string("message-group-tag-prefix"));
+        let this = self.clone();
+        imp.group_prefix_row.connect_apply(move |row| {
+            let prefix = row.text().trim().to_string();
+            if let Err(e) = this
+                .imp()
+                .settings
+                .set_string("message-group-tag-prefix", &prefix)
+            {
+                warn!(error = %e, "Failed to save the message group prefix");
+                return;
+            }
+            row.set_text(&prefix);
+        });
+    }
+
+    fn bind_editor_scheme(&self) {
+        let imp = self.imp();
+        let manager = gsv::StyleSchemeManager::default();
+
+        // The empty id means "follow the system style"
+        let mut ids = vec![String::new()];
+        ids.extend(manager.scheme_ids().iter().map(|id| id.to_string()));
+        let names: Vec<String> = ids
+            .iter()
+            .map(|id| match manager.scheme(id) {
+                Some(scheme) => scheme.name().to_string(),
+                None => "Automatic".to_string(),
+            })
+            .collect();
+        let names: Vec<&str> = names.iter().map(|x| x.as_str()).collect();
+        imp.editor_scheme_row
+            .set_model(Some(&gtk::StringList::new(&names)));
+
+        let current = imp.settings.string("editor-color-scheme");
+        let selected = ids.iter().position(|id| *id == current).unwrap_or(0);
+        imp.editor_scheme_row.set_selected(selected as u32);
+
+        let settings = imp.settings.clone();
+        imp.editor_scheme_row.connect_selected_notify(move |row| {
+            let Some(id) = ids.get(row.selected() as usize) else {
+                return;
+            };
+            if let Err(e) = settings.set_string("editor-color-scheme", id) {
+                warn!(error = %e, "Failed to save the editor color scheme");
+            }
+        });
+    }
+
     pub async fn show_accounts(&self) -> anyhow::Result<()> {
         let imp = self.imp();
+        if !imp.notifier.get().unwrap().credentials_available().await? {
+            imp.new_account_group.set_sensitive(false);
+            imp.new_account_group
+                .set_description(Some(ntfy_daemon::credentials::UNAVAILABLE_MESSAGE));
+        }
         let accounts = imp.notifier.get().unwrap().list_accounts().await?;
 
         imp.added_accounts_group.set_visible(!accounts.is_empty());
 
         imp.added_accounts.remove_all();
-        for a in accounts {
+        for a in accounts.iter().cloned() {
             let row = adw::ActionRow::builder()
                 .title(&a.server)
                 .subtitle(&a.username)
@@ -128,19 +468,114 @@ impl NotifyPreferences {
             });
             imp.added_accounts.append(&row);
         }
+        self.show_server_access(&accounts).await;
         Ok(())
     }
+
+    // Servers without reservations or tokens have nothing to show,
+    // the group stays hidden if none of them has.
+    async fn show_server_access(&self, accounts: &[ntfy_daemon::models::Account]) {
+        let imp = self.imp();
+        let notifier = imp.notifier.get().unwrap();
+        imp.server_access.remove_all();
+        let mut shown = false;
+        for a in accounts {
+            let res = async {
+                anyhow::Ok((
+                    notifier.list_reservations(&a.server).await?,
+                    notifier.list_tokens(&a.server).await?,
+                ))
+            };
+            let (reservations, tokens) = match res.await {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!(server = %a.server, error = %e, "can't list the reservations and tokens");
+                    continue;
+                }
+            };
+            for r in reservations {
+                let row = adw::ActionRow::builder()
+                    .title(&r.topic)
+                    .subtitle(format!(
+                        "Reserved on {}, everyone: {}",
+                        a.server, r.everyone
+                    ))
+                    .build();
+                let this = self.clone();
+                let server = a.server.clone();
+                row.add_suffix(&self.revoke_button(move || {
+                    let this = this.clone();
+                    let server = server.clone();
+                    let topic = r.topic.clone();
+                    async move {
+                        let notifier = this.imp().notifier.get().unwrap();
+                        notifier.delete_reservation(&server, &topic).await?;
+                        this.show_accounts().await
+                    }
+                }));
+                imp.server_access.append(&row);
+                shown = true;
+            }
+            for t in tokens {
+                let title = match &t.label {
+                    Some(label) if !label.is_empty() => label.clone(),
+                    _ => format!("{}…", models::truncate_chars(&t.token, 8).0),
+                };
+                let row = adw::ActionRow::builder()
+                    .title(&title)
+                    .subtitle(format!("Access token for {}", a.server))
+                    .build();
+                let this = self.clone();
+                let server = a.server.clone();
+                row.add_suffix(&self.revoke_button(move || {
+                    let this = this.clone();
+                    let server = server.clone();
+                    let token = t.token.clone();
+                    async move {
+                        let notifier = this.imp().notifier.get().unwrap();
+                        notifier.delete_token(&server, &token).await?;
+                        this.show_accounts().await
+                    }
+                }));
+                imp.server_access.append(&row);
+                shown = true;
+            }
+        }
+        imp.server_access_group.set_visible(shown);
+    }
+
+    fn revoke_button<F, Fut>(&self, revoke: F) -> gtk::Button
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: std::future::Future<Output = anyhow::Result<()>> + 'static,
+    {
+        let btn = gtk::Button::builder()
+            .icon_name("user-trash-symbolic")
+            .valign(gtk::Align::Center)
+            .tooltip_text("Revoke")
+            .build();
+        btn.add_css_class("flat");
+        btn.connect_clicked(move |btn| {
+            btn.error_boundary().spawn(revoke());
+        });
+        btn
+    }
     pub async fn add_account(&self) -> anyhow::Result<()> {
         let imp = self.imp();
         let password = imp.password_entry.text();
         let server = imp.server_entry.text();
         let username = imp.username_entry.text();
 
-        imp.notifier
+        // The server is contacted to check the credentials
+        imp.add_btn.set_sensitive(false);
+        let res = imp
+            .notifier
             .get()
             .unwrap()
             .add_account(&server, &username, &password)
-            .await?;
+            .await;
+        imp.add_btn.set_sensitive(true);
+        res?;
         self.show_accounts().await?;
 
         Ok(())
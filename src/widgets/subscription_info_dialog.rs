@@ -5,6 +5,7 @@ use adw::subclass::prelude::*;
 use glib::Properties;
 use gtk::gio;
 use gtk::glib;
+use ntfy_daemon::models;
 
 use crate::error::*;
 
@@ -20,6 +21,28 @@ mod imp {
         pub display_name_entry: TemplateChild<adw::EntryRow>,
         #[template_child]
         pub muted_switch_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub notify_regex_entry: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub persistence_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub sound_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub sound_choose_btn: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub sound_reset_btn: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub auto_download_switch_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub code_messages_switch_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub reconnects_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub uptime_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub disconnect_reason_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub received_row: TemplateChild<adw::ActionRow>,
     }
 
     #[glib::object_subclass]
@@ -47,6 +70,19 @@ mod imp {
                 .set_text(&this.subscription().unwrap().display_name());
             self.muted_switch_row
                 .set_active(this.subscription().unwrap().muted());
+            self.notify_regex_entry
+                .set_text(&this.subscription().unwrap().notify_regex());
+            self.auto_download_switch_row
+                .set_active(this.subscription().unwrap().auto_download_attachments());
+            self.code_messages_switch_row
+                .set_active(this.subscription().unwrap().code_messages());
+            let persistence = this.subscription().unwrap().notification_persistence();
+            if let Some(i) = models::NotificationPersistence::ALL
+                .iter()
+                .position(|p| *p == persistence)
+            {
+                self.persistence_row.set_selected(i as u32);
+            }
 
             let debouncer = crate::async_utils::Debouncer::new();
             self.display_name_entry.connect_changed({
@@ -64,6 +100,45 @@ mod imp {
                     this.update_muted(switch);
                 }
             });
+            let this = self.obj().clone();
+            self.auto_download_switch_row.connect_active_notify({
+                move |switch| {
+                    this.update_auto_download(switch);
+                }
+            });
+            let this = self.obj().clone();
+            self.code_messages_switch_row.connect_active_notify({
+                move |switch| {
+                    this.update_code_messages(switch);
+                }
+            });
+            let this = self.obj().clone();
+            self.persistence_row.connect_selected_notify(move |row| {
+                this.update_persistence(row);
+            });
+            let this = self.obj().clone();
+            self.notify_regex_entry.connect_apply(move |entry| {
+                this.update_notify_regex(entry);
+            });
+            let this = self.obj().clone();
+            self.sound_choose_btn.connect_clicked(move |_| {
+                this.choose_sound();
+            });
+            let this = self.obj().clone();
+            self.sound_reset_btn.connect_clicked(move |_| {
+                this.update_sound(String::new());
+            });
+
+            let this = self.obj().clone();
+            let sub = this.subscription().unwrap();
+            // Weak, the subscription outlives the dialog
+            sub.connect_sound_notify(glib::clone!(
+                #[weak]
+                this,
+                move |sub| this.show_sound(&sub.sound())
+            ));
+            self.obj().show_sound(&sub.sound());
+            self.obj().show_stats(&sub.listener_stats());
         }
     }
     impl WidgetImpl for SubscriptionInfoDialog {}
@@ -92,6 +167,86 @@ impl SubscriptionInfoDialog {
             });
         }
     }
+    fn update_notify_regex(&self, entry: &adw::EntryRow) {
+        if let Some(sub) = self.subscription() {
+            let entry = entry.clone();
+            self.error_boundary()
+                .spawn(async move { sub.set_notify_regex(entry.text().to_string()).await });
+        }
+    }
+    fn update_persistence(&self, row: &adw::ComboRow) {
+        let Some(persistence) = models::NotificationPersistence::ALL
+            .get(row.selected() as usize)
+            .copied()
+        else {
+            return;
+        };
+        if let Some(sub) = self.subscription() {
+            self.error_boundary()
+                .spawn(async move { sub.set_notification_persistence(persistence).await });
+        }
+    }
+    fn show_sound(&self, path: &str) {
+        let imp = self.imp();
+        let name = std::path::Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string());
+        imp.sound_row
+            .set_subtitle(name.as_deref().unwrap_or("Default"));
+        imp.sound_reset_btn.set_visible(name.is_some());
+    }
+    // Counted since Notify started, to tell apart a flaky connection from a stable one
+    fn show_stats(&self, stats: &ntfy_daemon::ListenerStats) {
+        let imp = self.imp();
+        imp.reconnects_row
+            .set_subtitle(&stats.reconnects.to_string());
+        imp.uptime_row.set_subtitle(
+            &stats
+                .average_connection_uptime()
+                .map(|d| format_duration(d.as_secs()))
+                .unwrap_or_else(|| "No connection lost".to_string()),
+        );
+        imp.disconnect_reason_row
+            .set_subtitle(stats.last_disconnect_reason.as_deref().unwrap_or("None"));
+        imp.received_row.set_subtitle(&format!(
+            "{} messages, {}",
+            stats.messages_received,
+            glib::format_size(stats.bytes_received)
+        ));
+    }
+    fn choose_sound(&self) {
+        let filter = gtk::FileFilter::new();
+        filter.set_name(Some("Audio Files"));
+        filter.add_mime_type("audio/*");
+        let filters = gio::ListStore::new::<gtk::FileFilter>();
+        filters.append(&filter);
+        let dialog = gtk::FileDialog::builder()
+            .title("Choose Notification Sound")
+            .filters(&filters)
+            .modal(true)
+            .build();
+
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let root = this.root().and_downcast::<gtk::Window>();
+            let file = match dialog.open_future(root.as_ref()).await {
+                Ok(file) => file,
+                Err(e) if e.matches(gtk::DialogError::Dismissed) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let path = file
+                .path()
+                .ok_or_else(|| anyhow::anyhow!("the sound file must be a local file"))?;
+            this.update_sound(path.to_string_lossy().to_string());
+            Ok(())
+        });
+    }
+    fn update_sound(&self, path: String) {
+        if let Some(sub) = self.subscription() {
+            self.error_boundary()
+                .spawn(async move { sub.set_sound(path).await });
+        }
+    }
     fn update_muted(&self, switch: &adw::SwitchRow) {
         if let Some(sub) = self.subscription() {
             let switch = switch.clone();
@@ -99,4 +254,26 @@ impl SubscriptionInfoDialog {
                 .spawn(async move { sub.set_muted(switch.is_active()).await })
         }
     }
+    fn update_code_messages(&self, switch: &adw::SwitchRow) {
+        if let Some(sub) = self.subscription() {
+            let switch = switch.clone();
+            self.error_boundary()
+                .spawn(async move { sub.set_code_messages(switch.is_active()).await })
+        }
+    }
+    fn update_auto_download(&self, switch: &adw::SwitchRow) {
+        if let Some(sub) = self.subscription() {
+            let switch = switch.clone();
+            self.error_boundary()
+                .spawn(async move { sub.set_auto_download_attachments(switch.is_active()).await })
+        }
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs} s"),
+        60..=3599 => format!("{} min", secs / 60),
+        _ => format!("{} h {} min", secs / 3600, secs % 3600 / 60),
+    }
 }
@@ -1,20 +1,36 @@
 use std::cell::Cell;
 use std::cell::OnceCell;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use adw::prelude::*;
 use adw::subclass::prelude::*;
-use gtk::{gio, glib};
+use chrono::Datelike;
+use gtk::{gdk, gio, glib};
 use ntfy_daemon::models;
-use ntfy_daemon::NtfyHandle;
+use ntfy_daemon::{NtfyHandle, SubscribeOutcome};
 use tracing::warn;
 
 use crate::application::NotifyApplication;
 use crate::config::{APP_ID, PROFILE};
 use crate::error::*;
-use crate::subscription::Status;
 use crate::subscription::Subscription;
 use crate::widgets::*;
 
+// While in focus mode, only high and max priority messages show notifications
+const FOCUS_MODE_MIN_PRIORITY: i8 = 4;
+// How often relative message times ("5m ago") are updated
+const RELATIVE_TIME_REFRESH: Duration = Duration::from_secs(30);
+
+// Messages of the selected subscription sharing a `models::group_key`
+#[derive(Debug, Default)]
+pub struct MessageGroup {
+    // Shown even when the group is collapsed
+    newest: String,
+    len: usize,
+}
+
 mod imp {
     use super::*;
 
@@ -35,6 +51,8 @@ mod imp {
         pub subscription_view: TemplateChild<adw::ToolbarView>,
         #[template_child]
         pub subscription_menu_btn: TemplateChild<gtk::MenuButton>,
+        #[template_child]
+        pub refresh_stack: TemplateChild<gtk::Stack>,
         pub subscription_list_model: gio::ListStore,
         #[template_child]
         pub toast_overlay: TemplateChild<adw::ToastOverlay>,
@@ -45,6 +63,8 @@ mod imp {
         #[template_child]
         pub list_view: TemplateChild<gtk::ScrolledWindow>,
         #[template_child]
+        pub message_stack: TemplateChild<gtk::Stack>,
+        #[template_child]
         pub message_scroll: TemplateChild<gtk::ScrolledWindow>,
         #[template_child]
         pub banner: TemplateChild<adw::Banner>,
@@ -52,10 +72,38 @@ mod imp {
         pub send_btn: TemplateChild<gtk::Button>,
         #[template_child]
         pub code_btn: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub markdown_btn: TemplateChild<gtk::ToggleButton>,
+        #[template_child]
+        pub priority_dropdown: TemplateChild<gtk::DropDown>,
+        #[template_child]
+        pub message_search_btn: TemplateChild<gtk::ToggleButton>,
+        #[template_child]
+        pub message_search_bar: TemplateChild<gtk::SearchBar>,
+        #[template_child]
+        pub message_search_entry: TemplateChild<gtk::SearchEntry>,
+        #[template_child]
+        pub quick_switch_btn: TemplateChild<gtk::MenuButton>,
+        #[template_child]
+        pub quick_switch_entry: TemplateChild<gtk::SearchEntry>,
+        #[template_child]
+        pub quick_switch_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub pause_btn: TemplateChild<gtk::MenuButton>,
+        #[template_child]
+        pub pause_label: TemplateChild<gtk::Label>,
+        // Refreshes the remaining time shown while notifications are paused
+        pub pause_timer: RefCell<Option<glib::SourceId>>,
         pub notifier: OnceCell<NtfyHandle>,
         pub conn: OnceCell<gio::SocketConnection>,
         pub settings: gio::Settings,
-        pub banner_binding: Cell<Option<(Subscription, glib::SignalHandlerId)>>,
+        pub banner_binding: Cell<Option<(Subscription, Vec<glib::SignalHandlerId>)>>,
+        // Shows the "No Messages Yet" page while the selected subscription is empty
+        pub messages_binding: Cell<Option<(gio::ListStore, glib::SignalHandlerId)>>,
+        // Filled by `update_message_groups` with the "message-group-tag-prefix" setting
+        pub group_prefix: RefCell<String>,
+        pub message_groups: RefCell<HashMap<String, MessageGroup>>,
+        pub expanded_groups: RefCell<HashSet<String>>,
     }
 
     impl Default for NotifyWindow {
@@ -67,11 +115,13 @@ mod imp {
                 subscription_view: Default::default(),
                 navigation_split_view: Default::default(),
                 subscription_menu_btn: Default::default(),
+                refresh_stack: Default::default(),
                 subscription_list: Default::default(),
                 toast_overlay: Default::default(),
                 stack: Default::default(),
                 welcome_view: Default::default(),
                 list_view: Default::default(),
+                message_stack: Default::default(),
                 message_scroll: Default::default(),
                 banner: Default::default(),
                 subscription_list_model: gio::ListStore::new::<Subscription>(),
@@ -79,8 +129,23 @@ mod imp {
                 notifier: Default::default(),
                 conn: Default::default(),
                 banner_binding: Default::default(),
+                messages_binding: Default::default(),
                 send_btn: Default::default(),
                 code_btn: Default::default(),
+                markdown_btn: Default::default(),
+                priority_dropdown: Default::default(),
+                message_search_btn: Default::default(),
+                message_search_bar: Default::default(),
+                message_search_entry: Default::default(),
+                quick_switch_btn: Default::default(),
+                quick_switch_entry: Default::default(),
+                quick_switch_list: Default::default(),
+                pause_btn: Default::default(),
+                pause_label: Default::default(),
+                pause_timer: Default::default(),
+                group_prefix: Default::default(),
+                message_groups: Default::default(),
+                expanded_groups: Default::default(),
             };
 
             this
@@ -92,23 +157,7 @@ mod imp {
         #[template_callback]
         fn show_add_topic(&self, _btn: &gtk::Button) {
             let this = self.obj().clone();
-            let dialog =
-                AddSubscriptionDialog::new(this.selected_subscription().map(|x| x.server()));
-            dialog.present(Some(&self.obj().clone()));
-
-            let dc = dialog.clone();
-            dialog.connect_local("subscribe-request", true, move |_| {
-                let sub = match dc.subscription() {
-                    Ok(sub) => sub,
-                    Err(e) => {
-                        warn!(errors = ?e, "trying to add invalid subscription");
-                        return None;
-                    }
-                };
-                this.add_subscription(sub);
-                dc.close();
-                None
-            });
+            this.show_add_subscription_dialog(this.selected_subscription().map(|x| x.server()));
         }
         #[template_callback]
         fn discover_integrations(&self, _btn: &gtk::Button) {
@@ -131,17 +180,82 @@ mod imp {
             klass.bind_template_callbacks();
 
             klass.install_action("win.unsubscribe", None, |this, _, _| {
-                this.unsubscribe();
+                this.confirm_unsubscribe();
             });
             klass.install_action("win.show-subscription-info", None, |this, _, _| {
                 this.show_subscription_info();
             });
+            klass.install_action("win.resync", None, |this, _, _| {
+                this.confirm_resync();
+            });
+            klass.install_action("win.login", None, |this, _, _| {
+                this.show_login_dialog();
+            });
+            klass.install_action("win.refresh-subscription", None, |this, _, _| {
+                this.refresh_subscription();
+            });
+            klass.install_action("win.export-messages", None, |this, _, _| {
+                this.export_messages();
+            });
+            klass.install_action("win.open-in-browser", None, |this, _, _| {
+                this.open_in_browser();
+            });
+            klass.install_action(
+                "win.pause-notifications",
+                Some(glib::VariantTy::STRING),
+                |this, _, param| {
+                    let minutes = param
+                        .and_then(|p| p.get::<String>())
+                        .and_then(|p| p.parse().ok())
+                        .unwrap_or(0);
+                    this.pause_notifications(minutes);
+                },
+            );
+            klass.install_action(
+                "win.snooze-subscription",
+                Some(glib::VariantTy::STRING),
+                |this, _, param| {
+                    let minutes = param
+                        .and_then(|p| p.get::<String>())
+                        .and_then(|p| p.parse().ok())
+                        .unwrap_or(0);
+                    this.snooze_subscription(minutes);
+                },
+            );
+            klass.install_action("win.resume-notifications", None, |this, _, _| {
+                this.resume_notifications();
+            });
+            klass.install_action("win.copy-subscription-link", None, |this, _, _| {
+                this.copy_subscription_link();
+            });
+            klass.install_action("win.quick-switch", None, |this, _, _| {
+                this.show_quick_switcher();
+            });
+            klass.install_action("win.filter-messages", None, |this, _, _| {
+                this.show_message_filter();
+            });
+            klass.install_action(
+                "win.forward-message",
+                Some(glib::VariantTy::STRING),
+                |this, _, param| {
+                    let Some(msg) = param
+                        .and_then(|p| p.get::<String>())
+                        .and_then(|p| serde_json::from_str(&p).ok())
+                    else {
+                        return;
+                    };
+                    this.show_forward_dialog(msg);
+                },
+            );
             klass.install_action("win.clear-notifications", None, |this, _, _| {
                 this.selected_subscription().map(|sub| {
                     this.error_boundary()
                         .spawn(async move { sub.clear_notifications().await });
                 });
             });
+            klass.install_action("win.clear-all-notifications", None, |this, _, _| {
+                this.confirm_clear_all_notifications();
+            });
             //klass.bind_template_instance_callbacks();
         }
 
@@ -203,9 +317,17 @@ impl NotifyWindow {
         obj.bind_message_list();
         obj.connect_entry_and_send_btn();
         obj.connect_code_btn();
+        obj.connect_priority_dropdown();
         obj.connect_items_changed();
         obj.selected_subscription_changed(None);
         obj.bind_flag_read();
+        obj.bind_quick_switcher();
+        obj.bind_relative_times();
+        obj.bind_tag_listing();
+        obj.bind_message_groups();
+        obj.bind_row_fields();
+        obj.setup_focus_mode();
+        obj.action_set_enabled("win.resume-notifications", false);
 
         obj
     }
@@ -219,19 +341,52 @@ impl NotifyWindow {
     }
     fn publish_msg(&self) {
         let entry = self.imp().entry.clone();
+        let markdown = self.imp().markdown_btn.is_active();
         let this = self.clone();
 
         entry.error_boundary().spawn(async move {
-            this.selected_subscription()
-                .unwrap()
-                .publish_msg(models::OutgoingMessage {
-                    message: Some(entry.text().as_str().to_string()),
-                    ..models::OutgoingMessage::default()
-                })
-                .await?;
+            let sub = this.selected_subscription().unwrap();
+            // Left to the server when it's the default
+            let priority = Some(sub.publish_priority()).filter(|p| *p != models::DEFAULT_PRIORITY);
+            sub.publish_msg(models::OutgoingMessage {
+                message: Some(entry.text().as_str().to_string()),
+                markdown,
+                priority,
+                ..models::OutgoingMessage::default()
+            })
+            .await?;
             Ok(())
         });
     }
+    // Higher priorities are highlighted, to not send them by mistake
+    fn show_publish_priority(&self, priority: i8) {
+        let dropdown = &self.imp().priority_dropdown;
+        dropdown.set_selected(priority.clamp(1, 5) as u32 - 1);
+        dropdown.remove_css_class("warning");
+        dropdown.remove_css_class("error");
+        match priority {
+            4 => dropdown.add_css_class("warning"),
+            5 => dropdown.add_css_class("error"),
+            _ => {}
+        }
+    }
+    // The chosen priority is kept for the next messages to the subscription
+    fn connect_priority_dropdown(&self) {
+        let this = self.clone();
+        self.imp()
+            .priority_dropdown
+            .connect_selected_notify(move |dropdown| {
+                let priority = dropdown.selected() as i8 + 1;
+                this.show_publish_priority(priority);
+                let Some(sub) = this.selected_subscription() else {
+                    return;
+                };
+                if sub.publish_priority() != priority {
+                    this.error_boundary()
+                        .spawn(async move { sub.set_publish_priority(priority).await });
+                }
+            });
+    }
     fn connect_code_btn(&self) {
         let imp = self.imp();
         let this = self.clone();
@@ -247,6 +402,492 @@ impl NotifyWindow {
         let sub = SubscriptionInfoDialog::new(self.selected_subscription().unwrap());
         sub.present(Some(self));
     }
+    fn confirm_resync(&self) {
+        let Some(sub) = self.selected_subscription() else {
+            return;
+        };
+        let dialog = adw::AlertDialog::new(
+            Some("Resync From Beginning?"),
+            Some("All the messages stored for this topic will be deleted and downloaded again from the server. Messages the server doesn't retain anymore will be lost."),
+        );
+        dialog.add_response("cancel", "Cancel");
+        dialog.add_response("resync", "Resync");
+        dialog.set_response_appearance("resync", adw::ResponseAppearance::Destructive);
+        dialog.set_default_response(Some("cancel"));
+        dialog.set_close_response("cancel");
+
+        let this = self.clone();
+        dialog.connect_response(Some("resync"), move |_, _| {
+            let sub = sub.clone();
+            this.error_boundary()
+                .spawn(async move { sub.resync().await });
+        });
+        dialog.present(Some(self));
+    }
+    fn confirm_clear_all_notifications(&self) {
+        let dialog = adw::AlertDialog::new(
+            Some("Clear All Notifications?"),
+            Some("The messages of every topic will be deleted. Topics unsubscribed keeping their messages aren't affected."),
+        );
+        dialog.add_response("cancel", "Cancel");
+        dialog.add_response("clear", "Clear All");
+        dialog.set_response_appearance("clear", adw::ResponseAppearance::Destructive);
+        dialog.set_default_response(Some("cancel"));
+        dialog.set_close_response("cancel");
+
+        let this = self.clone();
+        dialog.connect_response(Some("clear"), move |_, _| {
+            let this = this.clone();
+            this.clone().error_boundary().spawn(async move {
+                let cleared = this.notifier().clear_all_notifications().await?;
+                for sub in this
+                    .imp()
+                    .subscription_list_model
+                    .iter::<Subscription>()
+                    .flatten()
+                {
+                    sub.forget_messages();
+                }
+                if let Some(app) = this.application().and_downcast::<NotifyApplication>() {
+                    app.withdraw_all_notifications();
+                }
+                this.imp()
+                    .toast_overlay
+                    .add_toast(adw::Toast::new(&format!("Cleared {cleared} messages")));
+                Ok(())
+            });
+        });
+        dialog.present(Some(self));
+    }
+    // Publishes the message again to the chosen subscription, which may be on another server
+    fn show_forward_dialog(&self, msg: models::ReceivedMessage) {
+        let dialog = adw::AlertDialog::new(
+            Some("Forward Message"),
+            Some("Choose the topic to publish this message to"),
+        );
+        dialog.add_response("cancel", "Cancel");
+        dialog.set_close_response("cancel");
+
+        let list = gtk::ListBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .build();
+        list.add_css_class("boxed-list");
+        list.bind_model(Some(&self.imp().subscription_list_model), |obj| {
+            let sub = obj.downcast_ref::<Subscription>().unwrap();
+            adw::ActionRow::builder()
+                .activatable(true)
+                .title(sub.display_name())
+                .subtitle(format!("{}/{}", sub.server(), sub.topic()))
+                .build()
+                .upcast()
+        });
+        dialog.set_extra_child(Some(&list));
+
+        let this = self.clone();
+        let dialogc = dialog.clone();
+        list.connect_row_activated(move |_, row| {
+            let Some(sub) = this
+                .imp()
+                .subscription_list_model
+                .item(row.index() as u32)
+                .and_downcast::<Subscription>()
+            else {
+                return;
+            };
+            dialogc.close();
+            let msg = msg.forward();
+            this.error_boundary().spawn_with_near_toast(async move {
+                sub.publish_msg(msg).await?;
+                Ok(Some(format!("Forwarded to {}", sub.display_name())))
+            });
+        });
+        dialog.present(Some(self));
+    }
+    fn setup_focus_mode(&self) {
+        let action = gio::ActionEntry::builder("focus-mode")
+            .state(false.to_variant())
+            .activate(|this: &Self, action, _| {
+                let enabled = !action
+                    .state()
+                    .and_then(|s| s.get::<bool>())
+                    .unwrap_or(false);
+                action.set_state(&enabled.to_variant());
+                let min_priority = if enabled {
+                    FOCUS_MODE_MIN_PRIORITY
+                } else {
+                    models::NotificationSettings::default().min_priority
+                };
+                let notifier = this.notifier().clone();
+                this.error_boundary()
+                    .spawn(async move { notifier.set_min_priority(min_priority).await });
+            })
+            .build();
+        self.add_action_entries([action]);
+    }
+    // Pauses every notification for some minutes, 0 meaning until resumed
+    fn pause_notifications(&self, minutes: u64) {
+        let duration = (minutes > 0).then(|| Duration::from_secs(minutes * 60));
+        let notifier = self.notifier().clone();
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            notifier.pause_notifications(duration).await?;
+            this.show_paused(duration.map(|d| Instant::now() + d));
+            Ok(())
+        });
+    }
+    // Zero minutes ends the snooze
+    fn snooze_subscription(&self, minutes: u64) {
+        let Some(sub) = self.selected_subscription() else {
+            return;
+        };
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            sub.snooze(Duration::from_secs(minutes * 60)).await?;
+            let text = match chrono::DateTime::from_timestamp(sub.snoozed_until() as i64, 0) {
+                Some(until) if minutes > 0 => format!(
+                    "{} snoozed until {}",
+                    sub.display_name(),
+                    until.with_timezone(&chrono::Local).format("%a %H:%M")
+                ),
+                _ => format!("{} is no longer snoozed", sub.display_name()),
+            };
+            this.imp().toast_overlay.add_toast(adw::Toast::new(&text));
+            Ok(())
+        });
+    }
+    fn resume_notifications(&self) {
+        let notifier = self.notifier().clone();
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            notifier.resume_notifications().await?;
+            this.show_resumed();
+            Ok(())
+        });
+    }
+    fn show_paused(&self, resume_at: Option<Instant>) {
+        let imp = self.imp();
+        if let Some(timer) = imp.pause_timer.take() {
+            timer.remove();
+        }
+        imp.pause_btn.add_css_class("accent");
+        self.action_set_enabled("win.resume-notifications", true);
+
+        let Some(resume_at) = resume_at else {
+            imp.pause_label.set_visible(false);
+            imp.pause_btn
+                .set_tooltip_text(Some("Notifications paused until resumed"));
+            return;
+        };
+        // The daemon resumes by itself, this only keeps the remaining time up to date
+        let this = self.clone();
+        let update = move || {
+            let remaining = resume_at.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                this.imp().pause_timer.take();
+                this.show_resumed();
+                return glib::ControlFlow::Break;
+            }
+            let minutes = remaining.as_secs().div_ceil(60);
+            let imp = this.imp();
+            imp.pause_label.set_label(&format!("{minutes} min"));
+            imp.pause_label.set_visible(true);
+            imp.pause_btn.set_tooltip_text(Some(&format!(
+                "Notifications paused for {minutes} more minutes"
+            )));
+            glib::ControlFlow::Continue
+        };
+        if update().is_continue() {
+            imp.pause_timer
+                .replace(Some(glib::timeout_add_seconds_local(15, update)));
+        }
+    }
+    fn show_resumed(&self) {
+        let imp = self.imp();
+        if let Some(timer) = imp.pause_timer.take() {
+            timer.remove();
+        }
+        imp.pause_btn.remove_css_class("accent");
+        imp.pause_label.set_visible(false);
+        imp.pause_btn.set_tooltip_text(Some("Pause Notifications"));
+        self.action_set_enabled("win.resume-notifications", false);
+    }
+    // Asks the credentials for the server of the selected subscription, then reconnects with them
+    fn show_login_dialog(&self) {
+        let Some(sub) = self.selected_subscription() else {
+            return;
+        };
+        let username_entry = adw::EntryRow::builder().title("Username").build();
+        let password_entry = adw::PasswordEntryRow::builder().title("Password").build();
+        let fields = gtk::ListBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .build();
+        fields.add_css_class("boxed-list");
+        fields.append(&username_entry);
+        fields.append(&password_entry);
+
+        let dialog = adw::AlertDialog::new(
+            Some("Log In"),
+            Some(&format!(
+                "{} requires an account to read this topic. The account will be used for every topic of this server.",
+                sub.server()
+            )),
+        );
+        dialog.set_extra_child(Some(&fields));
+        dialog.add_response("cancel", "Cancel");
+        dialog.add_response("login", "Log In");
+        dialog.set_response_appearance("login", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("login"));
+        dialog.set_close_response("cancel");
+
+        let this = self.clone();
+        dialog.connect_response(Some("login"), move |_, _| {
+            let sub = sub.clone();
+            let notifier = this.notifier().clone();
+            let username = username_entry.text();
+            let password = password_entry.text();
+            this.error_boundary().spawn(async move {
+                notifier
+                    .add_account(&sub.server(), &username, &password)
+                    .await?;
+                sub.restart().await
+            });
+        });
+        dialog.present(Some(self));
+    }
+    // Reconnects only the selected subscription. The spinner is shown until the connection
+    // state changes again.
+    fn refresh_subscription(&self) {
+        let Some(sub) = self.selected_subscription() else {
+            return;
+        };
+        self.imp().refresh_stack.set_visible_child_name("spinner");
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let res = sub.restart().await;
+            if res.is_err() {
+                this.imp().refresh_stack.set_visible_child_name("button");
+            }
+            res
+        });
+    }
+    fn export_messages(&self) {
+        use ntfy_daemon::export::ExportFormat;
+
+        let Some(sub) = self.selected_subscription() else {
+            return;
+        };
+        let filters = gio::ListStore::new::<gtk::FileFilter>();
+        for (name, format) in [("JSON", ExportFormat::Json), ("CSV", ExportFormat::Csv)] {
+            let filter = gtk::FileFilter::new();
+            filter.set_name(Some(name));
+            filter.add_suffix(format.extension());
+            filters.append(&filter);
+        }
+        let dialog = gtk::FileDialog::builder()
+            .title("Export Messages")
+            .initial_name(format!("{}.json", sub.topic()))
+            .filters(&filters)
+            .build();
+
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let file = match dialog.save_future(Some(&this)).await {
+                Ok(file) => file,
+                Err(e) if e.matches(gtk::DialogError::Dismissed) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let is_csv = file
+                .path()
+                .and_then(|p| p.extension().map(|ext| ext.eq_ignore_ascii_case("csv")))
+                .unwrap_or(false);
+            let format = if is_csv {
+                ExportFormat::Csv
+            } else {
+                ExportFormat::Json
+            };
+            let data = sub.export_messages(format).await?;
+            file.replace_contents_future(
+                data.into_bytes(),
+                None,
+                false,
+                gio::FileCreateFlags::REPLACE_DESTINATION,
+            )
+            .await
+            .map_err(|(_, e)| e)?;
+            this.imp()
+                .toast_overlay
+                .add_toast(adw::Toast::new("Messages exported"));
+            Ok(())
+        });
+    }
+    fn open_in_browser(&self) {
+        let Some(sub) = self.selected_subscription() else {
+            return;
+        };
+        let url = match models::Subscription::build_web_url(&sub.server(), &sub.topic()) {
+            Ok(url) => url,
+            Err(e) => {
+                warn!(error = %e, "can't build the topic web url");
+                return;
+            }
+        };
+        gtk::UriLauncher::new(url.as_str()).launch(Some(self), gio::Cancellable::NONE, |_| {});
+    }
+    // From the environment or the settings, ntfy.sh if they are invalid
+    fn default_server(&self) -> String {
+        let server = std::env::var("NOTIFY_DEFAULT_SERVER")
+            .unwrap_or_else(|_| self.imp().settings.string("default-server").to_string());
+        match models::validate_server(&server) {
+            Ok(_) => server,
+            Err(e) => {
+                warn!(error = %e, server, "invalid default server");
+                models::DEFAULT_SERVER.to_string()
+            }
+        }
+    }
+    fn show_add_subscription_dialog(&self, server: Option<String>) -> AddSubscriptionDialog {
+        let dialog = AddSubscriptionDialog::new(server.or_else(|| Some(self.default_server())));
+        match models::SubscriptionPreset::parse_list(
+            &self.imp().settings.string("subscription-presets"),
+        ) {
+            Ok(presets) => dialog.set_presets(presets),
+            Err(e) => warn!(error = %e, "invalid subscription presets"),
+        }
+        dialog.present(Some(self));
+
+        let dc = dialog.clone();
+        let notifier = self.notifier().clone();
+        dialog.error_boundary().spawn(async move {
+            dc.set_accounts(notifier.list_accounts().await?);
+            Ok(())
+        });
+
+        let this = self.clone();
+        let dc = dialog.clone();
+        dialog.connect_local("subscribe-request", true, move |_| {
+            let sub = match dc.subscription() {
+                Ok(sub) => sub,
+                Err(e) => {
+                    warn!(errors = ?e, "trying to add invalid subscription");
+                    return None;
+                }
+            };
+            this.add_subscription(sub);
+            dc.close();
+            None
+        });
+        dialog
+    }
+    // Opens a link made by `build_deep_link`: an existing subscription is selected,
+    // otherwise the user confirms subscribing to it
+    pub fn open_subscription_link(&self, link: &str) -> anyhow::Result<()> {
+        let (server, topic) = models::Subscription::parse_deep_link(link)?;
+        let existing = self
+            .imp()
+            .subscription_list_model
+            .iter::<Subscription>()
+            .flatten()
+            .find(|s| s.server() == server && s.topic() == topic);
+        match existing {
+            Some(existing) => self.select_subscription(&existing),
+            None => self
+                .show_add_subscription_dialog(Some(server))
+                .set_topic(&topic),
+        }
+        Ok(())
+    }
+    // Selects the subscription of a clicked notification
+    pub fn show_notified_message(&self, source: &models::NotificationSource, mark_read: bool) {
+        let existing = self
+            .imp()
+            .subscription_list_model
+            .iter::<Subscription>()
+            .flatten()
+            .find(|s| s.server() == source.server && s.topic() == source.topic);
+        if let Some(sub) = &existing {
+            self.select_subscription(sub);
+            if !source.id.is_empty() {
+                self.scroll_to_message(sub.clone(), source.id.clone());
+            }
+        }
+        if !mark_read {
+            return;
+        }
+        let time = source.time;
+        match existing {
+            Some(sub) => self
+                .error_boundary()
+                .spawn(async move { sub.flag_as_read_until(time).await }),
+            // The subscriptions of a new window aren't loaded yet
+            None => {
+                let notifier = self.imp().notifier.get().unwrap().clone();
+                let source = source.clone();
+                self.error_boundary().spawn(async move {
+                    notifier.wait_ready().await?;
+                    for sub in notifier.list_subscriptions().await? {
+                        let model = sub.model().await;
+                        if model.server == source.server
+                            && model.topic == source.topic
+                            && model.read_until < time
+                        {
+                            sub.update_read_until(time).await?;
+                        }
+                    }
+                    anyhow::Ok(())
+                });
+            }
+        }
+    }
+    // Brings the notified message into view
+    fn scroll_to_message(&self, sub: Subscription, id: String) {
+        let this = self.clone();
+        self.error_boundary().spawn(async move {
+            let Some(msg) = sub.get_message(&id).await? else {
+                this.imp()
+                    .toast_overlay
+                    .add_toast(adw::Toast::new("The message isn't stored anymore"));
+                return Ok(());
+            };
+            this.expand_message_group(&msg);
+            // The list of a newly selected subscription is filled asynchronously
+            let mut position = sub.message_position(&id);
+            for _ in 0..10 {
+                if position.is_some() {
+                    break;
+                }
+                glib::timeout_future(Duration::from_millis(100)).await;
+                position = sub.message_position(&id);
+            }
+            let imp = this.imp();
+            let Some(row) = position.and_then(|i| imp.message_list.row_at_index(i as i32)) else {
+                return Ok(());
+            };
+            if let Some(point) =
+                row.compute_point(&*imp.message_list, &gtk::graphene::Point::new(0.0, 0.0))
+            {
+                imp.message_scroll.vadjustment().set_value(point.y() as f64);
+            }
+            row.grab_focus();
+            anyhow::Ok(())
+        });
+    }
+    // The ntfy:// link opens the topic in Notify and in the ntfy apps
+    fn copy_subscription_link(&self) {
+        let Some(sub) = self.selected_subscription() else {
+            return;
+        };
+        let url = match models::Subscription::build_deep_link(&sub.server(), &sub.topic()) {
+            Ok(url) => url,
+            Err(e) => {
+                warn!(error = %e, "can't build the subscription link");
+                return;
+            }
+        };
+        self.clipboard().set_text(url.as_str());
+        self.imp()
+            .toast_overlay
+            .add_toast(adw::Toast::new("Subscription link copied"));
+    }
     fn connect_items_changed(&self) {
         let this = self.clone();
         self.imp()
@@ -264,11 +905,32 @@ impl NotifyWindow {
     fn add_subscription(&self, sub: models::Subscription) {
         let this = self.clone();
         self.error_boundary().spawn(async move {
-            let sub = this.notifier().subscribe(&sub.server, &sub.topic).await?;
+            let outcome = this.notifier().subscribe(&sub.server, &sub.topic).await?;
             let imp = this.imp();
 
+            let handle = match outcome {
+                SubscribeOutcome::Subscribed(handle) => {
+                    // The daemon only knows the topic, the rest comes from a preset
+                    if !sub.display_name.is_empty() || sub.notify_regex.is_some() {
+                        handle.update_info(sub.clone()).await?;
+                    }
+                    handle
+                }
+                SubscribeOutcome::AlreadySubscribed(_) => {
+                    let existing = imp
+                        .subscription_list_model
+                        .iter::<Subscription>()
+                        .flatten()
+                        .find(|s| s.server() == sub.server && s.topic() == sub.topic);
+                    if let Some(existing) = existing {
+                        this.select_subscription(&existing);
+                    }
+                    return Ok(());
+                }
+            };
+
             // Subscription::new will use the pipelined client to retrieve info about the subscription
-            let subscription = Subscription::new(sub);
+            let subscription = Subscription::new(handle);
             // We want to still check if there were any errors adding the subscription.
 
             imp.subscription_list_model.append(&subscription);
@@ -279,13 +941,35 @@ impl NotifyWindow {
         });
     }
 
-    fn unsubscribe(&self) {
-        let sub = self.selected_subscription().unwrap();
+    fn confirm_unsubscribe(&self) {
+        let Some(sub) = self.selected_subscription() else {
+            return;
+        };
+        let dialog = adw::AlertDialog::new(
+            Some("Unsubscribe?"),
+            Some("The stored messages can be kept, to find them again when subscribing to this topic, or deleted for good."),
+        );
+        dialog.add_response("cancel", "Cancel");
+        dialog.add_response("delete", "Delete Messages");
+        dialog.add_response("keep", "Keep Messages");
+        dialog.set_response_appearance("delete", adw::ResponseAppearance::Destructive);
+        dialog.set_response_appearance("keep", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("keep"));
+        dialog.set_close_response("cancel");
 
+        let this = self.clone();
+        dialog.connect_response(None, move |_, response| match response {
+            "keep" => this.unsubscribe(sub.clone(), true),
+            "delete" => this.unsubscribe(sub.clone(), false),
+            _ => {}
+        });
+        dialog.present(Some(self));
+    }
+    fn unsubscribe(&self, sub: Subscription, keep_history: bool) {
         let this = self.clone();
         self.error_boundary().spawn(async move {
             this.notifier()
-                .unsubscribe(sub.server().as_str(), sub.topic().as_str())
+                .unsubscribe(sub.server().as_str(), sub.topic().as_str(), keep_history)
                 .await?;
 
             let imp = this.imp();
@@ -305,14 +989,111 @@ impl NotifyWindow {
             .and_then(|row| imp.subscription_list_model.item(row.index() as u32))
             .and_downcast::<Subscription>()
     }
+    fn select_subscription(&self, sub: &Subscription) {
+        let imp = self.imp();
+        if let Some(i) = imp.subscription_list_model.find(sub) {
+            let row = imp.subscription_list.row_at_index(i as i32);
+            imp.subscription_list.select_row(row.as_ref());
+        }
+    }
+    fn show_message_filter(&self) {
+        let imp = self.imp();
+        imp.navigation_split_view.set_show_content(true);
+        imp.message_search_bar.set_search_mode(true);
+        imp.message_search_entry.grab_focus();
+    }
+    fn show_quick_switcher(&self) {
+        let imp = self.imp();
+        // The switcher lives in the sidebar, which is hidden when the view is collapsed
+        imp.navigation_split_view.set_show_content(false);
+        imp.quick_switch_entry.set_text("");
+        imp.quick_switch_btn.popup();
+        imp.quick_switch_entry.grab_focus();
+    }
+    fn bind_quick_switcher(&self) {
+        let imp = self.imp();
+
+        let entry = imp.quick_switch_entry.clone();
+        let filter = gtk::CustomFilter::new(move |obj| {
+            let sub = obj.downcast_ref::<Subscription>().unwrap();
+            let query = entry.text().to_lowercase();
+            sub.display_name().to_lowercase().contains(&query)
+                || sub.topic().to_lowercase().contains(&query)
+        });
+        let model = gtk::FilterListModel::new(
+            Some(imp.subscription_list_model.clone()),
+            Some(filter.clone()),
+        );
+
+        imp.quick_switch_list.bind_model(Some(&model), |obj| {
+            let sub = obj.downcast_ref::<Subscription>().unwrap();
+            let row = adw::ActionRow::builder()
+                .activatable(true)
+                .subtitle(sub.topic())
+                .build();
+            sub.bind_property("display-name", &row, "title")
+                .sync_create()
+                .build();
+            row.upcast()
+        });
+
+        imp.quick_switch_entry
+            .connect_search_changed(move |_| filter.changed(gtk::FilterChange::Different));
+
+        let this = self.clone();
+        let modelc = model.clone();
+        imp.quick_switch_entry.connect_activate(move |_| {
+            if let Some(sub) = modelc.item(0).and_downcast::<Subscription>() {
+                this.select_subscription(&sub);
+                this.imp().quick_switch_btn.popdown();
+            }
+        });
+        let this = self.clone();
+        imp.quick_switch_list.connect_row_activated(move |_, row| {
+            if let Some(sub) = model
+                .item(row.index() as u32)
+                .and_downcast::<Subscription>()
+            {
+                this.select_subscription(&sub);
+                this.imp().quick_switch_btn.popdown();
+            }
+        });
+    }
     fn bind_message_list(&self) {
         let imp = self.imp();
 
+        let this = self.clone();
+        imp.message_list
+            .set_header_func(move |row, before| this.update_message_header(row, before));
+
+        // Only hides the loaded rows, unlike searching the stored messages
+        let this = self.clone();
+        imp.message_list.set_filter_func(move |row| {
+            let Some(m) = row.child().and_downcast::<MessageRow>() else {
+                return true;
+            };
+            let query = this.imp().message_search_entry.text().to_lowercase();
+            if query.is_empty() {
+                !this.collapsed_in_group(&m)
+            } else {
+                // Searching looks inside the collapsed groups too
+                m.matches(&query)
+            }
+        });
+        let list = imp.message_list.clone();
+        imp.message_search_entry
+            .connect_search_changed(move |_| list.invalidate_filter());
+        imp.message_search_bar
+            .connect_entry(&*imp.message_search_entry);
+
+        let this = self.clone();
         imp.subscription_list
-            .bind_model(Some(&imp.subscription_list_model), |obj| {
+            .bind_model(Some(&imp.subscription_list_model), move |obj| {
                 let sub = obj.downcast_ref::<Subscription>().unwrap();
 
-                Self::build_subscription_row(&sub).upcast()
+                let row = Self::build_subscription_row(sub).upcast();
+                this.bind_row_reorder(&row, sub);
+                row
             });
 
         let this = self.clone();
@@ -322,27 +1103,265 @@ impl NotifyWindow {
 
         let this = self.clone();
         self.error_boundary().spawn(async move {
-            glib::timeout_future_seconds(1).await;
+            this.notifier().wait_ready().await?;
             let list = this.notifier().list_subscriptions().await?;
             for sub in list {
                 this.imp()
                     .subscription_list_model
                     .append(&Subscription::new(sub));
             }
+            if !this.notifier().credentials_available().await? {
+                this.imp().toast_overlay.add_toast(adw::Toast::new(
+                    ntfy_daemon::credentials::UNAVAILABLE_MESSAGE,
+                ));
+            }
             Ok(())
         });
     }
+    // Subscriptions are reordered by dropping them on another one, taking its place
+    fn bind_row_reorder(&self, row: &gtk::Widget, sub: &Subscription) {
+        let source = gtk::DragSource::builder()
+            .actions(gdk::DragAction::MOVE)
+            .content(&gdk::ContentProvider::for_value(&sub.to_value()))
+            .build();
+        row.add_controller(source);
+
+        let target = gtk::DropTarget::new(Subscription::static_type(), gdk::DragAction::MOVE);
+        let this = self.clone();
+        let sub = sub.clone();
+        target.connect_drop(move |_, value, _, _| {
+            let Ok(dragged) = value.get::<Subscription>() else {
+                return false;
+            };
+            this.move_subscription(&dragged, &sub);
+            true
+        });
+        row.add_controller(target);
+    }
+    fn move_subscription(&self, sub: &Subscription, to: &Subscription) {
+        let imp = self.imp();
+        let model = &imp.subscription_list_model;
+        let (Some(from_i), Some(to_i)) = (model.find(sub), model.find(to)) else {
+            return;
+        };
+        if from_i == to_i {
+            return;
+        }
+        let selected = self.selected_subscription();
+        model.remove(from_i);
+        model.insert(to_i, sub);
+        if let Some(selected) = selected {
+            self.select_subscription(&selected);
+        }
+
+        let order = model
+            .iter::<Subscription>()
+            .flatten()
+            .map(|s| (s.server(), s.topic()))
+            .collect();
+        let this = self.clone();
+        self.error_boundary()
+            .spawn(async move { this.notifier().reorder_subscriptions(order).await });
+    }
     fn update_banner(&self, sub: Option<&Subscription>) {
         let imp = self.imp();
         if let Some(sub) = sub {
+            if sub.needs_login() {
+                imp.banner.set_title("This topic requires a login");
+                imp.banner.set_button_label(Some("Log In"));
+            } else if sub.nice_status() == models::Status::Up && sub.expires_soon() {
+                imp.banner
+                    .set_title("This topic's server-side cache expires soon");
+                imp.banner.set_button_label(None);
+            } else {
+                imp.banner.set_title("Reconnecting...");
+                imp.banner.set_button_label(None);
+            }
             match sub.nice_status() {
-                Status::Degraded | Status::Down => imp.banner.set_revealed(true),
-                Status::Up => imp.banner.set_revealed(false),
+                models::Status::Degraded | models::Status::Down => imp.banner.set_revealed(true),
+                models::Status::Up => imp.banner.set_revealed(sub.expires_soon()),
             }
         } else {
             imp.banner.set_revealed(false);
         }
     }
+    fn local_date(time: u64) -> Option<chrono::NaiveDate> {
+        chrono::DateTime::from_timestamp(time as i64, 0)
+            .map(|t| t.with_timezone(&chrono::Local).date_naive())
+    }
+    fn day_label(date: chrono::NaiveDate) -> String {
+        let today = chrono::Local::now().date_naive();
+        if date == today {
+            "Today".to_string()
+        } else if today.pred_opt() == Some(date) {
+            "Yesterday".to_string()
+        } else if date.year() == today.year() {
+            date.format("%A, %B %-d").to_string()
+        } else {
+            date.format("%B %-d, %Y").to_string()
+        }
+    }
+    // Shows a separator above the first message of each day.
+    // The list box calls this again for the neighbours of each new row.
+    fn update_message_header(&self, row: &gtk::ListBoxRow, before: Option<&gtk::ListBoxRow>) {
+        let day = Self::day_header(row, before);
+        let group = row
+            .child()
+            .and_downcast::<MessageRow>()
+            .and_then(|m| self.group_header(&m));
+        let header = match (day, group) {
+            (None, None) => None,
+            (Some(w), None) | (None, Some(w)) => Some(w),
+            (Some(day), Some(group)) => {
+                let b = gtk::Box::new(gtk::Orientation::Vertical, 0);
+                b.append(&day);
+                b.append(&group);
+                Some(b.upcast())
+            }
+        };
+        row.set_header(header.as_ref());
+    }
+    fn day_header(row: &gtk::ListBoxRow, before: Option<&gtk::ListBoxRow>) -> Option<gtk::Widget> {
+        let date_of = |row: &gtk::ListBoxRow| {
+            row.child()
+                .and_downcast::<MessageRow>()
+                .and_then(|m| Self::local_date(m.time()))
+        };
+        let date = date_of(row)?;
+        if before.and_then(date_of) == Some(date) {
+            return None;
+        }
+        let label = gtk::Label::builder()
+            .label(Self::day_label(date))
+            .xalign(0.0)
+            .margin_top(12)
+            .margin_start(8)
+            .margin_bottom(4)
+            .build();
+        label.add_css_class("heading");
+        label.add_css_class("dim-label");
+        Some(label.upcast())
+    }
+    // The toggle of a group, above its newest message
+    fn group_header(&self, row: &MessageRow) -> Option<gtk::Widget> {
+        let imp = self.imp();
+        let prefix = imp.group_prefix.borrow();
+        let key = row.group_key(&prefix)?;
+        let groups = imp.message_groups.borrow();
+        let group = groups.get(key)?;
+        if group.len < 2 || group.newest != row.id() {
+            return None;
+        }
+        let expanded = imp.expanded_groups.borrow().contains(key);
+        let content = adw::ButtonContent::builder()
+            .icon_name(if expanded {
+                "pan-down-symbolic"
+            } else {
+                "pan-end-symbolic"
+            })
+            .label(format!("{key} · {} messages", group.len))
+            .build();
+        let btn = gtk::Button::builder()
+            .child(&content)
+            .halign(gtk::Align::Start)
+            .margin_start(8)
+            .margin_top(4)
+            .tooltip_text(if expanded {
+                "Show Only the Newest"
+            } else {
+                "Show All Messages"
+            })
+            .build();
+        btn.add_css_class("flat");
+        let this = self.clone();
+        let key = key.to_string();
+        btn.connect_clicked(move |_| this.toggle_message_group(&key));
+        Some(btn.upcast())
+    }
+    // Only the newest message of a collapsed group is shown
+    fn collapsed_in_group(&self, row: &MessageRow) -> bool {
+        let imp = self.imp();
+        let prefix = imp.group_prefix.borrow();
+        let Some(key) = row.group_key(&prefix) else {
+            return false;
+        };
+        !imp.expanded_groups.borrow().contains(key)
+            && imp
+                .message_groups
+                .borrow()
+                .get(key)
+                .is_some_and(|g| g.newest != row.id())
+    }
+    fn toggle_message_group(&self, key: &str) {
+        let imp = self.imp();
+        {
+            let mut expanded = imp.expanded_groups.borrow_mut();
+            if !expanded.remove(key) {
+                expanded.insert(key.to_string());
+            }
+        }
+        imp.message_list.invalidate_filter();
+        imp.message_list.invalidate_headers();
+    }
+    fn expand_message_group(&self, msg: &models::ReceivedMessage) {
+        let imp = self.imp();
+        let Some(key) =
+            models::group_key(&msg.tags, &imp.group_prefix.borrow()).map(str::to_string)
+        else {
+            return;
+        };
+        if imp.expanded_groups.borrow_mut().insert(key) {
+            imp.message_list.invalidate_filter();
+            imp.message_list.invalidate_headers();
+        }
+    }
+    fn update_message_groups(&self, sub: Option<&Subscription>) {
+        let imp = self.imp();
+        let prefix = imp.settings.string("message-group-tag-prefix").to_string();
+        // Grouping is disabled, don't refilter the list every time a message arrives
+        if prefix.is_empty() && imp.message_groups.borrow().is_empty() {
+            imp.group_prefix.replace(prefix);
+            return;
+        }
+        let mut groups = HashMap::<String, MessageGroup>::new();
+        if let Some(sub) = sub {
+            // The messages are sorted from the oldest
+            for obj in sub.imp().messages.iter::<glib::BoxedAnyObject>().flatten() {
+                let msg = obj.borrow::<models::ReceivedMessage>();
+                if let Some(key) = models::group_key(&msg.tags, &prefix) {
+                    let group = groups.entry(key.to_string()).or_default();
+                    group.newest = msg.id.clone();
+                    group.len += 1;
+                }
+            }
+        }
+        imp.group_prefix.replace(prefix);
+        imp.message_groups.replace(groups);
+        imp.message_list.invalidate_filter();
+        imp.message_list.invalidate_headers();
+    }
+    // The rows are built again with the chosen fields
+    fn bind_row_fields(&self) {
+        for key in [
+            "show-message-time",
+            "show-message-priority",
+            "show-message-tags",
+            "show-message-actions",
+        ] {
+            let this = self.clone();
+            self.imp().settings.connect_changed(Some(key), move |_, _| {
+                this.selected_subscription_changed(this.selected_subscription().as_ref());
+            });
+        }
+    }
+    fn bind_message_groups(&self) {
+        let this = self.clone();
+        self.imp()
+            .settings
+            .connect_changed(Some("message-group-tag-prefix"), move |_, _| {
+                this.update_message_groups(this.selected_subscription().as_ref());
+            });
+    }
     fn selected_subscription_changed(&self, sub: Option<&Subscription>) {
         let imp = self.imp();
         self.update_banner(sub);
@@ -350,31 +1369,77 @@ impl NotifyWindow {
         let set_sensitive = move |b| {
             let imp = this.imp();
             imp.subscription_menu_btn.set_sensitive(b);
+            this.action_set_enabled("win.refresh-subscription", b);
+            this.action_set_enabled("win.filter-messages", b);
+            imp.message_search_btn.set_sensitive(b);
             imp.code_btn.set_sensitive(b);
+            imp.priority_dropdown.set_sensitive(b);
+            imp.markdown_btn.set_sensitive(b);
             imp.send_btn.set_sensitive(b);
             imp.entry.set_sensitive(b);
         };
-        if let Some((sub, id)) = imp.banner_binding.take() {
-            sub.disconnect(id);
+        if let Some((sub, ids)) = imp.banner_binding.take() {
+            for id in ids {
+                sub.disconnect(id);
+            }
+        }
+        if let Some((messages, id)) = imp.messages_binding.take() {
+            messages.disconnect(id);
         }
+        self.update_message_stack(sub);
+        imp.refresh_stack.set_visible_child_name("button");
+        // The filter is for the messages of a single subscription
+        imp.message_search_entry.set_text("");
+        imp.message_search_bar.set_search_mode(false);
+        imp.expanded_groups.borrow_mut().clear();
         if let Some(sub) = sub {
             set_sensitive(true);
             imp.navigation_split_view.set_show_content(true);
+            self.show_publish_priority(sub.publish_priority());
+            let settings = imp.settings.clone();
+            let code_messages = sub.code_messages();
             imp.message_list
                 .bind_model(Some(&sub.imp().messages), move |obj| {
+                    let relative_times = settings.boolean("relative-timestamps");
+                    let emoji_tags = settings.boolean("list-emoji-tags");
+                    let other_tags = settings.boolean("list-other-tags");
+                    let fields = RowFields::from_settings(&settings);
                     let b = obj.downcast_ref::<glib::BoxedAnyObject>().unwrap();
                     let msg = b.borrow::<models::ReceivedMessage>();
 
-                    MessageRow::new(msg.clone()).upcast()
+                    let row = MessageRow::new(msg.clone(), code_messages, fields);
+                    row.update_time(relative_times);
+                    row.update_tags(emoji_tags, other_tags);
+                    row.upcast()
                 });
 
             let this = self.clone();
+            let status_id = sub.connect_status_notify(move |sub| {
+                this.update_banner(Some(sub));
+                this.imp().refresh_stack.set_visible_child_name("button");
+            });
+            let this = self.clone();
+            let expires_id = sub.connect_expires_notify(move |sub| {
+                this.update_banner(Some(sub));
+            });
+            // The rows are built again with the new style
+            let this = self.clone();
+            let code_messages_id = sub.connect_code_messages_notify(move |sub| {
+                this.selected_subscription_changed(Some(sub));
+            });
             imp.banner_binding.set(Some((
                 sub.clone(),
-                sub.connect_status_notify(move |sub| {
-                    this.update_banner(Some(sub));
-                }),
+                vec![status_id, expires_id, code_messages_id],
             )));
+            let this = self.clone();
+            let subc = sub.clone();
+            let messages_id = sub.imp().messages.connect_items_changed(move |_, _, _, _| {
+                this.update_message_stack(Some(&subc));
+                this.update_message_groups(Some(&subc));
+            });
+            imp.messages_binding
+                .set(Some((sub.imp().messages.clone(), messages_id)));
+            self.update_message_groups(Some(sub));
 
             let this = self.clone();
             glib::idle_add_local_once(move || {
@@ -384,20 +1449,106 @@ impl NotifyWindow {
             set_sensitive(false);
             imp.message_list
                 .bind_model(gio::ListModel::NONE, |_| adw::Bin::new().into());
+            self.update_message_groups(None);
         }
     }
-    fn flag_read(&self) {
-        let vadj = self.imp().message_scroll.vadjustment();
-        // There is nothing to scroll, so the user viewed all the messages
-        if vadj.page_size() == vadj.upper()
-            || ((vadj.page_size() + vadj.value() - vadj.upper()).abs() <= 1.0)
-        {
-            self.selected_subscription().map(|sub| {
-                self.error_boundary()
-                    .spawn(async move { sub.flag_all_as_read().await });
+    fn update_message_stack(&self, sub: Option<&Subscription>) {
+        let stack = &self.imp().message_stack;
+        match sub {
+            None => stack.set_visible_child_name("no-selection"),
+            Some(sub) if sub.imp().messages.n_items() == 0 => stack.set_visible_child_name("empty"),
+            Some(_) => stack.set_visible_child_name("messages"),
+        }
+    }
+    // A single timer updates the times of every loaded message
+    fn bind_relative_times(&self) {
+        let this = self.clone();
+        self.imp()
+            .settings
+            .connect_changed(Some("relative-timestamps"), move |_, _| {
+                this.update_message_times()
             });
+        let this = self.downgrade();
+        glib::timeout_add_local(RELATIVE_TIME_REFRESH, move || {
+            let Some(this) = this.upgrade() else {
+                return glib::ControlFlow::Break;
+            };
+            if this.imp().settings.boolean("relative-timestamps") {
+                this.update_message_times();
+            }
+            glib::ControlFlow::Continue
+        });
+    }
+    fn update_message_times(&self) {
+        let relative = self.imp().settings.boolean("relative-timestamps");
+        self.for_each_message_row(|m| m.update_time(relative));
+    }
+    fn bind_tag_listing(&self) {
+        for key in ["list-emoji-tags", "list-other-tags"] {
+            let this = self.clone();
+            self.imp()
+                .settings
+                .connect_changed(Some(key), move |settings, _| {
+                    let emoji = settings.boolean("list-emoji-tags");
+                    let other = settings.boolean("list-other-tags");
+                    this.for_each_message_row(|m| m.update_tags(emoji, other));
+                });
+        }
+    }
+    // Only the loaded rows
+    fn for_each_message_row(&self, f: impl Fn(&MessageRow)) {
+        let mut child = self.imp().message_list.first_child();
+        while let Some(row) = child {
+            if let Some(m) = row
+                .downcast_ref::<gtk::ListBoxRow>()
+                .and_then(|r| r.child())
+                .and_downcast::<MessageRow>()
+            {
+                f(&m);
+            }
+            child = row.next_sibling();
+        }
+    }
+    // Flags the messages as read only if the user could actually see them
+    fn flag_read(&self) {
+        let imp = self.imp();
+        // Nothing is seen while the window is in the background or the list isn't shown
+        if !self.is_active() || !imp.message_list.is_mapped() {
+            return;
+        }
+        let Some(sub) = self.selected_subscription() else {
+            return;
+        };
+        let vadj = imp.message_scroll.vadjustment();
+        let seen = if vadj.upper() <= vadj.page_size() {
+            // Nothing to scroll because the list is short. It's all visible only once it's laid out.
+            self.newest_message_visible(&sub)
+        } else {
+            // Scrolled to the bottom
+            (vadj.page_size() + vadj.value() - vadj.upper()).abs() <= 1.0
+        };
+        if seen {
+            self.error_boundary()
+                .spawn(async move { sub.flag_all_as_read().await });
         }
     }
+    fn newest_message_visible(&self, sub: &Subscription) -> bool {
+        let imp = self.imp();
+        let Some(row) = sub
+            .imp()
+            .messages
+            .n_items()
+            .checked_sub(1)
+            .and_then(|i| imp.message_list.row_at_index(i as i32))
+        else {
+            return false;
+        };
+        let Some(bounds) = row.compute_bounds(&*imp.message_scroll) else {
+            return false;
+        };
+        let height = imp.message_scroll.height() as f32;
+        bounds.height() > 0.0 && bounds.y() < height && bounds.y() + bounds.height() > 0.0
+    }
     fn build_chip(text: &str) -> gtk::Label {
         let chip = gtk::Label::new(Some(text));
         chip.add_css_class("chip");
@@ -435,11 +1586,18 @@ impl NotifyWindow {
             counter_chip_clone.set_visible(c > 0);
         });
 
+        let avatar = gtk::Image::builder().pixel_size(24).visible(false).build();
+        Self::update_avatar(&avatar, sub);
+        let avatar_clone = avatar.clone();
+        sub.connect_symbolic_icon_notify(move |sub| Self::update_avatar(&avatar_clone, sub));
+        let avatar_clone = avatar.clone();
+        sub.connect_sender_icon_notify(move |sub| Self::update_avatar(&avatar_clone, sub));
+
         let status_chip = Self::build_chip("Degraded");
         let status_chip_clone = status_chip.clone();
 
         sub.connect_status_notify(move |sub| match sub.nice_status() {
-            Status::Degraded | Status::Down => {
+            models::Status::Degraded | models::Status::Down => {
                 status_chip_clone.add_css_class("chip--degraded");
                 status_chip_clone.set_visible(true);
             }
@@ -449,12 +1607,39 @@ impl NotifyWindow {
         });
 
         b.append(&counter_chip);
+        b.append(&avatar);
         b.append(&label);
         b.append(&status_chip);
 
         b
     }
 
+    // The icon set by the user wins, otherwise the one sent with the latest message is used
+    fn update_avatar(avatar: &gtk::Image, sub: &Subscription) {
+        let symbolic_icon = sub.symbolic_icon();
+        if !symbolic_icon.is_empty() {
+            avatar.set_icon_name(Some(&symbolic_icon));
+            avatar.set_visible(true);
+            return;
+        }
+        let url = sub.sender_icon();
+        if url.is_empty() {
+            avatar.set_visible(false);
+            return;
+        }
+        let avatar = avatar.clone();
+        let sub = sub.clone();
+        glib::spawn_future_local(async move {
+            match sub.fetch_icon(&url).await {
+                Ok(path) => {
+                    avatar.set_from_file(Some(&path));
+                    avatar.set_visible(true);
+                }
+                Err(e) => warn!(error = %e, url, "can't load sender icon"),
+            }
+        });
+    }
+
     fn save_window_size(&self) -> Result<(), glib::BoolError> {
         let imp = self.imp();
 
@@ -483,6 +1668,11 @@ impl NotifyWindow {
                 this.flag_read();
             }
         });
+        // Short lists never reach an edge, check again when they get laid out or grow
+        let this = self.clone();
+        imp.message_scroll.vadjustment().connect_changed(move |_| {
+            this.flag_read();
+        });
     }
 
     fn load_window_size(&self) {